@@ -25,7 +25,7 @@ fn main() -> Result<(), PdfError> {
             Op::LineTo { p: Point { x: 200., y: 200. } },
             Op::Close,
             Op::Stroke,
-        ]);
+        ])?;
         pages.push(PageBuilder::from_content(content));
     }
     let catalog = CatalogBuilder::from_pages(pages)
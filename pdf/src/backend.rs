@@ -50,6 +50,11 @@ pub trait Backend: Sized {
 
     /// Used internally by File, but could also be useful for applications that want to look at the raw PDF objects.
     fn read_xref_table_and_trailer(&self, start_offset: usize) -> Result<(XRefTable, Dictionary)> {
+        self.read_xref_table_and_trailer_limited(start_offset, None)
+    }
+
+    /// Like `read_xref_table_and_trailer`, but fails if the file declares more than `max_objects` objects.
+    fn read_xref_table_and_trailer_limited(&self, start_offset: usize, max_objects: Option<usize>) -> Result<(XRefTable, Dictionary)> {
         let xref_offset = t!(self.locate_xref_offset());
         let mut lexer = Lexer::new(t!(self.read(start_offset + xref_offset..)));
         
@@ -59,6 +64,12 @@ pub trait Backend: Sized {
             .ok_or_else(|| PdfError::MissingEntry {field: "Size".into(), typ: "XRefTable"})?
             .as_integer());
 
+        if let Some(max) = max_objects {
+            if highest_id as ObjNr > max as ObjNr {
+                return Err(PdfError::LimitExceeded { limit: "objects", max });
+            }
+        }
+
         let mut refs = XRefTable::new(highest_id as ObjNr);
         for section in xref_sections {
             refs.add_entries_from(section);
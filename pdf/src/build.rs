@@ -61,6 +61,7 @@ impl CatalogBuilder {
                 crop_box: page.crop_box,
                 trim_box: page.trim_box,
                 resources: None,
+                group: None,
             };
             update.fulfill(promise, PagesNode::Leaf(page))?;
         }
@@ -1,14 +1,14 @@
 /// PDF content streams.
 use std::fmt::{self, Display};
 use std::cmp::Ordering;
-use std::ops::Mul;
+use std::ops::{Mul, Deref, DerefMut};
 use itertools::Itertools;
 
 use crate::error::*;
 use crate::object::*;
 use crate::parser::{Lexer, parse_with_lexer};
 use crate::primitive::*;
-use crate::enc::StreamFilter;
+use crate::enc::{StreamFilter, LZWFlateParams, encode};
 
 /// Represents a PDF content stream - a `Vec` of `Operator`s
 #[derive(Debug, Clone)]
@@ -17,7 +17,48 @@ pub struct Content {
     pub parts: Vec<Stream<()>>,
 
     /// The parsed operations. You probably want to use these.
-    pub operations: Vec<Op>,
+    pub operations: Operations,
+}
+
+/// The operations of a `Content`, which remember whether they were changed.
+///
+/// Any mutable access counts as a change. Operations built from a `Vec` are changed from the start.
+#[derive(Debug, Clone, Default)]
+pub struct Operations {
+    ops: Vec<Op>,
+    modified: bool,
+}
+impl Operations {
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+    pub fn into_vec(self) -> Vec<Op> {
+        self.ops
+    }
+}
+impl Deref for Operations {
+    type Target = Vec<Op>;
+    fn deref(&self) -> &Vec<Op> {
+        &self.ops
+    }
+}
+impl DerefMut for Operations {
+    fn deref_mut(&mut self) -> &mut Vec<Op> {
+        self.modified = true;
+        &mut self.ops
+    }
+}
+impl From<Vec<Op>> for Operations {
+    fn from(ops: Vec<Op>) -> Operations {
+        Operations { ops, modified: true }
+    }
+}
+impl<'a> IntoIterator for &'a Operations {
+    type Item = &'a Op;
+    type IntoIter = std::slice::Iter<'a, Op>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.iter()
+    }
 }
 
 macro_rules! names {
@@ -108,6 +149,36 @@ fn expand_abbr(p: Primitive, alt: &[(&str, &str)]) -> Primitive {
     }
 }
 
+/// Abbreviations of the keys of an inline image dictionary.
+const INLINE_IMAGE_KEYS: &[(&str, &str)] = &[
+    ("BPC", "BitsPerComponent"),
+    ("CS", "ColorSpace"),
+    ("D", "Decode"),
+    ("DP", "DecodeParms"),
+    ("F", "Filter"),
+    ("H", "Height"),
+    ("IM", "ImageMask"),
+    ("I", "Interpolate"),
+    ("W", "Width"),
+];
+/// Abbreviations of color space names in inline images.
+const INLINE_IMAGE_COLOR_SPACES: &[(&str, &str)] = &[
+    ("G", "DeviceGray"),
+    ("RGB", "DeviceRGB"),
+    ("CMYK", "DeviceCMYK"),
+    ("I", "Indexed")
+];
+/// Abbreviations of filter names in inline images.
+const INLINE_IMAGE_FILTERS: &[(&str, &str)] = &[
+    ("AHx", "ASCIIHexDecode"),
+    ("A85", "ASCII85Decode"),
+    ("LZW", "LZWDecode"),
+    ("Fl", "FlateDecode"),
+    ("RL", "RunLengthDecode"),
+    ("CCF", "CCITTFaxDecode"),
+    ("DCT", "DCTDecode"),
+];
+
 fn inline_image(lexer: &mut Lexer, resolve: &impl Resolve) -> Result<Stream<ImageDict>> {
     let mut dict = Dictionary::new();
     loop {
@@ -122,17 +193,7 @@ fn inline_image(lexer: &mut Lexer, resolve: &impl Resolve) -> Result<Stream<Imag
             }
             Ok(_) => bail!("invalid key type")
         };
-        let key = expand_abbr_name(key, &[
-            ("BPC", "BitsPerComponent"),
-            ("CS", "ColorSpace"),
-            ("D", "Decode"),
-            ("DP", "DecodeParms"),
-            ("F", "Filter"),
-            ("H", "Height"),
-            ("IM", "ImageMask"),
-            ("I", "Interpolate"),
-            ("W", "Width"),
-        ]);
+        let key = expand_abbr_name(key, INLINE_IMAGE_KEYS);
         let val = parse_with_lexer(lexer, &NoResolve)?;
         dict.insert(key, val);
     }
@@ -140,48 +201,44 @@ fn inline_image(lexer: &mut Lexer, resolve: &impl Resolve) -> Result<Stream<Imag
     let data_start = lexer.get_pos() + 1;
 
     // ugh
-    let bits_per_component = dict.require("InlineImage", "BitsPerComponent")?.as_integer()?;
-    let color_space = expand_abbr(
-        dict.require("InlineImage", "ColorSpace")?,
-        &[
-            ("G", "DeviceGray"),
-            ("RGB", "DeviceRGB"),
-            ("CMYK", "DeviceCMYK"),
-            ("I", "Indexed")
-        ]
-    );
-    let decode = Object::from_primitive(dict.require("InlineImage", "Decode")?, resolve)?;
-    let decode_parms = dict.require("InlineImage", "DecodeParms")?.into_dictionary(resolve)?;
-    let filter = expand_abbr(
-        dict.require("InlineImage", "Filter")?,
-        &[
-            ("AHx", "ASCIIHexDecode"),
-            ("A85", "ASCII85Decode"),
-            ("LZW", "LZWDecode"),
-            ("Fl", "FlateDecode"),
-            ("RL", "RunLengthDecode"),
-            ("CCF", "CCITTFaxDecode"),
-            ("DCT", "DCTDecode"),
-        ]
-    );
+    let image_mask = dict.remove("ImageMask").map(|p| p.as_bool()).transpose()?.unwrap_or(false);
+    let bits_per_component = match dict.remove("BitsPerComponent") {
+        Some(p) => Some(p.as_integer()?),
+        None if image_mask => None,
+        None => bail!("inline image without BitsPerComponent")
+    };
+    let color_space = dict.remove("ColorSpace").map(|p| expand_abbr(p, INLINE_IMAGE_COLOR_SPACES));
+    let decode = dict.remove("Decode").map(|p| Object::from_primitive(p, resolve)).transpose()?;
+    let decode_parms = dict.remove("DecodeParms").unwrap_or(Primitive::Null);
+    let filter = dict.remove("Filter").map(|p| expand_abbr(p, INLINE_IMAGE_FILTERS));
+    let filter_params = |i: usize| -> Result<Dictionary> {
+        let params = match decode_parms {
+            Primitive::Array(ref parts) => parts.get(i).cloned().unwrap_or(Primitive::Null),
+            ref p => p.clone()
+        };
+        match params {
+            Primitive::Null => Ok(Dictionary::new()),
+            p => p.into_dictionary(resolve)
+        }
+    };
     let filters = match filter {
-        Primitive::Array(parts) => parts.into_iter()
-            .map(|p| p.as_name().and_then(|kind| StreamFilter::from_kind_and_params(kind, decode_parms.clone(), resolve)))
+        Some(Primitive::Array(parts)) => parts.into_iter().enumerate()
+            .map(|(i, p)| StreamFilter::from_kind_and_params(p.as_name()?, filter_params(i)?, resolve))
             .collect::<Result<_>>()?,
-        Primitive::Name(kind) => vec![StreamFilter::from_kind_and_params(&kind, decode_parms, resolve)?],
+        Some(Primitive::Name(kind)) => vec![StreamFilter::from_kind_and_params(&kind, filter_params(0)?, resolve)?],
+        None => vec![],
         _ => bail!("invalid filter")
     };
     
     let height = dict.require("InlineImage", "Height")?.as_integer()?;
-    let image_mask = dict.get("ImageMask").map(|p| p.as_bool()).transpose()?.unwrap_or(false);
     let intent = dict.remove("Intent").map(|p| RenderingIntent::from_primitive(p, &NoResolve)).transpose()?;
-    let interpolate = dict.get("Interpolate").map(|p| p.as_bool()).transpose()?.unwrap_or(false);
+    let interpolate = dict.remove("Interpolate").map(|p| p.as_bool()).transpose()?.unwrap_or(false);
     let width = dict.require("InlineImage", "Width")?.as_integer()?;
 
     let image_dict = ImageDict {
         width,
         height,
-        color_space: Some(color_space),
+        color_space,
         bits_per_component,
         intent,
         image_mask,
@@ -191,16 +248,95 @@ fn inline_image(lexer: &mut Lexer, resolve: &impl Resolve) -> Result<Stream<Imag
         struct_parent: None,
         id: None,
         smask: None,
+        smask_in_data: 0,
+        matte: None,
         other: dict,
     };
 
-    lexer.seek_substr("\nEI").expect("BUGZ");
+    if lexer.seek_substr("\nEI").is_none() {
+        return Err(PdfError::NotFound { word: "EI".into() });
+    }
     let data_end = lexer.get_pos() - 3;
 
     let data = lexer.new_substr(data_start .. data_end).to_vec();
 
     Ok(Stream::new_with_filters(image_dict, data, filters))
 }
+
+fn abbreviate(p: &Primitive, alt: &[(&str, &str)]) -> Primitive {
+    match *p {
+        Primitive::Name(ref name) => match alt.iter().find(|&&(_, full)| full == name) {
+            Some(&(abbr, _)) => Primitive::Name(abbr.into()),
+            None => p.clone()
+        }
+        Primitive::Array(ref items) => Primitive::Array(items.iter().map(|p| abbreviate(p, alt)).collect()),
+        _ => p.clone()
+    }
+}
+
+/// Write an inline image, from `BI` to `EI`, using the abbreviated keys and names.
+fn serialize_inline_image(image: &Stream<ImageDict>, f: &mut impl std::io::Write) -> Result<()> {
+    let dict = &image.info.info;
+    writeln!(f, "BI")?;
+    writeln!(f, "/W {} /H {}", dict.width, dict.height)?;
+    if dict.image_mask {
+        writeln!(f, "/IM true")?;
+    }
+    match dict.bits_per_component {
+        Some(1) | None if dict.image_mask => {}
+        Some(bpc) => writeln!(f, "/BPC {}", bpc)?,
+        None => bail!("inline image without BitsPerComponent")
+    }
+    if let Some(ref cs) = dict.color_space {
+        write!(f, "/CS ")?;
+        abbreviate(cs, INLINE_IMAGE_COLOR_SPACES).serialize(f, 0)?;
+        writeln!(f)?;
+    }
+    if let Some(ref decode) = dict.decode {
+        writeln!(f, "/D [{}]", decode.iter().format(" "))?;
+    }
+    if let Some(intent) = dict.intent {
+        writeln!(f, "/Intent /{}", intent.to_str())?;
+    }
+    if dict.interpolate {
+        writeln!(f, "/I true")?;
+    }
+
+    let filters = &image.info.filters;
+    if !filters.is_empty() {
+        let names: Vec<Primitive> = filters.iter()
+            .map(|filter| abbreviate(&Primitive::Name(filter.name().into()), INLINE_IMAGE_FILTERS))
+            .collect();
+        let params = filters.iter()
+            .map(|filter| filter.params(&mut NoUpdate))
+            .collect::<Result<Vec<_>>>()?;
+        write!(f, "/F ")?;
+        match names.len() {
+            1 => names[0].serialize(f, 0)?,
+            _ => Primitive::Array(names).serialize(f, 0)?,
+        }
+        writeln!(f)?;
+        if params.iter().any(Option::is_some) {
+            write!(f, "/DP ")?;
+            match params.len() {
+                1 => params[0].clone().unwrap().serialize(f, 0)?,
+                _ => Primitive::Array(params.into_iter().map(|p| p.unwrap_or(Primitive::Null)).collect()).serialize(f, 0)?,
+            }
+            writeln!(f)?;
+        }
+    }
+    for (key, val) in dict.other.iter() {
+        serialize_name(key, f)?;
+        write!(f, " ")?;
+        val.serialize(f, 0)?;
+        writeln!(f)?;
+    }
+    write!(f, "ID ")?;
+    f.write_all(image.raw_data())?;
+    writeln!(f, "\nEI")?;
+    Ok(())
+}
+
 struct OpBuilder {
     last: Point,
     compability_section: bool,
@@ -235,6 +371,9 @@ impl OpBuilder {
                     let op = t!(lexer.next());
                     let operator = t!(op.as_str());
                     t!(self.add(operator, buffer.drain(..), &mut lexer, resolve));
+                    if let Some(limits) = resolve.limits() {
+                        limits.check_operators(self.ops.len())?;
+                    }
                 }
             }
             match lexer.get_pos().cmp(&data.len()) {
@@ -272,7 +411,10 @@ impl OpBuilder {
                 properties: None
             }),
             "BT"  => push(Op::BeginText),
-            "BX"  => self.compability_section = true,
+            "BX"  => {
+                push(Op::BeginCompatibility);
+                self.compability_section = true;
+            }
             "c"   => {
                 points!(args, c1, c2, p);
                 push(Op::CurveTo { c1, c2, p });
@@ -296,8 +438,15 @@ impl OpBuilder {
                 let phase = args.next().ok_or(PdfError::NoOpArg)?.as_number()?;
                 push(Op::Dash { pattern, phase });
             }
-            "d0"  => {}
-            "d1"  => {}
+            "d0"  => {
+                numbers!(args, wx, wy);
+                push(Op::CharWidth { wx, wy });
+            }
+            "d1"  => {
+                numbers!(args, wx, wy);
+                points!(args, ll, ur);
+                push(Op::CacheDevice { wx, wy, ll, ur });
+            }
             "Do"  => {
                 names!(args, name);
                 push(Op::XObject { name });
@@ -309,7 +458,10 @@ impl OpBuilder {
             "EI"  => bail!("Parse Error. Unexpected 'EI'"),
             "EMC" => push(Op::EndMarkedContent),
             "ET"  => push(Op::EndText),
-            "EX"  => self.compability_section = false,
+            "EX"  => {
+                push(Op::EndCompatibility);
+                self.compability_section = false;
+            }
             "f" |
             "F"   => push(Op::Fill { winding: NonZero }),
             "f*"  => push(Op::Fill { winding: EvenOdd }),
@@ -382,9 +534,7 @@ impl OpBuilder {
             "sc" | "scn" => {
                 push(Op::FillColor { color: Color::Other(args.collect()) });
             }
-            "sh"  => {
-
-            }
+            "sh"  => push(Op::Shade { name: name(&mut args)? }),
             "T*"  => push(Op::TextNewline),
             "Tc"  => push(Op::CharSpacing { char_space: number(&mut args)? }),
             "Td"  => push(Op::MoveTextPosition { translation: point(&mut args)? }),
@@ -459,7 +609,7 @@ impl OpBuilder {
             o if !self.compability_section => {
                 bail!("invalid operator {}", o)
             },
-            _ => {}
+            o => push(Op::Unknown { op: o.into(), args: args.collect() }),
         }
         Ok(())
     }
@@ -490,7 +640,7 @@ impl Object for Content {
             }
         }
 
-        Ok(Content { operations: ops.ops, parts })
+        Ok(Content { parts, operations: Operations { ops: ops.ops, modified: false } })
     }
 }
 
@@ -516,8 +666,42 @@ impl Object for FormXObject {
         })
     }
 }
+impl ObjectWrite for FormXObject {
+    /// Writes `stream` as is, `operations` are not serialized.
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        self.stream.to_primitive(update)
+    }
+}
 
 
+/// A tiling pattern, see `Pattern`.
+#[derive(Debug)]
+pub struct TilingPattern {
+    pub operations: Vec<Op>,
+    pub stream: Stream<TilingPatternDict>,
+}
+impl TilingPattern {
+    /// Create a pattern cell painted by `operations`.
+    pub fn from_ops(dict: TilingPatternDict, operations: Vec<Op>) -> Result<Self> {
+        let data = serialize_ops(&operations)?;
+        Ok(TilingPattern { operations, stream: Stream::new(dict, data) })
+    }
+    pub fn dict(&self) -> &TilingPatternDict {
+        &self.stream.info.info
+    }
+}
+impl Object for TilingPattern {
+    /// Convert primitive to Self
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let stream = t!(Stream::<TilingPatternDict>::from_primitive(p, resolve));
+        let mut ops = OpBuilder::new();
+        ops.parse(stream.data()?, resolve)?;
+        Ok(TilingPattern {
+            stream,
+            operations: ops.ops
+        })
+    }
+}
 fn serialize_ops(mut ops: &[Op]) -> Result<Vec<u8>> {
     use Op::*;
     use std::io::Write;
@@ -579,7 +763,7 @@ fn serialize_ops(mut ops: &[Op]) -> Result<Vec<u8>> {
                 } else if c2 == p {
                     writeln!(f, "{} {} y", c1, p)?;
                 } else {
-                    writeln!(f, "{} {} {} y", c1, c2, p)?;
+                    writeln!(f, "{} {} {} c", c1, c2, p)?;
                 }
                 current_point = Some(p);
             },
@@ -600,7 +784,7 @@ fn serialize_ops(mut ops: &[Op]) -> Result<Vec<u8>> {
             Restore => writeln!(f, "Q")?,
             Transform { matrix } => writeln!(f, "{} cm", matrix)?,
             LineWidth { width } => writeln!(f, "{} w", width)?,
-            Dash { ref pattern, phase } => write!(f, "[{}] {} d", pattern.iter().format(" "), phase)?,
+            Dash { ref pattern, phase } => writeln!(f, "[{}] {} d", pattern.iter().format(" "), phase)?,
             LineJoin { join } => writeln!(f, "{} j", join as u8)?,
             LineCap { cap } => writeln!(f, "{} J", cap as u8)?,
             MiterLimit { limit } => writeln!(f, "{} M", limit)?,
@@ -659,7 +843,7 @@ fn serialize_ops(mut ops: &[Op]) -> Result<Vec<u8>> {
             }
             TextScaling { horiz_scale } => writeln!(f, "{} Tz", horiz_scale)?,
             Leading { leading } => match ops[1..] {
-                [Op::MoveTextPosition { translation }, ..] if leading == -translation.x => {
+                [Op::MoveTextPosition { translation }, ..] if leading == -translation.y => {
                     writeln!(f, "{} {} TD", translation.x, translation.y)?;
                     advance += 1;
                 }
@@ -691,7 +875,18 @@ fn serialize_ops(mut ops: &[Op]) -> Result<Vec<u8>> {
             TextDrawAdjusted { ref array } => {
                 writeln!(f, "[{}] TJ", array.iter().format(" "))?;
             },
-            InlineImage { ref image } => unimplemented!(),
+            InlineImage { ref image } => serialize_inline_image(image, f)?,
+            CharWidth { wx, wy } => writeln!(f, "{} {} d0", wx, wy)?,
+            CacheDevice { wx, wy, ll, ur } => writeln!(f, "{} {} {} {} d1", wx, wy, ll, ur)?,
+            BeginCompatibility => writeln!(f, "BX")?,
+            EndCompatibility => writeln!(f, "EX")?,
+            Unknown { ref op, ref args } => {
+                for p in args {
+                    p.serialize(f, 0)?;
+                    write!(f, " ")?;
+                }
+                writeln!(f, "{}", op)?;
+            }
             XObject { ref name } => {
                 serialize_name(name, f)?;
                 writeln!(f, " Do")?;
@@ -703,18 +898,39 @@ fn serialize_ops(mut ops: &[Op]) -> Result<Vec<u8>> {
 }
 
 impl Content {
-    pub fn from_ops(operations: Vec<Op>) -> Self {
-        let data = serialize_ops(&operations).unwrap();
-        Content {
-            operations,
+    pub fn from_ops(operations: Vec<Op>) -> Result<Self> {
+        let data = serialize_ops(&operations)?;
+        Ok(Content {
+            operations: Operations { ops: operations, modified: false },
             parts: vec![Stream::new((), data)]
-        }
+        })
+    }
+
+    /// Whether `operations` were changed since they were parsed from `parts`.
+    pub fn is_modified(&self) -> bool {
+        self.operations.is_modified()
     }
 }
 
 impl ObjectWrite for Content {
+    /// Writes the original `parts` if the operations are unchanged. Otherwise the operations are
+    /// serialized and encoded with the filters of the first part, or with `FlateDecode` if
+    /// those can't be used for encoding.
     fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
-        if self.parts.len() == 1 {
+        if self.is_modified() {
+            let data = serialize_ops(&self.operations)?;
+            let filters = self.parts.first().map(|part| part.info.filters.clone()).unwrap_or_default();
+            let encoded = filters.iter().rev().try_fold(data.clone(), |data, filter| encode(&data, filter));
+            let (data, filters) = match encoded {
+                Ok(encoded) => (encoded, filters),
+                Err(e) => {
+                    warn!("can't encode content with {:?}, using FlateDecode: {}", filters, e);
+                    let filter = StreamFilter::FlateDecode(LZWFlateParams::default());
+                    (encode(&data, &filter)?, vec![filter])
+                }
+            };
+            Stream::new_with_filters((), data, filters).to_primitive(update)
+        } else if self.parts.len() == 1 {
             self.parts[0].to_primitive(update)
         } else {
             self.parts.to_primitive(update)
@@ -807,6 +1023,44 @@ impl Default for Matrix {
         }
     }
 }
+impl Matrix {
+    /// Map `p` with this matrix.
+    pub fn transform(&self, p: Point) -> Point {
+        Point {
+            x: self.a * p.x + self.c * p.y + self.e,
+            y: self.b * p.x + self.d * p.y + self.f,
+        }
+    }
+    /// The inverse matrix, if this one is not singular.
+    pub fn inverse(&self) -> Option<Matrix> {
+        let det = self.a * self.d - self.b * self.c;
+        if det == 0. || !det.is_finite() {
+            return None;
+        }
+        Some(Matrix {
+            a: self.d / det,
+            b: -self.b / det,
+            c: -self.c / det,
+            d: self.a / det,
+            e: (self.c * self.f - self.d * self.e) / det,
+            f: (self.b * self.e - self.a * self.f) / det,
+        })
+    }
+}
+impl Object for Matrix {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let m = Vec::<f32>::from_primitive(p, resolve)?;
+        match m[..] {
+            [a, b, c, d, e, f] => Ok(Matrix { a, b, c, d, e, f }),
+            _ => bail!("invalid matrix {:?}", m)
+        }
+    }
+}
+impl ObjectWrite for Matrix {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        vec![self.a, self.b, self.c, self.d, self.e, self.f].to_primitive(update)
+    }
+}
 impl Mul for Matrix {
     type Output = Matrix;
 
@@ -840,6 +1094,29 @@ pub enum Color {
     Cmyk(Cmyk),
     Other(Vec<Primitive>),
 }
+impl Color {
+    /// Convert the colour to sRGB.
+    ///
+    /// `Gray`, `Rgb` and `Cmyk` are in their device colour spaces. The components of `Other`
+    /// (set by `sc`/`scn`) are in `color_space`, the current colour space, which a pattern
+    /// name at the end is ignored for. Coloured patterns have no RGB value.
+    pub fn to_rgb(&self, color_space: &ColorSpace) -> Result<Rgb> {
+        let [red, green, blue] = match *self {
+            Color::Gray(g) => ColorSpace::DeviceGray.to_rgb(&[g])?,
+            Color::Rgb(rgb) => return Ok(rgb),
+            Color::Cmyk(Cmyk { cyan, magenta, yellow, key }) => ColorSpace::DeviceCMYK.to_rgb(&[cyan, magenta, yellow, key])?,
+            Color::Other(ref args) => {
+                let args = match args.split_last() {
+                    Some((&Primitive::Name(_), rest)) => rest,
+                    _ => &args[..]
+                };
+                let c = args.iter().map(|p| p.as_number()).collect::<Result<Vec<f32>>>()?;
+                color_space.to_rgb(&c)?
+            }
+        };
+        Ok(Rgb { red, green, blue })
+    }
+}
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TextMode {
@@ -993,4 +1270,120 @@ pub enum Op {
     XObject { name: String },
 
     InlineImage { image: Stream::<ImageDict> },
-}
\ No newline at end of file
+
+    /// Set the glyph width of a Type 3 glyph that specifies its own color.
+    ///
+    /// operator: `d0`
+    CharWidth { wx: f32, wy: f32 },
+
+    /// Set the glyph width and bounding box of a Type 3 glyph that may be cached.
+    ///
+    /// `ll` and `ur` are the lower left and upper right corners of the bounding box.
+    ///
+    /// operator: `d1`
+    CacheDevice { wx: f32, wy: f32, ll: Point, ur: Point },
+
+    /// Begin a compatibility section, in which unrecognized operators are not an error.
+    ///
+    /// operator: `BX`
+    BeginCompatibility,
+
+    /// End a compatibility section.
+    ///
+    /// operator: `EX`
+    EndCompatibility,
+
+    /// An unrecognized operator inside a compatibility section, with its operands.
+    Unknown { op: String, args: Vec<Primitive> },
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ops(data: &[u8]) -> Vec<Op> {
+        let mut ops = OpBuilder::new();
+        ops.parse(data, &NoResolve).unwrap();
+        ops.ops
+    }
+
+    #[test]
+    fn round_trip_ops() {
+        let data = b"1000 0 0 0 750 750 d1\n/Sh1 sh\nBX /Foo 1 (bar) xyz EX\n10 20 m 1 2 3 4 5 6 c [3 1] 0 d";
+        let ops = parse_ops(data);
+        assert!(matches!(ops[0], Op::CacheDevice { wx, .. } if wx == 1000.));
+        assert!(matches!(ops[1], Op::Shade { ref name } if name == "Sh1"));
+        assert!(matches!(ops[3], Op::Unknown { ref op, ref args } if op == "xyz" && args.len() == 3));
+
+        let serialized = serialize_ops(&ops).unwrap();
+        assert_eq!(format!("{:?}", parse_ops(&serialized)), format!("{:?}", ops));
+
+        assert!(matches!(parse_ops(b"0 0 d0")[..], [Op::CharWidth { .. }]));
+    }
+
+    #[test]
+    fn keep_original_parts() {
+        use crate::enc::LZWFlateParams;
+
+        let source = b"1  0 0 1 0 0 cm % comment\n";
+        let filters = vec![StreamFilter::FlateDecode(LZWFlateParams::default())];
+        let encoded = encode(source, &filters[0]).unwrap();
+        let p = Stream::new_with_filters((), encoded.clone(), filters).to_primitive(&mut NoUpdate).unwrap();
+        let mut content = Content::from_primitive(p, &NoResolve).unwrap();
+        assert_eq!(content.operations.iter().count(), 1);
+        assert!(!content.is_modified());
+        let written = content.to_primitive(&mut NoUpdate).unwrap().into_stream(&NoResolve).unwrap();
+        assert_eq!(written.data, encoded);
+
+        content.operations.push(Op::Save);
+        assert!(content.is_modified());
+        let written = content.to_primitive(&mut NoUpdate).unwrap();
+        let written = Stream::<()>::from_primitive(written, &NoResolve).unwrap();
+        assert!(matches!(written.info.filters[..], [StreamFilter::FlateDecode(_)]));
+        assert_eq!(written.data().unwrap(), &serialize_ops(&content.operations).unwrap()[..]);
+
+        // JPX can't be encoded, so the modified content falls back to FlateDecode
+        let p = Stream::new_with_filters((), vec![], vec![StreamFilter::JPXDecode]).to_primitive(&mut NoUpdate).unwrap();
+        let content = Content { parts: vec![Stream::from_primitive(p, &NoResolve).unwrap()], operations: vec![Op::Save].into() };
+        let written = content.to_primitive(&mut NoUpdate).unwrap();
+        let written = Stream::<()>::from_primitive(written, &NoResolve).unwrap();
+        assert!(matches!(written.info.filters[..], [StreamFilter::FlateDecode(_)]));
+        assert_eq!(written.data().unwrap(), b"q\n");
+    }
+
+    #[test]
+    fn round_trip_inline_image() {
+        let data = b"q BI /W 2 /H 1 /BPC 8 /CS /G /F [/AHx] /DP [null] ID 00ff>\nEI Q";
+        let ops = parse_ops(data);
+        let serialized = serialize_ops(&ops).unwrap();
+        let reparsed = parse_ops(&serialized);
+        assert_eq!(format!("{:?}", reparsed), format!("{:?}", ops));
+        match reparsed[1] {
+            Op::InlineImage { ref image } => {
+                assert!(matches!(image.info.info.color_space, Some(Primitive::Name(ref cs)) if cs == "DeviceGray"));
+                assert_eq!(image.data().unwrap(), &[0x00, 0xff]);
+            }
+            ref op => panic!("expected an inline image, found {:?}", op)
+        }
+
+        // stencil masks don't need a bit depth
+        let ops = parse_ops(b"BI /W 8 /H 1 /IM true ID \xf0\nEI");
+        match ops[0] {
+            Op::InlineImage { ref image } => assert_eq!(image.info.info.bits_per_component, None),
+            ref op => panic!("expected an inline image, found {:?}", op)
+        }
+        let serialized = serialize_ops(&ops).unwrap();
+        assert!(!String::from_utf8_lossy(&serialized).contains("/BPC"));
+        assert_eq!(format!("{:?}", parse_ops(&serialized)), format!("{:?}", ops));
+
+        // without the stencil flag, the missing bit depth can't be serialized
+        let mut ops = ops;
+        if let Op::InlineImage { ref mut image } = ops[0] {
+            image.info.info.image_mask = false;
+        }
+        assert!(Content::from_ops(ops).is_err());
+
+        let mut ops = OpBuilder::new();
+        assert!(ops.parse(b"BI /W 1 /H 1 /BPC 8 /CS /G ID \x00", &NoResolve).is_err());
+        assert!(OpBuilder::new().parse(b"BI /W 1 /H 1 /CS /G ID \x00\nEI", &NoResolve).is_err());
+    }
+}
@@ -1,26 +1,39 @@
 use itertools::Itertools;
-use inflate::{inflate_bytes_zlib, inflate_bytes};
+use inflate::InflateStream;
 use deflate::deflate_bytes;
 
 use crate as pdf;
 use crate::error::*;
-use crate::object::{Object, Resolve};
+use crate::object::{Object, ObjectWrite, Resolve, Updater, Ref, Stream};
 use crate::primitive::{Primitive, Dictionary};
 use std::convert::TryInto;
+use std::borrow::Cow;
 
+mod mq;
+mod jbig2;
+mod reader;
+pub mod jpx;
 
+pub use self::reader::DecodeReader;
+
+
+/// Parameters of the `LZWDecode` and `FlateDecode` filters.
 #[derive(Object, ObjectWrite, Debug, Clone)]
 pub struct LZWFlateParams {
+    /// 1 for no prediction, 2 for TIFF predictor 2, 10 to 14 for a fixed PNG row filter
+    /// and 15 for PNG row filters chosen per row.
     #[pdf(key="Predictor", default="1")]
-    predictor: i32,
+    pub predictor: i32,
+    /// Interleaved colour components per sample.
     #[pdf(key="Colors", default="1")]
-    n_components: i32,
+    pub n_components: i32,
     #[pdf(key="BitsPerComponent", default="8")]
-    bits_per_component: i32,
+    pub bits_per_component: i32,
+    /// Samples per row.
     #[pdf(key="Columns", default="1")]
-    columns: i32,
+    pub columns: i32,
     #[pdf(key="EarlyChange", default="1")]
-    early_change: i32,
+    pub early_change: i32,
 }
 impl Default for LZWFlateParams {
     fn default() -> LZWFlateParams {
@@ -34,7 +47,7 @@ impl Default for LZWFlateParams {
     }
 }
 
-#[derive(Object, ObjectWrite, Debug, Clone)]
+#[derive(Object, ObjectWrite, Debug, Clone, Default)]
 pub struct DCTDecodeParams {
     // TODO The default value of ColorTransform is 1 if the image has three components and 0 otherwise.
     // 0:   No transformation.
@@ -42,35 +55,98 @@ pub struct DCTDecodeParams {
     //      If the image has four components, transform CMYK values to YUVK before encoding and from YUVK to CMYK after decoding.
     //      This option is ignored if the image has one or two color components.
     #[pdf(key="ColorTransform")]
-    color_transform: Option<i32>,
+    pub color_transform: Option<i32>,
+}
+
+/// Image dimensions and quality for encoding with the `DCTDecode` filter, see `dct_encode`.
+#[derive(Debug, Copy, Clone)]
+pub struct DCTEncoding {
+    pub width: u16,
+    pub height: u16,
+    pub components: u8,
+    /// From 1 to 100.
+    pub quality: u8,
 }
 
+/// Parameters of the `CCITTFaxDecode` filter.
 #[derive(Object, ObjectWrite, Debug, Clone)]
 pub struct CCITTFaxDecodeParams {
+    /// The encoding scheme: negative for Group 4, 0 for Group 3 1-D and positive for mixed Group 3 2-D.
     #[pdf(key="K", default="0")]
-    k: i32,
+    pub k: i32,
 
     #[pdf(key="EndOfLine", default="false")]
-    end_of_line: bool,
+    pub end_of_line: bool,
 
     #[pdf(key="EncodedByteAlign", default="false")]
-    encoded_byte_align: bool,
+    pub encoded_byte_align: bool,
 
+    /// Width of the image in pixels.
     #[pdf(key="Columns", default="1728")]
-    columns: u32,
+    pub columns: u32,
 
+    /// Height of the image in pixels, or 0 if not known.
     #[pdf(key="Rows", default="0")]
-    rows: u32,
+    pub rows: u32,
 
     #[pdf(key="EndOfBlock", default="true")]
-    end_of_block: bool,
+    pub end_of_block: bool,
 
+    /// Whether 1 bits are black pixels, instead of white ones.
     #[pdf(key="BlackIs1", default="false")]
-    black_is_1: bool,
+    pub black_is_1: bool,
 
     #[pdf(key="DamagedRowsBeforeError", default="0")]
-    damaged_rows_before_error: u32,
+    pub damaged_rows_before_error: u32,
+}
+impl Default for CCITTFaxDecodeParams {
+    fn default() -> CCITTFaxDecodeParams {
+        CCITTFaxDecodeParams {
+            k: 0,
+            end_of_line: false,
+            encoded_byte_align: false,
+            columns: 1728,
+            rows: 0,
+            end_of_block: true,
+            black_is_1: false,
+            damaged_rows_before_error: 0,
+        }
+    }
+}
+/// Parameters of the `JBIG2Decode` filter.
+#[derive(Debug, Clone, Default)]
+pub struct JBIG2DecodeParams {
+    /// The stream containing the global segments, shared by several images.
+    pub globals: Option<Ref<Stream<()>>>,
+
+    /// The decoded data of `globals`, read when the parameters are loaded.
+    pub globals_data: Option<Vec<u8>>,
 }
+impl Object for JBIG2DecodeParams {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let mut dict = p.into_dictionary(resolve)?;
+        let (globals, stream) = match dict.remove("JBIG2Globals") {
+            None | Some(Primitive::Null) => return Ok(JBIG2DecodeParams::default()),
+            Some(Primitive::Reference(r)) => (Some(Ref::new(r)), t!(resolve.resolve(r))),
+            Some(p) => (None, p)
+        };
+        let stream = t!(Stream::<()>::from_primitive(stream, resolve));
+        Ok(JBIG2DecodeParams {
+            globals,
+            globals_data: Some(t!(stream.data()).to_vec()),
+        })
+    }
+}
+impl ObjectWrite for JBIG2DecodeParams {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        let mut dict = Dictionary::new();
+        if let Some(globals) = self.globals {
+            dict.insert("JBIG2Globals", globals.to_primitive(update)?);
+        }
+        Ok(Primitive::Dictionary(dict))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum StreamFilter {
     ASCIIHexDecode,
@@ -80,6 +156,8 @@ pub enum StreamFilter {
     JPXDecode, //Jpeg2k
     DCTDecode (DCTDecodeParams),
     CCITTFaxDecode (CCITTFaxDecodeParams),
+    RunLengthDecode,
+    JBIG2Decode (JBIG2DecodeParams),
     Crypt
 }
 impl StreamFilter {
@@ -94,11 +172,57 @@ impl StreamFilter {
            "JPXDecode" => StreamFilter::JPXDecode,
            "DCTDecode" => StreamFilter::DCTDecode (DCTDecodeParams::from_primitive(params, r)?),
            "CCITTFaxDecode" => StreamFilter::CCITTFaxDecode (CCITTFaxDecodeParams::from_primitive(params, r)?),
+           "RunLengthDecode" => StreamFilter::RunLengthDecode,
+           "JBIG2Decode" => StreamFilter::JBIG2Decode (JBIG2DecodeParams::from_primitive(params, r)?),
            "Crypt" => StreamFilter::Crypt,
            ty => bail!("Unrecognized filter type {:?}", ty),
        } 
        )
     }
+
+    /// The name of the filter, as used in `/Filter`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StreamFilter::ASCIIHexDecode => "ASCIIHexDecode",
+            StreamFilter::ASCII85Decode => "ASCII85Decode",
+            StreamFilter::LZWDecode(_) => "LZWDecode",
+            StreamFilter::FlateDecode(_) => "FlateDecode",
+            StreamFilter::JPXDecode => "JPXDecode",
+            StreamFilter::DCTDecode(_) => "DCTDecode",
+            StreamFilter::CCITTFaxDecode(_) => "CCITTFaxDecode",
+            StreamFilter::RunLengthDecode => "RunLengthDecode",
+            StreamFilter::JBIG2Decode(_) => "JBIG2Decode",
+            StreamFilter::Crypt => "Crypt",
+        }
+    }
+
+    /// The parameters of the filter, as used in `/DecodeParms`, if it has any.
+    pub fn params(&self, update: &mut impl Updater) -> Result<Option<Primitive>> {
+        Ok(match *self {
+            StreamFilter::LZWDecode(ref p) => Some(p.to_primitive(update)?),
+            StreamFilter::FlateDecode(ref p) => Some(p.to_primitive(update)?),
+            StreamFilter::DCTDecode(ref p) => Some(p.to_primitive(update)?),
+            StreamFilter::CCITTFaxDecode(ref p) => Some(p.to_primitive(update)?),
+            StreamFilter::JBIG2Decode(ref p) if p.globals.is_some() => Some(p.to_primitive(update)?),
+            _ => None
+        })
+    }
+}
+
+/// Maximum number of bytes a filter may produce, and the name of the limit that imposes it.
+#[derive(Debug, Copy, Clone)]
+pub struct OutputLimit {
+    pub max: usize,
+    pub limit: &'static str,
+}
+impl OutputLimit {
+    fn check(&self, len: usize) -> Result<()> {
+        if len > self.max {
+            Err(PdfError::LimitExceeded { limit: self.limit, max: self.max })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[inline]
@@ -260,63 +384,111 @@ fn base_85() {
 }
 
 
-fn flate_decode(data: &[u8], params: &LZWFlateParams) -> Result<Vec<u8>> {
-    let predictor = params.predictor as usize;
-    let n_components = params.n_components as usize;
-    let columns = params.columns as usize;
-    let stride = columns * n_components;
+fn inflate(mut stream: InflateStream, data: &[u8], limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        let (n, bytes) = stream.update(&data[pos ..])?;
+        if bytes.is_empty() {
+            break;
+        }
+        pos += n;
+        decoded.extend_from_slice(bytes);
+        if let Some(limit) = limit {
+            limit.check(decoded.len())?;
+        }
+    }
+    Ok(decoded)
+}
+
+fn run_length_decode(data: &[u8], limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while let Some(&length) = data.get(pos) {
+        match length {
+            // EOD
+            128 => break,
+            // copy the next `length + 1` bytes literally
+            0 ..= 127 => {
+                let n = length as usize + 1;
+                let bytes = data.get(pos + 1 .. pos + 1 + n).ok_or(PdfError::EOF)?;
+                out.extend_from_slice(bytes);
+                pos += 1 + n;
+            }
+            // repeat the next byte `257 - length` times
+            _ => {
+                let &byte = data.get(pos + 1).ok_or(PdfError::EOF)?;
+                out.resize(out.len() + 257 - length as usize, byte);
+                pos += 2;
+            }
+        }
+        if let Some(limit) = limit {
+            limit.check(out.len())?;
+        }
+    }
+    Ok(out)
+}
+
+fn run_length_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 128 + 1);
+    let mut pos = 0;
+    while pos < data.len() {
+        let byte = data[pos];
+        let run = data[pos ..].iter().take(128).take_while(|&&b| b == byte).count();
+        if run > 1 {
+            out.push((257 - run) as u8);
+            out.push(byte);
+            pos += run;
+        } else {
+            // collect literal bytes up to the next run of at least two equal bytes
+            let mut end = pos + 1;
+            while end < data.len() && end - pos < 128 && (end + 1 >= data.len() || data[end] != data[end + 1]) {
+                end += 1;
+            }
+            out.push((end - pos - 1) as u8);
+            out.extend_from_slice(&data[pos .. end]);
+            pos = end;
+        }
+    }
+    out.push(128);
+    out
+}
 
-    // First flate decode
-    let decoded = match inflate_bytes_zlib(data) {
+#[test]
+fn run_length() {
+    let case: Vec<u8> = b"abcccccd".iter().cloned().chain(std::iter::repeat_n(7, 300)).chain(b"xy".iter().cloned()).collect();
+    let encoded = run_length_encode(&case);
+    assert_eq!(&encoded[.. 6], &[1, b'a', b'b', 252, b'c', 0]);
+    assert_eq!(run_length_decode(&encoded, None).unwrap(), case);
+    assert_eq!(run_length_decode(&[2, 1, 2, 3, 254, 9, 128, 5], None).unwrap(), &[1, 2, 3, 9, 9, 9]);
+    assert!(run_length_decode(&[5, 1], None).is_err());
+}
+
+fn flate_decode(data: &[u8], params: &LZWFlateParams, limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    let decoded = match inflate(InflateStream::from_zlib(), data, limit) {
         Ok(data) => data,
+        Err(e @ PdfError::LimitExceeded { .. }) => return Err(e),
         Err(_) => {
             info!("invalid zlib header. trying without");
-            inflate_bytes(data)?
+            inflate(InflateStream::new(), data, limit)?
         }
     };
-    // Then unfilter (PNG)
-    // For this, take the old out as input, and write output to out
-
-    if predictor > 10 {
-        let inp = decoded; // input buffer
-        let rows = inp.len() / (stride+1);
-        
-        // output buffer
-        let mut out = vec![0; rows * stride];
-    
-        // Apply inverse predictor
-        let null_vec = vec![0; stride];
-        
-        let mut in_off = 0; // offset into input buffer
-        
-        let mut out_off = 0; // offset into output buffer
-        let mut last_out_off = 0; // last offset to output buffer
-        
-        while in_off < inp.len() {
-            let predictor = PredictorType::from_u8(inp[in_off])?;
-            in_off += 1; // +1 because the first byte on each row is predictor
-            
-            let row_in = &inp[in_off .. in_off + stride];
-            let (prev_row, row_out) = if out_off == 0 {
-                (&null_vec[..], &mut out[out_off .. out_off+stride])
-            } else {
-                let (prev, curr) = out.split_at_mut(out_off);
-                (&prev[last_out_off ..], &mut curr[.. stride])
-            };
-            unfilter(predictor, n_components, prev_row, row_in, row_out);
-            
-            last_out_off = out_off;
-            
-            in_off += stride;
-            out_off += stride;
-        }
-        Ok(out)
-    } else {
-        Ok(decoded)
-    }
+    unpredict(decoded, params)
 }
-fn flate_encode(data: &[u8]) -> Vec<u8> {
-    deflate_bytes(data)
+fn flate_encode(data: &[u8], params: &LZWFlateParams) -> Result<Vec<u8>> {
+    Ok(deflate_bytes(&predict(data, params)?))
+}
+
+#[test]
+fn flate_limit() {
+    let bomb = flate_encode(&vec![0; 1 << 20], &LZWFlateParams::default()).unwrap();
+    let filter = StreamFilter::FlateDecode(LZWFlateParams::default());
+    let limit = OutputLimit { max: 1000, limit: "decompression ratio" };
+    assert!(matches!(
+        decode(&bomb, &filter, Some(limit)),
+        Err(PdfError::LimitExceeded { max: 1000, .. })
+    ));
+    assert_eq!(decode(&bomb, &filter, None).unwrap().len(), 1 << 20);
 }
 
 fn dct_decode(data: &[u8], _params: &DCTDecodeParams) -> Result<Vec<u8>> {
@@ -326,63 +498,223 @@ fn dct_decode(data: &[u8], _params: &DCTDecodeParams) -> Result<Vec<u8>> {
     Ok(pixels)
 }
 
-fn lzw_decode(data: &[u8], params: &LZWFlateParams) -> Result<Vec<u8>> {
-    use weezl::{BitOrder, decode::Decoder};
+#[test]
+fn dct() {
+    let (width, height) = (16u16, 8u16);
+    let data: Vec<u8> = (0 .. width as usize * height as usize * 3).map(|i| (i % 3 * 100) as u8).collect();
+    let params = DCTDecodeParams::default();
+    let encoding = DCTEncoding { width, height, components: 3, quality: 90 };
+    let encoded = dct_encode(&data, &encoding, &params).unwrap();
+    let decoded = dct_decode(&encoded, &params).unwrap();
+    assert_eq!(decoded.len(), data.len());
+    assert!(decoded.iter().zip(&data).all(|(&a, &b)| (a as i32 - b as i32).abs() < 8));
+    assert!(dct_encode(&data, &DCTEncoding { height: 9, ..encoding }, &params).is_err());
+}
+
+fn lzw_decode(data: &[u8], params: &LZWFlateParams, limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    use weezl::{BitOrder, LzwStatus, decode::Decoder};
     let mut out = vec![];
 
     let mut decoder = if params.early_change != 0 {
-        Decoder::with_tiff_size_switch(BitOrder::Msb, 9)
+        Decoder::with_tiff_size_switch(BitOrder::Msb, 8)
     } else {
-        Decoder::new(BitOrder::Msb, 9)
+        Decoder::new(BitOrder::Msb, 8)
     };
 
-    decoder
-        .into_stream(&mut out)
-        .decode_all(data).status?;
-    Ok(out)
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            decoder
+                .into_stream(&mut out)
+                .decode_all(data).status?;
+            return unpredict(out, params);
+        }
+    };
+
+    let mut buf = [0; 4096];
+    let mut input = data;
+    loop {
+        let result = decoder.decode_bytes(input, &mut buf);
+        input = &input[result.consumed_in ..];
+        out.extend_from_slice(&buf[.. result.consumed_out]);
+        limit.check(out.len())?;
+        match result.status {
+            Ok(LzwStatus::Ok) => {}
+            Ok(LzwStatus::Done) | Ok(LzwStatus::NoProgress) => break,
+            Err(e) => bail!("{}", e)
+        }
+    }
+    unpredict(out, params)
 }
 fn lzw_encode(data: &[u8], params: &LZWFlateParams) -> Result<Vec<u8>> {
     use weezl::{BitOrder, encode::Encoder};
-    if params.early_change != 0 {
-        bail!("encoding early_change != 0 is not supported");
-    }
+    let data = predict(data, params)?;
     let mut compressed = vec![];
-    Encoder::new(BitOrder::Msb, 9)
+    let mut encoder = if params.early_change != 0 {
+        Encoder::with_tiff_size_switch(BitOrder::Msb, 8)
+    } else {
+        Encoder::new(BitOrder::Msb, 8)
+    };
+    encoder
         .into_stream(&mut compressed)
-        .encode_all(data).status?;
+        .encode_all(&*data).status?;
     Ok(compressed)
 }
 
-fn fax_decode(data: &[u8], params: &CCITTFaxDecodeParams) -> Result<Vec<u8>> {
-    use fax::{Color, decoder::{pels, decode_g4}};
+/// Decode Group 4 data into one byte per pixel, 0 for black and 255 for white.
+///
+/// `BlackIs1` is not applied here; `ImageXObject::raster` packs the pixels into bits with it.
+fn fax_decode(data: &[u8], params: &CCITTFaxDecodeParams, limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    use fax::{Color, decoder::{pels, Group4Decoder, DecodeStatus}};
 
-    if params.k < 0 {
-        let mut buf = Vec::with_capacity(params.columns as usize * params.rows as usize);
-        decode_g4(data.iter().cloned(), params.columns as u16, |line| {
-            buf.extend(pels(line, params.columns as u16).map(|c| match c {
-                Color::Black => 0,
-                Color::White => 255
-            }));
-        });
-        if buf.len() != params.columns as usize * params.rows as usize {
-            bail!("decoded length does not match (expected {}∙{}, got {})", params.rows, params.columns, buf.len());
+    if params.k >= 0 {
+        bail!("only Group 4 (K < 0) CCITT data can be decoded, K = {}", params.k);
+    }
+    if params.columns == 0 || params.columns > u16::MAX as u32 {
+        bail!("invalid number of columns {}", params.columns);
+    }
+    if params.rows > u16::MAX as u32 {
+        bail!("too many rows {}", params.rows);
+    }
+    let columns = params.columns as u16;
+
+    let mut buf = Vec::new();
+    let push_line = |buf: &mut Vec<u8>, transitions: &[u16]| -> Result<()> {
+        if let Some(limit) = limit {
+            limit.check(buf.len() + columns as usize)?;
         }
-        Ok(buf)
-    } else {
-        unimplemented!()
+        buf.extend(pels(transitions, columns).map(|c| match c {
+            Color::Black => 0,
+            Color::White => 255
+        }));
+        Ok(())
+    };
+    let input = data.iter().map(|&b| Ok::<u8, std::convert::Infallible>(b));
+    let mut decoder = Group4Decoder::new(input, columns).unwrap_or_else(|e| match e {});
+    let mut rows = 0;
+    // without Rows, decode until the end of block
+    while params.rows == 0 || rows < params.rows {
+        match decoder.advance() {
+            Ok(DecodeStatus::Incomplete) => push_line(&mut buf, decoder.transition())?,
+            Ok(DecodeStatus::End) => break,
+            // without Rows, there is nothing to tell the end of the data from corrupt data
+            Err(e) if params.rows == 0 => {
+                warn!("CCITT data ends with an error after {} rows: {:?}", rows, e);
+                break;
+            }
+            Err(e) => bail!("invalid CCITT data in row {} of {}: {:?}", rows, params.rows, e),
+        }
+        rows += 1;
     }
+    if rows < params.rows {
+        bail!("CCITT data ends after {} of {} rows", rows, params.rows);
+    }
+    Ok(buf)
 }
 
-pub fn decode(data: &[u8], filter: &StreamFilter) -> Result<Vec<u8>> {
-    match *filter {
-        StreamFilter::ASCIIHexDecode => decode_hex(data),
-        StreamFilter::ASCII85Decode => decode_85(data),
-        StreamFilter::LZWDecode(ref params) => lzw_decode(data, params),
-        StreamFilter::FlateDecode(ref params) => flate_decode(data, params),
-        StreamFilter::DCTDecode(ref params) => dct_decode(data, params),
-        StreamFilter::CCITTFaxDecode(ref params) => fax_decode(data, params),
-        _ => unimplemented!(),
+/// Group 4 encode `data`, which holds one byte per pixel, 0 for black and anything else for white.
+///
+/// `params.k` has to be negative. An end-of-block marker is always written.
+pub fn fax_encode(data: &[u8], params: &CCITTFaxDecodeParams) -> Result<Vec<u8>> {
+    use fax::{Color, VecWriter, encoder::Encoder};
+
+    if params.k >= 0 {
+        bail!("only Group 4 (K < 0) encoding is supported");
+    }
+    if params.encoded_byte_align {
+        bail!("EncodedByteAlign is not supported for encoding");
+    }
+    let columns = params.columns as usize;
+    if columns == 0 || columns > u16::MAX as usize {
+        bail!("invalid number of columns {}", columns);
+    }
+    if !data.len().is_multiple_of(columns) {
+        bail!("data length {} is not a multiple of the row length {}", data.len(), columns);
+    }
+    let rows = data.len() / columns;
+    if params.rows != 0 && rows != params.rows as usize {
+        bail!("expected {} rows, got {}", params.rows, rows);
+    }
+
+    let mut encoder = Encoder::new(VecWriter::new());
+    for row in data.chunks_exact(columns) {
+        let pels = row.iter().map(|&b| match b {
+            0 => Color::Black,
+            _ => Color::White,
+        });
+        encoder.encode_line(pels, columns as u16).unwrap_or_else(|e| match e {});
+    }
+    let writer = encoder.finish().unwrap_or_else(|e| match e {});
+    Ok(writer.finish())
+}
+
+#[test]
+fn fax_g4() {
+    let (columns, rows) = (37, 9);
+    let data: Vec<u8> = (0 .. columns * rows).map(|i| if i * 73 % 251 % 3 == 0 { 0 } else { 255 }).collect();
+    let params = CCITTFaxDecodeParams { k: -1, columns: columns as u32, rows: rows as u32, ..Default::default() };
+    let encoded = fax_encode(&data, &params).unwrap();
+    assert_eq!(fax_decode(&encoded, &params, None).unwrap(), data);
+    assert!(fax_encode(&data[1 ..], &params).is_err());
+
+    // data that ends early or is corrupt is not padded to Rows
+    let short = fax_encode(&data[.. columns * (rows - 2)], &CCITTFaxDecodeParams { rows: 0, ..params }).unwrap();
+    assert!(fax_decode(&short, &params, None).is_err());
+    assert!(fax_decode(&[0; 16], &params, None).is_err());
+
+    // every 1 bit is a row
+    let ones = [0xff; 4000];
+    let params = CCITTFaxDecodeParams { k: -1, columns: 65535, ..Default::default() };
+    let limit = OutputLimit { max: 1 << 20, limit: "test" };
+    assert!(matches!(fax_decode(&ones, &params, Some(limit)), Err(PdfError::LimitExceeded { .. })));
+    let params = CCITTFaxDecodeParams { k: -1, columns: 65536, ..Default::default() };
+    assert!(fax_decode(&ones, &params, None).is_err());
+    let params = CCITTFaxDecodeParams { k: -1, columns: 8, rows: 65536, ..Default::default() };
+    assert!(fax_decode(&ones, &params, None).is_err());
+}
+
+/// JPEG encode `data`, which holds the pixels described by `encoding`.
+///
+/// Images with four components are taken as CMYK, and written as YCCK if `params` asks for a
+/// colour transform.
+pub fn dct_encode(data: &[u8], encoding: &DCTEncoding, params: &DCTDecodeParams) -> Result<Vec<u8>> {
+    use jpeg_encoder::{Encoder, ColorType};
+    let DCTEncoding { width, height, components, quality } = *encoding;
+
+    let color_type = match (components, params.color_transform) {
+        (1, _) => ColorType::Luma,
+        (3, None) | (3, Some(1)) => ColorType::Rgb,
+        (3, Some(t)) => bail!("ColorTransform {} is not supported for encoding RGB images", t),
+        (4, Some(1)) => ColorType::CmykAsYcck,
+        (4, _) => ColorType::Cmyk,
+        (n, _) => bail!("can't encode images with {} components as JPEG", n),
+    };
+    if data.len() != width as usize * height as usize * components as usize {
+        bail!("expected {}∙{}∙{} bytes, got {}", width, height, components, data.len());
+    }
+    let mut out = Vec::new();
+    Encoder::new(&mut out, quality).encode(data, width, height, color_type)?;
+    Ok(out)
+}
+
+/// Decode `data` with `filter`, failing as soon as the output grows beyond `limit`.
+pub fn decode(data: &[u8], filter: &StreamFilter, limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    let out = match *filter {
+        StreamFilter::ASCIIHexDecode => decode_hex(data)?,
+        StreamFilter::ASCII85Decode => decode_85(data)?,
+        StreamFilter::LZWDecode(ref params) => lzw_decode(data, params, limit)?,
+        StreamFilter::FlateDecode(ref params) => flate_decode(data, params, limit)?,
+        StreamFilter::DCTDecode(ref params) => dct_decode(data, params)?,
+        StreamFilter::CCITTFaxDecode(ref params) => fax_decode(data, params, limit)?,
+        StreamFilter::RunLengthDecode => run_length_decode(data, limit)?,
+        StreamFilter::JBIG2Decode(ref params) => jbig2::decode(data, params.globals_data.as_deref(), limit)?,
+        StreamFilter::JPXDecode => jpx::decode(data, limit)?,
+        StreamFilter::Crypt => bail!("the Crypt filter is applied when the file is decrypted"),
+    };
+    if let Some(limit) = limit {
+        limit.check(out.len())?;
     }
+    Ok(out)
 }
 
 pub fn encode(data: &[u8], filter: &StreamFilter) -> Result<Vec<u8>> {
@@ -390,8 +722,13 @@ pub fn encode(data: &[u8], filter: &StreamFilter) -> Result<Vec<u8>> {
         StreamFilter::ASCIIHexDecode => Ok(encode_hex(data)),
         StreamFilter::ASCII85Decode => Ok(encode_85(data)),
         StreamFilter::LZWDecode(ref params) => lzw_encode(data, params),
-        StreamFilter::FlateDecode (ref params) => Ok(flate_encode(data)),
-        _ => unimplemented!(),
+        StreamFilter::FlateDecode (ref params) => flate_encode(data, params),
+        StreamFilter::RunLengthDecode => Ok(run_length_encode(data)),
+        StreamFilter::CCITTFaxDecode(ref params) => fax_encode(data, params),
+        StreamFilter::DCTDecode(_) => bail!("DCT encoding needs the image layout, use dct_encode"),
+        StreamFilter::JBIG2Decode(_) | StreamFilter::JPXDecode | StreamFilter::Crypt => {
+            bail!("encoding with {} is not supported", filter.name())
+        }
     }
 }
 
@@ -401,7 +738,6 @@ pub fn encode(data: &[u8], filter: &StreamFilter) -> Result<Vec<u8>> {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-#[allow(dead_code)]
 pub enum PredictorType {
     NoFilter = 0,
     Sub = 1,
@@ -499,7 +835,6 @@ pub fn unfilter(filter: PredictorType, bpp: usize, prev: &[u8], inp: &[u8], out:
     }
 }
 
-#[allow(unused)]
 pub fn filter(method: PredictorType, bpp: usize, previous: &[u8], current: &mut [u8]) {
     use self::PredictorType::*;
     let len  = current.len();
@@ -518,7 +853,7 @@ pub fn filter(method: PredictorType, bpp: usize, previous: &[u8], current: &mut
         }
         Avg => {
             for i in (bpp..len).rev() {
-                current[i] = current[i].wrapping_sub(current[i - bpp].wrapping_add(previous[i]) / 2);
+                current[i] = current[i].wrapping_sub(((current[i - bpp] as u16 + previous[i] as u16) / 2) as u8);
             }
 
             for i in 0..bpp {
@@ -536,3 +871,172 @@ pub fn filter(method: PredictorType, bpp: usize, previous: &[u8], current: &mut
         }
     }
 }
+
+/// Bytes per pixel (at least one) and bytes per row of the data a predictor works on.
+fn predictor_layout(params: &LZWFlateParams) -> Result<(usize, usize)> {
+    if params.n_components < 1 || params.columns < 1 {
+        bail!("invalid predictor parameters {:?}", params);
+    }
+    let bits_per_pixel = match params.bits_per_component {
+        n @ 1 | n @ 2 | n @ 4 | n @ 8 | n @ 16 => n as usize * params.n_components as usize,
+        n => bail!("invalid BitsPerComponent {}", n)
+    };
+    Ok((bits_per_pixel.div_ceil(8), (params.columns as usize * bits_per_pixel).div_ceil(8)))
+}
+
+/// The `i`-th sample of `bpc` bits in `row`.
+pub(crate) fn sample(row: &[u8], bpc: usize, i: usize) -> u16 {
+    match bpc {
+        16 => u16::from_be_bytes([row[2 * i], row[2 * i + 1]]),
+        8 => row[i] as u16,
+        _ => {
+            let bit = i * bpc;
+            (row[bit / 8] >> (8 - bpc - bit % 8)) as u16 & ((1 << bpc) - 1)
+        }
+    }
+}
+
+pub(crate) fn set_sample(row: &mut [u8], bpc: usize, i: usize, value: u16) {
+    match bpc {
+        16 => row[2 * i .. 2 * i + 2].copy_from_slice(&value.to_be_bytes()),
+        8 => row[i] = value as u8,
+        _ => {
+            let bit = i * bpc;
+            let shift = 8 - bpc - bit % 8;
+            let mask = ((1 << bpc) - 1) << shift;
+            row[bit / 8] = (row[bit / 8] & !mask) | ((value as u8) << shift & mask);
+        }
+    }
+}
+
+/// Apply (or undo, if `decode` is set) TIFF predictor 2 to one row.
+fn tiff_predictor(row: &mut [u8], params: &LZWFlateParams, decode: bool) {
+    let bpc = params.bits_per_component as usize;
+    let colors = params.n_components as usize;
+    let n = params.columns as usize * colors;
+    if decode {
+        for i in colors .. n {
+            let value = sample(row, bpc, i).wrapping_add(sample(row, bpc, i - colors));
+            set_sample(row, bpc, i, value);
+        }
+    } else {
+        for i in (colors .. n).rev() {
+            let value = sample(row, bpc, i).wrapping_sub(sample(row, bpc, i - colors));
+            set_sample(row, bpc, i, value);
+        }
+    }
+}
+
+/// Undo the predictor of `params`. Incomplete trailing rows are dropped.
+fn unpredict(mut data: Vec<u8>, params: &LZWFlateParams) -> Result<Vec<u8>> {
+    match params.predictor {
+        2 => {
+            let (_, stride) = predictor_layout(params)?;
+            data.truncate(data.len() - data.len() % stride);
+            for row in data.chunks_exact_mut(stride) {
+                tiff_predictor(row, params, true);
+            }
+            Ok(data)
+        }
+        p if p >= 10 => {
+            let (bpp, stride) = predictor_layout(params)?;
+            let mut out = Vec::with_capacity(data.len() / (stride + 1) * stride);
+            let mut prev = vec![0; stride];
+            let mut row_out = vec![0; stride];
+            for row in data.chunks_exact(stride + 1) {
+                let predictor = PredictorType::from_u8(row[0])?;
+                unfilter(predictor, bpp, &prev, &row[1 ..], &mut row_out);
+                out.extend_from_slice(&row_out);
+                std::mem::swap(&mut prev, &mut row_out);
+            }
+            Ok(out)
+        }
+        _ => Ok(data)
+    }
+}
+
+/// Apply the predictor of `params`. Predictor 15 picks the PNG filter with the
+/// smallest sum of absolute differences for each row.
+fn predict<'a>(data: &'a [u8], params: &LZWFlateParams) -> Result<Cow<'a, [u8]>> {
+    use self::PredictorType::*;
+
+    let (bpp, stride) = match params.predictor {
+        1 => return Ok(Cow::Borrowed(data)),
+        2 | 10 ..= 15 => predictor_layout(params)?,
+        p => bail!("invalid predictor {}", p)
+    };
+    if !data.len().is_multiple_of(stride) {
+        bail!("data length {} is not a multiple of the row length {}", data.len(), stride);
+    }
+    if params.predictor == 2 {
+        let mut data = data.to_vec();
+        for row in data.chunks_exact_mut(stride) {
+            tiff_predictor(row, params, false);
+        }
+        return Ok(Cow::Owned(data));
+    }
+
+    let fixed = match params.predictor {
+        10 => Some(NoFilter),
+        11 => Some(Sub),
+        12 => Some(Up),
+        13 => Some(Avg),
+        14 => Some(Paeth),
+        _ => None
+    };
+    let mut out = Vec::with_capacity(data.len() / stride * (stride + 1));
+    let mut prev: &[u8] = &vec![0; stride];
+    let mut current = vec![0; stride];
+    for row in data.chunks_exact(stride) {
+        let method = match fixed {
+            Some(method) => method,
+            None => *[NoFilter, Sub, Up, Avg, Paeth].iter().min_by_key(|&&method| {
+                current.copy_from_slice(row);
+                filter(method, bpp, prev, &mut current);
+                current.iter().map(|&b| (b as i8).unsigned_abs() as u64).sum::<u64>()
+            }).unwrap()
+        };
+        current.copy_from_slice(row);
+        filter(method, bpp, prev, &mut current);
+        out.push(method as u8);
+        out.extend_from_slice(&current);
+        prev = row;
+    }
+    Ok(Cow::Owned(out))
+}
+
+#[test]
+fn predictors() {
+    let data: Vec<u8> = (0 .. 240u32).map(|i| (i * i / 7 + i % 5) as u8).collect();
+    for &(predictor, colors, bpc, columns) in &[
+        (2, 3, 8, 10), (2, 1, 4, 12), (2, 2, 16, 6), (2, 1, 1, 61),
+        (10, 3, 8, 10), (11, 3, 8, 10), (12, 1, 8, 24), (13, 2, 16, 5), (14, 3, 8, 10), (15, 1, 2, 48), (15, 3, 8, 10),
+    ] {
+        let params = LZWFlateParams { predictor, n_components: colors, bits_per_component: bpc, columns, early_change: 0 };
+        let (_, stride) = predictor_layout(&params).unwrap();
+        let data = &data[.. data.len() - data.len() % stride];
+        for filter in [StreamFilter::FlateDecode(params.clone()), StreamFilter::LZWDecode(params.clone())] {
+            let encoded = encode(data, &filter).unwrap();
+            assert_eq!(decode(&encoded, &filter, None).unwrap(), data, "{:?}", filter);
+        }
+    }
+    let params = LZWFlateParams { predictor: 12, columns: 7, ..Default::default() };
+    assert!(predict(&data[.. 10], &params).is_err());
+}
+
+#[test]
+fn lzw_early_change() {
+    // the example from section 3.3.3 of the PDF 1.7 reference
+    let encoded = [0x80, 0x0B, 0x60, 0x50, 0x22, 0x0C, 0x0C, 0x85, 0x01];
+    let filter = StreamFilter::LZWDecode(LZWFlateParams::default());
+    assert_eq!(decode(&encoded, &filter, None).unwrap(), b"-----A---B");
+    assert_eq!(encode(b"-----A---B", &filter).unwrap(), encoded);
+
+    // long enough to switch to 10, 11 and 12 bit codes
+    let data: Vec<u8> = (0 .. 20000u32).map(|i| (i * i / 13 % 251) as u8).collect();
+    for early_change in [0, 1] {
+        let filter = StreamFilter::LZWDecode(LZWFlateParams { early_change, ..Default::default() });
+        let encoded = encode(&data, &filter).unwrap();
+        assert_eq!(decode(&encoded, &filter, None).unwrap(), data);
+    }
+}
@@ -0,0 +1,2052 @@
+//! JBIG2 decoding (ITU T.88), as used by the `JBIG2Decode` filter.
+//!
+//! Supports the embedded stream organization used in PDF files: generic regions, refinement
+//! regions, symbol dictionaries, text regions, pattern dictionaries, halftone regions and
+//! custom Huffman tables, with arithmetic, Huffman and MMR coding.
+//! Extended generic region templates are not supported.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::error::*;
+use super::OutputLimit;
+use super::mq::{MqDecoder, Context};
+
+macro_rules! other {
+    ($($t:tt)*) => (PdfError::Other { msg: format!($($t)*) })
+}
+
+/// A bilevel image with one byte per pixel. `1` is black.
+#[derive(Debug, Clone)]
+struct Bitmap {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+impl Bitmap {
+    /// Decoders allocate through `Budget::bitmap`, which applies the output limit.
+    fn new(width: u32, height: u32, value: u8) -> Result<Bitmap> {
+        let size = (width as usize).checked_mul(height as usize)
+            .filter(|&size| size <= isize::MAX as usize)
+            .ok_or_else(|| other!("JBIG2 bitmap too large ({}x{})", width, height))?;
+        Ok(Bitmap { width, height, data: vec![value; size] })
+    }
+    #[inline]
+    fn get(&self, x: i32, y: i32) -> u8 {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            0
+        } else {
+            self.data[y as usize * self.width as usize + x as usize]
+        }
+    }
+    #[inline]
+    fn set(&mut self, x: u32, y: u32, value: u8) {
+        self.data[y as usize * self.width as usize + x as usize] = value;
+    }
+    fn row(&self, y: u32) -> &[u8] {
+        let w = self.width as usize;
+        &self.data[y as usize * w .. (y as usize + 1) * w]
+    }
+    fn copy_row(&mut self, from: u32, to: u32) {
+        let w = self.width as usize;
+        self.data.copy_within(from as usize * w .. (from as usize + 1) * w, to as usize * w);
+    }
+
+    /// The `width` x `height` part of the bitmap starting at `x`, `y`.
+    fn sub(&self, budget: &Budget, x: u32, y: u32, width: u32, height: u32) -> Result<Bitmap> {
+        let mut out = budget.bitmap(width, height, 0)?;
+        for j in 0 .. height {
+            for i in 0 .. width {
+                out.set(i, j, self.get((x + i) as i32, (y + j) as i32));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Combine `other` into this bitmap with its top left corner at `x`, `y`.
+    fn compose(&mut self, other: &Bitmap, x: i64, y: i64, op: CombinationOp) {
+        for j in 0 .. other.height as i64 {
+            let ty = y + j;
+            if ty < 0 || ty >= self.height as i64 {
+                continue;
+            }
+            for i in 0 .. other.width as i64 {
+                let tx = x + i;
+                if tx < 0 || tx >= self.width as i64 {
+                    continue;
+                }
+                let src = other.data[(j * other.width as i64 + i) as usize];
+                let dst = &mut self.data[(ty * self.width as i64 + tx) as usize];
+                *dst = op.apply(*dst, src);
+            }
+        }
+    }
+
+    /// Grow the bitmap to `height` rows, filling new rows with `value`.
+    fn grow(&mut self, budget: &Budget, height: u32, value: u8) -> Result<()> {
+        if height > self.height {
+            budget.charge(self.width, height - self.height)?;
+            let size = (self.width as usize).checked_mul(height as usize)
+                .ok_or_else(|| other!("JBIG2 page too large"))?;
+            self.data.resize(size, value);
+            self.height = height;
+        }
+        Ok(())
+    }
+}
+
+/// Accounts for the bitmaps allocated while decoding a stream against its `OutputLimit`.
+///
+/// Bitmaps count with one bit per pixel, like the decoded page, and at least one byte each.
+#[derive(Default)]
+struct Budget {
+    limit: Option<OutputLimit>,
+    used: Cell<usize>,
+}
+impl Budget {
+    fn charge(&self, width: u32, height: u32) -> Result<()> {
+        let size = (width as usize).div_ceil(8).saturating_mul(height as usize).max(1);
+        let used = self.used.get().saturating_add(size);
+        if let Some(limit) = self.limit {
+            limit.check(used)?;
+        }
+        self.used.set(used);
+        Ok(())
+    }
+    /// Allocate a bitmap, see `Bitmap::new`.
+    fn bitmap(&self, width: u32, height: u32, value: u8) -> Result<Bitmap> {
+        self.charge(width, height)?;
+        Bitmap::new(width, height, value)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum CombinationOp {
+    Or,
+    And,
+    Xor,
+    Xnor,
+    Replace,
+}
+impl CombinationOp {
+    fn from_u8(n: u8) -> Result<CombinationOp> {
+        Ok(match n {
+            0 => CombinationOp::Or,
+            1 => CombinationOp::And,
+            2 => CombinationOp::Xor,
+            3 => CombinationOp::Xnor,
+            4 => CombinationOp::Replace,
+            _ => bail!("invalid JBIG2 combination operator {}", n)
+        })
+    }
+    #[inline]
+    fn apply(self, dst: u8, src: u8) -> u8 {
+        match self {
+            CombinationOp::Or => dst | src,
+            CombinationOp::And => dst & src,
+            CombinationOp::Xor => dst ^ src,
+            CombinationOp::Xnor => 1 ^ dst ^ src,
+            CombinationOp::Replace => src,
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len()).ok_or(PdfError::EOF)?;
+        let bytes = &self.data[self.pos .. end];
+        self.pos = end;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn i8(&mut self) -> Result<i8> {
+        Ok(self.u8()? as i8)
+    }
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn i32(&mut self) -> Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos ..];
+        self.pos = self.data.len();
+        rest
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/// Reads Huffman and MMR coded data bit by bit, most significant bit first.
+struct BitStream<'a> {
+    data: &'a [u8],
+    /// The position in bits.
+    pos: usize,
+}
+impl<'a> BitStream<'a> {
+    fn new(data: &'a [u8]) -> BitStream<'a> {
+        BitStream { data, pos: 0 }
+    }
+    fn bit(&mut self) -> Result<u32> {
+        let byte = *self.data.get(self.pos / 8).ok_or(PdfError::EOF)?;
+        self.pos += 1;
+        Ok((byte >> (7 - (self.pos - 1) % 8)) as u32 & 1)
+    }
+    /// Read an `n` bit number, `n` being at most 32.
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0 .. n {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+    /// Skip to the next byte boundary.
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+    /// The next `n` bytes, starting at the next byte boundary.
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        self.align();
+        let start = self.pos / 8;
+        let end = start.checked_add(n).filter(|&end| end <= self.data.len()).ok_or(PdfError::EOF)?;
+        self.pos = end * 8;
+        Ok(&self.data[start .. end])
+    }
+}
+/// The MMR code tables look further ahead than the last code, so bits past the end read as 0.
+impl fax::BitReader for BitStream<'_> {
+    type Error = std::convert::Infallible;
+
+    fn peek(&self, bits: u8) -> Option<u16> {
+        if bits > 16 {
+            return None;
+        }
+        let mut value = 0;
+        for pos in self.pos .. self.pos + bits as usize {
+            let bit = self.data.get(pos / 8).map_or(0, |&b| (b >> (7 - pos % 8)) & 1);
+            value = (value << 1) | bit as u16;
+        }
+        Some(value)
+    }
+    fn consume(&mut self, bits: u8) -> std::result::Result<(), Self::Error> {
+        self.pos += bits as usize;
+        Ok(())
+    }
+    fn bits_to_byte_boundary(&self) -> u8 {
+        ((8 - self.pos % 8) % 8) as u8
+    }
+}
+
+struct Segment<'a> {
+    number: u32,
+    kind: u8,
+    referred: Vec<u32>,
+    data: &'a [u8],
+    /// The data length was not given in the header, and the data ends with a row count.
+    unknown_length: bool,
+}
+
+fn parse_segment<'a>(r: &mut Reader<'a>) -> Result<Segment<'a>> {
+    let number = r.u32()?;
+    let flags = r.u8()?;
+    let kind = flags & 0x3f;
+    let page_association_size = if flags & 0x40 != 0 { 4 } else { 1 };
+
+    let b = r.u8()?;
+    let referred_count = match b >> 5 {
+        7 => {
+            r.pos -= 1;
+            let count = r.u32()? & 0x1fff_ffff;
+            r.bytes((count as usize + 1).div_ceil(8))?;
+            count
+        }
+        n => n as u32
+    };
+    let referred_size = match number {
+        0 ..= 256 => 1,
+        257 ..= 65536 => 2,
+        _ => 4
+    };
+    let mut referred = Vec::with_capacity(referred_count.min(256) as usize);
+    for _ in 0 .. referred_count {
+        referred.push(match referred_size {
+            1 => r.u8()? as u32,
+            2 => r.u16()? as u32,
+            _ => r.u32()?
+        });
+    }
+    r.bytes(page_association_size)?;
+
+    let length = r.u32()?;
+    if length == 0xffff_ffff {
+        // Only allowed for immediate generic regions. The data ends with a marker and the row count.
+        if kind != 38 {
+            bail!("JBIG2 segment {} of type {} has unknown length", number, kind);
+        }
+        let start = r.pos;
+        let flags = *r.data.get(start + 17).ok_or(PdfError::EOF)?;
+        let marker: &[u8] = if flags & 1 != 0 { &[0x00, 0x00] } else { &[0xff, 0xac] };
+        let end = r.data[start + 18 ..].windows(2).position(|w| w == marker)
+            .ok_or_else(|| other!("missing end of JBIG2 generic region {}", number))?;
+        let data = r.bytes(18 + end + 2 + 4)?;
+        Ok(Segment { number, kind, referred, data, unknown_length: true })
+    } else {
+        let data = r.bytes(length as usize)?;
+        Ok(Segment { number, kind, referred, data, unknown_length: false })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct RegionInfo {
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    op: CombinationOp,
+}
+impl RegionInfo {
+    fn parse(r: &mut Reader) -> Result<RegionInfo> {
+        let width = r.u32()?;
+        let height = r.u32()?;
+        let x = r.u32()?;
+        let y = r.u32()?;
+        let op = CombinationOp::from_u8(r.u8()? & 7)?;
+        Ok(RegionInfo { width, height, x, y, op })
+    }
+}
+
+/// Integer arithmetic decoding procedure (Annex A.2).
+struct IntDecoder {
+    contexts: Vec<Context>,
+}
+impl IntDecoder {
+    fn new() -> IntDecoder {
+        IntDecoder { contexts: vec![Context::default(); 512] }
+    }
+    /// Returns `None` for OOB.
+    fn decode(&mut self, d: &mut MqDecoder) -> Option<i32> {
+        let contexts = &mut self.contexts;
+        let mut prev = 1usize;
+        let mut bit = |d: &mut MqDecoder| {
+            let b = d.decode(&mut contexts[prev]) as usize;
+            prev = if prev < 256 {
+                (prev << 1) | b
+            } else {
+                (((prev << 1) | b) & 511) | 256
+            };
+            b as u32
+        };
+        let s = bit(d);
+        let (n, offset) = if bit(d) == 0 {
+            (2, 0)
+        } else if bit(d) == 0 {
+            (4, 4)
+        } else if bit(d) == 0 {
+            (6, 20)
+        } else if bit(d) == 0 {
+            (8, 84)
+        } else if bit(d) == 0 {
+            (12, 340)
+        } else {
+            (32, 4436)
+        };
+        let mut v: u32 = 0;
+        for _ in 0 .. n {
+            v = (v << 1) | bit(d);
+        }
+        let v = v.wrapping_add(offset) as i32;
+        match (s, v) {
+            (1, 0) => None,
+            (1, v) => Some(-v),
+            (_, v) => Some(v)
+        }
+    }
+    fn decode_value(&mut self, d: &mut MqDecoder) -> Result<i32> {
+        self.decode(d).ok_or_else(|| other!("unexpected OOB in JBIG2 data"))
+    }
+}
+
+/// Symbol ID decoding procedure (Annex A.3).
+struct IdDecoder {
+    code_len: u32,
+    contexts: Vec<Context>,
+}
+/// The length of fixed length codes for `num_symbols` symbols.
+fn symbol_code_len(num_symbols: usize) -> u32 {
+    let mut code_len = 0;
+    while code_len < usize::BITS && (1usize << code_len) < num_symbols {
+        code_len += 1;
+    }
+    code_len
+}
+
+impl IdDecoder {
+    fn new(num_symbols: usize) -> Result<IdDecoder> {
+        let code_len = symbol_code_len(num_symbols);
+        if code_len > 24 {
+            bail!("too many JBIG2 symbols ({})", num_symbols);
+        }
+        Ok(IdDecoder { code_len, contexts: vec![Context::default(); 1 << (code_len + 1)] })
+    }
+    fn decode(&mut self, d: &mut MqDecoder) -> usize {
+        let mut prev = 1usize;
+        for _ in 0 .. self.code_len {
+            prev = (prev << 1) | d.decode(&mut self.contexts[prev]) as usize;
+        }
+        prev - (1 << self.code_len)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RangeKind {
+    Normal,
+    /// Values below the table, with a 32 bit offset downwards.
+    Lower,
+    /// Values above the table, with a 32 bit offset upwards.
+    Upper,
+    Oob,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct TableLine {
+    prefix_len: u32,
+    range_len: u32,
+    low: i32,
+    kind: RangeKind,
+}
+impl TableLine {
+    fn new(prefix_len: u32, range_len: u32, low: i32) -> TableLine {
+        TableLine { prefix_len, range_len, low, kind: RangeKind::Normal }
+    }
+}
+
+/// A Huffman table with canonical prefix codes (B.3).
+#[derive(Debug, Clone)]
+struct HuffmanTable {
+    /// The lines with a prefix, ordered by prefix length.
+    lines: Vec<TableLine>,
+    /// For each prefix length: the first code, the index of its first line and the number of lines.
+    lengths: Vec<(u32, usize, usize)>,
+}
+impl HuffmanTable {
+    fn new(lines: Vec<TableLine>) -> Result<HuffmanTable> {
+        let mut lines: Vec<TableLine> = lines.into_iter().filter(|l| l.prefix_len > 0).collect();
+        if let Some(l) = lines.iter().find(|l| l.prefix_len > 32 || l.range_len > 32) {
+            bail!("invalid JBIG2 Huffman table line ({} prefix bits, {} range bits)", l.prefix_len, l.range_len);
+        }
+        lines.sort_by_key(|l| l.prefix_len);
+
+        let max_len = lines.last().map_or(0, |l| l.prefix_len);
+        let mut lengths = Vec::with_capacity(max_len as usize);
+        let mut code: u64 = 0;
+        let mut start = 0;
+        for len in 1 ..= max_len {
+            code <<= 1;
+            let count = lines[start ..].iter().take_while(|l| l.prefix_len == len).count();
+            if code + count as u64 > 1 << len {
+                bail!("invalid JBIG2 Huffman table (too many codes of length {})", len);
+            }
+            lengths.push((code as u32, start, count));
+            code += count as u64;
+            start += count;
+        }
+        Ok(HuffmanTable { lines, lengths })
+    }
+
+    /// Standard Huffman table B.`n`.
+    fn standard(n: usize) -> HuffmanTable {
+        let (lines, lower, upper, oob) = STANDARD_TABLES[n - 1];
+        let mut table: Vec<TableLine> = lines.iter().map(|&(p, r, low)| TableLine::new(p, r, low)).collect();
+        if let Some((prefix_len, low)) = lower {
+            table.push(TableLine { prefix_len, range_len: 32, low, kind: RangeKind::Lower });
+        }
+        if let Some((prefix_len, low)) = upper {
+            table.push(TableLine { prefix_len, range_len: 32, low, kind: RangeKind::Upper });
+        }
+        if let Some(prefix_len) = oob {
+            table.push(TableLine { prefix_len, range_len: 0, low: 0, kind: RangeKind::Oob });
+        }
+        HuffmanTable::new(table).expect("invalid standard table")
+    }
+
+    /// Read a table segment (7.4.13).
+    fn parse(r: &mut Reader) -> Result<HuffmanTable> {
+        let flags = r.u8()?;
+        let oob = flags & 1 != 0;
+        let prefix_bits = ((flags >> 1) & 7) as u32 + 1;
+        let range_bits = ((flags >> 4) & 7) as u32 + 1;
+        let low = r.i32()?;
+        let high = r.i32()?;
+
+        let mut s = BitStream::new(r.rest());
+        let mut lines = vec![];
+        let mut cur = low as i64;
+        while cur < high as i64 {
+            let line = TableLine::new(s.bits(prefix_bits)?, s.bits(range_bits)?, cur as i32);
+            if line.range_len >= 32 {
+                bail!("invalid JBIG2 Huffman table range length {}", line.range_len);
+            }
+            cur += 1 << line.range_len;
+            lines.push(line);
+        }
+        lines.push(TableLine { prefix_len: s.bits(prefix_bits)?, range_len: 32, low: low.wrapping_sub(1), kind: RangeKind::Lower });
+        lines.push(TableLine { prefix_len: s.bits(prefix_bits)?, range_len: 32, low: high, kind: RangeKind::Upper });
+        if oob {
+            lines.push(TableLine { prefix_len: s.bits(prefix_bits)?, range_len: 0, low: 0, kind: RangeKind::Oob });
+        }
+        HuffmanTable::new(lines)
+    }
+
+    /// Returns `None` for OOB.
+    fn decode(&self, s: &mut BitStream) -> Result<Option<i32>> {
+        let mut code = 0;
+        for &(first, start, count) in &self.lengths {
+            code = (code << 1) | s.bit()?;
+            if code >= first && code - first < count as u32 {
+                let line = self.lines[start + (code - first) as usize];
+                return Ok(match line.kind {
+                    RangeKind::Normal => Some(line.low.wrapping_add(s.bits(line.range_len)? as i32)),
+                    RangeKind::Lower => Some(line.low.wrapping_sub(s.bits(32)? as i32)),
+                    RangeKind::Upper => Some(line.low.wrapping_add(s.bits(32)? as i32)),
+                    RangeKind::Oob => None,
+                });
+            }
+        }
+        bail!("invalid JBIG2 Huffman code")
+    }
+    fn decode_value(&self, s: &mut BitStream) -> Result<i32> {
+        self.decode(s)?.ok_or_else(|| other!("unexpected OOB in JBIG2 data"))
+    }
+}
+
+/// A standard Huffman table (Annex B.5): lines of (prefix length, range length, range low),
+/// the lower and upper range lines as (prefix length, low) and the OOB prefix length.
+type StandardTable = (&'static [(u32, u32, i32)], Option<(u32, i32)>, Option<(u32, i32)>, Option<u32>);
+
+const STANDARD_TABLES: [StandardTable; 15] = [
+    (&[(1, 4, 0), (2, 8, 16), (3, 16, 272)], None, Some((3, 65808)), None),
+    (&[(1, 0, 0), (2, 0, 1), (3, 0, 2), (4, 3, 3), (5, 6, 11)], None, Some((6, 75)), Some(6)),
+    (&[(8, 8, -256), (1, 0, 0), (2, 0, 1), (3, 0, 2), (4, 3, 3), (5, 6, 11)], Some((8, -257)), Some((7, 75)), Some(6)),
+    (&[(1, 0, 1), (2, 0, 2), (3, 0, 3), (4, 3, 4), (5, 6, 12)], None, Some((5, 76)), None),
+    (&[(7, 8, -255), (1, 0, 1), (2, 0, 2), (3, 0, 3), (4, 3, 4), (5, 6, 12)], Some((7, -256)), Some((6, 76)), None),
+    (&[
+        (5, 10, -2048), (4, 9, -1024), (4, 8, -512), (4, 7, -256), (5, 6, -128), (5, 5, -64), (4, 5, -32),
+        (2, 7, 0), (3, 7, 128), (3, 8, 256), (4, 9, 512), (4, 10, 1024),
+    ], Some((6, -2049)), Some((6, 2048)), None),
+    (&[
+        (4, 9, -1024), (3, 8, -512), (4, 7, -256), (5, 6, -128), (5, 5, -64), (4, 5, -32), (4, 5, 0),
+        (5, 5, 32), (5, 6, 64), (4, 7, 128), (3, 8, 256), (3, 9, 512), (3, 10, 1024),
+    ], Some((5, -1025)), Some((5, 2048)), None),
+    (&[
+        (8, 3, -15), (9, 1, -7), (8, 1, -5), (9, 0, -3), (7, 0, -2), (4, 0, -1), (2, 1, 0), (5, 0, 2), (6, 0, 3),
+        (3, 4, 4), (6, 1, 20), (4, 4, 22), (4, 5, 38), (5, 6, 70), (5, 7, 134), (6, 7, 262), (7, 8, 390), (6, 10, 646),
+    ], Some((9, -16)), Some((9, 1670)), Some(2)),
+    (&[
+        (8, 4, -31), (9, 2, -15), (8, 2, -11), (9, 1, -7), (7, 1, -5), (4, 1, -3), (3, 1, -1), (3, 1, 1), (5, 1, 3),
+        (6, 1, 5), (3, 5, 7), (6, 2, 39), (4, 5, 43), (4, 6, 75), (5, 7, 139), (5, 8, 267), (6, 8, 523), (7, 9, 779),
+        (6, 11, 1291),
+    ], Some((9, -32)), Some((9, 3339)), Some(2)),
+    (&[
+        (7, 4, -21), (8, 0, -5), (7, 0, -4), (5, 0, -3), (2, 2, -2), (5, 0, 2), (6, 0, 3), (7, 0, 4), (8, 0, 5),
+        (2, 6, 6), (5, 5, 70), (6, 5, 102), (6, 6, 134), (6, 7, 198), (6, 8, 326), (6, 9, 582), (6, 10, 1094),
+        (7, 11, 2118),
+    ], Some((8, -22)), Some((8, 4166)), Some(2)),
+    (&[
+        (1, 0, 1), (2, 1, 2), (4, 0, 4), (4, 1, 5), (5, 1, 7), (5, 2, 9), (6, 2, 13), (7, 2, 17), (7, 3, 21),
+        (7, 4, 29), (7, 5, 45), (7, 6, 77),
+    ], None, Some((7, 141)), None),
+    (&[
+        (1, 0, 1), (2, 0, 2), (3, 1, 3), (5, 0, 5), (5, 1, 6), (6, 1, 8), (7, 0, 10), (7, 1, 11), (7, 2, 13),
+        (7, 3, 17), (7, 4, 25), (8, 5, 41),
+    ], None, Some((8, 73)), None),
+    (&[
+        (1, 0, 1), (3, 0, 2), (4, 0, 3), (5, 0, 4), (4, 1, 5), (3, 3, 7), (6, 1, 15), (6, 2, 17), (6, 3, 21),
+        (6, 4, 29), (6, 5, 45), (7, 6, 77),
+    ], None, Some((7, 141)), None),
+    (&[(3, 0, -2), (3, 0, -1), (1, 0, 0), (3, 0, 1), (3, 0, 2)], None, None, None),
+    (&[
+        (7, 4, -24), (6, 2, -8), (5, 1, -4), (4, 0, -2), (3, 0, -1), (1, 0, 0), (3, 0, 1), (4, 0, 2), (5, 1, 3),
+        (6, 2, 5), (7, 4, 9),
+    ], Some((7, -25)), Some((7, 25)), None),
+];
+
+/// Choose a Huffman table: `selection` indexes the standard tables in `standard`, or takes the
+/// next referred table segment when it is `custom_selection`.
+fn select_table<'a>(selection: u16, standard: &[usize], custom_selection: u16, custom: &mut impl Iterator<Item=&'a HuffmanTable>) -> Result<HuffmanTable> {
+    if selection == custom_selection {
+        return custom.next().cloned().ok_or_else(|| other!("missing JBIG2 Huffman table segment"));
+    }
+    match standard.get(selection as usize) {
+        Some(&n) => Ok(HuffmanTable::standard(n)),
+        None => bail!("invalid JBIG2 Huffman table selection {}", selection)
+    }
+}
+
+/// Read the symbol ID Huffman table of a text region (7.4.3.1.7).
+fn symbol_id_table(s: &mut BitStream, num_symbols: usize) -> Result<HuffmanTable> {
+    let mut run_codes = Vec::with_capacity(35);
+    for i in 0 .. 35 {
+        run_codes.push(TableLine::new(s.bits(4)?, 0, i));
+    }
+    let run_codes = HuffmanTable::new(run_codes)?;
+
+    let mut lengths: Vec<u32> = Vec::with_capacity(num_symbols);
+    while lengths.len() < num_symbols {
+        let (length, count) = match run_codes.decode_value(s)? {
+            n @ 0 ..= 31 => (n as u32, 1),
+            32 => {
+                let previous = *lengths.last().ok_or_else(|| other!("JBIG2 symbol code length repeat without a length"))?;
+                (previous, 3 + s.bits(2)?)
+            }
+            33 => (0, 3 + s.bits(3)?),
+            _ => (0, 11 + s.bits(7)?),
+        };
+        if lengths.len() + count as usize > num_symbols {
+            bail!("too many JBIG2 symbol code lengths");
+        }
+        lengths.resize(lengths.len() + count as usize, length);
+    }
+    s.align();
+    HuffmanTable::new(lengths.iter().enumerate().map(|(i, &len)| TableLine::new(len, 0, i as i32)).collect())
+}
+
+/// Generic region template pixels, most significant bit first. `None` marks the adaptive pixels.
+const GENERIC_TEMPLATES: [&[Option<(i32, i32)>]; 4] = [
+    &[
+        None, Some((-1, -2)), Some((0, -2)), Some((1, -2)), None, None,
+        Some((-2, -1)), Some((-1, -1)), Some((0, -1)), Some((1, -1)), Some((2, -1)), None,
+        Some((-4, 0)), Some((-3, 0)), Some((-2, 0)), Some((-1, 0)),
+    ],
+    &[
+        Some((-1, -2)), Some((0, -2)), Some((1, -2)), Some((2, -2)),
+        Some((-2, -1)), Some((-1, -1)), Some((0, -1)), Some((1, -1)), Some((2, -1)), None,
+        Some((-3, 0)), Some((-2, 0)), Some((-1, 0)),
+    ],
+    &[
+        Some((-1, -2)), Some((0, -2)), Some((1, -2)),
+        Some((-2, -1)), Some((-1, -1)), Some((0, -1)), Some((1, -1)), None,
+        Some((-2, 0)), Some((-1, 0)),
+    ],
+    &[
+        Some((-3, -1)), Some((-2, -1)), Some((-1, -1)), Some((0, -1)), Some((1, -1)), None,
+        Some((-4, 0)), Some((-3, 0)), Some((-2, 0)), Some((-1, 0)),
+    ],
+];
+/// Order in which the adaptive pixels appear in `GENERIC_TEMPLATES[0]`.
+const TEMPLATE_0_AT_ORDER: [usize; 4] = [3, 2, 1, 0];
+
+/// Context used to decode SLTP when typical prediction is on.
+const GENERIC_SLTP_CONTEXTS: [usize; 4] = [0x9b25, 0x0795, 0x00e5, 0x0195];
+
+fn generic_contexts(template: u8) -> Vec<Context> {
+    let bits = match template {
+        0 => 16,
+        1 => 13,
+        _ => 10
+    };
+    vec![Context::default(); 1 << bits]
+}
+
+struct GenericParams<'a> {
+    template: u8,
+    typical_prediction: bool,
+    at: [(i32, i32); 4],
+    skip: Option<&'a Bitmap>,
+}
+
+/// Generic region decoding procedure (6.2) with arithmetic coding.
+fn decode_generic(budget: &Budget, d: &mut MqDecoder, contexts: &mut [Context], width: u32, height: u32, params: &GenericParams) -> Result<Bitmap> {
+    let template = GENERIC_TEMPLATES[params.template as usize & 3];
+    let mut at = 0;
+    let pixels: Vec<(i32, i32)> = template.iter().map(|p| match *p {
+        Some(p) => p,
+        None => {
+            let i = if params.template == 0 { TEMPLATE_0_AT_ORDER[at] } else { at };
+            at += 1;
+            params.at[i]
+        }
+    }).collect();
+
+    let mut bitmap = budget.bitmap(width, height, 0)?;
+    let mut ltp = 0;
+    for y in 0 .. height {
+        if params.typical_prediction {
+            ltp ^= d.decode(&mut contexts[GENERIC_SLTP_CONTEXTS[params.template as usize & 3]]);
+            if ltp == 1 {
+                if y > 0 {
+                    bitmap.copy_row(y - 1, y);
+                }
+                continue;
+            }
+        }
+        for x in 0 .. width {
+            if let Some(skip) = params.skip {
+                if skip.get(x as i32, y as i32) == 1 {
+                    continue;
+                }
+            }
+            let mut cx = 0;
+            for &(dx, dy) in &pixels {
+                cx = (cx << 1) | bitmap.get(x as i32 + dx, y as i32 + dy) as usize;
+            }
+            if d.decode(&mut contexts[cx]) == 1 {
+                bitmap.set(x, y, 1);
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+/// Generic region decoding procedure (6.2) with MMR coding.
+///
+/// Returns the bitmap and the number of bytes used, including a trailing end of block.
+/// Decoding stops at the end of block or at invalid data, leaving the remaining rows white.
+fn decode_mmr(budget: &Budget, data: &[u8], width: u32, height: u32) -> Result<(Bitmap, usize)> {
+    use fax::maps::{mode, white, black, Mode};
+
+    fn run(s: &mut BitStream, black: bool) -> Option<usize> {
+        let mut total = 0;
+        loop {
+            let n = if black { black::decode(s)? } else { white::decode(s)? } as usize;
+            total += n;
+            if n < 64 {
+                return Some(total);
+            }
+        }
+    }
+
+    let mut bitmap = budget.bitmap(width, height, 0)?;
+    let w = width as usize;
+    let mut fill = |y: u32, from: usize, to: usize, black: bool| {
+        if black {
+            for x in from .. to.min(w) {
+                bitmap.set(x as u32, y, 1);
+            }
+        }
+    };
+    let mut s = BitStream::new(data);
+    // changing elements of the reference and the current row, the first one changing to black
+    let mut reference: Vec<usize> = vec![];
+    let mut current: Vec<usize> = vec![];
+    'rows: for y in 0 .. height {
+        let mut a0: Option<usize> = None;
+        let mut color = false;
+        while a0.is_none_or(|a0| a0 < w) {
+            if s.pos > data.len() * 8 {
+                break 'rows;
+            }
+            let start = a0.unwrap_or(0);
+            // b1 is the first change to the other color right of a0, b2 the change after it
+            let i = reference.iter().enumerate()
+                .position(|(i, &b)| a0.is_none_or(|a0| b > a0) && (i % 2 == 1) == color)
+                .unwrap_or(reference.len());
+            let b1 = reference.get(i).cloned().unwrap_or(w);
+            let b2 = reference.get(i + 1).cloned().unwrap_or(w);
+            match mode::decode(&mut s) {
+                Some(Mode::Pass) => {
+                    fill(y, start, b2, color);
+                    a0 = Some(b2);
+                }
+                Some(Mode::Horizontal) => {
+                    let (a1, a2) = match (run(&mut s, color), run(&mut s, !color)) {
+                        (Some(r1), Some(r2)) => (start + r1, start + r1 + r2),
+                        _ => break 'rows
+                    };
+                    fill(y, start, a1, color);
+                    fill(y, a1, a2, !color);
+                    current.push(a1);
+                    current.push(a2);
+                    a0 = Some(a2);
+                }
+                Some(Mode::Vertical(d)) => {
+                    let a1 = b1 as i64 + d as i64;
+                    if a1 < start as i64 {
+                        break 'rows;
+                    }
+                    let a1 = (a1 as usize).min(w);
+                    fill(y, start, a1, color);
+                    current.push(a1);
+                    a0 = Some(a1);
+                    color = !color;
+                }
+                Some(Mode::EOF) if a0.is_none() => {
+                    s.pos -= 12;
+                    break 'rows;
+                }
+                _ => break 'rows
+            }
+        }
+        std::mem::swap(&mut reference, &mut current);
+        current.clear();
+    }
+    // end of block
+    let mut eofb = BitStream { data, pos: s.pos };
+    if eofb.bits(24).ok() == Some(0x001001) {
+        s.pos += 24;
+    }
+    Ok((bitmap, s.pos.div_ceil(8).min(data.len())))
+}
+
+fn refinement_contexts(template: u8) -> Vec<Context> {
+    vec![Context::default(); if template == 0 { 1 << 13 } else { 1 << 10 }]
+}
+
+struct RefinementParams<'a> {
+    template: u8,
+    reference: &'a Bitmap,
+    dx: i32,
+    dy: i32,
+    typical_prediction: bool,
+    at: [(i32, i32); 2],
+}
+
+/// Generic refinement region decoding procedure (6.3).
+fn decode_refinement(budget: &Budget, d: &mut MqDecoder, contexts: &mut [Context], width: u32, height: u32, params: &RefinementParams) -> Result<Bitmap> {
+    // (reference image?, x, y), least significant bit first
+    let pixels: Vec<(bool, i32, i32)> = if params.template == 0 {
+        vec![
+            (false, -1, 0), (false, 1, -1), (false, 0, -1), (false, params.at[0].0, params.at[0].1),
+            (true, 1, 1), (true, 0, 1), (true, -1, 1), (true, 1, 0), (true, 0, 0), (true, -1, 0),
+            (true, 1, -1), (true, 0, -1), (true, params.at[1].0, params.at[1].1),
+        ]
+    } else {
+        vec![
+            (false, -1, 0), (false, 1, -1), (false, 0, -1), (false, -1, -1),
+            (true, 1, 1), (true, 0, 1), (true, 1, 0), (true, 0, 0), (true, -1, 0), (true, 0, -1),
+        ]
+    };
+    let sltp_context = if params.template == 0 { 0x100 } else { 0x80 };
+    let reference = params.reference;
+
+    let mut bitmap = budget.bitmap(width, height, 0)?;
+    let mut ltp = 0;
+    for y in 0 .. height as i32 {
+        if params.typical_prediction {
+            ltp ^= d.decode(&mut contexts[sltp_context]);
+        }
+        for x in 0 .. width as i32 {
+            let (rx, ry) = (x - params.dx, y - params.dy);
+            if ltp == 1 {
+                let value = reference.get(rx, ry);
+                let typical = (-1 ..= 1).all(|j| (-1 ..= 1).all(|i| reference.get(rx + i, ry + j) == value));
+                if typical {
+                    if value == 1 {
+                        bitmap.set(x as u32, y as u32, 1);
+                    }
+                    continue;
+                }
+            }
+            let mut cx = 0;
+            for (i, &(is_ref, px, py)) in pixels.iter().enumerate() {
+                let bit = if is_ref {
+                    reference.get(rx + px, ry + py)
+                } else {
+                    bitmap.get(x + px, y + py)
+                };
+                cx |= (bit as usize) << i;
+            }
+            if d.decode(&mut contexts[cx]) == 1 {
+                bitmap.set(x as u32, y as u32, 1);
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+/// The integer decoders of a text region, shared by all text regions of a symbol dictionary.
+struct TextDecoders {
+    dt: IntDecoder,
+    fs: IntDecoder,
+    ds: IntDecoder,
+    it: IntDecoder,
+    ri: IntDecoder,
+    rdw: IntDecoder,
+    rdh: IntDecoder,
+    rdx: IntDecoder,
+    rdy: IntDecoder,
+    id: IdDecoder,
+}
+impl TextDecoders {
+    fn new(num_symbols: usize) -> Result<TextDecoders> {
+        Ok(TextDecoders {
+            dt: IntDecoder::new(),
+            fs: IntDecoder::new(),
+            ds: IntDecoder::new(),
+            it: IntDecoder::new(),
+            ri: IntDecoder::new(),
+            rdw: IntDecoder::new(),
+            rdh: IntDecoder::new(),
+            rdx: IntDecoder::new(),
+            rdy: IntDecoder::new(),
+            id: IdDecoder::new(num_symbols)?,
+        })
+    }
+}
+
+impl TextDecoders {
+    fn get(&mut self, value: TextValue) -> &mut IntDecoder {
+        match value {
+            TextValue::Dt => &mut self.dt,
+            TextValue::Fs => &mut self.fs,
+            TextValue::Ds => &mut self.ds,
+            TextValue::Rdw => &mut self.rdw,
+            TextValue::Rdh => &mut self.rdh,
+            TextValue::Rdx => &mut self.rdx,
+            TextValue::Rdy => &mut self.rdy,
+        }
+    }
+}
+
+/// How the symbol IDs of a Huffman coded text region are coded.
+enum SymbolCodes {
+    Table(HuffmanTable),
+    /// Codes of a fixed length, in refinement/aggregate coded symbol dictionaries.
+    Fixed(u32),
+}
+
+/// The Huffman tables of a text region (7.4.3.1.6).
+struct TextTables {
+    fs: HuffmanTable,
+    ds: HuffmanTable,
+    dt: HuffmanTable,
+    rdw: HuffmanTable,
+    rdh: HuffmanTable,
+    rdx: HuffmanTable,
+    rdy: HuffmanTable,
+    rsize: HuffmanTable,
+    ids: SymbolCodes,
+}
+impl TextTables {
+    /// The tables for the refinement/aggregate coding of a symbol dictionary (6.5.8.2.3).
+    fn symbol_dict(num_symbols: usize) -> TextTables {
+        TextTables {
+            fs: HuffmanTable::standard(6),
+            ds: HuffmanTable::standard(8),
+            dt: HuffmanTable::standard(11),
+            rdw: HuffmanTable::standard(15),
+            rdh: HuffmanTable::standard(15),
+            rdx: HuffmanTable::standard(15),
+            rdy: HuffmanTable::standard(15),
+            rsize: HuffmanTable::standard(1),
+            ids: SymbolCodes::Fixed(symbol_code_len(num_symbols)),
+        }
+    }
+    fn get(&self, value: TextValue) -> &HuffmanTable {
+        match value {
+            TextValue::Dt => &self.dt,
+            TextValue::Fs => &self.fs,
+            TextValue::Ds => &self.ds,
+            TextValue::Rdw => &self.rdw,
+            TextValue::Rdh => &self.rdh,
+            TextValue::Rdx => &self.rdx,
+            TextValue::Rdy => &self.rdy,
+        }
+    }
+}
+
+/// The integer values of a text region.
+#[derive(Debug, Copy, Clone)]
+enum TextValue {
+    Dt,
+    Fs,
+    Ds,
+    Rdw,
+    Rdh,
+    Rdx,
+    Rdy,
+}
+
+/// The values of a text region, arithmetic or Huffman coded.
+enum TextCoding<'a, 'b> {
+    Arithmetic(&'a mut MqDecoder<'b>, &'a mut TextDecoders),
+    Huffman(&'a mut BitStream<'b>, &'a TextTables),
+}
+impl TextCoding<'_, '_> {
+    /// Returns `None` for OOB.
+    fn decode(&mut self, value: TextValue) -> Result<Option<i32>> {
+        match self {
+            TextCoding::Arithmetic(d, ids) => Ok(ids.get(value).decode(d)),
+            TextCoding::Huffman(s, tables) => tables.get(value).decode(s),
+        }
+    }
+    fn decode_value(&mut self, value: TextValue) -> Result<i32> {
+        self.decode(value)?.ok_or_else(|| other!("unexpected OOB in JBIG2 data"))
+    }
+    /// The T coordinate within a strip of `strips` rows.
+    fn strip_t(&mut self, strips: i32) -> Result<i32> {
+        match self {
+            TextCoding::Arithmetic(d, ids) => ids.it.decode_value(d),
+            TextCoding::Huffman(s, _) => Ok(s.bits(strips.trailing_zeros())? as i32),
+        }
+    }
+    fn symbol_id(&mut self) -> Result<usize> {
+        match self {
+            TextCoding::Arithmetic(d, ids) => Ok(ids.id.decode(d)),
+            TextCoding::Huffman(s, tables) => Ok(match tables.ids {
+                SymbolCodes::Table(ref table) => table.decode_value(s)? as u32 as usize,
+                SymbolCodes::Fixed(len) => s.bits(len)? as usize,
+            }),
+        }
+    }
+    fn refinement_flag(&mut self) -> Result<i32> {
+        match self {
+            TextCoding::Arithmetic(d, ids) => ids.ri.decode_value(d),
+            TextCoding::Huffman(s, _) => Ok(s.bit()? as i32),
+        }
+    }
+    /// Decode a refined symbol. Huffman coded data embeds it as arithmetic coded data, after
+    /// its size in bytes (6.4.11.1).
+    fn refinement(&mut self, budget: &Budget, contexts: &mut [Context], width: u32, height: u32, params: &RefinementParams) -> Result<Bitmap> {
+        match self {
+            TextCoding::Arithmetic(d, _) => decode_refinement(budget, d, contexts, width, height, params),
+            TextCoding::Huffman(s, tables) => {
+                let size = tables.rsize.decode_value(s)?;
+                let size = usize::try_from(size).map_err(|_| other!("invalid JBIG2 refinement size {}", size))?;
+                let mut d = MqDecoder::new(s.bytes(size)?);
+                decode_refinement(budget, &mut d, contexts, width, height, params)
+            }
+        }
+    }
+}
+
+struct TextParams<'a> {
+    width: u32,
+    height: u32,
+    num_instances: u32,
+    strips: i32,
+    symbols: &'a [Rc<Bitmap>],
+    default_pixel: u8,
+    op: CombinationOp,
+    transposed: bool,
+    ref_corner: u8,
+    ds_offset: i32,
+    refine: bool,
+    refinement_template: u8,
+    refinement_at: [(i32, i32); 2],
+}
+
+const BOTTOM_LEFT: u8 = 0;
+const TOP_LEFT: u8 = 1;
+const BOTTOM_RIGHT: u8 = 2;
+const TOP_RIGHT: u8 = 3;
+
+/// Text region decoding procedure (6.4).
+fn decode_text(budget: &Budget, coding: &mut TextCoding, gr_contexts: &mut [Context], params: &TextParams) -> Result<Bitmap> {
+    let mut bitmap = budget.bitmap(params.width, params.height, params.default_pixel)?;
+    let mut strip_t = coding.decode_value(TextValue::Dt)?.wrapping_mul(params.strips).wrapping_neg();
+    let mut first_s: i32 = 0;
+    let mut instances = 0;
+    while instances < params.num_instances {
+        strip_t = strip_t.wrapping_add(coding.decode_value(TextValue::Dt)?.wrapping_mul(params.strips));
+        let mut cur_s = 0;
+        let mut first = true;
+        loop {
+            if first {
+                first_s = first_s.wrapping_add(coding.decode_value(TextValue::Fs)?);
+                cur_s = first_s;
+                first = false;
+            } else {
+                match coding.decode(TextValue::Ds)? {
+                    Some(ds) => cur_s = cur_s.wrapping_add(ds).wrapping_add(params.ds_offset),
+                    None => break
+                }
+            }
+            if instances >= params.num_instances {
+                break;
+            }
+            let cur_t = if params.strips == 1 { 0 } else { coding.strip_t(params.strips)? };
+            let t = strip_t.wrapping_add(cur_t);
+            let id = coding.symbol_id()?;
+            let symbol = params.symbols.get(id).ok_or_else(|| other!("invalid JBIG2 symbol id {}", id))?;
+            let refine = params.refine && coding.refinement_flag()? != 0;
+
+            let refined;
+            let symbol: &Bitmap = if refine {
+                let rdw = coding.decode_value(TextValue::Rdw)?;
+                let rdh = coding.decode_value(TextValue::Rdh)?;
+                let rdx = coding.decode_value(TextValue::Rdx)?;
+                let rdy = coding.decode_value(TextValue::Rdy)?;
+                let width = (symbol.width as i32).wrapping_add(rdw).max(0) as u32;
+                let height = (symbol.height as i32).wrapping_add(rdh).max(0) as u32;
+                refined = coding.refinement(budget, gr_contexts, width, height, &RefinementParams {
+                    template: params.refinement_template,
+                    reference: symbol,
+                    dx: rdw.div_euclid(2).wrapping_add(rdx),
+                    dy: rdh.div_euclid(2).wrapping_add(rdy),
+                    typical_prediction: false,
+                    at: params.refinement_at,
+                })?;
+                &refined
+            } else {
+                symbol
+            };
+            let (w, h) = (symbol.width as i64, symbol.height as i64);
+
+            let mut s = cur_s as i64;
+            match (params.transposed, params.ref_corner) {
+                (false, TOP_RIGHT) | (false, BOTTOM_RIGHT) => s += w - 1,
+                (true, BOTTOM_LEFT) | (true, BOTTOM_RIGHT) => s += h - 1,
+                _ => {}
+            }
+            let (px, py) = if params.transposed { (t as i64, s) } else { (s, t as i64) };
+            let x = match params.ref_corner {
+                TOP_RIGHT | BOTTOM_RIGHT => px - w + 1,
+                _ => px
+            };
+            let y = match params.ref_corner {
+                BOTTOM_LEFT | BOTTOM_RIGHT => py - h + 1,
+                _ => py
+            };
+            bitmap.compose(symbol, x, y, params.op);
+            match (params.transposed, params.ref_corner) {
+                (false, TOP_LEFT) | (false, BOTTOM_LEFT) => s += w - 1,
+                (true, TOP_LEFT) | (true, TOP_RIGHT) => s += h - 1,
+                _ => {}
+            }
+            cur_s = s as i32;
+            instances += 1;
+        }
+    }
+    Ok(bitmap)
+}
+
+fn read_at(r: &mut Reader, n: usize) -> Result<[(i32, i32); 4]> {
+    let mut at = [(0, 0); 4];
+    for p in at.iter_mut().take(n) {
+        *p = (r.i8()? as i32, r.i8()? as i32);
+    }
+    Ok(at)
+}
+
+/// The values of a symbol dictionary, arithmetic or Huffman coded.
+enum SymbolCoding<'a> {
+    Arithmetic {
+        d: MqDecoder<'a>,
+        dh: IntDecoder,
+        dw: IntDecoder,
+        ex: IntDecoder,
+        ai: IntDecoder,
+        text: TextDecoders,
+    },
+    Huffman {
+        s: BitStream<'a>,
+        dh: HuffmanTable,
+        dw: HuffmanTable,
+        ex: HuffmanTable,
+        ai: HuffmanTable,
+        bmsize: HuffmanTable,
+        text: Box<TextTables>,
+    },
+}
+impl<'a> SymbolCoding<'a> {
+    fn height_delta(&mut self) -> Result<i32> {
+        match self {
+            SymbolCoding::Arithmetic { d, dh, .. } => dh.decode_value(d),
+            SymbolCoding::Huffman { s, dh, .. } => dh.decode_value(s),
+        }
+    }
+    /// Returns `None` for OOB, at the end of a height class.
+    fn width_delta(&mut self) -> Result<Option<i32>> {
+        match self {
+            SymbolCoding::Arithmetic { d, dw, .. } => Ok(dw.decode(d)),
+            SymbolCoding::Huffman { s, dw, .. } => dw.decode(s),
+        }
+    }
+    fn export_run(&mut self) -> Result<i32> {
+        match self {
+            SymbolCoding::Arithmetic { d, ex, .. } => ex.decode_value(d),
+            SymbolCoding::Huffman { s, ex, .. } => ex.decode_value(s),
+        }
+    }
+    fn aggregate_instances(&mut self) -> Result<i32> {
+        match self {
+            SymbolCoding::Arithmetic { d, ai, .. } => ai.decode_value(d),
+            SymbolCoding::Huffman { s, ai, .. } => ai.decode_value(s),
+        }
+    }
+    /// The values of the refinement and aggregate coding.
+    fn text(&mut self) -> TextCoding<'_, 'a> {
+        match self {
+            SymbolCoding::Arithmetic { d, text, .. } => TextCoding::Arithmetic(d, text),
+            SymbolCoding::Huffman { s, text, .. } => TextCoding::Huffman(s, text),
+        }
+    }
+}
+
+/// Read the collective bitmap of a height class in a Huffman coded symbol dictionary (6.5.9).
+fn collective_bitmap(budget: &Budget, s: &mut BitStream, bmsize: &HuffmanTable, width: u32, height: u32) -> Result<Bitmap> {
+    let size = bmsize.decode_value(s)?;
+    let size = usize::try_from(size).map_err(|_| other!("invalid JBIG2 collective bitmap size {}", size))?;
+    s.align();
+    if size != 0 {
+        return Ok(decode_mmr(budget, s.bytes(size)?, width, height)?.0);
+    }
+    // uncompressed
+    let stride = (width as usize).div_ceil(8);
+    let data = s.bytes(stride.checked_mul(height as usize).ok_or(PdfError::EOF)?)?;
+    let mut bitmap = budget.bitmap(width, height, 0)?;
+    for y in 0 .. height {
+        let row = &data[y as usize * stride ..];
+        for x in 0 .. width {
+            if (row[x as usize / 8] >> (7 - x % 8)) & 1 == 1 {
+                bitmap.set(x, y, 1);
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+struct SymbolDict {
+    symbols: Vec<Rc<Bitmap>>,
+    generic_contexts: Vec<Context>,
+    refinement_contexts: Vec<Context>,
+}
+
+enum SegmentResult {
+    Symbols(SymbolDict),
+    Patterns(Vec<Bitmap>),
+    Region(Bitmap),
+    Table(HuffmanTable),
+}
+
+struct Page {
+    bitmap: Bitmap,
+    default_pixel: u8,
+    /// The page height is not known in advance, and grows with the end of stripe segments.
+    striped: bool,
+}
+
+#[derive(Default)]
+struct Decoder {
+    page: Option<Page>,
+    results: HashMap<u32, SegmentResult>,
+    budget: Budget,
+}
+impl Decoder {
+    fn symbol_dicts<'a>(&'a self, referred: &'a [u32]) -> impl Iterator<Item=&'a SymbolDict> + 'a {
+        referred.iter().filter_map(move |n| match self.results.get(n) {
+            Some(SegmentResult::Symbols(dict)) => Some(dict),
+            _ => None
+        })
+    }
+
+    /// The custom Huffman tables among the `referred` segments.
+    fn tables<'a>(&'a self, referred: &'a [u32]) -> impl Iterator<Item=&'a HuffmanTable> + 'a {
+        referred.iter().filter_map(move |n| match self.results.get(n) {
+            Some(SegmentResult::Table(table)) => Some(table),
+            _ => None
+        })
+    }
+
+    fn process(&mut self, segment: &Segment) -> Result<()> {
+        let mut r = Reader::new(segment.data);
+        match segment.kind {
+            0 => {
+                let dict = self.symbol_dict(segment, &mut r)?;
+                self.results.insert(segment.number, SegmentResult::Symbols(dict));
+            }
+            4 | 6 | 7 => {
+                let info = RegionInfo::parse(&mut r)?;
+                let bitmap = self.text_region(segment, &info, &mut r)?;
+                self.region_result(segment, &info, bitmap)?;
+            }
+            16 => {
+                let patterns = pattern_dict(&self.budget, &mut r)?;
+                self.results.insert(segment.number, SegmentResult::Patterns(patterns));
+            }
+            20 | 22 | 23 => {
+                let info = RegionInfo::parse(&mut r)?;
+                let bitmap = self.halftone_region(segment, &info, &mut r)?;
+                self.region_result(segment, &info, bitmap)?;
+            }
+            36 | 38 | 39 => {
+                let mut info = RegionInfo::parse(&mut r)?;
+                if segment.unknown_length {
+                    let data = segment.data;
+                    let b = &data[data.len() - 4 ..];
+                    info.height = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+                }
+                let bitmap = generic_region(&self.budget, &info, &mut r)?;
+                self.region_result(segment, &info, bitmap)?;
+            }
+            40 | 42 | 43 => {
+                let info = RegionInfo::parse(&mut r)?;
+                let bitmap = self.refinement_region(segment, &info, &mut r)?;
+                self.region_result(segment, &info, bitmap)?;
+            }
+            48 => {
+                let width = r.u32()?;
+                let height = r.u32()?;
+                let _resolution = (r.u32()?, r.u32()?);
+                let flags = r.u8()?;
+                let default_pixel = (flags >> 2) & 1;
+                let striped = height == 0xffff_ffff;
+                let height = if striped { 0 } else { height };
+                let bitmap = self.budget.bitmap(width, height, default_pixel)?;
+                self.page = Some(Page { bitmap, default_pixel, striped });
+            }
+            50 => {
+                let row = r.u32()?;
+                if let Some(ref mut page) = self.page {
+                    if page.striped {
+                        page.bitmap.grow(&self.budget, row.saturating_add(1), page.default_pixel)?;
+                    }
+                }
+            }
+            53 => {
+                let table = HuffmanTable::parse(&mut r)?;
+                self.results.insert(segment.number, SegmentResult::Table(table));
+            }
+            // end of page, end of file, profiles and extensions
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Store an intermediate region, or draw an immediate region on the page.
+    fn region_result(&mut self, segment: &Segment, info: &RegionInfo, bitmap: Bitmap) -> Result<()> {
+        match segment.kind {
+            4 | 20 | 36 | 40 => {
+                self.results.insert(segment.number, SegmentResult::Region(bitmap));
+            }
+            _ => {
+                let page = self.page.as_mut().ok_or_else(|| other!("JBIG2 region before page information"))?;
+                if page.striped {
+                    page.bitmap.grow(&self.budget, info.y.saturating_add(bitmap.height), page.default_pixel)?;
+                }
+                page.bitmap.compose(&bitmap, info.x as i64, info.y as i64, info.op);
+            }
+        }
+        Ok(())
+    }
+
+    fn symbol_dict(&self, segment: &Segment, r: &mut Reader) -> Result<SymbolDict> {
+        let flags = r.u16()?;
+        let huffman = flags & 1 != 0;
+        let refine_aggregate = flags & 2 != 0;
+        let context_used = flags & 0x100 != 0;
+        let template = ((flags >> 10) & 3) as u8;
+        let refinement_template = ((flags >> 12) & 1) as u8;
+        let at = if huffman {
+            [(0, 0); 4]
+        } else {
+            read_at(r, if template == 0 { 4 } else { 1 })?
+        };
+        let refinement_at = if refine_aggregate && refinement_template == 0 {
+            let at = read_at(r, 2)?;
+            [at[0], at[1]]
+        } else {
+            [(0, 0); 2]
+        };
+        let num_exported = r.u32()? as usize;
+        let num_new = r.u32()? as usize;
+
+        let mut symbols: Vec<Rc<Bitmap>> = vec![];
+        let mut last_dict = None;
+        for dict in self.symbol_dicts(&segment.referred) {
+            symbols.extend(dict.symbols.iter().cloned());
+            last_dict = Some(dict);
+        }
+        let num_inputs = symbols.len();
+        let (mut gb_contexts, mut gr_contexts) = match last_dict {
+            Some(dict) if context_used => (dict.generic_contexts.clone(), dict.refinement_contexts.clone()),
+            _ => (generic_contexts(template), refinement_contexts(refinement_template))
+        };
+
+        let mut coding = if huffman {
+            let mut custom = self.tables(&segment.referred);
+            SymbolCoding::Huffman {
+                dh: select_table((flags >> 2) & 3, &[4, 5], 3, &mut custom)?,
+                dw: select_table((flags >> 4) & 3, &[2, 3], 3, &mut custom)?,
+                bmsize: select_table((flags >> 6) & 1, &[1], 1, &mut custom)?,
+                ai: select_table((flags >> 7) & 1, &[1], 1, &mut custom)?,
+                ex: HuffmanTable::standard(1),
+                text: Box::new(TextTables::symbol_dict(num_inputs + num_new)),
+                s: BitStream::new(r.rest()),
+            }
+        } else {
+            SymbolCoding::Arithmetic {
+                d: MqDecoder::new(r.rest()),
+                dh: IntDecoder::new(),
+                dw: IntDecoder::new(),
+                ex: IntDecoder::new(),
+                ai: IntDecoder::new(),
+                text: TextDecoders::new(num_inputs + num_new)?,
+            }
+        };
+
+        let mut height: i32 = 0;
+        while symbols.len() < num_inputs + num_new {
+            height = height.wrapping_add(coding.height_delta()?);
+            if height < 0 {
+                bail!("negative JBIG2 symbol height");
+            }
+            let mut width: i32 = 0;
+            // symbols sharing a collective bitmap, in Huffman coded dictionaries
+            let mut widths = vec![];
+            while let Some(dw) = coding.width_delta()? {
+                if symbols.len() + widths.len() >= num_inputs + num_new {
+                    bail!("too many symbols in JBIG2 symbol dictionary");
+                }
+                width = width.wrapping_add(dw);
+                if width < 0 {
+                    bail!("negative JBIG2 symbol width");
+                }
+                if refine_aggregate {
+                    let instances = coding.aggregate_instances()?;
+                    let mut text = coding.text();
+                    let bitmap = if instances == 1 {
+                        let id = text.symbol_id()?;
+                        let rdx = text.decode_value(TextValue::Rdx)?;
+                        let rdy = text.decode_value(TextValue::Rdy)?;
+                        let reference = symbols.get(id).ok_or_else(|| other!("invalid JBIG2 symbol id {}", id))?;
+                        text.refinement(&self.budget, &mut gr_contexts, width as u32, height as u32, &RefinementParams {
+                            template: refinement_template,
+                            reference,
+                            dx: rdx,
+                            dy: rdy,
+                            typical_prediction: false,
+                            at: refinement_at,
+                        })?
+                    } else {
+                        decode_text(&self.budget, &mut text, &mut gr_contexts, &TextParams {
+                            width: width as u32,
+                            height: height as u32,
+                            num_instances: instances.max(0) as u32,
+                            strips: 1,
+                            symbols: &symbols,
+                            default_pixel: 0,
+                            op: CombinationOp::Or,
+                            transposed: false,
+                            ref_corner: TOP_LEFT,
+                            ds_offset: 0,
+                            refine: true,
+                            refinement_template,
+                            refinement_at,
+                        })?
+                    };
+                    symbols.push(Rc::new(bitmap));
+                } else if let SymbolCoding::Arithmetic { ref mut d, .. } = coding {
+                    let bitmap = decode_generic(&self.budget, d, &mut gb_contexts, width as u32, height as u32, &GenericParams {
+                        template,
+                        typical_prediction: false,
+                        at,
+                        skip: None,
+                    })?;
+                    symbols.push(Rc::new(bitmap));
+                } else {
+                    widths.push(width as u32);
+                }
+            }
+            if let SymbolCoding::Huffman { ref mut s, ref bmsize, .. } = coding {
+                if !refine_aggregate {
+                    let total_width = widths.iter().try_fold(0u32, |sum, &w| sum.checked_add(w))
+                        .ok_or_else(|| other!("JBIG2 collective bitmap too wide"))?;
+                    let collective = collective_bitmap(&self.budget, s, bmsize, total_width, height as u32)?;
+                    let mut x = 0;
+                    for w in widths {
+                        symbols.push(Rc::new(collective.sub(&self.budget, x, 0, w, height as u32)?));
+                        x += w;
+                    }
+                }
+            }
+        }
+
+        let mut exported = Vec::with_capacity(num_exported.min(symbols.len()));
+        let mut export = false;
+        let mut i = 0;
+        while i < symbols.len() {
+            let run = coding.export_run()?.max(0) as usize;
+            let end = i.saturating_add(run).min(symbols.len());
+            if export {
+                exported.extend(symbols[i .. end].iter().cloned());
+            }
+            i = end;
+            export = !export;
+            if run == 0 && !export {
+                // two empty runs in a row can not make progress
+                break;
+            }
+        }
+        if exported.len() != num_exported {
+            warn!("JBIG2 symbol dictionary exports {} symbols instead of {}", exported.len(), num_exported);
+        }
+
+        Ok(SymbolDict {
+            symbols: exported,
+            generic_contexts: gb_contexts,
+            refinement_contexts: gr_contexts,
+        })
+    }
+
+    fn text_region(&self, segment: &Segment, info: &RegionInfo, r: &mut Reader) -> Result<Bitmap> {
+        let flags = r.u16()?;
+        let huffman = flags & 1 != 0;
+        let refine = flags & 2 != 0;
+        let strips = 1 << ((flags >> 2) & 3);
+        let ref_corner = ((flags >> 4) & 3) as u8;
+        let transposed = flags & 0x40 != 0;
+        let op = CombinationOp::from_u8(((flags >> 7) & 3) as u8)?;
+        let default_pixel = ((flags >> 9) & 1) as u8;
+        // 5 bit two's complement
+        let ds_offset = (((flags >> 10) & 0x1f) as i32) << 27 >> 27;
+        let refinement_template = ((flags >> 15) & 1) as u8;
+        let huffman_flags = if huffman { r.u16()? } else { 0 };
+        let refinement_at = if refine && refinement_template == 0 {
+            let at = read_at(r, 2)?;
+            [at[0], at[1]]
+        } else {
+            [(0, 0); 2]
+        };
+        let num_instances = r.u32()?;
+
+        let symbols: Vec<Rc<Bitmap>> = self.symbol_dicts(&segment.referred)
+            .flat_map(|dict| dict.symbols.iter().cloned())
+            .collect();
+
+        let mut gr_contexts = refinement_contexts(refinement_template);
+        let params = TextParams {
+            width: info.width,
+            height: info.height,
+            num_instances,
+            strips,
+            symbols: &symbols,
+            default_pixel,
+            op,
+            transposed,
+            ref_corner,
+            ds_offset,
+            refine,
+            refinement_template,
+            refinement_at,
+        };
+        if huffman {
+            let mut custom = self.tables(&segment.referred);
+            let fs = select_table(huffman_flags & 3, &[6, 7], 3, &mut custom)?;
+            let ds = select_table((huffman_flags >> 2) & 3, &[8, 9, 10], 3, &mut custom)?;
+            let dt = select_table((huffman_flags >> 4) & 3, &[11, 12, 13], 3, &mut custom)?;
+            let rdw = select_table((huffman_flags >> 6) & 3, &[14, 15], 3, &mut custom)?;
+            let rdh = select_table((huffman_flags >> 8) & 3, &[14, 15], 3, &mut custom)?;
+            let rdx = select_table((huffman_flags >> 10) & 3, &[14, 15], 3, &mut custom)?;
+            let rdy = select_table((huffman_flags >> 12) & 3, &[14, 15], 3, &mut custom)?;
+            let rsize = select_table((huffman_flags >> 14) & 1, &[1], 1, &mut custom)?;
+            let mut s = BitStream::new(r.rest());
+            let ids = SymbolCodes::Table(symbol_id_table(&mut s, symbols.len())?);
+            let tables = TextTables { fs, ds, dt, rdw, rdh, rdx, rdy, rsize, ids };
+            decode_text(&self.budget, &mut TextCoding::Huffman(&mut s, &tables), &mut gr_contexts, &params)
+        } else {
+            let mut d = MqDecoder::new(r.rest());
+            let mut ids = TextDecoders::new(symbols.len())?;
+            decode_text(&self.budget, &mut TextCoding::Arithmetic(&mut d, &mut ids), &mut gr_contexts, &params)
+        }
+    }
+
+    fn halftone_region(&self, segment: &Segment, info: &RegionInfo, r: &mut Reader) -> Result<Bitmap> {
+        let flags = r.u8()?;
+        let mmr = flags & 1 != 0;
+        let template = (flags >> 1) & 3;
+        let enable_skip = flags & 8 != 0;
+        let op = CombinationOp::from_u8((flags >> 4) & 7)?;
+        let default_pixel = flags >> 7;
+        let grid_width = r.u32()?;
+        let grid_height = r.u32()?;
+        let grid_x = r.i32()? as i64;
+        let grid_y = r.i32()? as i64;
+        let step_x = r.u16()? as i64;
+        let step_y = r.u16()? as i64;
+
+        let patterns = segment.referred.iter().find_map(|n| match self.results.get(n) {
+            Some(SegmentResult::Patterns(patterns)) => Some(patterns),
+            _ => None
+        }).ok_or_else(|| other!("JBIG2 halftone region without pattern dictionary"))?;
+        let (pattern_width, pattern_height) = match patterns.first() {
+            Some(p) => (p.width as i64, p.height as i64),
+            None => bail!("empty JBIG2 pattern dictionary")
+        };
+
+        let mut bitmap = self.budget.bitmap(info.width, info.height, default_pixel)?;
+        let position = |m: i64, n: i64| {
+            let x = (grid_x + m * step_y + n * step_x) >> 8;
+            let y = (grid_y + m * step_x - n * step_y) >> 8;
+            (x, y)
+        };
+
+        let skip = if enable_skip {
+            let mut skip = self.budget.bitmap(grid_width, grid_height, 0)?;
+            for m in 0 .. grid_height {
+                for n in 0 .. grid_width {
+                    let (x, y) = position(m as i64, n as i64);
+                    if x + pattern_width <= 0 || x >= info.width as i64 || y + pattern_height <= 0 || y >= info.height as i64 {
+                        skip.set(n, m, 1);
+                    }
+                }
+            }
+            Some(skip)
+        } else {
+            None
+        };
+
+        // Gray-scale image decoding procedure (Annex C.5)
+        let mut bits_per_value = 0;
+        while (1usize << bits_per_value) < patterns.len() {
+            bits_per_value += 1;
+        }
+        let mut data = r.rest();
+        let mut d = MqDecoder::new(data);
+        let mut contexts = generic_contexts(template);
+        let params = GenericParams {
+            template,
+            typical_prediction: false,
+            at: [(if template <= 1 { 3 } else { 2 }, -1), (-3, -1), (2, -2), (-2, -2)],
+            skip: skip.as_ref(),
+        };
+        // one usize per grid cell
+        self.budget.charge(grid_width, grid_height.saturating_mul(usize::BITS))?;
+        let mut values = vec![0usize; grid_width as usize * grid_height as usize];
+        let mut previous: Option<Bitmap> = None;
+        for j in (0 .. bits_per_value).rev() {
+            let mut plane = if mmr {
+                let (plane, used) = decode_mmr(&self.budget, data, grid_width, grid_height)?;
+                data = &data[used ..];
+                plane
+            } else {
+                decode_generic(&self.budget, &mut d, &mut contexts, grid_width, grid_height, &params)?
+            };
+            if let Some(ref previous) = previous {
+                // gray code
+                for (p, &q) in plane.data.iter_mut().zip(previous.data.iter()) {
+                    *p ^= q;
+                }
+            }
+            for (v, &p) in values.iter_mut().zip(plane.data.iter()) {
+                *v |= (p as usize) << j;
+            }
+            previous = Some(plane);
+        }
+
+        for m in 0 .. grid_height {
+            for n in 0 .. grid_width {
+                let (x, y) = position(m as i64, n as i64);
+                let value = values[m as usize * grid_width as usize + n as usize].min(patterns.len() - 1);
+                bitmap.compose(&patterns[value], x, y, op);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    fn refinement_region(&mut self, segment: &Segment, info: &RegionInfo, r: &mut Reader) -> Result<Bitmap> {
+        let flags = r.u8()?;
+        let template = flags & 1;
+        let typical_prediction = flags & 2 != 0;
+        let at = if template == 0 {
+            let at = read_at(r, 2)?;
+            [at[0], at[1]]
+        } else {
+            [(0, 0); 2]
+        };
+
+        let intermediate = segment.referred.iter().find_map(|n| match self.results.remove(n) {
+            Some(SegmentResult::Region(bitmap)) => Some(bitmap),
+            Some(other) => {
+                self.results.insert(*n, other);
+                None
+            }
+            None => None
+        });
+        let reference = match intermediate {
+            Some(bitmap) => bitmap,
+            None => {
+                let page = self.page.as_ref().ok_or_else(|| other!("JBIG2 region before page information"))?;
+                page.bitmap.sub(&self.budget, info.x, info.y, info.width, info.height)?
+            }
+        };
+
+        let mut d = MqDecoder::new(r.rest());
+        let mut contexts = refinement_contexts(template);
+        decode_refinement(&self.budget, &mut d, &mut contexts, info.width, info.height, &RefinementParams {
+            template,
+            reference: &reference,
+            dx: 0,
+            dy: 0,
+            typical_prediction,
+            at,
+        })
+    }
+}
+
+fn generic_region(budget: &Budget, info: &RegionInfo, r: &mut Reader) -> Result<Bitmap> {
+    let flags = r.u8()?;
+    let mmr = flags & 1 != 0;
+    let template = (flags >> 1) & 3;
+    let typical_prediction = flags & 8 != 0;
+    if flags & 0x10 != 0 {
+        bail!("extended JBIG2 generic region templates are not supported");
+    }
+    if mmr {
+        return Ok(decode_mmr(budget, r.rest(), info.width, info.height)?.0);
+    }
+    let at = read_at(r, if template == 0 { 4 } else { 1 })?;
+    let mut d = MqDecoder::new(r.rest());
+    let mut contexts = generic_contexts(template);
+    decode_generic(budget, &mut d, &mut contexts, info.width, info.height, &GenericParams {
+        template,
+        typical_prediction,
+        at,
+        skip: None,
+    })
+}
+
+fn pattern_dict(budget: &Budget, r: &mut Reader) -> Result<Vec<Bitmap>> {
+    let flags = r.u8()?;
+    let mmr = flags & 1 != 0;
+    let template = (flags >> 1) & 3;
+    let width = r.u8()? as u32;
+    let height = r.u8()? as u32;
+    let gray_max = r.u32()?;
+    let count = gray_max.checked_add(1).ok_or_else(|| other!("too many JBIG2 patterns"))?;
+    let total_width = count.checked_mul(width).ok_or_else(|| other!("too many JBIG2 patterns"))?;
+
+    let collective = if mmr {
+        decode_mmr(budget, r.rest(), total_width, height)?.0
+    } else {
+        let mut d = MqDecoder::new(r.rest());
+        let mut contexts = generic_contexts(template);
+        decode_generic(budget, &mut d, &mut contexts, total_width, height, &GenericParams {
+            template,
+            typical_prediction: false,
+            at: [(-(width as i32), 0), (-3, -1), (2, -2), (-2, -2)],
+            skip: None,
+        })?
+    };
+    (0 .. count).map(|i| collective.sub(budget, i * width, 0, width, height)).collect()
+}
+
+/// Decode the embedded JBIG2 stream `data`, using the segments in `globals`.
+///
+/// Returns the page as 1 bit per pixel, with 0 for black like the `DeviceGray` color space.
+pub fn decode(data: &[u8], globals: Option<&[u8]>, limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    let mut decoder = Decoder {
+        budget: Budget { limit, ..Budget::default() },
+        ..Decoder::default()
+    };
+    for data in globals.into_iter().chain(Some(data)) {
+        let mut r = Reader::new(data);
+        while !r.is_empty() {
+            let segment = parse_segment(&mut r)?;
+            if segment.kind == 51 {
+                break;
+            }
+            decoder.process(&segment)?;
+        }
+    }
+
+    let page = decoder.page.ok_or_else(|| other!("JBIG2 stream without page information"))?;
+    let bitmap = page.bitmap;
+    let stride = (bitmap.width as usize).div_ceil(8);
+    let mut out = vec![0xff; stride * bitmap.height as usize];
+    for y in 0 .. bitmap.height {
+        let row = &mut out[y as usize * stride .. (y as usize + 1) * stride];
+        for (x, &pixel) in bitmap.row(y).iter().enumerate() {
+            if pixel == 1 {
+                row[x / 8] &= !(0x80 >> (x % 8));
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(number: u8, kind: u8, referred: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0, 0, 0, number, kind, (referred.len() as u8) << 5];
+        out.extend_from_slice(referred);
+        out.push(1);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn page_info(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0, 0, 0]);
+        data
+    }
+
+    fn region_info(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.push(0);
+        data
+    }
+
+    /// MMR coded rows with black pixels at the given positions, and an end of block.
+    fn mmr(width: u16, rows: &[&[usize]]) -> Vec<u8> {
+        use fax::{Color, encoder::Encoder, VecWriter};
+
+        let mut encoder = Encoder::new(VecWriter::new());
+        for &black in rows {
+            let line = (0 .. width as usize).map(|x| if black.contains(&x) { Color::Black } else { Color::White });
+            encoder.encode_line(line, width).unwrap();
+        }
+        encoder.finish().unwrap().finish()
+    }
+
+    /// Writes Huffman coded data, most significant bit first.
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        pos: usize,
+    }
+    impl BitWriter {
+        fn bits(&mut self, value: u32, n: u32) -> &mut Self {
+            for i in (0 .. n).rev() {
+                if self.pos.is_multiple_of(8) {
+                    self.data.push(0);
+                }
+                if (value >> i) & 1 == 1 {
+                    *self.data.last_mut().unwrap() |= 0x80 >> (self.pos % 8);
+                }
+                self.pos += 1;
+            }
+            self
+        }
+        fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+            self.data.extend_from_slice(bytes);
+            self.pos = self.data.len() * 8;
+            self
+        }
+        /// Write `value`, or OOB for `None`, with a line of `table` that is not a lower or upper range.
+        fn huffman(&mut self, table: &HuffmanTable, value: Option<i32>) -> &mut Self {
+            for (i, &(first, start, count)) in table.lengths.iter().enumerate() {
+                for j in 0 .. count {
+                    let line = table.lines[start + j];
+                    let offset = match (line.kind, value) {
+                        (RangeKind::Oob, None) => 0,
+                        (RangeKind::Normal, Some(v)) if v >= line.low && ((v - line.low) as u64) < 1 << line.range_len => (v - line.low) as u32,
+                        _ => continue
+                    };
+                    return self.bits(first + j as u32, i as u32 + 1).bits(offset, line.range_len);
+                }
+            }
+            panic!("{:?} not in table", value);
+        }
+    }
+
+    /// An MMR coded 8x2 generic region.
+    #[test]
+    fn mmr_generic_region() {
+        let mmr = mmr(8, &[&[2, 3, 4, 5], &[]]);
+
+        let mut region = region_info(8, 2);
+        region.push(1);
+        region.extend_from_slice(&mmr);
+
+        let mut data = segment(0, 48, &[], &page_info(8, 2));
+        data.extend(segment(1, 38, &[], &region));
+        assert_eq!(decode(&data, None, None).unwrap(), [0b1100_0011, 0xff]);
+    }
+
+    #[test]
+    fn generic_region_contexts() {
+        // all white data decodes to some bitmap without panicking, in every template
+        for template in 0 .. 4 {
+            let mut d = MqDecoder::new(&[0; 16]);
+            let mut contexts = generic_contexts(template);
+            let bitmap = decode_generic(&Budget::default(), &mut d, &mut contexts, 5, 3, &GenericParams {
+                template,
+                typical_prediction: true,
+                at: [(3, -1), (-3, -1), (2, -2), (-2, -2)],
+                skip: None,
+            }).unwrap();
+            assert_eq!(bitmap.data.len(), 15);
+        }
+    }
+
+    /// The canonical codes of the standard tables (Annex B.5).
+    #[test]
+    fn standard_tables() {
+        fn decode(n: usize, bits: &[(u32, u32)]) -> Option<i32> {
+            let mut w = BitWriter::default();
+            for &(value, len) in bits {
+                w.bits(value, len);
+            }
+            HuffmanTable::standard(n).decode(&mut BitStream::new(&w.data)).unwrap()
+        }
+        for n in 1 ..= 15 {
+            // every table is a complete prefix code
+            let table = HuffmanTable::standard(n);
+            let kraft: u64 = table.lengths.iter().enumerate().map(|(i, &(_, _, count))| (count as u64) << (31 - i)).sum();
+            assert_eq!(kraft, 1 << 32, "table B.{}", n);
+        }
+        assert_eq!(decode(1, &[(0b111, 3), (2, 32)]), Some(65810));
+        assert_eq!(decode(3, &[(0xfe, 8), (0, 8)]), Some(-256));
+        assert_eq!(decode(3, &[(0xff, 8), (1, 32)]), Some(-258));
+        assert_eq!(decode(3, &[(0x7e, 7), (5, 32)]), Some(80));
+        assert_eq!(decode(3, &[(0x3e, 6)]), None);
+        assert_eq!(decode(8, &[(0b01, 2)]), None);
+        assert_eq!(decode(8, &[(0b00, 2), (1, 1)]), Some(1));
+        assert_eq!(decode(10, &[(0b00, 2), (3, 2)]), Some(1));
+        assert_eq!(decode(10, &[(0b01, 2), (0, 6)]), Some(6));
+        assert_eq!(decode(10, &[(0b10, 2)]), None);
+        assert_eq!(decode(15, &[(0x7e, 7), (0, 32)]), Some(-25));
+        assert_eq!(decode(15, &[(0x7f, 7), (0, 32)]), Some(25));
+    }
+
+    /// A table segment with the lines of B.2 below 11, an upper range and OOB.
+    fn table_segment() -> Vec<u8> {
+        // OOB, 3 prefix bits, 2 range bits
+        let mut data = vec![0x15];
+        data.extend_from_slice(&0i32.to_be_bytes());
+        data.extend_from_slice(&11i32.to_be_bytes());
+        let mut w = BitWriter::default();
+        for &(prefix, range) in &[(1, 0), (2, 0), (3, 0), (4, 3)] {
+            w.bits(prefix, 3).bits(range, 2);
+        }
+        // no lower range, upper range and OOB
+        w.bits(0, 3).bits(5, 3).bits(5, 3);
+        data.extend_from_slice(&w.data);
+        data
+    }
+
+    #[test]
+    fn custom_table() {
+        let table = HuffmanTable::parse(&mut Reader::new(&table_segment())).unwrap();
+        let mut w = BitWriter::default();
+        w.bits(0b0, 1).bits(0b1110, 4).bits(5, 3).bits(0b11110, 5).bits(7, 32).bits(0b11111, 5);
+        let mut s = BitStream::new(&w.data);
+        assert_eq!(table.decode(&mut s).unwrap(), Some(0));
+        assert_eq!(table.decode(&mut s).unwrap(), Some(8));
+        assert_eq!(table.decode(&mut s).unwrap(), Some(18));
+        assert_eq!(table.decode(&mut s).unwrap(), None);
+    }
+
+    /// A Huffman coded symbol dictionary with two symbols of height 2: `111 101` and `1001 0110`.
+    fn huffman_symbol_dict(mmr_bitmap: bool) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&2u32.to_be_bytes());
+        let b1 = HuffmanTable::standard(1);
+        let b2 = HuffmanTable::standard(2);
+        let mut w = BitWriter::default();
+        w.huffman(&HuffmanTable::standard(4), Some(2));
+        w.huffman(&b2, Some(3)).huffman(&b2, Some(1)).huffman(&b2, None);
+        if mmr_bitmap {
+            let collective = mmr(7, &[&[0, 1, 2, 3, 6], &[0, 2, 4, 5]]);
+            w.huffman(&b1, Some(collective.len() as i32)).bytes(&collective);
+        } else {
+            w.huffman(&b1, Some(0)).bytes(&[0b1111_0010, 0b1010_1100]);
+        }
+        // export both symbols
+        w.huffman(&b1, Some(0)).huffman(&b1, Some(2));
+        data.extend_from_slice(&w.data);
+        data
+    }
+
+    /// A Huffman coded text region placing both symbols, with a custom DT table.
+    #[test]
+    fn huffman_text_region() {
+        let mut region = region_info(8, 4);
+        // top left reference corner, custom DT table
+        region.extend_from_slice(&0x0011u16.to_be_bytes());
+        region.extend_from_slice(&0x0030u16.to_be_bytes());
+        region.extend_from_slice(&2u32.to_be_bytes());
+        let mut w = BitWriter::default();
+        // symbol ID table: one code length of 1 for both symbols
+        for i in 0 .. 35 {
+            w.bits(if i == 1 { 1 } else { 0 }, 4);
+        }
+        w.bits(0b00, 2).bytes(&[]);
+        // strip T 0, first S 0, symbol 0; S 4, symbol 1; end of strip
+        w.bits(0, 1).bits(0, 1);
+        w.huffman(&HuffmanTable::standard(6), Some(0)).bits(0, 1);
+        w.huffman(&HuffmanTable::standard(8), Some(2)).bits(1, 1);
+        w.huffman(&HuffmanTable::standard(8), None);
+        region.extend_from_slice(&w.data);
+
+        for &mmr_bitmap in &[false, true] {
+            let mut data = segment(0, 48, &[], &page_info(8, 4));
+            data.extend(segment(1, 0, &[], &huffman_symbol_dict(mmr_bitmap)));
+            data.extend(segment(2, 53, &[], &table_segment()));
+            data.extend(segment(3, 6, &[1, 2], &region));
+            assert_eq!(decode(&data, None, None).unwrap(), [!0b1110_1001, !0b1010_0110, 0xff, 0xff]);
+        }
+    }
+
+    /// An MMR coded halftone region with two bitplanes.
+    #[test]
+    fn mmr_halftone_region() {
+        // four 1x1 patterns, 1 and 2 black
+        let mut patterns = vec![1, 1, 1];
+        patterns.extend_from_slice(&3u32.to_be_bytes());
+        patterns.extend(mmr(4, &[&[1, 2]]));
+
+        // gray values 0 1 / 2 3, gray coded
+        let mut region = region_info(2, 2);
+        region.push(1);
+        region.extend_from_slice(&2u32.to_be_bytes());
+        region.extend_from_slice(&2u32.to_be_bytes());
+        region.extend_from_slice(&[0; 8]);
+        region.extend_from_slice(&256u16.to_be_bytes());
+        region.extend_from_slice(&0u16.to_be_bytes());
+        region.extend(mmr(2, &[&[], &[0, 1]]));
+        region.extend(mmr(2, &[&[1], &[0]]));
+
+        let mut data = segment(0, 48, &[], &page_info(2, 2));
+        data.extend(segment(1, 16, &[], &patterns));
+        data.extend(segment(2, 22, &[1], &region));
+        assert_eq!(decode(&data, None, None).unwrap(), [!0b0100_0000, !0b1000_0000]);
+    }
+
+    /// Regions count against the output limit like the page.
+    #[test]
+    fn limit() {
+        let limit = OutputLimit { max: 1000, limit: "test" };
+        let mut region = region_info(1 << 16, 1 << 16);
+        region.push(1);
+        let mut data = segment(0, 48, &[], &page_info(8, 8));
+        data.extend(segment(1, 38, &[], &region));
+        assert!(decode(&data, None, Some(limit)).unwrap_err().is_limit_exceeded());
+
+        let page = segment(0, 48, &[], &page_info(1 << 16, 1 << 16));
+        assert!(decode(&page, None, Some(limit)).unwrap_err().is_limit_exceeded());
+    }
+
+    #[test]
+    fn compose() {
+        let symbol = Rc::new(Bitmap { width: 2, height: 2, data: vec![1, 0, 0, 1] });
+        let mut page = Bitmap::new(4, 4, 0).unwrap();
+        page.compose(&symbol, 1, 1, CombinationOp::Or);
+        page.compose(&symbol, 2, 2, CombinationOp::Xor);
+        assert_eq!(page.data, [
+            0, 0, 0, 0,
+            0, 1, 0, 0,
+            0, 0, 0, 0,
+            0, 0, 0, 1,
+        ]);
+    }
+}
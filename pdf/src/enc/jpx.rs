@@ -0,0 +1,1867 @@
+//! JPEG 2000 decoding (ITU T.800), as used by the `JPXDecode` filter.
+//!
+//! Reads JP2 files and raw codestreams with any progression order, tiling, precinct partition
+//! and code-block coding style, both wavelet filters, the component transforms and region of
+//! interest shifts. The colour specification, palette, component mapping and channel definition
+//! boxes of JP2 files are applied.
+//! Progression order changes, packed packet headers and high throughput code-blocks are not
+//! supported.
+
+use crate::error::*;
+use super::OutputLimit;
+use super::mq::{MqDecoder, Context};
+
+macro_rules! other {
+    ($($t:tt)*) => (PdfError::Other { msg: format!($($t)*) })
+}
+
+/// The colour space given in the header of a JP2 file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JpxColorSpace {
+    DeviceGray,
+    /// sRGB, or sYCC which is converted to RGB while decoding.
+    DeviceRGB,
+    DeviceCMYK,
+    /// An embedded ICC profile.
+    Icc(Vec<u8>),
+}
+impl JpxColorSpace {
+    /// The number of colour components, if known.
+    pub fn components(&self) -> Option<usize> {
+        match *self {
+            JpxColorSpace::DeviceGray => Some(1),
+            JpxColorSpace::DeviceRGB => Some(3),
+            JpxColorSpace::DeviceCMYK => Some(4),
+            JpxColorSpace::Icc(ref profile) => match profile.get(16 .. 20) {
+                Some(b"GRAY") => Some(1),
+                Some(b"RGB ") | Some(b"Lab ") | Some(b"XYZ ") => Some(3),
+                Some(b"CMYK") => Some(4),
+                _ => None
+            }
+        }
+    }
+}
+
+/// A decoded JPEG 2000 image.
+///
+/// All samples are scaled to 8 bits, whatever the precision of the codestream.
+#[derive(Debug, Clone)]
+pub struct JpxImage {
+    pub width: u32,
+    pub height: u32,
+    /// The colour space of a JP2 file. Raw codestreams do not have one.
+    pub color_space: Option<JpxColorSpace>,
+    /// The number of colour components per pixel in `data`.
+    pub components: usize,
+    /// Interleaved colour samples.
+    pub data: Vec<u8>,
+    /// The opacity channel, one sample per pixel.
+    pub alpha: Option<Vec<u8>>,
+    /// Whether the colour samples have been multiplied with `alpha`.
+    pub premultiplied: bool,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len()).ok_or(PdfError::EOF)?;
+        let bytes = &self.data[self.pos .. end];
+        self.pos = end;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos ..];
+        self.pos = self.data.len();
+        rest
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+/*
+ * JP2 file format (Annex I)
+ */
+
+#[derive(Default)]
+struct Jp2Header {
+    color_space: Option<JpxColorSpace>,
+    sycc: bool,
+    palette: Option<Palette>,
+    /// `(component, mapping type, palette column)` for each channel
+    mapping: Vec<(u16, u8, u8)>,
+    /// `(channel, type, association)`
+    channels: Vec<(u16, u16, u16)>,
+}
+
+struct Palette {
+    /// precision of each column
+    precision: Vec<u8>,
+    /// `entries[index][column]`
+    entries: Vec<Vec<u64>>,
+}
+
+fn parse_boxes(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut r = Reader::new(data);
+    let mut boxes = vec![];
+    while !r.is_empty() {
+        let mut len = r.u32()? as u64;
+        let mut kind = [0; 4];
+        kind.copy_from_slice(r.bytes(4)?);
+        let mut header = 8;
+        if len == 1 {
+            len = (r.u32()? as u64) << 32 | r.u32()? as u64;
+            header = 16;
+        }
+        let content = if len == 0 {
+            r.rest()
+        } else if len < header {
+            bail!("invalid JP2 box length {}", len);
+        } else {
+            // be lenient with truncated files
+            let n = ((len - header) as usize).min(data.len() - r.pos);
+            r.bytes(n)?
+        };
+        boxes.push((kind, content));
+    }
+    Ok(boxes)
+}
+
+fn parse_jp2_header(data: &[u8], header: &mut Jp2Header) -> Result<()> {
+    for (kind, content) in parse_boxes(data)? {
+        let mut r = Reader::new(content);
+        match &kind {
+            b"colr" if header.color_space.is_none() => {
+                let method = r.u8()?;
+                let _precedence = r.u8()?;
+                let _approximation = r.u8()?;
+                header.color_space = match method {
+                    1 => match r.u32()? {
+                        12 => Some(JpxColorSpace::DeviceCMYK),
+                        16 => Some(JpxColorSpace::DeviceRGB),
+                        17 => Some(JpxColorSpace::DeviceGray),
+                        18 => {
+                            header.sycc = true;
+                            Some(JpxColorSpace::DeviceRGB)
+                        }
+                        cs => {
+                            warn!("unsupported JP2 colour space {}", cs);
+                            None
+                        }
+                    }
+                    2 | 3 => Some(JpxColorSpace::Icc(r.rest().to_vec())),
+                    _ => None
+                };
+            }
+            b"pclr" => {
+                let num_entries = r.u16()?;
+                let num_columns = r.u8()?;
+                let precision: Vec<u8> = r.bytes(num_columns as usize)?.iter().map(|&b| (b & 0x7f) + 1).collect();
+                if let Some(&bits) = precision.iter().find(|&&bits| bits > 38) {
+                    bail!("invalid JP2 palette precision {}", bits);
+                }
+                let mut entries = Vec::with_capacity(num_entries as usize);
+                for _ in 0 .. num_entries {
+                    let mut entry = Vec::with_capacity(num_columns as usize);
+                    for &bits in &precision {
+                        let value = r.bytes((bits as usize).div_ceil(8))?.iter().fold(0, |v, &b| v << 8 | b as u64);
+                        entry.push(value);
+                    }
+                    entries.push(entry);
+                }
+                header.palette = Some(Palette { precision, entries });
+            }
+            b"cmap" => {
+                while !r.is_empty() {
+                    header.mapping.push((r.u16()?, r.u8()?, r.u8()?));
+                }
+            }
+            b"cdef" => {
+                let n = r.u16()?;
+                for _ in 0 .. n {
+                    header.channels.push((r.u16()?, r.u16()?, r.u16()?));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Split a JP2 file into its header and codestream.
+fn parse_jp2(data: &[u8]) -> Result<(Jp2Header, &[u8])> {
+    let mut header = Jp2Header::default();
+    for (kind, content) in parse_boxes(data)? {
+        match &kind {
+            b"jp2h" => parse_jp2_header(content, &mut header)?,
+            b"jp2c" => return Ok((header, content)),
+            _ => {}
+        }
+    }
+    Err(other!("JP2 file without codestream"))
+}
+
+/*
+ * Codestream syntax (Annex A)
+ */
+
+const SOC: u16 = 0xFF4F;
+const SIZ: u16 = 0xFF51;
+const COD: u16 = 0xFF52;
+const COC: u16 = 0xFF53;
+const QCD: u16 = 0xFF5C;
+const QCC: u16 = 0xFF5D;
+const RGN: u16 = 0xFF5E;
+const POC: u16 = 0xFF5F;
+const PPM: u16 = 0xFF60;
+const PPT: u16 = 0xFF61;
+const SOT: u16 = 0xFF90;
+const SOD: u16 = 0xFF93;
+const EOC: u16 = 0xFFD9;
+
+// code-block styles
+const BYPASS: u8 = 0x01;
+const RESET: u8 = 0x02;
+const TERMALL: u8 = 0x04;
+const CAUSAL: u8 = 0x08;
+const SEGMENTATION: u8 = 0x20;
+const HIGH_THROUGHPUT: u8 = 0x40;
+
+// subbands
+const LL: u8 = 0;
+const HL: u8 = 1;
+const LH: u8 = 2;
+const HH: u8 = 3;
+
+#[derive(Debug, Clone)]
+struct ImageComponent {
+    precision: u8,
+    dx: u32,
+    dy: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Siz {
+    x1: u32,
+    y1: u32,
+    x0: u32,
+    y0: u32,
+    tile_width: u32,
+    tile_height: u32,
+    tile_x0: u32,
+    tile_y0: u32,
+    components: Vec<ImageComponent>,
+}
+impl Siz {
+    fn parse(r: &mut Reader) -> Result<Siz> {
+        let _capabilities = r.u16()?;
+        let x1 = r.u32()?;
+        let y1 = r.u32()?;
+        let x0 = r.u32()?;
+        let y0 = r.u32()?;
+        let tile_width = r.u32()?;
+        let tile_height = r.u32()?;
+        let tile_x0 = r.u32()?;
+        let tile_y0 = r.u32()?;
+        let n = r.u16()?;
+        let mut components = Vec::with_capacity(n as usize);
+        for _ in 0 .. n {
+            let s = r.u8()?;
+            let dx = r.u8()? as u32;
+            let dy = r.u8()? as u32;
+            let precision = (s & 0x7f) + 1;
+            if precision > 31 || dx == 0 || dy == 0 {
+                bail!("invalid JPX component (precision {}, subsampling {}x{})", precision, dx, dy);
+            }
+            components.push(ImageComponent { precision, dx, dy });
+        }
+        // the first tile has to overlap the image
+        let first_tile_empty = tile_x0 as u64 + tile_width as u64 <= x0 as u64 || tile_y0 as u64 + tile_height as u64 <= y0 as u64;
+        if x0 >= x1 || y0 >= y1 || tile_width == 0 || tile_height == 0 || tile_x0 > x0 || tile_y0 > y0 || first_tile_empty || components.is_empty() {
+            bail!("invalid JPX image size");
+        }
+        Ok(Siz { x1, y1, x0, y0, tile_width, tile_height, tile_x0, tile_y0, components })
+    }
+    fn tiles_x(&self) -> u32 {
+        (self.x1 - self.tile_x0).div_ceil(self.tile_width)
+    }
+    fn tiles_y(&self) -> u32 {
+        (self.y1 - self.tile_y0).div_ceil(self.tile_height)
+    }
+    fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+    fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CodingStyle {
+    levels: u8,
+    /// log2 of the nominal code-block size
+    cb_width: u8,
+    cb_height: u8,
+    cb_style: u8,
+    reversible: bool,
+    /// log2 of the precinct size for each resolution
+    precincts: Vec<(u8, u8)>,
+}
+impl CodingStyle {
+    fn parse(r: &mut Reader, precincts: bool) -> Result<CodingStyle> {
+        let levels = r.u8()?;
+        let cb_width = r.u8()?;
+        let cb_height = r.u8()?;
+        let cb_style = r.u8()?;
+        let transform = r.u8()?;
+        // the code-block size exponents are stored minus 2
+        if levels > 32 || cb_width > 8 || cb_height > 8 || cb_width + cb_height > 8 {
+            bail!("invalid JPX coding style");
+        }
+        let (cb_width, cb_height) = (cb_width + 2, cb_height + 2);
+        if cb_style & HIGH_THROUGHPUT != 0 {
+            bail!("high throughput JPX code-blocks are not supported");
+        }
+        let precincts = if precincts {
+            (0 ..= levels).map(|_| r.u8().map(|b| (b & 0xf, b >> 4))).collect::<Result<_>>()?
+        } else {
+            vec![(15, 15); levels as usize + 1]
+        };
+        Ok(CodingStyle { levels, cb_width, cb_height, cb_style, reversible: transform == 1, precincts })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Quantization {
+    style: u8,
+    guard_bits: u8,
+    /// `(exponent, mantissa)` for each subband
+    steps: Vec<(u8, u16)>,
+}
+impl Quantization {
+    fn parse(r: &mut Reader) -> Result<Quantization> {
+        let s = r.u8()?;
+        let style = s & 0x1f;
+        let mut steps = vec![];
+        while !r.is_empty() {
+            steps.push(match style {
+                0 => (r.u8()? >> 3, 0),
+                _ => {
+                    let v = r.u16()?;
+                    ((v >> 11) as u8, v & 0x7ff)
+                }
+            });
+        }
+        if steps.is_empty() {
+            bail!("JPX quantization without step sizes");
+        }
+        Ok(Quantization { style, guard_bits: s >> 5, steps })
+    }
+
+    /// Exponent and mantissa of band `kind` at resolution `r`.
+    fn step(&self, levels: u8, r: usize, kind: u8) -> Result<(u8, u16)> {
+        if self.style == 1 {
+            let (exponent, mantissa) = self.steps[0];
+            let nb = if r == 0 { levels as i32 } else { levels as i32 + 1 - r as i32 };
+            Ok(((exponent as i32 - levels as i32 + nb).max(0) as u8, mantissa))
+        } else {
+            let index = if r == 0 { 0 } else { 3 * (r - 1) + kind as usize };
+            self.steps.get(index).cloned().ok_or_else(|| other!("missing JPX quantization step {}", index))
+        }
+    }
+}
+
+struct Cod {
+    flags: u8,
+    progression: u8,
+    layers: u16,
+    mct: bool,
+    style: CodingStyle,
+}
+
+/// Coding parameter markers of the main header or a tile header.
+#[derive(Default)]
+struct Header {
+    cod: Option<Cod>,
+    coc: Vec<Option<CodingStyle>>,
+    qcd: Option<Quantization>,
+    qcc: Vec<Option<Quantization>>,
+    roi: Vec<Option<u8>>,
+}
+impl Header {
+    fn new(components: usize) -> Header {
+        Header {
+            cod: None,
+            coc: vec![None; components],
+            qcd: None,
+            qcc: vec![None; components],
+            roi: vec![None; components],
+        }
+    }
+
+    /// Read the marker segment `marker`, ignoring those that do not affect decoding.
+    fn parse(&mut self, marker: u16, r: &mut Reader) -> Result<()> {
+        let n = self.coc.len();
+        let component = |r: &mut Reader| -> Result<usize> {
+            let c = if n < 257 { r.u8()? as usize } else { r.u16()? as usize };
+            if c >= n {
+                bail!("invalid JPX component index {}", c);
+            }
+            Ok(c)
+        };
+        match marker {
+            COD => {
+                let flags = r.u8()?;
+                let progression = r.u8()?;
+                let layers = r.u16()?;
+                let mct = r.u8()? != 0;
+                let style = CodingStyle::parse(r, flags & 1 != 0)?;
+                self.cod = Some(Cod { flags, progression, layers, mct, style });
+            }
+            COC => {
+                let c = component(r)?;
+                let flags = r.u8()?;
+                self.coc[c] = Some(CodingStyle::parse(r, flags & 1 != 0)?);
+            }
+            QCD => self.qcd = Some(Quantization::parse(r)?),
+            QCC => {
+                let c = component(r)?;
+                self.qcc[c] = Some(Quantization::parse(r)?);
+            }
+            RGN => {
+                let c = component(r)?;
+                let _style = r.u8()?;
+                self.roi[c] = Some(r.u8()?);
+            }
+            POC => bail!("JPX progression order changes are not supported"),
+            PPM | PPT => bail!("JPX packed packet headers are not supported"),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CodingParams {
+    progression: u8,
+    layers: u16,
+    mct: bool,
+    sop: bool,
+    eph: bool,
+    styles: Vec<CodingStyle>,
+    quantization: Vec<Quantization>,
+    roi: Vec<u8>,
+}
+impl CodingParams {
+    fn from_main_header(header: &Header) -> Result<CodingParams> {
+        let cod = header.cod.as_ref().ok_or_else(|| other!("JPX main header without COD"))?;
+        let qcd = header.qcd.as_ref().ok_or_else(|| other!("JPX main header without QCD"))?;
+        let n = header.coc.len();
+        let mut params = CodingParams {
+            progression: 0,
+            layers: 0,
+            mct: false,
+            sop: false,
+            eph: false,
+            styles: vec![cod.style.clone(); n],
+            quantization: vec![qcd.clone(); n],
+            roi: vec![0; n],
+        };
+        params.apply(header);
+        Ok(params)
+    }
+
+    /// Override these parameters with those of a tile header.
+    fn apply(&mut self, header: &Header) {
+        if let Some(ref cod) = header.cod {
+            self.progression = cod.progression;
+            self.layers = cod.layers;
+            self.mct = cod.mct;
+            self.sop = cod.flags & 2 != 0;
+            self.eph = cod.flags & 4 != 0;
+            for (style, coc) in self.styles.iter_mut().zip(&header.coc) {
+                if coc.is_none() {
+                    *style = cod.style.clone();
+                }
+            }
+        }
+        for (style, coc) in self.styles.iter_mut().zip(&header.coc) {
+            if let Some(coc) = coc {
+                *style = coc.clone();
+            }
+        }
+        if let Some(ref qcd) = header.qcd {
+            for (q, qcc) in self.quantization.iter_mut().zip(&header.qcc) {
+                if qcc.is_none() {
+                    *q = qcd.clone();
+                }
+            }
+        }
+        for (q, qcc) in self.quantization.iter_mut().zip(&header.qcc) {
+            if let Some(qcc) = qcc {
+                *q = qcc.clone();
+            }
+        }
+        for (roi, &shift) in self.roi.iter_mut().zip(&header.roi) {
+            if let Some(shift) = shift {
+                *roi = shift;
+            }
+        }
+    }
+}
+
+/// The decoded samples of one component, level shifted to be unsigned even for signed
+/// components.
+struct ComponentImage {
+    width: u32,
+    height: u32,
+    /// position of the first sample on the reference grid, divided by the subsampling
+    x0: u32,
+    y0: u32,
+    data: Vec<i32>,
+}
+
+/// Decode all tiles of `data`.
+fn decode_codestream(data: &[u8], limit: Option<OutputLimit>) -> Result<(Siz, Vec<ComponentImage>)> {
+    let mut r = Reader::new(data);
+    if r.u16()? != SOC {
+        bail!("JPX codestream does not start with SOC");
+    }
+    if r.u16()? != SIZ {
+        bail!("JPX codestream without SIZ");
+    }
+    let len = r.u16()?;
+    let siz = Siz::parse(&mut Reader::new(r.bytes(len.saturating_sub(2) as usize)?))?;
+    let n = siz.components.len();
+
+    let pixels = (siz.width() as usize).checked_mul(siz.height() as usize)
+        .and_then(|p| p.checked_mul(n))
+        .filter(|&size| size <= isize::MAX as usize / 4)
+        .ok_or_else(|| other!("JPX image too large ({}x{})", siz.width(), siz.height()))?;
+    if let Some(limit) = limit {
+        limit.check(pixels)?;
+    }
+
+    let mut main = Header::new(n);
+    loop {
+        let marker = r.u16()?;
+        if marker == SOT {
+            break;
+        }
+        let len = r.u16()?;
+        main.parse(marker, &mut Reader::new(r.bytes(len.saturating_sub(2) as usize)?))?;
+    }
+    let params = CodingParams::from_main_header(&main)?;
+
+    let num_tiles = siz.tiles_x() as usize * siz.tiles_y() as usize;
+    let mut tiles: Vec<Option<(Header, Vec<u8>)>> = (0 .. num_tiles).map(|_| None).collect();
+    loop {
+        // at this point, the SOT marker has been read
+        let start = r.pos - 2;
+        let _len = r.u16()?;
+        let index = r.u16()? as usize;
+        let length = r.u32()? as usize;
+        let _part = r.u8()?;
+        let _parts = r.u8()?;
+        let (header, tile_data) = tiles.get_mut(index)
+            .ok_or_else(|| other!("invalid JPX tile index {}", index))?
+            .get_or_insert_with(|| (Header::new(n), vec![]));
+        loop {
+            let marker = r.u16()?;
+            if marker == SOD {
+                break;
+            }
+            let len = r.u16()?;
+            header.parse(marker, &mut Reader::new(r.bytes(len.saturating_sub(2) as usize)?))?;
+        }
+        let end = match length {
+            0 => data.len(),
+            n => start.saturating_add(n).min(data.len()),
+        };
+        if end < r.pos {
+            bail!("invalid JPX tile-part length");
+        }
+        tile_data.extend_from_slice(&data[r.pos .. end]);
+        r.pos = end;
+
+        match r.u16() {
+            Ok(SOT) => continue,
+            Ok(EOC) | Err(_) => break,
+            Ok(marker) => {
+                warn!("unexpected marker {:04X} after JPX tile-part", marker);
+                break;
+            }
+        }
+    }
+
+    let mut images: Vec<ComponentImage> = siz.components.iter().map(|c| {
+        let x0 = siz.x0.div_ceil(c.dx);
+        let y0 = siz.y0.div_ceil(c.dy);
+        let width = siz.x1.div_ceil(c.dx) - x0;
+        let height = siz.y1.div_ceil(c.dy) - y0;
+        ComponentImage { width, height, x0, y0, data: vec![0; width as usize * height as usize] }
+    }).collect();
+    if images.iter().any(|image| image.width == 0 || image.height == 0) {
+        bail!("empty JPX component");
+    }
+
+    for (index, tile) in tiles.iter().enumerate() {
+        if let Some((ref header, ref tile_data)) = *tile {
+            let mut tile_params = params.clone();
+            tile_params.apply(header);
+            decode_tile(&siz, &tile_params, index as u32, tile_data, &mut images)?;
+        }
+    }
+    Ok((siz, images))
+}
+
+/*
+ * Tiles, subbands, precincts and code-blocks (Annex B)
+ */
+
+struct Segment {
+    id: u32,
+    first_pass: u32,
+    passes: u32,
+    data: Vec<u8>,
+}
+
+struct CodeBlock {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    included: bool,
+    lblock: u32,
+    zero_planes: u32,
+    passes: u32,
+    segments: Vec<Segment>,
+}
+
+struct Precinct {
+    /// indices into `Band::blocks` in raster order
+    blocks: Vec<usize>,
+    width: usize,
+    inclusion: TagTree,
+    zero_planes: TagTree,
+}
+
+struct Band {
+    kind: u8,
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    /// number of magnitude bit-planes
+    magnitude_bits: u32,
+    /// quantization step size
+    delta: f32,
+    blocks: Vec<CodeBlock>,
+    precincts: Vec<Precinct>,
+}
+impl Band {
+    fn width(&self) -> usize {
+        (self.x1 - self.x0) as usize
+    }
+    fn height(&self) -> usize {
+        (self.y1 - self.y0) as usize
+    }
+}
+
+struct Resolution {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    /// log2 of the precinct size
+    ppx: u8,
+    ppy: u8,
+    /// number of precincts
+    npx: u32,
+    npy: u32,
+    bands: Vec<Band>,
+}
+
+struct TileComponent {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    style: CodingStyle,
+    roi: u8,
+    resolutions: Vec<Resolution>,
+}
+
+fn ceil_shift(x: u32, shift: u32) -> u32 {
+    ((x as u64 + (1 << shift) - 1) >> shift) as u32
+}
+
+/// `ceil((x - offset) / 2^shift)`, computed without overflow.
+fn band_coordinate(x: u32, offset: u64, shift: u32) -> u32 {
+    let v = x as i64 - offset as i64;
+    (v + (1i64 << shift) - 1).div_euclid(1i64 << shift).max(0) as u32
+}
+
+impl TileComponent {
+    #[allow(clippy::too_many_arguments)]
+    fn new(x0: u32, y0: u32, x1: u32, y1: u32, component: &ImageComponent, style: &CodingStyle, quantization: &Quantization, roi: u8) -> Result<TileComponent> {
+        // subbands, precincts and code-blocks inherit ordered bounds from the tile-component
+        if x0 > x1 || y0 > y1 {
+            bail!("invalid JPX tile-component ({}, {}) - ({}, {})", x0, y0, x1, y1);
+        }
+        let levels = style.levels as u32;
+        let mut resolutions = Vec::with_capacity(levels as usize + 1);
+        for r in 0 ..= levels {
+            let shift = levels - r;
+            let (ppx, ppy) = style.precincts[r as usize];
+            let (rx0, ry0, rx1, ry1) = (ceil_shift(x0, shift), ceil_shift(y0, shift), ceil_shift(x1, shift), ceil_shift(y1, shift));
+            let (npx, npy) = if rx1 > rx0 && ry1 > ry0 {
+                (ceil_shift(rx1, ppx as u32) - (rx0 >> ppx), ceil_shift(ry1, ppy as u32) - (ry0 >> ppy))
+            } else {
+                (0, 0)
+            };
+            let mut res = Resolution { x0: rx0, y0: ry0, x1: rx1, y1: ry1, ppx, ppy, npx, npy, bands: vec![] };
+
+            let kinds: &[u8] = if r == 0 { &[LL] } else { &[HL, LH, HH] };
+            for &kind in kinds {
+                let (bx0, by0, bx1, by1) = if r == 0 {
+                    (ceil_shift(x0, levels), ceil_shift(y0, levels), ceil_shift(x1, levels), ceil_shift(y1, levels))
+                } else {
+                    let nb = levels - r + 1;
+                    let xo = if kind & 1 != 0 { 1u64 << (nb - 1) } else { 0 };
+                    let yo = if kind & 2 != 0 { 1u64 << (nb - 1) } else { 0 };
+                    (band_coordinate(x0, xo, nb), band_coordinate(y0, yo, nb), band_coordinate(x1, xo, nb), band_coordinate(y1, yo, nb))
+                };
+
+                let (exponent, mantissa) = quantization.step(style.levels, r as usize, kind)?;
+                let magnitude_bits = (quantization.guard_bits as u32 + exponent as u32 + roi as u32).saturating_sub(1);
+                let gain = match kind { LL => 0, HH => 2, _ => 1 };
+                let delta = if style.reversible {
+                    1.0
+                } else {
+                    2f32.powi(component.precision as i32 + gain - exponent as i32) * (1.0 + mantissa as f32 / 2048.)
+                };
+
+                // precinct and code-block partition in subband coordinates
+                let (pbx, pby) = if r == 0 { (ppx, ppy) } else { (ppx.saturating_sub(1), ppy.saturating_sub(1)) };
+                let cbw = style.cb_width.min(pbx) as u32;
+                let cbh = style.cb_height.min(pby) as u32;
+                let mut precincts: Vec<(Vec<usize>, u32, u32, u32, u32)> = (0 .. npx * npy).map(|_| (vec![], u32::MAX, u32::MAX, 0, 0)).collect();
+                let mut blocks = vec![];
+                if bx1 > bx0 && by1 > by0 {
+                    for cby in (by0 >> cbh) ..= ((by1 - 1) >> cbh) {
+                        for cbx in (bx0 >> cbw) ..= ((bx1 - 1) >> cbw) {
+                            let px = ((cbx << cbw) >> pbx).wrapping_sub(rx0 >> ppx);
+                            let py = ((cby << cbh) >> pby).wrapping_sub(ry0 >> ppy);
+                            if px >= npx || py >= npy {
+                                bail!("JPX code-block outside of precincts");
+                            }
+                            let precinct = &mut precincts[(py * npx + px) as usize];
+                            precinct.0.push(blocks.len());
+                            precinct.1 = precinct.1.min(cbx);
+                            precinct.2 = precinct.2.min(cby);
+                            precinct.3 = precinct.3.max(cbx);
+                            precinct.4 = precinct.4.max(cby);
+                            blocks.push(CodeBlock {
+                                x0: (cbx << cbw).max(bx0),
+                                y0: (cby << cbh).max(by0),
+                                x1: ((cbx + 1) << cbw).min(bx1),
+                                y1: ((cby + 1) << cbh).min(by1),
+                                included: false,
+                                lblock: 3,
+                                zero_planes: 0,
+                                passes: 0,
+                                segments: vec![],
+                            });
+                        }
+                    }
+                }
+                let precincts = precincts.into_iter().map(|(blocks, x0, y0, x1, y1)| {
+                    let (width, height) = if blocks.is_empty() { (0, 0) } else { ((x1 - x0 + 1) as usize, (y1 - y0 + 1) as usize) };
+                    Precinct { blocks, width, inclusion: TagTree::new(width, height), zero_planes: TagTree::new(width, height) }
+                }).collect();
+                res.bands.push(Band { kind, x0: bx0, y0: by0, x1: bx1, y1: by1, magnitude_bits, delta, blocks, precincts });
+            }
+            resolutions.push(res);
+        }
+        Ok(TileComponent { x0, y0, x1, y1, style: style.clone(), roi, resolutions })
+    }
+
+    fn width(&self) -> usize {
+        (self.x1 - self.x0) as usize
+    }
+    fn height(&self) -> usize {
+        (self.y1 - self.y0) as usize
+    }
+}
+
+fn decode_tile(siz: &Siz, params: &CodingParams, index: u32, data: &[u8], images: &mut [ComponentImage]) -> Result<()> {
+    let p = index % siz.tiles_x();
+    let q = index / siz.tiles_x();
+    let tx0 = (siz.tile_x0 as u64 + p as u64 * siz.tile_width as u64).max(siz.x0 as u64) as u32;
+    let ty0 = (siz.tile_y0 as u64 + q as u64 * siz.tile_height as u64).max(siz.y0 as u64) as u32;
+    let tx1 = (siz.tile_x0 as u64 + (p as u64 + 1) * siz.tile_width as u64).min(siz.x1 as u64) as u32;
+    let ty1 = (siz.tile_y0 as u64 + (q as u64 + 1) * siz.tile_height as u64).min(siz.y1 as u64) as u32;
+
+    let mut components = vec![];
+    for (c, component) in siz.components.iter().enumerate() {
+        components.push(TileComponent::new(
+            tx0.div_ceil(component.dx), ty0.div_ceil(component.dy),
+            tx1.div_ceil(component.dx), ty1.div_ceil(component.dy),
+            component, &params.styles[c], &params.quantization[c], params.roi[c]
+        )?);
+    }
+
+    let mut pos = 0;
+    for (layer, c, r, p) in packet_order(params, &components, siz, tx0, ty0)? {
+        let tc = &mut components[c];
+        let cb_style = tc.style.cb_style;
+        if !read_packet(data, &mut pos, &mut tc.resolutions[r], p, layer, params.sop, params.eph, cb_style)? {
+            break;
+        }
+    }
+
+    let mut samples = components.iter().map(reconstruct).collect::<Result<Vec<_>>>()?;
+
+    if params.mct && samples.len() >= 3 {
+        let len = samples[0].len();
+        if samples[1].len() == len && samples[2].len() == len {
+            let (first, rest) = samples.split_at_mut(1);
+            let (second, third) = rest.split_at_mut(1);
+            let (y0, y1, y2) = (&mut first[0], &mut second[0], &mut third[0]);
+            for i in 0 .. len {
+                let (a, b, c) = (y0[i], y1[i], y2[i]);
+                if components[0].style.reversible {
+                    let g = a - ((b + c) / 4.).floor();
+                    y0[i] = c + g;
+                    y1[i] = g;
+                    y2[i] = b + g;
+                } else {
+                    y0[i] = a + 1.402 * c;
+                    y1[i] = a - 0.34413 * b - 0.71414 * c;
+                    y2[i] = a + 1.772 * b;
+                }
+            }
+        }
+    }
+
+    for ((tc, samples), (image, component)) in components.iter().zip(&samples).zip(images.iter_mut().zip(&siz.components)) {
+        let max = (1i64 << component.precision) - 1;
+        let shift = 1i64 << (component.precision - 1);
+        let width = tc.width();
+        for y in 0 .. tc.height() {
+            let iy = (tc.y0 + y as u32 - image.y0) as usize;
+            let ix = (tc.x0 - image.x0) as usize;
+            let out = &mut image.data[iy * image.width as usize + ix ..][.. width];
+            for (o, &v) in out.iter_mut().zip(&samples[y * width .. (y + 1) * width]) {
+                *o = (v.round() as i64 + shift).max(0).min(max) as i32;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The sequence of `(layer, component, resolution, precinct)` in which packets appear.
+fn packet_order(params: &CodingParams, components: &[TileComponent], siz: &Siz, tx0: u32, ty0: u32) -> Result<Vec<(u32, usize, usize, usize)>> {
+    let layers = params.layers as u32;
+    let max_resolutions = components.iter().map(|c| c.resolutions.len()).max().unwrap_or(0);
+    let precincts = |c: usize, r: usize| {
+        components[c].resolutions.get(r).map(|res| (res.npx * res.npy) as usize).unwrap_or(0)
+    };
+    let mut order = vec![];
+    match params.progression {
+        // LRCP
+        0 => for l in 0 .. layers {
+            for r in 0 .. max_resolutions {
+                for c in 0 .. components.len() {
+                    for p in 0 .. precincts(c, r) {
+                        order.push((l, c, r, p));
+                    }
+                }
+            }
+        }
+        // RLCP
+        1 => for r in 0 .. max_resolutions {
+            for l in 0 .. layers {
+                for c in 0 .. components.len() {
+                    for p in 0 .. precincts(c, r) {
+                        order.push((l, c, r, p));
+                    }
+                }
+            }
+        }
+        // RPCL, PCRL, CPRL
+        progression @ 2 ..= 4 => {
+            let mut items = vec![];
+            for (c, tc) in components.iter().enumerate() {
+                let component = &siz.components[c];
+                let levels = tc.resolutions.len() - 1;
+                for (r, res) in tc.resolutions.iter().enumerate() {
+                    for p in 0 .. (res.npx * res.npy) {
+                        let (px, py) = (p % res.npx, p / res.npx);
+                        // position of the precinct on the reference grid
+                        let x = match px {
+                            0 => tx0 as u64,
+                            _ => ((((res.x0 >> res.ppx) + px) as u64) << res.ppx << (levels - r)) * component.dx as u64
+                        };
+                        let y = match py {
+                            0 => ty0 as u64,
+                            _ => ((((res.y0 >> res.ppy) + py) as u64) << res.ppy << (levels - r)) * component.dy as u64
+                        };
+                        let key = match progression {
+                            2 => (r as u64, y, x, c as u64),
+                            3 => (y, x, c as u64, r as u64),
+                            _ => (c as u64, y, x, r as u64),
+                        };
+                        items.push((key, c, r, p as usize));
+                    }
+                }
+            }
+            items.sort_by_key(|item| item.0);
+            for (_, c, r, p) in items {
+                for l in 0 .. layers {
+                    order.push((l, c, r, p));
+                }
+            }
+        }
+        p => bail!("invalid JPX progression order {}", p)
+    }
+    Ok(order)
+}
+
+/*
+ * Packets (B.10)
+ */
+
+struct TagTree {
+    /// width and height of each level, starting with the leaves
+    sizes: Vec<(usize, usize)>,
+    /// `(value, known)` for each node of each level
+    nodes: Vec<Vec<(u32, bool)>>,
+}
+impl TagTree {
+    fn new(mut width: usize, mut height: usize) -> TagTree {
+        let mut sizes = vec![];
+        loop {
+            sizes.push((width, height));
+            if width <= 1 && height <= 1 {
+                break;
+            }
+            width = width.div_ceil(2);
+            height = height.div_ceil(2);
+        }
+        let nodes = sizes.iter().map(|&(w, h)| vec![(0, false); w * h]).collect();
+        TagTree { sizes, nodes }
+    }
+
+    /// Decode the value of leaf `x`, `y` as far as needed to tell whether it is below `threshold`.
+    fn decode(&mut self, bits: &mut BitReader, x: usize, y: usize, threshold: u32) -> Result<bool> {
+        let mut low = 0;
+        let mut value = 0;
+        for level in (0 .. self.sizes.len()).rev() {
+            let width = self.sizes[level].0;
+            let node = &mut self.nodes[level][(y >> level) * width + (x >> level)];
+            if node.0 < low {
+                node.0 = low;
+            }
+            while !node.1 && node.0 < threshold {
+                if bits.bit()? == 1 {
+                    node.1 = true;
+                } else {
+                    node.0 += 1;
+                }
+            }
+            low = node.0;
+            value = node.0;
+        }
+        Ok(value < threshold)
+    }
+
+    fn value(&mut self, bits: &mut BitReader, x: usize, y: usize) -> Result<u32> {
+        self.decode(bits, x, y, u32::MAX)?;
+        Ok(self.nodes[0][y * self.sizes[0].0 + x].0)
+    }
+}
+
+/// Reads packet headers, which skip a bit after each 0xFF byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    bits: u8,
+}
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0, byte: 0, bits: 0 }
+    }
+    fn bit(&mut self) -> Result<u32> {
+        if self.bits == 0 {
+            self.bits = if self.byte == 0xFF { 7 } else { 8 };
+            self.byte = *self.data.get(self.pos).ok_or(PdfError::EOF)?;
+            self.pos += 1;
+        }
+        self.bits -= 1;
+        Ok((self.byte >> self.bits) as u32 & 1)
+    }
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        let mut v = 0;
+        for _ in 0 .. n {
+            v = v << 1 | self.bit()?;
+        }
+        Ok(v)
+    }
+    /// The number of bytes used, including a stuffed byte after a final 0xFF.
+    fn finish(self) -> usize {
+        if self.byte == 0xFF { self.pos + 1 } else { self.pos }
+    }
+}
+
+/// The codeword segment that coding pass `pass` belongs to.
+fn segment_id(pass: u32, cb_style: u8) -> u32 {
+    if cb_style & TERMALL != 0 {
+        pass
+    } else if cb_style & BYPASS != 0 && pass >= 10 {
+        let j = pass - 10;
+        1 + j / 3 * 2 + (j % 3 == 2) as u32
+    } else {
+        0
+    }
+}
+
+fn read_passes(bits: &mut BitReader) -> Result<u32> {
+    Ok(if bits.bit()? == 0 {
+        1
+    } else if bits.bit()? == 0 {
+        2
+    } else {
+        match bits.bits(2)? {
+            3 => match bits.bits(5)? {
+                31 => 37 + bits.bits(7)?,
+                n => 6 + n
+            },
+            n => 3 + n
+        }
+    })
+}
+
+/// Read one packet at `pos`. Returns false if the data ended.
+#[allow(clippy::too_many_arguments)]
+fn read_packet(data: &[u8], pos: &mut usize, res: &mut Resolution, p: usize, layer: u32, sop: bool, eph: bool, cb_style: u8) -> Result<bool> {
+    if sop && data[*pos ..].starts_with(&[0xFF, 0x91]) {
+        *pos += 6;
+    }
+    if *pos >= data.len() {
+        return Ok(false);
+    }
+
+    // (band, block, [(segment, first pass, passes, length)])
+    let mut included = vec![];
+    let mut bits = BitReader::new(&data[*pos ..]);
+    let header = (|| -> Result<()> {
+        if bits.bit()? == 0 {
+            return Ok(());
+        }
+        for (b, band) in res.bands.iter_mut().enumerate() {
+            let precinct = &mut band.precincts[p];
+            for (i, &k) in precinct.blocks.iter().enumerate() {
+                let (x, y) = (i % precinct.width, i / precinct.width);
+                let block = &mut band.blocks[k];
+                let inclusion = if block.included {
+                    bits.bit()? == 1
+                } else {
+                    precinct.inclusion.decode(&mut bits, x, y, layer + 1)?
+                };
+                if !inclusion {
+                    continue;
+                }
+                if !block.included {
+                    block.included = true;
+                    block.zero_planes = precinct.zero_planes.value(&mut bits, x, y)?;
+                }
+                let passes = read_passes(&mut bits)?;
+                while bits.bit()? == 1 {
+                    block.lblock += 1;
+                }
+                let mut segments = vec![];
+                let mut pass = block.passes;
+                let end = block.passes + passes;
+                while pass < end {
+                    let id = segment_id(pass, cb_style);
+                    let mut n = 1;
+                    while pass + n < end && segment_id(pass + n, cb_style) == id {
+                        n += 1;
+                    }
+                    let len = bits.bits(block.lblock + (31 - n.leading_zeros()))?;
+                    segments.push((id, pass, n, len as usize));
+                    pass += n;
+                }
+                block.passes = end;
+                included.push((b, k, segments));
+            }
+        }
+        Ok(())
+    })();
+    match header {
+        Ok(()) => {}
+        Err(e) if e.is_eof() => return Ok(false),
+        Err(e) => return Err(e)
+    }
+    *pos += bits.finish();
+    if eph && data[(*pos).min(data.len()) ..].starts_with(&[0xFF, 0x92]) {
+        *pos += 2;
+    }
+
+    for (b, k, segments) in included {
+        let block = &mut res.bands[b].blocks[k];
+        for (id, first_pass, passes, len) in segments {
+            let start = (*pos).min(data.len());
+            let end = pos.saturating_add(len).min(data.len());
+            *pos = pos.saturating_add(len);
+            match block.segments.last_mut() {
+                Some(segment) if segment.id == id => {
+                    segment.passes += passes;
+                    segment.data.extend_from_slice(&data[start .. end]);
+                }
+                _ => block.segments.push(Segment { id, first_pass, passes, data: data[start .. end].to_vec() })
+            }
+        }
+    }
+    Ok(*pos <= data.len())
+}
+
+/*
+ * Code-block decoding (Annex D)
+ */
+
+/// Reads raw bits of bypassed coding passes.
+struct RawDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    byte: u8,
+    bits: u8,
+}
+impl<'a> RawDecoder<'a> {
+    fn bit(&mut self) -> u8 {
+        if self.bits == 0 {
+            self.bits = if self.byte == 0xFF { 7 } else { 8 };
+            self.byte = self.data.get(self.pos).cloned().unwrap_or(0xFF);
+            self.pos += 1;
+        }
+        self.bits -= 1;
+        (self.byte >> self.bits) & 1
+    }
+}
+
+enum Coder<'a> {
+    Mq(MqDecoder<'a>),
+    Raw(RawDecoder<'a>),
+}
+impl<'a> Coder<'a> {
+    #[inline]
+    fn decode(&mut self, cx: &mut Context) -> u8 {
+        match *self {
+            Coder::Mq(ref mut d) => d.decode(cx),
+            Coder::Raw(ref mut d) => d.bit(),
+        }
+    }
+}
+
+const SIG: u8 = 1;
+const VISITED: u8 = 2;
+const REFINED: u8 = 4;
+const NEGATIVE: u8 = 8;
+
+const CX_RUN_LENGTH: usize = 17;
+const CX_UNIFORM: usize = 18;
+
+fn initial_contexts() -> [Context; 19] {
+    let mut contexts = [Context::default(); 19];
+    contexts[0] = Context::with_index(4);
+    contexts[CX_RUN_LENGTH] = Context::with_index(3);
+    contexts[CX_UNIFORM] = Context::with_index(46);
+    contexts
+}
+
+struct BlockDecoder {
+    width: usize,
+    height: usize,
+    kind: u8,
+    causal: bool,
+    /// flags with a border of one coefficient
+    flags: Vec<u8>,
+    magnitude: Vec<u32>,
+    contexts: [Context; 19],
+}
+impl BlockDecoder {
+    fn new(width: usize, height: usize, kind: u8, causal: bool) -> BlockDecoder {
+        BlockDecoder {
+            width,
+            height,
+            kind,
+            causal,
+            flags: vec![0; (width + 2) * (height + 2)],
+            magnitude: vec![0; width * height],
+            contexts: initial_contexts(),
+        }
+    }
+
+    #[inline]
+    fn index(&self, x: usize, y: usize) -> usize {
+        (y + 1) * (self.width + 2) + x + 1
+    }
+
+    /// Whether the row below `y` may be used for context formation.
+    #[inline]
+    fn below(&self, y: usize) -> bool {
+        !(self.causal && y % 4 == 3)
+    }
+
+    /// Number of significant horizontal, vertical and diagonal neighbours.
+    #[inline]
+    fn neighbours(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = self.index(x, y);
+        let s = self.width + 2;
+        let f = &self.flags;
+        let h = (f[i - 1] & SIG) + (f[i + 1] & SIG);
+        let mut v = f[i - s] & SIG;
+        let mut d = (f[i - s - 1] & SIG) + (f[i - s + 1] & SIG);
+        if self.below(y) {
+            v += f[i + s] & SIG;
+            d += (f[i + s - 1] & SIG) + (f[i + s + 1] & SIG);
+        }
+        (h, v, d)
+    }
+
+    /// Zero coding context (table D.1).
+    #[inline]
+    fn zero_context(&self, x: usize, y: usize) -> usize {
+        let (h, v, d) = self.neighbours(x, y);
+        match self.kind {
+            HH => match (d, h + v) {
+                (0, 0) => 0,
+                (0, 1) => 1,
+                (0, _) => 2,
+                (1, 0) => 3,
+                (1, 1) => 4,
+                (1, _) => 5,
+                (2, 0) => 6,
+                (2, _) => 7,
+                _ => 8,
+            },
+            kind => {
+                let (h, v) = if kind == HL { (v, h) } else { (h, v) };
+                match (h, v, d) {
+                    (0, 0, 0) => 0,
+                    (0, 0, 1) => 1,
+                    (0, 0, _) => 2,
+                    (0, 1, _) => 3,
+                    (0, _, _) => 4,
+                    (1, 0, 0) => 5,
+                    (1, 0, _) => 6,
+                    (1, _, _) => 7,
+                    _ => 8,
+                }
+            }
+        }
+    }
+
+    /// Sign coding context and XOR bit (table D.3).
+    #[inline]
+    fn sign_context(&self, x: usize, y: usize) -> (usize, u8) {
+        let i = self.index(x, y);
+        let s = self.width + 2;
+        let sign = |f: u8| -> i32 {
+            if f & SIG == 0 { 0 } else if f & NEGATIVE != 0 { -1 } else { 1 }
+        };
+        let h = (sign(self.flags[i - 1]) + sign(self.flags[i + 1])).signum();
+        let below = if self.below(y) { sign(self.flags[i + s]) } else { 0 };
+        let v = (sign(self.flags[i - s]) + below).signum();
+        match (h, v) {
+            (1, 1) => (13, 0),
+            (1, 0) => (12, 0),
+            (1, _) => (11, 0),
+            (0, 1) => (10, 0),
+            (0, 0) => (9, 0),
+            (0, _) => (10, 1),
+            (_, 1) => (11, 1),
+            (_, 0) => (12, 1),
+            _ => (13, 1),
+        }
+    }
+
+    fn decode_sign(&mut self, coder: &mut Coder, x: usize, y: usize, one: u32) {
+        let negative = match *coder {
+            Coder::Raw(ref mut d) => d.bit(),
+            Coder::Mq(ref mut d) => {
+                let (cx, xor) = self.sign_context(x, y);
+                d.decode(&mut self.contexts[cx]) ^ xor
+            }
+        };
+        let i = self.index(x, y);
+        self.flags[i] |= SIG | if negative == 1 { NEGATIVE } else { 0 };
+        self.magnitude[y * self.width + x] |= one;
+    }
+
+    fn significance_pass(&mut self, coder: &mut Coder, one: u32) {
+        for y0 in (0 .. self.height).step_by(4) {
+            for x in 0 .. self.width {
+                for y in y0 .. (y0 + 4).min(self.height) {
+                    let i = self.index(x, y);
+                    if self.flags[i] & SIG != 0 {
+                        continue;
+                    }
+                    let cx = self.zero_context(x, y);
+                    if cx == 0 {
+                        continue;
+                    }
+                    self.flags[i] |= VISITED;
+                    if coder.decode(&mut self.contexts[cx]) == 1 {
+                        self.decode_sign(coder, x, y, one);
+                    }
+                }
+            }
+        }
+    }
+
+    fn refinement_pass(&mut self, coder: &mut Coder, one: u32) {
+        for y0 in (0 .. self.height).step_by(4) {
+            for x in 0 .. self.width {
+                for y in y0 .. (y0 + 4).min(self.height) {
+                    let i = self.index(x, y);
+                    let flags = self.flags[i];
+                    if flags & (SIG | VISITED) != SIG {
+                        continue;
+                    }
+                    let cx = if flags & REFINED != 0 {
+                        16
+                    } else {
+                        let (h, v, d) = self.neighbours(x, y);
+                        if h + v + d == 0 { 14 } else { 15 }
+                    };
+                    if coder.decode(&mut self.contexts[cx]) == 1 {
+                        self.magnitude[y * self.width + x] |= one;
+                    }
+                    self.flags[i] |= REFINED;
+                }
+            }
+        }
+    }
+
+    fn cleanup_pass(&mut self, coder: &mut Coder, one: u32) {
+        for y0 in (0 .. self.height).step_by(4) {
+            let y1 = (y0 + 4).min(self.height);
+            for x in 0 .. self.width {
+                let mut y = y0;
+                let run = y1 - y0 == 4 && (y0 .. y1).all(|y| {
+                    self.flags[self.index(x, y)] & (SIG | VISITED) == 0 && self.zero_context(x, y) == 0
+                });
+                if run {
+                    if coder.decode(&mut self.contexts[CX_RUN_LENGTH]) == 0 {
+                        continue;
+                    }
+                    let high = coder.decode(&mut self.contexts[CX_UNIFORM]) as usize;
+                    let low = coder.decode(&mut self.contexts[CX_UNIFORM]) as usize;
+                    y = y0 + (high << 1 | low);
+                    self.decode_sign(coder, x, y, one);
+                    y += 1;
+                }
+                for y in y .. y1 {
+                    let i = self.index(x, y);
+                    if self.flags[i] & (SIG | VISITED) != 0 {
+                        continue;
+                    }
+                    let cx = self.zero_context(x, y);
+                    if coder.decode(&mut self.contexts[cx]) == 1 {
+                        self.decode_sign(coder, x, y, one);
+                    }
+                }
+            }
+        }
+        for f in self.flags.iter_mut() {
+            *f &= !VISITED;
+        }
+    }
+}
+
+/// Decode the coefficients of `block`, multiplied by `delta`, into `out`.
+fn decode_block(block: &CodeBlock, band: &Band, style: &CodingStyle, roi: u8, out: &mut [f32], stride: usize) -> Result<()> {
+    let width = (block.x1 - block.x0) as usize;
+    let height = (block.y1 - block.y0) as usize;
+    if block.passes == 0 || block.zero_planes >= band.magnitude_bits {
+        return Ok(());
+    }
+    let first_plane = band.magnitude_bits - 1 - block.zero_planes;
+    if first_plane > 30 {
+        bail!("too many JPX bit-planes ({})", first_plane + 1);
+    }
+    let cb_style = style.cb_style;
+    let mut decoder = BlockDecoder::new(width, height, band.kind, cb_style & CAUSAL != 0);
+    let mut last_plane = None;
+    'segments: for segment in &block.segments {
+        let raw = cb_style & BYPASS != 0 && segment.first_pass >= 10 && (segment.first_pass - 10) % 3 != 2;
+        let mut coder = if raw {
+            Coder::Raw(RawDecoder { data: &segment.data, pos: 0, byte: 0, bits: 0 })
+        } else {
+            Coder::Mq(MqDecoder::new(&segment.data))
+        };
+        for pass in segment.first_pass .. segment.first_pass + segment.passes {
+            let (kind, steps) = if pass == 0 { (2, 0) } else { ((pass - 1) % 3, pass.div_ceil(3)) };
+            if steps > first_plane {
+                break 'segments;
+            }
+            let plane = first_plane - steps;
+            let one = 1 << plane;
+            match kind {
+                0 => decoder.significance_pass(&mut coder, one),
+                1 => decoder.refinement_pass(&mut coder, one),
+                _ => {
+                    decoder.cleanup_pass(&mut coder, one);
+                    if cb_style & SEGMENTATION != 0 {
+                        for _ in 0 .. 4 {
+                            coder.decode(&mut decoder.contexts[CX_UNIFORM]);
+                        }
+                    }
+                }
+            }
+            if cb_style & RESET != 0 {
+                decoder.contexts = initial_contexts();
+            }
+            last_plane = Some(plane);
+        }
+    }
+
+    let last_plane = match last_plane {
+        Some(plane) => plane,
+        None => return Ok(())
+    };
+    let offset = (block.y0 - band.y0) as usize * stride + (block.x0 - band.x0) as usize;
+    for y in 0 .. height {
+        for x in 0 .. width {
+            let mut m = decoder.magnitude[y * width + x];
+            if m == 0 {
+                continue;
+            }
+            let mut plane = last_plane;
+            if roi > 0 && m >= 1 << roi {
+                m >>= roi;
+                plane = plane.saturating_sub(roi as u32);
+            }
+            // reconstruct at the middle of the interval that was not decoded
+            let mut value = m as f32;
+            if style.reversible {
+                if plane > 0 {
+                    value += (1u32 << (plane - 1)) as f32;
+                }
+            } else {
+                value += (1u32 << plane) as f32 * 0.5;
+            }
+            if decoder.flags[decoder.index(x, y)] & NEGATIVE != 0 {
+                value = -value;
+            }
+            out[offset + y * stride + x] = value * band.delta;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Inverse wavelet transform (Annex F)
+ */
+
+const PAD: usize = 4;
+
+/// One dimensional inverse transform of `line`, whose first sample is at `i0`.
+fn inverse_1d(line: &mut [f32], i0: u32, reversible: bool, buf: &mut Vec<f32>) {
+    let n = line.len();
+    if n == 1 {
+        if i0 % 2 == 1 {
+            line[0] /= 2.;
+        }
+        return;
+    }
+    // symmetric extension
+    buf.clear();
+    for k in 0 .. n + 2 * PAD {
+        let mut i = k as isize - PAD as isize;
+        while i < 0 || i >= n as isize {
+            i = if i < 0 { -i } else { 2 * (n as isize - 1) - i };
+        }
+        buf.push(line[i as usize]);
+    }
+    // parity of buf[0]
+    let odd = (i0 as usize + PAD) % 2;
+    let len = buf.len();
+    let lift = |buf: &mut Vec<f32>, parity: usize, f: &dyn Fn(f32, f32, f32) -> f32| {
+        let mut k = if odd == parity { 2 } else { 1 };
+        while k + 1 < len {
+            buf[k] = f(buf[k], buf[k - 1], buf[k + 1]);
+            k += 2;
+        }
+    };
+    if reversible {
+        lift(buf, 0, &|x, a, b| x - ((a + b + 2.) / 4.).floor());
+        lift(buf, 1, &|x, a, b| x + ((a + b) / 2.).floor());
+    } else {
+        const ALPHA: f32 = -1.586_134_3;
+        const BETA: f32 = -0.052_980_117;
+        const GAMMA: f32 = 0.882_911_1;
+        const DELTA: f32 = 0.443_506_87;
+        const K: f32 = 1.230_174_1;
+        for (k, v) in buf.iter_mut().enumerate() {
+            if (k + odd) & 1 == 0 {
+                *v *= K;
+            } else {
+                *v /= K;
+            }
+        }
+        lift(buf, 0, &|x, a, b| x - DELTA * (a + b));
+        lift(buf, 1, &|x, a, b| x - GAMMA * (a + b));
+        lift(buf, 0, &|x, a, b| x - BETA * (a + b));
+        lift(buf, 1, &|x, a, b| x - ALPHA * (a + b));
+    }
+    line.copy_from_slice(&buf[PAD .. PAD + n]);
+}
+
+fn band_coefficients(band: &Band, tc: &TileComponent) -> Result<Vec<f32>> {
+    let stride = band.width();
+    let mut out = vec![0.; stride * band.height()];
+    for block in &band.blocks {
+        decode_block(block, band, &tc.style, tc.roi, &mut out, stride)?;
+    }
+    Ok(out)
+}
+
+/// Decode the code-blocks of `tc` and apply the inverse wavelet transform.
+fn reconstruct(tc: &TileComponent) -> Result<Vec<f32>> {
+    let ll = &tc.resolutions[0].bands[0];
+    let mut current = band_coefficients(ll, tc)?;
+    let (mut cx0, mut cy0, mut cw) = (ll.x0, ll.y0, ll.width());
+    let mut buf = vec![];
+    for res in &tc.resolutions[1 ..] {
+        let bands = res.bands.iter().map(|b| band_coefficients(b, tc)).collect::<Result<Vec<_>>>()?;
+        let width = (res.x1 - res.x0) as usize;
+        let height = (res.y1 - res.y0) as usize;
+        let mut a = vec![0.; width * height];
+        for y in 0 .. height {
+            let ay = res.y0 + y as u32;
+            for x in 0 .. width {
+                let ax = res.x0 + x as u32;
+                let (data, x0, y0, w) = match (ax % 2, ay % 2) {
+                    (0, 0) => (&current, cx0, cy0, cw),
+                    (1, 0) => (&bands[0], res.bands[0].x0, res.bands[0].y0, res.bands[0].width()),
+                    (0, _) => (&bands[1], res.bands[1].x0, res.bands[1].y0, res.bands[1].width()),
+                    _ => (&bands[2], res.bands[2].x0, res.bands[2].y0, res.bands[2].width()),
+                };
+                let i = (ay / 2).wrapping_sub(y0) as usize * w + (ax / 2).wrapping_sub(x0) as usize;
+                a[y * width + x] = data.get(i).cloned().unwrap_or(0.);
+            }
+        }
+        if width > 0 {
+            for row in a.chunks_mut(width) {
+                inverse_1d(row, res.x0, tc.style.reversible, &mut buf);
+            }
+        }
+        let mut column = vec![0.; height];
+        for x in 0 .. width {
+            for y in 0 .. height {
+                column[y] = a[y * width + x];
+            }
+            if height > 0 {
+                inverse_1d(&mut column, res.y0, tc.style.reversible, &mut buf);
+            }
+            for y in 0 .. height {
+                a[y * width + x] = column[y];
+            }
+        }
+        current = a;
+        cx0 = res.x0;
+        cy0 = res.y0;
+        cw = width;
+    }
+    Ok(current)
+}
+
+/*
+ * Output
+ */
+
+/// Scale a sample of up to 38 bits to 8 bits.
+fn to_u8(v: u64, precision: u8) -> u8 {
+    if precision >= 8 {
+        (v >> (precision - 8)) as u8
+    } else {
+        (v * 255 / ((1 << precision) - 1)) as u8
+    }
+}
+
+/// Decode a JP2 file or JPEG 2000 codestream.
+pub fn decode_image(data: &[u8], limit: Option<OutputLimit>) -> Result<JpxImage> {
+    let (header, codestream) = if data.starts_with(&[0xFF, 0x4F]) {
+        (Jp2Header::default(), data)
+    } else {
+        parse_jp2(data)?
+    };
+    let (siz, images) = decode_codestream(codestream, limit)?;
+    let (width, height) = (siz.width(), siz.height());
+
+    // components on the reference grid, with their precision
+    let components: Vec<(Vec<u32>, u8)> = images.iter().zip(&siz.components).map(|(image, component)| {
+        let mut out = Vec::with_capacity(width as usize * height as usize);
+        for y in siz.y0 .. siz.y1 {
+            let iy = (y / component.dy).saturating_sub(image.y0).min(image.height - 1) as usize;
+            let row = &image.data[iy * image.width as usize ..][.. image.width as usize];
+            for x in siz.x0 .. siz.x1 {
+                let ix = (x / component.dx).saturating_sub(image.x0).min(image.width - 1) as usize;
+                out.push(row[ix] as u32);
+            }
+        }
+        (out, component.precision)
+    }).collect();
+
+    let channels: Vec<Vec<u8>> = match (&header.palette, header.mapping.is_empty()) {
+        (Some(palette), false) => header.mapping.iter().map(|&(c, kind, column)| {
+            let (ref samples, precision) = *components.get(c as usize).ok_or_else(|| other!("invalid JP2 component mapping"))?;
+            Ok(if kind == 1 {
+                let column = column as usize;
+                let column_precision = *palette.precision.get(column).ok_or_else(|| other!("invalid JP2 palette column"))?;
+                samples.iter().map(|&i| {
+                    let entry = palette.entries.get(i as usize).or_else(|| palette.entries.last());
+                    to_u8(entry.map(|e| e[column]).unwrap_or(0), column_precision)
+                }).collect()
+            } else {
+                samples.iter().map(|&v| to_u8(v as u64, precision)).collect()
+            })
+        }).collect::<Result<_>>()?,
+        _ => components.iter().map(|(samples, precision)| samples.iter().map(|&v| to_u8(v as u64, *precision)).collect()).collect()
+    };
+
+    let mut color = vec![];
+    let mut alpha = None;
+    let mut premultiplied = false;
+    if header.channels.is_empty() {
+        let n = header.color_space.as_ref().and_then(|cs| cs.components()).unwrap_or(channels.len()).min(channels.len());
+        color.extend(0 .. n);
+        if header.color_space.is_some() && channels.len() == n + 1 {
+            alpha = Some(n);
+        }
+    } else {
+        let mut associated = vec![];
+        for &(channel, kind, association) in &header.channels {
+            let channel = channel as usize;
+            if channel >= channels.len() {
+                continue;
+            }
+            match kind {
+                0 => associated.push((association, channel)),
+                1 | 2 if alpha.is_none() => {
+                    alpha = Some(channel);
+                    premultiplied = kind == 2;
+                }
+                _ => {}
+            }
+        }
+        associated.sort_unstable();
+        color.extend(associated.into_iter().map(|(_, channel)| channel));
+    }
+    if color.is_empty() {
+        bail!("JPX image without colour channels");
+    }
+
+    let pixels = width as usize * height as usize;
+    let color_channels: Vec<&Vec<u8>> = color.iter().map(|&c| &channels[c]).collect();
+    let mut data: Vec<u8> = (0 .. pixels).flat_map(|i| color_channels.iter().map(move |channel| channel[i])).collect();
+    if header.sycc && color.len() == 3 {
+        for pixel in data.chunks_exact_mut(3) {
+            let (y, cb, cr) = (pixel[0] as f32, pixel[1] as f32 - 128., pixel[2] as f32 - 128.);
+            pixel[0] = (y + 1.402 * cr).round().clamp(0., 255.) as u8;
+            pixel[1] = (y - 0.344_136 * cb - 0.714_136 * cr).round().clamp(0., 255.) as u8;
+            pixel[2] = (y + 1.772 * cb).round().clamp(0., 255.) as u8;
+        }
+    }
+
+    Ok(JpxImage {
+        width,
+        height,
+        color_space: header.color_space,
+        components: color.len(),
+        data,
+        alpha: alpha.map(|a| channels[a].clone()),
+        premultiplied,
+    })
+}
+
+/// Decode the colour samples of a JP2 file or JPEG 2000 codestream, eight bits per component.
+pub fn decode(data: &[u8], limit: Option<OutputLimit>) -> Result<Vec<u8>> {
+    Ok(decode_image(data, limit)?.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A losslessly coded 8x8 greyscale codestream with two resolution levels.
+    const GRAY: [u8; 152] = [
+        0xFF, 0x4F, 0xFF, 0x51, 0x00, 0x29, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x08,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x08,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x01, 0xFF, 0x52, 0x00,
+        0x0C, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x04, 0x04, 0x00, 0x01, 0xFF, 0x5C, 0x00, 0x07, 0x40,
+        0x40, 0x48, 0x48, 0x50, 0xFF, 0x90, 0x00, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x52, 0x00, 0x01,
+        0xFF, 0x93, 0xCF, 0xB4, 0x44, 0x11, 0x1D, 0xB6, 0x71, 0x47, 0x2C, 0x15, 0x0D, 0x22, 0x6F, 0x0A,
+        0x66, 0x68, 0x54, 0xD8, 0xA5, 0x1F, 0xC3, 0xEA, 0x0D, 0x8F, 0xB4, 0x36, 0x0F, 0xA8, 0x34, 0x23,
+        0xC1, 0x26, 0x45, 0x7C, 0xF7, 0x16, 0xE4, 0x13, 0xF6, 0x93, 0x35, 0x57, 0x24, 0x72, 0x07, 0x51,
+        0x73, 0x71, 0xC0, 0x61, 0x6B, 0xA8, 0x8C, 0x53, 0xDF, 0x1F, 0x75, 0x6A, 0x2A, 0xC3, 0x16, 0xA8,
+        0xA5, 0x01, 0x2C, 0x6F, 0x78, 0xA8, 0xFF, 0xD9,
+    ];
+
+    fn expected() -> Vec<u8> {
+        (0 .. 8u32).flat_map(|y| (0 .. 8u32).map(move |x| (((x * 7 + y * 13) ^ (x * y)) % 256) as u8)).collect()
+    }
+
+    fn jp2_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut out = (content.len() as u32 + 8).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(content);
+        out
+    }
+
+    #[test]
+    fn codestream() {
+        let image = decode_image(&GRAY, None).unwrap();
+        assert_eq!((image.width, image.height, image.components), (8, 8, 1));
+        assert_eq!(image.color_space, None);
+        assert_eq!(image.data, expected());
+
+        let limit = OutputLimit { max: 63, limit: "test" };
+        assert!(decode(&GRAY, Some(limit)).unwrap_err().is_limit_exceeded());
+    }
+
+    #[test]
+    fn jp2_palette() {
+        // 256 entries with two 8 bit columns
+        let mut palette = vec![1, 0, 2, 7, 7];
+        for i in 0 ..= 255u8 {
+            palette.extend_from_slice(&[i, 255 - i]);
+        }
+        let header = [
+            jp2_box(b"colr", &[1, 0, 0, 0, 0, 0, 16]),
+            jp2_box(b"pclr", &palette),
+            jp2_box(b"cmap", &[0, 0, 1, 0, 0, 0, 1, 1]),
+            jp2_box(b"cdef", &[0, 2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1]),
+        ].concat();
+        let file = [jp2_box(b"jp2h", &header), jp2_box(b"jp2c", &GRAY)].concat();
+
+        // the first palette column is opacity, the second one the colour
+        let image = decode_image(&file, None).unwrap();
+        assert_eq!(image.color_space, Some(JpxColorSpace::DeviceRGB));
+        assert_eq!(image.components, 1);
+        assert_eq!(image.alpha, Some(expected()));
+        assert_eq!(image.data, expected().iter().map(|v| 255 - v).collect::<Vec<_>>());
+    }
+
+    /// The 8 bit test pattern of component `c` that the codestreams in `data/jpx` were encoded
+    /// from with OpenJPEG.
+    fn sample(c: u32, x: u32, y: u32) -> u8 {
+        let v = ((x * 7 + y * 13 + c * 50) ^ (x * y)) % 256;
+        (if (x / 8 + y / 8).is_multiple_of(2) { v } else { 255 - v / 3 }) as u8
+    }
+
+    /// Decode a 37x29 RGB codestream and compare it with `sample`, with the chroma components
+    /// subsampled by `sub`.
+    fn check_rgb(data: &[u8], sub: u32, tolerance: u8) {
+        let image = decode_image(data, None).unwrap();
+        assert_eq!((image.width, image.height, image.components), (37, 29, 3));
+        for y in 0 .. 29 {
+            for x in 0 .. 37 {
+                for c in 0 .. 3 {
+                    let expected = if c == 0 { sample(0, x, y) } else { sample(c, x / sub, y / sub) };
+                    let v = image.data[((y * 37 + x) * 3 + c) as usize];
+                    assert!(v.abs_diff(expected) <= tolerance, "({}, {}) component {}: {} != {}", x, y, c, v, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn irreversible() {
+        // 9/7 wavelet with the irreversible colour transform
+        check_rgb(include_bytes!("../data/jpx/rgb_97.j2k"), 1, 2);
+    }
+
+    #[test]
+    fn reversible_color_transform() {
+        check_rgb(include_bytes!("../data/jpx/rgb_rct.j2k"), 1, 0);
+    }
+
+    #[test]
+    fn tiles() {
+        // 16x12 tiles, so the last row and column are partial
+        check_rgb(include_bytes!("../data/jpx/tiles.j2k"), 1, 0);
+    }
+
+    #[test]
+    fn progression_order() {
+        // PCRL with 16x16 precincts and 4:2:0 subsampled chroma
+        check_rgb(include_bytes!("../data/jpx/pcrl_420.j2k"), 2, 0);
+    }
+
+    #[test]
+    fn palette_precision() {
+        // a 40 bit palette column can't be scaled to 8 bits
+        let mut palette = vec![0, 1, 1, 39];
+        palette.extend_from_slice(&[0xff; 5]);
+        let header = [
+            jp2_box(b"colr", &[1, 0, 0, 0, 0, 0, 17]),
+            jp2_box(b"pclr", &palette),
+            jp2_box(b"cmap", &[0, 0, 1, 0]),
+        ].concat();
+        let file = [jp2_box(b"jp2h", &header), jp2_box(b"jp2c", &GRAY)].concat();
+        assert!(decode_image(&file, None).unwrap_err().to_string().contains("palette precision 40"));
+
+        // 38 bits is the most a palette column can have
+        palette[3] = 37;
+        palette[4 ..].copy_from_slice(&[0x20, 0, 0, 0, 0]);
+        let header = [
+            jp2_box(b"colr", &[1, 0, 0, 0, 0, 0, 17]),
+            jp2_box(b"pclr", &palette),
+            jp2_box(b"cmap", &[0, 0, 1, 0]),
+        ].concat();
+        let file = [jp2_box(b"jp2h", &header), jp2_box(b"jp2c", &GRAY)].concat();
+        let image = decode_image(&file, None).unwrap();
+        assert_eq!(image.data, [128; 64]);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        let data = include_bytes!("../data/jpx/rgb_rct.j2k");
+        assert!(decode(data, None).is_ok());
+
+        // code-block size exponent byte too large to add 2 to
+        let cod = data.windows(2).position(|w| w == [0xff, 0x52]).unwrap();
+        let mut broken = data.to_vec();
+        broken[cod + 10] = 0xff;
+        assert!(decode(&broken, None).is_err());
+
+        // the first tile ends left of the image
+        let mut broken = data.to_vec();
+        broken[16 .. 20].copy_from_slice(&10u32.to_be_bytes());
+        broken[24 .. 28].copy_from_slice(&5u32.to_be_bytes());
+        assert!(decode(&broken, None).is_err());
+    }
+}
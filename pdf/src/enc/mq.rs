@@ -0,0 +1,198 @@
+//! The MQ arithmetic decoder shared by JBIG2 and JPEG 2000.
+//!
+//! See ITU T.88 Annex E and ITU T.800 Annex C.
+
+/// `(Qe, NMPS, NLPS, SWITCH)` for each probability state.
+const QE_TABLE: [(u16, u8, u8, bool); 47] = [
+    (0x5601,  1,  1, true),
+    (0x3401,  2,  6, false),
+    (0x1801,  3,  9, false),
+    (0x0AC1,  4, 12, false),
+    (0x0521,  5, 29, false),
+    (0x0221, 38, 33, false),
+    (0x5601,  7,  6, true),
+    (0x5401,  8, 14, false),
+    (0x4801,  9, 14, false),
+    (0x3801, 10, 14, false),
+    (0x3001, 11, 17, false),
+    (0x2401, 12, 18, false),
+    (0x1C01, 13, 20, false),
+    (0x1601, 29, 21, false),
+    (0x5601, 15, 14, true),
+    (0x5401, 16, 14, false),
+    (0x5101, 17, 15, false),
+    (0x4801, 18, 16, false),
+    (0x3801, 19, 17, false),
+    (0x3401, 20, 18, false),
+    (0x3001, 21, 19, false),
+    (0x2801, 22, 19, false),
+    (0x2401, 23, 20, false),
+    (0x2201, 24, 21, false),
+    (0x1C01, 25, 22, false),
+    (0x1801, 26, 23, false),
+    (0x1601, 27, 24, false),
+    (0x1401, 28, 25, false),
+    (0x1201, 29, 26, false),
+    (0x1101, 30, 27, false),
+    (0x0AC1, 31, 28, false),
+    (0x09C1, 32, 29, false),
+    (0x08A1, 33, 30, false),
+    (0x0521, 34, 31, false),
+    (0x0441, 35, 32, false),
+    (0x02A1, 36, 33, false),
+    (0x0221, 37, 34, false),
+    (0x0141, 38, 35, false),
+    (0x0111, 39, 36, false),
+    (0x0085, 40, 37, false),
+    (0x0049, 41, 38, false),
+    (0x0025, 42, 39, false),
+    (0x0015, 43, 40, false),
+    (0x0009, 44, 41, false),
+    (0x0005, 45, 42, false),
+    (0x0001, 45, 43, false),
+    (0x5601, 46, 46, false),
+];
+
+/// Adaptive state of one context: the index into `QE_TABLE` and the more probable symbol.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Context {
+    index: u8,
+    mps: u8,
+}
+impl Context {
+    /// A context starting in probability state `index`.
+    pub fn with_index(index: u8) -> Context {
+        Context { index, mps: 0 }
+    }
+}
+
+/// Decodes bits from MQ coded `data`.
+///
+/// Reading past the end of `data` behaves as if it was followed by `0xFF` bytes.
+pub struct MqDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    c: u32,
+    a: u32,
+    ct: u32,
+}
+impl<'a> MqDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> MqDecoder<'a> {
+        let mut decoder = MqDecoder {
+            data,
+            pos: 0,
+            c: (*data.first().unwrap_or(&0xFF) as u32) << 16,
+            a: 0,
+            ct: 0,
+        };
+        decoder.byte_in();
+        decoder.c <<= 7;
+        decoder.ct -= 7;
+        decoder.a = 0x8000;
+        decoder
+    }
+
+    fn byte(&self, pos: usize) -> u32 {
+        self.data.get(pos).map(|&b| b as u32).unwrap_or(0xFF)
+    }
+
+    fn byte_in(&mut self) {
+        if self.byte(self.pos) == 0xFF {
+            if self.byte(self.pos + 1) > 0x8F {
+                // marker: feed 1 bits
+                self.c = self.c.wrapping_add(0xFF00);
+                self.ct = 8;
+            } else {
+                self.pos += 1;
+                self.c = self.c.wrapping_add(self.byte(self.pos) << 9);
+                self.ct = 7;
+            }
+        } else {
+            self.pos += 1;
+            self.c = self.c.wrapping_add(self.byte(self.pos) << 8);
+            self.ct = 8;
+        }
+    }
+
+    fn renormalize(&mut self) {
+        loop {
+            if self.ct == 0 {
+                self.byte_in();
+            }
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.a & 0x8000 != 0 {
+                break;
+            }
+        }
+    }
+
+    /// Decode one bit in context `cx`.
+    pub fn decode(&mut self, cx: &mut Context) -> u8 {
+        let (qe, nmps, nlps, switch) = QE_TABLE[cx.index as usize];
+        let qe = qe as u32;
+        self.a -= qe;
+        let d;
+        if (self.c >> 16) < qe {
+            // LPS exchange
+            if self.a < qe {
+                d = cx.mps;
+                cx.index = nmps;
+            } else {
+                d = 1 - cx.mps;
+                if switch {
+                    cx.mps = d;
+                }
+                cx.index = nlps;
+            }
+            self.a = qe;
+            self.renormalize();
+        } else {
+            self.c -= qe << 16;
+            if self.a & 0x8000 != 0 {
+                return cx.mps;
+            }
+            // MPS exchange
+            if self.a < qe {
+                d = 1 - cx.mps;
+                if switch {
+                    cx.mps = d;
+                }
+                cx.index = nlps;
+            } else {
+                d = cx.mps;
+                cx.index = nmps;
+            }
+            self.renormalize();
+        }
+        d
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test sequence from ITU T.88 H.2, decoded with a single context.
+    #[test]
+    fn t88_test_sequence() {
+        let encoded = [
+            0x84, 0xC7, 0x3B, 0xFC, 0xE1, 0xA1, 0x43, 0x04, 0x02, 0x20, 0x00, 0x00, 0x41, 0x0D, 0xBB, 0x86,
+            0xF4, 0x31, 0x7F, 0xFF, 0x88, 0xFF, 0x37, 0x47, 0x1A, 0xDB, 0x6A, 0xDF, 0xFF, 0xAC,
+        ];
+        let expected = [
+            0x00, 0x02, 0x00, 0x51, 0x00, 0x00, 0x00, 0xC0, 0x03, 0x52, 0x87, 0x2A, 0xAA, 0xAA, 0xAA, 0xAA,
+            0x82, 0xC0, 0x20, 0x00, 0xFC, 0xD7, 0x9E, 0xF6, 0xBF, 0x7F, 0xED, 0x90, 0x4F, 0x46, 0xA3, 0xBF,
+        ];
+        let mut decoder = MqDecoder::new(&encoded);
+        let mut cx = Context::default();
+        for &byte in expected.iter() {
+            let mut decoded = 0;
+            for _ in 0 .. 8 {
+                decoded = decoded << 1 | decoder.decode(&mut cx);
+            }
+            assert_eq!(decoded, byte);
+        }
+    }
+}
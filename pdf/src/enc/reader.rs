@@ -0,0 +1,361 @@
+//! Incremental decoding of stream data.
+//!
+//! Flate, LZW and their predictors are decoded chunk by chunk. All other filters
+//! read their whole input and decode it in one go.
+
+use std::io::{self, Read};
+use std::cell::Cell;
+use inflate::InflateStream;
+
+use crate::error::*;
+use crate::file::Limits;
+use super::{StreamFilter, LZWFlateParams, OutputLimit, PredictorType, decode, unfilter, tiff_predictor, predictor_layout};
+
+const CHUNK_SIZE: usize = 8192;
+
+fn io_error(e: PdfError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Reads the data of a stream, decoding it with its filters as it goes.
+pub struct DecodeReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    limit: Option<OutputLimit>,
+    limits: Option<Limits>,
+    counted: Option<&'a Cell<bool>>,
+    len: usize,
+}
+impl<'a> DecodeReader<'a> {
+    /// Decode `input`, which is `input_len` bytes long, with `filters`, failing once more
+    /// than `max` bytes were read.
+    ///
+    /// If `limits` are given, the output of each filter is limited like in `Stream::decode`,
+    /// and the bytes read are accounted for in the total of the file, unless `counted` is set.
+    /// `counted` is set once all data was read.
+    pub(crate) fn new(input: Box<dyn Read + 'a>, mut input_len: usize, filters: &'a [StreamFilter], max: Option<usize>, limits: Option<&Limits>, counted: Option<&'a Cell<bool>>) -> DecodeReader<'a> {
+        let max = max.map(|max| OutputLimit { max, limit: "stream reader output" });
+        let mut inner = input;
+        for (i, filter) in filters.iter().enumerate() {
+            let limit = match counted.is_some_and(Cell::get) {
+                true => limits.and_then(|l| l.ratio_limit(filter, input_len)),
+                false => limits.and_then(|l| l.output_limit(filter, input_len))
+            };
+            // filters that decode all at once stop at the limit of the reader, too
+            let stage_limit = match (limit, max) {
+                (Some(a), Some(b)) if i + 1 == filters.len() => Some(if a.max <= b.max { a } else { b }),
+                (None, Some(b)) if i + 1 == filters.len() => Some(b),
+                (a, _) => a
+            };
+            inner = Box::new(Limited { inner: stage(inner, filter, stage_limit), limit, len: 0 });
+            input_len = usize::MAX;
+        }
+        DecodeReader {
+            inner,
+            limit: max,
+            limits: limits.cloned(),
+            counted,
+            len: 0,
+        }
+    }
+}
+impl<'a> Read for DecodeReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.len += n;
+        if let Some(limit) = self.limit {
+            limit.check(self.len).map_err(io_error)?;
+        }
+        match (&self.limits, self.counted) {
+            (_, Some(counted)) if counted.get() => {}
+            (Some(ref limits), _) => limits.add_decoded_bytes(n).map_err(io_error)?,
+            (None, _) => {}
+        }
+        if let Some(counted) = self.counted {
+            if n == 0 && !buf.is_empty() {
+                counted.set(true);
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn stage<'a>(input: Box<dyn Read + 'a>, filter: &'a StreamFilter, limit: Option<OutputLimit>) -> Box<dyn Read + 'a> {
+    match *filter {
+        StreamFilter::FlateDecode(ref params) => {
+            let reader = ChunkReader::new(input, FlateChunks { stream: None });
+            predicted(Box::new(reader), params)
+        }
+        StreamFilter::LZWDecode(ref params) => {
+            use weezl::{BitOrder, decode::Decoder};
+            let decoder = if params.early_change != 0 {
+                Decoder::with_tiff_size_switch(BitOrder::Msb, 8)
+            } else {
+                Decoder::new(BitOrder::Msb, 8)
+            };
+            let reader = ChunkReader::new(input, LzwChunks { decoder });
+            predicted(Box::new(reader), params)
+        }
+        _ => Box::new(Buffered { input: Some(input), filter, limit, out: io::Cursor::new(Vec::new()) })
+    }
+}
+
+fn predicted<'a>(input: Box<dyn Read + 'a>, params: &'a LZWFlateParams) -> Box<dyn Read + 'a> {
+    match params.predictor {
+        2 | 10 ..= 255 => Box::new(PredictorReader {
+            input,
+            params,
+            row: Vec::new(),
+            prev: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+        }),
+        _ => input
+    }
+}
+
+/// Fails once `inner` produced more than `limit` bytes.
+struct Limited<R> {
+    inner: R,
+    limit: Option<OutputLimit>,
+    len: usize,
+}
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.len += n;
+        if let Some(limit) = self.limit {
+            limit.check(self.len).map_err(io_error)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Decodes a chunk of input at a time.
+trait Chunks {
+    /// Decode the start of `input` into `out` and return the number of bytes consumed
+    /// and whether the end of the data was reached. `eof` is set if no more input follows.
+    fn decode(&mut self, input: &[u8], eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)>;
+}
+
+struct ChunkReader<R, D> {
+    input: R,
+    decoder: D,
+    pending: Vec<u8>,
+    eof: bool,
+    out: Vec<u8>,
+    out_pos: usize,
+    done: bool,
+}
+impl<R: Read, D: Chunks> ChunkReader<R, D> {
+    fn new(input: R, decoder: D) -> Self {
+        ChunkReader {
+            input,
+            decoder,
+            pending: Vec::new(),
+            eof: false,
+            out: Vec::new(),
+            out_pos: 0,
+            done: false,
+        }
+    }
+    fn fill(&mut self) -> io::Result<()> {
+        let start = self.pending.len();
+        self.pending.resize(start + CHUNK_SIZE, 0);
+        let n = self.input.read(&mut self.pending[start ..])?;
+        self.pending.truncate(start + n);
+        if n == 0 {
+            self.eof = true;
+        }
+        Ok(())
+    }
+}
+impl<R: Read, D: Chunks> Read for ChunkReader<R, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.out_pos < self.out.len() {
+                let n = buf.len().min(self.out.len() - self.out_pos);
+                buf[.. n].copy_from_slice(&self.out[self.out_pos .. self.out_pos + n]);
+                self.out_pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            self.out.clear();
+            self.out_pos = 0;
+            if self.pending.is_empty() && !self.eof {
+                self.fill()?;
+            }
+            let (consumed, finished) = self.decoder.decode(&self.pending, self.eof, &mut self.out).map_err(io_error)?;
+            self.pending.drain(.. consumed);
+            if finished {
+                self.done = true;
+            } else if consumed == 0 && self.out.is_empty() {
+                // the decoder needs more input
+                if self.eof {
+                    self.done = true;
+                } else {
+                    self.fill()?;
+                }
+            }
+        }
+    }
+}
+
+struct FlateChunks {
+    stream: Option<InflateStream>,
+}
+impl Chunks for FlateChunks {
+    fn decode(&mut self, input: &[u8], eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)> {
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None if input.len() < 2 && !eof => return Ok((0, false)),
+            None => {
+                let zlib = match *input {
+                    [cmf, flg, ..] => cmf & 0x0F == 8 && (cmf as u16 * 256 + flg as u16).is_multiple_of(31),
+                    _ => false
+                };
+                if !zlib {
+                    info!("invalid zlib header. trying without");
+                }
+                self.stream.get_or_insert_with(|| if zlib { InflateStream::from_zlib() } else { InflateStream::new() })
+            }
+        };
+        let (n, bytes) = stream.update(input)?;
+        out.extend_from_slice(bytes);
+        Ok((n, eof && bytes.is_empty() && n == input.len()))
+    }
+}
+
+struct LzwChunks {
+    decoder: weezl::decode::Decoder,
+}
+impl Chunks for LzwChunks {
+    fn decode(&mut self, input: &[u8], eof: bool, out: &mut Vec<u8>) -> Result<(usize, bool)> {
+        use weezl::LzwStatus;
+
+        let mut buf = [0; 4096];
+        let result = self.decoder.decode_bytes(input, &mut buf);
+        out.extend_from_slice(&buf[.. result.consumed_out]);
+        match result.status {
+            Ok(LzwStatus::Ok) => Ok((result.consumed_in, false)),
+            Ok(LzwStatus::Done) => Ok((result.consumed_in, true)),
+            Ok(LzwStatus::NoProgress) => Ok((result.consumed_in, eof)),
+            Err(e) => bail!("{}", e)
+        }
+    }
+}
+
+/// Undoes TIFF and PNG predictors one row at a time. An incomplete last row is dropped.
+struct PredictorReader<'a> {
+    input: Box<dyn Read + 'a>,
+    params: &'a LZWFlateParams,
+    row: Vec<u8>,
+    prev: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+}
+impl<'a> PredictorReader<'a> {
+    /// Read the next row into `self.out`. Returns false at the end of the data.
+    fn next_row(&mut self) -> Result<bool> {
+        let (bpp, stride) = predictor_layout(self.params)?;
+        let png = self.params.predictor >= 10;
+        self.row.resize(stride + png as usize, 0);
+        let mut filled = 0;
+        while filled < self.row.len() {
+            match self.input.read(&mut self.row[filled ..])? {
+                0 => return Ok(false),
+                n => filled += n,
+            }
+        }
+        if png {
+            self.prev.resize(stride, 0);
+            self.out.resize(stride, 0);
+            unfilter(PredictorType::from_u8(self.row[0])?, bpp, &self.prev, &self.row[1 ..], &mut self.out);
+            self.prev.copy_from_slice(&self.out);
+        } else {
+            tiff_predictor(&mut self.row, self.params, true);
+            std::mem::swap(&mut self.row, &mut self.out);
+        }
+        self.out_pos = 0;
+        Ok(true)
+    }
+}
+impl<'a> Read for PredictorReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.out_pos == self.out.len() && !self.next_row().map_err(io_error)? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.out.len() - self.out_pos);
+        buf[.. n].copy_from_slice(&self.out[self.out_pos .. self.out_pos + n]);
+        self.out_pos += n;
+        Ok(n)
+    }
+}
+
+/// Reads all of `input` and decodes it at once, failing once the output exceeds `limit`.
+struct Buffered<'a> {
+    input: Option<Box<dyn Read + 'a>>,
+    filter: &'a StreamFilter,
+    limit: Option<OutputLimit>,
+    out: io::Cursor<Vec<u8>>,
+}
+impl<'a> Read for Buffered<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(mut input) = self.input.take() {
+            let mut data = Vec::new();
+            input.read_to_end(&mut data)?;
+            let decoded = decode(&data, self.filter, self.limit).map_err(io_error)?;
+            self.out = io::Cursor::new(decoded);
+        }
+        self.out.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enc::encode;
+
+    #[test]
+    fn chained_filters() {
+        let data: Vec<u8> = (0 .. 100_000u32).map(|i| (i / 3 % 256) as u8 ^ (i % 7) as u8).collect();
+        let chains = vec![
+            vec![
+                StreamFilter::ASCIIHexDecode,
+                StreamFilter::LZWDecode(LZWFlateParams::default()),
+                StreamFilter::FlateDecode(LZWFlateParams { predictor: 15, n_components: 2, columns: 50, ..Default::default() }),
+            ],
+            vec![
+                StreamFilter::LZWDecode(LZWFlateParams { predictor: 2, early_change: 0, columns: 100, ..Default::default() }),
+            ],
+        ];
+        for filters in &chains {
+            let mut encoded = data.clone();
+            for filter in filters.iter().rev() {
+                encoded = encode(&encoded, filter).unwrap();
+            }
+            let mut decoded = Vec::new();
+            DecodeReader::new(Box::new(&encoded[..]), encoded.len(), filters, None, None, None).read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, data);
+        }
+
+        let filters = &chains[0];
+        let mut encoded = data.clone();
+        for filter in filters.iter().rev() {
+            encoded = encode(&encoded, filter).unwrap();
+        }
+        let mut reader = DecodeReader::new(Box::new(&encoded[..]), encoded.len(), filters, Some(1000), None, None);
+        let mut buf = [0; 600];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[.. 600]);
+        assert!(reader.read_exact(&mut buf).is_err());
+
+        // filters without a streaming decoder stop at the limit as well
+        let filters = [StreamFilter::RunLengthDecode];
+        let bomb = [129, 0].repeat(10_000);
+        let mut reader = DecodeReader::new(Box::new(&bomb[..]), bomb.len(), &filters, Some(1000), None, None);
+        let e = reader.read(&mut buf).unwrap_err();
+        assert!(e.into_inner().unwrap().downcast::<PdfError>().unwrap().is_limit_exceeded());
+    }
+}
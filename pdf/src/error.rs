@@ -102,6 +102,9 @@ pub enum PdfError {
     #[snafu(display("Page {} could not be found in the page tree.", page_nr))]
     PageNotFound {page_nr: u32},
     
+    #[snafu(display("Limit exceeded: {} (max {})", limit, max))]
+    LimitExceeded { limit: &'static str, max: usize },
+    
     #[snafu(display("Entry {} in xref table unspecified", id))]
     UnspecifiedXRefEntry {id: ObjNr},
     
@@ -114,6 +117,9 @@ pub enum PdfError {
     #[snafu(display("JPEG"))]
     Jpeg { source: jpeg_decoder::Error },
 
+    #[snafu(display("JPEG encoding"))]
+    JpegEncode { source: jpeg_encoder::EncodingError },
+
     #[snafu(display("IO Error"))]
     Io { source: io::Error },
     
@@ -146,6 +152,15 @@ impl PdfError {
             _ => false
         }
     }
+    /// Whether this error, or the error that caused it, is a `LimitExceeded` error.
+    pub fn is_limit_exceeded(&self) -> bool {
+        match self {
+            &PdfError::LimitExceeded { .. } => true,
+            &PdfError::Try { ref source, .. } | PdfError::TryContext { ref source, .. } => source.is_limit_exceeded(),
+            PdfError::FromPrimitive { source, .. } => source.is_limit_exceeded(),
+            _ => false
+        }
+    }
 }
 fn trace(err: &dyn Error, depth: usize) {
     println!("{}: {}", depth, err);
@@ -228,6 +243,7 @@ macro_rules! err_from {
 err_from!(std::str::Utf8Error, std::string::FromUtf8Error, std::string::FromUtf16Error => Encoding);
 err_from!(std::num::ParseIntError, std::string::ParseError => Parse);
 err_from!(jpeg_decoder::Error => Jpeg);
+err_from!(jpeg_encoder::EncodingError => JpegEncode);
 
 macro_rules! err {
     ($e: expr) => ({
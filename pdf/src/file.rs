@@ -2,10 +2,10 @@
 use std::fs;
 use std::marker::PhantomData;
 use std::collections::HashMap;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use std::path::Path;
-use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::io::{self, Write};
 
 use crate as pdf;
 use crate::error::*;
@@ -18,6 +18,144 @@ use crate::parser::{parse_indirect_object, parse};
 use crate::xref::{XRef, XRefTable, XRefInfo};
 use crate::crypt::Decoder;
 use crate::crypt::CryptDict;
+use crate::enc::{StreamFilter, OutputLimit};
+
+/// Limits and other options that apply while reading a file.
+///
+/// All limits are disabled by default. Use them when opening files from untrusted sources,
+/// to guard against decompression bombs and excessively large object graphs.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// Maximum number of objects the cross-reference table may declare.
+    pub max_objects: Option<usize>,
+
+    /// Maximum number of decoded stream bytes, summed over all streams read from the file.
+    pub max_decoded_bytes: Option<usize>,
+
+    /// Maximum ratio of output to input size for `FlateDecode`, `LZWDecode` and `RunLengthDecode`.
+    pub max_decompression_ratio: Option<usize>,
+
+    /// Maximum number of operators in a content stream.
+    pub max_operators: Option<usize>,
+
+    /// Maximum nesting depth of the page tree.
+    pub max_page_tree_depth: Option<usize>,
+
+    /// Loads the data of streams stored in external files. Disabled by default, as the
+    /// file names come from the document; see `DirResolver`.
+    pub file_resolver: Option<Rc<dyn FileResolver>>,
+
+    /// Without a `file_resolver`, have `File::open_with_options` read external stream data
+    /// from the directory the document is in. Disabled by default.
+    pub resolve_relative_files: bool,
+}
+
+/// Opens the external files that streams with an `/F` entry keep their data in.
+pub trait FileResolver: std::fmt::Debug {
+    /// Open the file that the file specification string `name` refers to.
+    fn open(&self, name: &str) -> Result<Box<dyn io::Read>>;
+}
+
+/// Resolves file names relative to a directory.
+///
+/// Absolute names, names containing `..` and names that lead outside of `dir` through
+/// symbolic links are rejected.
+#[derive(Debug, Clone)]
+pub struct DirResolver {
+    pub dir: PathBuf,
+}
+impl FileResolver for DirResolver {
+    fn open(&self, name: &str) -> Result<Box<dyn io::Read>> {
+        let relative = Path::new(name);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            bail!("external file name {:?} is not a relative path inside the directory", name);
+        }
+        let dir = self.dir.canonicalize()?;
+        let path = dir.join(relative).canonicalize()?;
+        if !path.starts_with(&dir) {
+            bail!("external file {:?} is outside of {:?}", name, self.dir);
+        }
+        let file = fs::File::open(path)?;
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+/// The `OpenOptions` of a file together with the state needed to enforce them.
+///
+/// Shared by all objects read from the same file.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub options: OpenOptions,
+    decoded_bytes: Rc<Cell<usize>>,
+}
+impl Limits {
+    pub fn new(options: OpenOptions) -> Limits {
+        Limits {
+            options,
+            decoded_bytes: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// The number of bytes that may still be decoded before `max_decoded_bytes` is reached.
+    pub fn decoded_bytes_limit(&self) -> Option<OutputLimit> {
+        self.options.max_decoded_bytes.map(|max| OutputLimit {
+            max: max.saturating_sub(self.decoded_bytes.get()),
+            limit: "decoded stream bytes",
+        })
+    }
+
+    /// The largest output `filter` may produce from `input_len` bytes of input.
+    pub fn output_limit(&self, filter: &StreamFilter, input_len: usize) -> Option<OutputLimit> {
+        let total = self.decoded_bytes_limit();
+        let ratio = self.ratio_limit(filter, input_len);
+        match (total, ratio) {
+            (Some(a), Some(b)) => Some(if a.max <= b.max { a } else { b }),
+            (a, b) => a.or(b)
+        }
+    }
+
+    /// Like `output_limit`, but only for `max_decompression_ratio`. Used for data that was
+    /// decoded and counted before.
+    pub fn ratio_limit(&self, filter: &StreamFilter, input_len: usize) -> Option<OutputLimit> {
+        match filter {
+            StreamFilter::FlateDecode(_) | StreamFilter::LZWDecode(_) | StreamFilter::RunLengthDecode => {
+                self.options.max_decompression_ratio.map(|ratio| OutputLimit {
+                    max: input_len.saturating_mul(ratio),
+                    limit: "decompression ratio",
+                })
+            }
+            _ => None
+        }
+    }
+
+    /// Account for `n` decoded bytes.
+    pub fn add_decoded_bytes(&self, n: usize) -> Result<()> {
+        let total = self.decoded_bytes.get().saturating_add(n);
+        if let Some(max) = self.options.max_decoded_bytes {
+            if total > max {
+                return Err(PdfError::LimitExceeded { limit: "decoded stream bytes", max });
+            }
+        }
+        self.decoded_bytes.set(total);
+        Ok(())
+    }
+
+    /// Fail if a content stream has more than `max_operators` operators.
+    pub fn check_operators(&self, n: usize) -> Result<()> {
+        match self.options.max_operators {
+            Some(max) if n > max => Err(PdfError::LimitExceeded { limit: "operators per content stream", max }),
+            _ => Ok(())
+        }
+    }
+
+    /// Fail if the page tree is nested deeper than `max_page_tree_depth`.
+    pub fn check_page_tree_depth(&self, depth: usize) -> Result<()> {
+        match self.options.max_page_tree_depth {
+            Some(max) if depth > max => Err(PdfError::LimitExceeded { limit: "page tree depth", max }),
+            _ => Ok(())
+        }
+    }
+}
 
 #[must_use]
 pub struct PromisedRef<T> {
@@ -48,6 +186,8 @@ pub struct Storage<B: Backend> {
 
     // Position of the PDF header in the file.
     start_offset: usize,
+
+    limits: Option<Limits>,
 }
 impl<B: Backend> Storage<B> {
     pub fn new(backend: B, refs: XRefTable, start_offset: usize) -> Storage<B> {
@@ -58,6 +198,7 @@ impl<B: Backend> Storage<B> {
             cache: RefCell::new(HashMap::new()),
             changes: HashMap::new(),
             decoder: None,
+            limits: None,
         }
     }
 }
@@ -97,6 +238,9 @@ impl<B: Backend> Resolve for Storage<B> {
         
         Ok(RcRef::new(key, rc))
     }
+    fn limits(&self) -> Option<&Limits> {
+        self.limits.as_ref()
+    }
 }
 impl<B: Backend> Updater for Storage<B> {
     fn create<T: ObjectWrite>(&mut self, obj: T) -> Result<RcRef<T>> {
@@ -119,6 +263,8 @@ impl<B: Backend> Updater for Storage<B> {
         };
         let primitive = obj.to_primitive(self)?;
         self.changes.insert(old.id, primitive);
+        // the cached object is outdated now
+        self.cache.get_mut().retain(|key, _| key.id != old.id);
         let rc = Rc::new(obj);
         
         Ok(RcRef::new(r, rc))
@@ -158,7 +304,7 @@ impl Storage<Vec<u8>> {
             self.refs.set(id, XRef::Raw { pos: pos as _, gen_nr: 0 });
             write!(&mut self.backend, "{} {} obj\n", id, 0)?;
             primitive.serialize(&mut self.backend, 0)?;
-            write!(self.backend, "endobj\n")?;
+            write!(self.backend, "\nendobj\n")?;
         }
 
         let xref_pos = self.backend.len();
@@ -190,10 +336,20 @@ pub fn load_storage_and_trailer<B: Backend>(backend: B) -> Result<(Storage<B>, D
 pub fn load_storage_and_trailer_password<B: Backend>(
     backend: B,
     password: &[u8],
+) -> Result<(Storage<B>, Dictionary)> {
+    load_storage_and_trailer_options(backend, password, None)
+}
+
+pub fn load_storage_and_trailer_options<B: Backend>(
+    backend: B,
+    password: &[u8],
+    options: Option<OpenOptions>,
 ) -> Result<(Storage<B>, Dictionary)> {
     let start_offset = t!(backend.locate_start_offset());
-    let (refs, trailer) = t!(backend.read_xref_table_and_trailer(start_offset));
+    let max_objects = options.as_ref().and_then(|o| o.max_objects);
+    let (refs, trailer) = t!(backend.read_xref_table_and_trailer_limited(start_offset, max_objects));
     let mut storage = Storage::new(backend, refs, start_offset);
+    storage.limits = options.map(Limits::new);
 
     if let Some(crypt) = trailer.get("Encrypt") {
         let key = trailer
@@ -231,6 +387,9 @@ impl<B: Backend> Resolve for File<B> {
     fn get<T: Object>(&self, r: Ref<T>) -> Result<RcRef<T>> {
         self.storage.get(r)
     }
+    fn limits(&self) -> Option<&Limits> {
+        self.storage.limits()
+    }
 }
 impl<B: Backend> Updater for File<B> {
     fn create<T: ObjectWrite>(&mut self, obj: T) -> Result<RcRef<T>> {
@@ -250,12 +409,28 @@ impl<B: Backend> Updater for File<B> {
 impl File<Vec<u8>> {
     /// Opens the file at `path` and uses Vec<u8> as backend.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        Self::from_data(fs::read(path)?)
+        Self::open_password(path, b"")
     }
 
     /// Opens the file at `path`, with a password, and uses Vec<u8> as backend.
     pub fn open_password(path: impl AsRef<Path>, password: &[u8]) -> Result<Self> {
-        Self::from_data_password(fs::read(path)?, password)
+        Self::open_with_options(path, password, OpenOptions::default())
+    }
+
+    /// Opens the file at `path`, with a password, enforcing the limits in `options`.
+    ///
+    /// External stream data is only read if `options` has a `file_resolver`, or if
+    /// `resolve_relative_files` is set, in which case it is read relative to `path`.
+    pub fn open_with_options(path: impl AsRef<Path>, password: &[u8], mut options: OpenOptions) -> Result<Self> {
+        let path = path.as_ref();
+        if options.resolve_relative_files && options.file_resolver.is_none() {
+            let dir = match path.parent() {
+                Some(dir) if dir != Path::new("") => dir.to_owned(),
+                _ => PathBuf::from(".")
+            };
+            options.file_resolver = Some(Rc::new(DirResolver { dir }));
+        }
+        Self::from_data_with_options(fs::read(path)?, password, options)
     }
 
     pub fn save_to(&mut self, path: impl AsRef<Path>) -> Result<()> {
@@ -265,15 +440,20 @@ impl File<Vec<u8>> {
 }
 impl<B: Backend> File<B> {
     pub fn from_data_password(backend: B, password: &[u8]) -> Result<Self> {
-        Self::load_data(backend, password)
+        Self::load_data(backend, password, None)
     }
 
     pub fn from_data(backend: B) -> Result<Self> {
         Self::from_data_password(backend, b"")
     }
 
-    fn load_data(backend: B, password: &[u8]) -> Result<Self> {
-        let (storage, trailer) = load_storage_and_trailer_password(backend, password)?;
+    /// Like `from_data_password`, but enforces the limits in `options`.
+    pub fn from_data_with_options(backend: B, password: &[u8], options: OpenOptions) -> Result<Self> {
+        Self::load_data(backend, password, Some(options))
+    }
+
+    fn load_data(backend: B, password: &[u8], options: Option<OpenOptions>) -> Result<Self> {
+        let (storage, trailer) = load_storage_and_trailer_options(backend, password, options)?;
         let trailer = t!(Trailer::from_primitive(
             Primitive::Dictionary(trailer),
             &storage,
@@ -285,8 +465,15 @@ impl<B: Backend> File<B> {
         &self.trailer.root
     }
 
+    /// Iterate over the pages, giving `num_pages()` results. If the page tree holds fewer
+    /// pages than its `/Count`, the missing ones are `PageOutOfBounds` errors.
     pub fn pages<'a>(&'a self) -> impl Iterator<Item=Result<PageRc>> + 'a {
-        (0 .. self.num_pages()).map(move |n| self.get_page(n))
+        let mut pages = self.trailer.root.pages.pages(self);
+        let mut found = None;
+        (0 .. self.num_pages()).map(move |page_nr| match pages.next() {
+            Some(page) => page,
+            None => Err(PdfError::PageOutOfBounds { page_nr, max: *found.get_or_insert(page_nr) })
+        })
     }
     pub fn num_pages(&self) -> u32 {
         self.trailer.root.pages.count
@@ -0,0 +1,824 @@
+//! ICC colour profiles (ICC.1), as embedded by `ICCBased` colour spaces.
+//!
+//! Profiles are parsed into transforms between the device colour space and the profile
+//! connection space (PCS): the matrix/TRC transforms of RGB and gray profiles and the LUT based
+//! `lut8`, `lut16`, `lutAToB` and `lutBToA` transforms. Named colour and device link profiles,
+//! `DToB` tags and black point compensation are not supported.
+
+use std::fmt;
+use crate::error::*;
+use crate::object::{RenderingIntent, xyz_to_rgb};
+
+pub type Signature = [u8; 4];
+
+/// The white point of the PCS.
+const D50: [f32; 3] = [0.9642, 1., 0.8249];
+
+/// Channels of a CLUT may not have more grid points than this in total.
+const MAX_CLUT_SIZE: usize = 1 << 24;
+
+#[derive(Debug, Clone)]
+pub struct IccHeader {
+    pub size: u32,
+    pub cmm: Signature,
+    /// Major and minor version.
+    pub version: (u8, u8),
+    /// The device class, like `mntr` for displays or `prtr` for printers.
+    pub class: Signature,
+    /// The colour space of the device values, like `RGB ` or `CMYK`.
+    pub color_space: Signature,
+    /// The profile connection space, `XYZ ` or `Lab `.
+    pub pcs: Signature,
+    pub intent: u32,
+    pub illuminant: [f32; 3],
+    pub creator: Signature,
+}
+
+/// A parsed ICC profile.
+#[derive(Clone)]
+pub struct IccProfile {
+    pub header: IccHeader,
+    data: Vec<u8>,
+    /// Signature, offset and size of each tag.
+    tags: Vec<(Signature, usize, usize)>,
+    /// `A2B0` to `A2B2` and `B2A0` to `B2A2`, by intent.
+    a2b: [Option<Pipeline>; 3],
+    b2a: [Option<Pipeline>; 3],
+    matrix_trc: Option<MatrixTrc>,
+    media_white: [f32; 3],
+}
+impl fmt::Debug for IccProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IccProfile")
+            .field("header", &self.header)
+            .field("tags", &self.tag_signatures().map(|s| String::from_utf8_lossy(&s[..])).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl IccProfile {
+    pub fn parse(data: &[u8]) -> Result<IccProfile> {
+        if data.len() < 132 || &data[36 .. 40] != b"acsp" {
+            bail!("not an ICC profile");
+        }
+        let header = IccHeader {
+            size: u32_at(data, 0)?,
+            cmm: sig_at(data, 4)?,
+            version: (data[8], data[9] >> 4),
+            class: sig_at(data, 12)?,
+            color_space: sig_at(data, 16)?,
+            pcs: sig_at(data, 20)?,
+            intent: u32_at(data, 64)?,
+            illuminant: xyz_number(data, 68)?,
+            creator: sig_at(data, 80)?,
+        };
+        let count = u32_at(data, 128)? as usize;
+        let mut tags = Vec::new();
+        for i in 0 .. count {
+            let pos = i.checked_mul(12).and_then(|p| p.checked_add(132)).ok_or(PdfError::EOF)?;
+            let (offset, size) = (u32_at(data, pos + 4)? as usize, u32_at(data, pos + 8)? as usize);
+            if !matches!(offset.checked_add(size), Some(end) if end <= data.len()) {
+                bail!("ICC tag at {} with size {} is outside of the profile", offset, size);
+            }
+            tags.push((sig_at(data, pos)?, offset, size));
+        }
+
+        let mut profile = IccProfile {
+            header,
+            data: data.to_vec(),
+            tags,
+            a2b: [None, None, None],
+            b2a: [None, None, None],
+            matrix_trc: None,
+            media_white: D50,
+        };
+        let pcs_xyz = match &profile.header.pcs {
+            b"XYZ " => true,
+            b"Lab " => false,
+            pcs => bail!("unsupported profile connection space {:?}", String::from_utf8_lossy(pcs))
+        };
+        let n = profile.components()?;
+        for i in 0 .. 3 {
+            if let Some(data) = profile.tag(&[b'A', b'2', b'B', b'0' + i as u8]) {
+                profile.a2b[i] = Some(t!(Pipeline::parse(data, true, pcs_xyz, n)));
+            }
+            if let Some(data) = profile.tag(&[b'B', b'2', b'A', b'0' + i as u8]) {
+                profile.b2a[i] = Some(t!(Pipeline::parse(data, false, pcs_xyz, n)));
+            }
+        }
+        profile.matrix_trc = match &profile.header.color_space {
+            b"GRAY" => match profile.tag(b"kTRC") {
+                Some(data) => Some(MatrixTrc::Gray(Curve::parse(data)?.0)),
+                None => None
+            },
+            b"RGB " => match (profile.tag(b"rXYZ"), profile.tag(b"gXYZ"), profile.tag(b"bXYZ")) {
+                (Some(r), Some(g), Some(b)) => {
+                    let (r, g, b) = (xyz_tag(r)?, xyz_tag(g)?, xyz_tag(b)?);
+                    let matrix = [r[0], g[0], b[0], r[1], g[1], b[1], r[2], g[2], b[2]];
+                    let curve = |sig| match profile.tag(sig) {
+                        Some(data) => Curve::parse(data).map(|(c, _)| c),
+                        None => bail!("RGB profile without {:?}", String::from_utf8_lossy(sig))
+                    };
+                    let curves = [curve(b"rTRC")?, curve(b"gTRC")?, curve(b"bTRC")?];
+                    let inverse = match invert(&matrix) {
+                        Some(m) => m,
+                        None => bail!("singular colorant matrix {:?}", matrix)
+                    };
+                    Some(MatrixTrc::Rgb { matrix, inverse, curves })
+                }
+                _ => None
+            },
+            _ => None
+        };
+        if let Some(data) = profile.tag(b"wtpt") {
+            profile.media_white = xyz_tag(data)?;
+        }
+        Ok(profile)
+    }
+
+    /// The number of device colour components.
+    pub fn components(&self) -> Result<usize> {
+        Ok(match &self.header.color_space {
+            b"GRAY" => 1,
+            b"2CLR" => 2,
+            b"RGB " | b"Lab " | b"XYZ " | b"Luv " | b"YCbr" | b"Yxy " | b"HSV " | b"HLS " | b"CMY " => 3,
+            b"CMYK" | b"4CLR" => 4,
+            &[n, b'C', b'L', b'R'] if n.is_ascii_hexdigit() => (n as char).to_digit(16).unwrap() as usize,
+            s => bail!("unknown ICC colour space {:?}", String::from_utf8_lossy(s))
+        })
+    }
+
+    /// The signatures of all tags.
+    pub fn tag_signatures(&self) -> impl Iterator<Item=&Signature> {
+        self.tags.iter().map(|(sig, _, _)| sig)
+    }
+
+    /// The data of the tag `sig`, starting with its type signature.
+    pub fn tag(&self, sig: &Signature) -> Option<&[u8]> {
+        self.tags.iter()
+            .find(|(s, _, _)| s == sig)
+            .map(|&(_, offset, size)| &self.data[offset .. offset + size])
+    }
+
+    fn pipeline(pipelines: &[Option<Pipeline>; 3], intent: RenderingIntent) -> Option<&Pipeline> {
+        let i = match intent {
+            RenderingIntent::Perceptual => 0,
+            RenderingIntent::RelativeColorimetric | RenderingIntent::AbsoluteColorimetric => 1,
+            RenderingIntent::Saturation => 2,
+        };
+        pipelines[i].as_ref().or(pipelines[0].as_ref())
+    }
+
+    /// Convert the device colour `c` to CIE XYZ, relative to the D50 white point of the PCS.
+    ///
+    /// Components of Lab profiles are L*, a* and b*, all others are in `0 ..= 1`.
+    pub fn to_xyz(&self, c: &[f32], intent: RenderingIntent) -> Result<[f32; 3]> {
+        let n = self.components()?;
+        if c.len() < n {
+            bail!("{} components for an ICC profile with {}", c.len(), n);
+        }
+        let xyz = match IccProfile::pipeline(&self.a2b, intent) {
+            Some(pipeline) => {
+                let input = match &self.header.color_space {
+                    b"Lab " => encode_lab([c[0], c[1], c[2]], pipeline.legacy_lab).to_vec(),
+                    _ => c[.. n].to_vec()
+                };
+                let out = pipeline.apply(&input);
+                match &self.header.pcs {
+                    b"XYZ " => [0, 1, 2].map(|i| out[i] * 65535. / 32768.),
+                    _ => lab_to_xyz(decode_lab([out[0], out[1], out[2]], pipeline.legacy_lab))
+                }
+            }
+            None => match self.matrix_trc {
+                Some(ref m) => m.to_xyz(c),
+                None => bail!("ICC profile without a transform to the PCS")
+            }
+        };
+        Ok(match intent {
+            RenderingIntent::AbsoluteColorimetric => [0, 1, 2].map(|i| xyz[i] * self.media_white[i] / D50[i]),
+            _ => xyz
+        })
+    }
+
+    /// Convert `xyz`, relative to the D50 white point of the PCS, to device colour components.
+    pub fn to_device(&self, xyz: [f32; 3], intent: RenderingIntent, out: &mut [f32]) -> Result<()> {
+        let n = self.components()?;
+        if out.len() != n {
+            bail!("{} output components for an ICC profile with {}", out.len(), n);
+        }
+        let xyz = match intent {
+            RenderingIntent::AbsoluteColorimetric => [0, 1, 2].map(|i| xyz[i] * D50[i] / self.media_white[i]),
+            _ => xyz
+        };
+        match IccProfile::pipeline(&self.b2a, intent) {
+            Some(pipeline) => {
+                let input = match &self.header.pcs {
+                    b"XYZ " => xyz.map(|v| v * 32768. / 65535.),
+                    _ => encode_lab(xyz_to_lab(xyz), pipeline.legacy_lab)
+                };
+                let c = pipeline.apply(&input);
+                match &self.header.color_space {
+                    b"Lab " => out.copy_from_slice(&decode_lab([c[0], c[1], c[2]], pipeline.legacy_lab)),
+                    _ => out.copy_from_slice(&c)
+                }
+            }
+            None => match self.matrix_trc {
+                Some(ref m) => m.to_device(xyz, out),
+                None => bail!("ICC profile without a transform from the PCS")
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert the device colour `c` to sRGB.
+    pub fn to_rgb(&self, c: &[f32], intent: RenderingIntent) -> Result<[f32; 3]> {
+        Ok(xyz_to_rgb(self.to_xyz(c, intent)?, D50))
+    }
+
+    /// Convert the device colour `c` to the colour space of the `output` profile.
+    pub fn convert(&self, c: &[f32], intent: RenderingIntent, output: &IccProfile, out: &mut [f32]) -> Result<()> {
+        output.to_device(self.to_xyz(c, intent)?, intent, out)
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Result<u16> {
+    match data.get(pos .. pos.wrapping_add(2)) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(PdfError::EOF)
+    }
+}
+fn u32_at(data: &[u8], pos: usize) -> Result<u32> {
+    match data.get(pos .. pos.wrapping_add(4)) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(PdfError::EOF)
+    }
+}
+fn sig_at(data: &[u8], pos: usize) -> Result<Signature> {
+    Ok(u32_at(data, pos)?.to_be_bytes())
+}
+/// A `s15Fixed16Number`.
+fn fixed_at(data: &[u8], pos: usize) -> Result<f32> {
+    Ok(u32_at(data, pos)? as i32 as f32 / 65536.)
+}
+fn xyz_number(data: &[u8], pos: usize) -> Result<[f32; 3]> {
+    Ok([fixed_at(data, pos)?, fixed_at(data, pos + 4)?, fixed_at(data, pos + 8)?])
+}
+/// The first value of an `XYZType` tag.
+fn xyz_tag(data: &[u8]) -> Result<[f32; 3]> {
+    if !data.starts_with(b"XYZ ") {
+        bail!("expected an XYZ tag");
+    }
+    xyz_number(data, 8)
+}
+
+/// The Lab values of the PCS, mapped to `0 ..= 1`. The legacy encoding of `lut16` has
+/// L* = 100 at 0xFF00 instead of 0xFFFF.
+fn encode_lab([l, a, b]: [f32; 3], legacy: bool) -> [f32; 3] {
+    let s = if legacy { 65280. / 65535. } else { 1. };
+    [l / 100. * s, (a + 128.) / 255. * s, (b + 128.) / 255. * s]
+}
+fn decode_lab([l, a, b]: [f32; 3], legacy: bool) -> [f32; 3] {
+    let s = if legacy { 65535. / 65280. } else { 1. };
+    [l * s * 100., a * s * 255. - 128., b * s * 255. - 128.]
+}
+
+fn lab_to_xyz([l, a, b]: [f32; 3]) -> [f32; 3] {
+    let f = |x: f32| if x >= 6. / 29. { x * x * x } else { 108. / 841. * (x - 4. / 29.) };
+    let m = (l + 16.) / 116.;
+    [D50[0] * f(m + a / 500.), D50[1] * f(m), D50[2] * f(m - b / 200.)]
+}
+fn xyz_to_lab(xyz: [f32; 3]) -> [f32; 3] {
+    let f = |x: f32| if x > 216. / 24389. { x.cbrt() } else { 841. / 108. * x + 4. / 29. };
+    let [x, y, z] = [0, 1, 2].map(|i| f(xyz[i] / D50[i]));
+    [116. * y - 16., 500. * (x - y), 200. * (y - z)]
+}
+
+/// Invert a row major 3x3 matrix.
+fn invert(m: &[f32; 9]) -> Option<[f32; 9]> {
+    let c = [
+        m[4] * m[8] - m[5] * m[7], m[2] * m[7] - m[1] * m[8], m[1] * m[5] - m[2] * m[4],
+        m[5] * m[6] - m[3] * m[8], m[0] * m[8] - m[2] * m[6], m[2] * m[3] - m[0] * m[5],
+        m[3] * m[7] - m[4] * m[6], m[1] * m[6] - m[0] * m[7], m[0] * m[4] - m[1] * m[3],
+    ];
+    let det = m[0] * c[0] + m[1] * c[3] + m[2] * c[6];
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    Some(c.map(|v| v / det))
+}
+
+fn mul(m: &[f32; 9], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[3 * i] * v[0] + m[3 * i + 1] * v[1] + m[3 * i + 2] * v[2])
+}
+
+/// A tone reproduction curve, mapping `0 ..= 1` to `0 ..= 1`.
+#[derive(Debug, Clone)]
+enum Curve {
+    Identity,
+    Gamma(f32),
+    /// Equally spaced samples.
+    Table(Vec<f32>),
+    /// A `parametricCurveType` function with its parameters g, a, b, c, d, e and f.
+    Parametric(u16, [f32; 7]),
+}
+impl Curve {
+    /// Parse a `curv` or `para` curve and return it with its size.
+    fn parse(data: &[u8]) -> Result<(Curve, usize)> {
+        match data.get(.. 4) {
+            Some(b"curv") => {
+                let count = u32_at(data, 8)? as usize;
+                let curve = match count {
+                    0 => Curve::Identity,
+                    1 => Curve::Gamma(u16_at(data, 12)? as f32 / 256.),
+                    _ => Curve::Table((0 .. count).map(|i| Ok(u16_at(data, 12 + 2 * i)? as f32 / 65535.)).collect::<Result<_>>()?)
+                };
+                Ok((curve, 12 + 2 * count))
+            }
+            Some(b"para") => {
+                let function = u16_at(data, 8)?;
+                let n = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    f => bail!("unknown parametric curve type {}", f)
+                };
+                let mut params = [0.; 7];
+                for (i, p) in params[.. n].iter_mut().enumerate() {
+                    *p = fixed_at(data, 12 + 4 * i)?;
+                }
+                Ok((Curve::Parametric(function, params), 12 + 4 * n))
+            }
+            _ => bail!("expected a curve")
+        }
+    }
+
+    fn eval(&self, x: f32) -> f32 {
+        let x = x.clamp(0., 1.);
+        let y = match *self {
+            Curve::Identity => x,
+            Curve::Gamma(g) => x.powf(g),
+            Curve::Table(ref table) => {
+                let last = table.len() - 1;
+                let pos = x * last as f32;
+                let i = (pos as usize).min(last.saturating_sub(1));
+                let t = pos - i as f32;
+                match table.get(i + 1) {
+                    Some(&next) => table[i] + t * (next - table[i]),
+                    None => table[i]
+                }
+            }
+            Curve::Parametric(function, [g, a, b, c, d, e, f]) => {
+                let pow = |base: f32| if base > 0. { base.powf(g) } else { 0. };
+                match function {
+                    0 => pow(x),
+                    1 => if x >= -b / a { pow(a * x + b) } else { 0. },
+                    2 => if x >= -b / a { pow(a * x + b) + c } else { c },
+                    3 => if x >= d { pow(a * x + b) } else { c * x },
+                    _ => if x >= d { pow(a * x + b) + e } else { c * x + f },
+                }
+            }
+        };
+        if y.is_nan() { 0. } else { y.clamp(0., 1.) }
+    }
+
+    /// The input that maps to `y`, for curves that only rise or fall.
+    fn invert(&self, y: f32) -> f32 {
+        let y = y.clamp(0., 1.);
+        match *self {
+            Curve::Identity => y,
+            Curve::Gamma(g) if g > 0. => y.powf(1. / g),
+            _ => {
+                let rising = self.eval(0.) <= self.eval(1.);
+                let (mut lo, mut hi) = (0f32, 1f32);
+                for _ in 0 .. 24 {
+                    let mid = (lo + hi) / 2.;
+                    if (self.eval(mid) < y) == rising {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                (lo + hi) / 2.
+            }
+        }
+    }
+}
+
+/// Parse `n` curves, each starting on a 4 byte boundary.
+fn parse_curves(data: &[u8], n: usize) -> Result<Vec<Curve>> {
+    let mut pos = 0;
+    let mut curves = Vec::with_capacity(n);
+    for _ in 0 .. n {
+        let (curve, size) = Curve::parse(data.get(pos ..).ok_or(PdfError::EOF)?)?;
+        curves.push(curve);
+        pos += size.div_ceil(4) * 4;
+    }
+    Ok(curves)
+}
+
+/// Read the `count` tables of `entries` values with `bytes` bytes each at `pos` of a `lut8` or
+/// `lut16`.
+fn lut_tables(data: &[u8], pos: &mut usize, count: usize, entries: usize, bytes: usize) -> Result<Vec<Curve>> {
+    let mut curves = Vec::with_capacity(count);
+    for _ in 0 .. count {
+        let table = (0 .. entries).map(|i| Ok(match bytes {
+            1 => *data.get(*pos + i).ok_or(PdfError::EOF)? as f32 / 255.,
+            _ => u16_at(data, *pos + 2 * i)? as f32 / 65535.
+        })).collect::<Result<_>>()?;
+        curves.push(Curve::Table(table));
+        *pos += entries * bytes;
+    }
+    Ok(curves)
+}
+
+#[derive(Debug, Clone)]
+enum MatrixTrc {
+    Gray(Curve),
+    /// `matrix` maps the linear components to XYZ.
+    Rgb { matrix: [f32; 9], inverse: [f32; 9], curves: [Curve; 3] },
+}
+impl MatrixTrc {
+    fn to_xyz(&self, c: &[f32]) -> [f32; 3] {
+        match *self {
+            MatrixTrc::Gray(ref curve) => {
+                let y = curve.eval(c[0]);
+                D50.map(|w| w * y)
+            }
+            MatrixTrc::Rgb { ref matrix, ref curves, .. } => mul(matrix, [0, 1, 2].map(|i| curves[i].eval(c[i]))),
+        }
+    }
+    fn to_device(&self, xyz: [f32; 3], out: &mut [f32]) {
+        match *self {
+            MatrixTrc::Gray(ref curve) => out[0] = curve.invert(xyz[1]),
+            MatrixTrc::Rgb { ref inverse, ref curves, .. } => {
+                let linear = mul(inverse, xyz);
+                for i in 0 .. 3 {
+                    out[i] = curves[i].invert(linear[i]);
+                }
+            }
+        }
+    }
+}
+
+/// A colour lookup table.
+#[derive(Debug, Clone)]
+struct Clut {
+    /// The number of grid points along each input.
+    grid: Vec<usize>,
+    outputs: usize,
+    /// The outputs of each grid point, with the first input varying slowest.
+    values: Vec<f32>,
+}
+impl Clut {
+    fn parse(data: &[u8], grid: Vec<usize>, outputs: usize, bytes: usize) -> Result<Clut> {
+        let count = grid.iter()
+            .try_fold(outputs, |count, &g| count.checked_mul(g))
+            .filter(|&count| count <= MAX_CLUT_SIZE && grid.iter().all(|&g| g > 0));
+        let count = match count {
+            Some(count) => count,
+            None => bail!("invalid CLUT with grid {:?} and {} outputs", grid, outputs)
+        };
+        let values = match bytes {
+            1 => data.get(.. count).ok_or(PdfError::EOF)?.iter().map(|&b| b as f32 / 255.).collect(),
+            2 => (0 .. count).map(|i| Ok(u16_at(data, 2 * i)? as f32 / 65535.)).collect::<Result<_>>()?,
+            b => bail!("invalid CLUT precision {}", b)
+        };
+        Ok(Clut { grid, outputs, values })
+    }
+
+    /// Interpolate multilinearly between the grid points around `x`.
+    fn apply(&self, x: &[f32]) -> Vec<f32> {
+        let mut base = 0;
+        // the inputs between two grid points, with their stride and weight of the upper point
+        let mut between = Vec::new();
+        let mut stride = self.outputs;
+        for (i, &g) in self.grid.iter().enumerate().rev() {
+            let pos = x[i].clamp(0., 1.) * (g - 1) as f32;
+            let j = (pos as usize).min(g.saturating_sub(2));
+            let t = pos - j as f32;
+            base += j * stride;
+            if t > 0. {
+                between.push((stride, t));
+            }
+            stride *= g;
+        }
+        let mut out = vec![0.; self.outputs];
+        for corner in 0 .. 1usize << between.len() {
+            let mut index = base;
+            let mut weight = 1.;
+            for (k, &(stride, t)) in between.iter().enumerate() {
+                if corner & (1 << k) != 0 {
+                    index += stride;
+                    weight *= t;
+                } else {
+                    weight *= 1. - t;
+                }
+            }
+            for (y, &v) in out.iter_mut().zip(&self.values[index .. index + self.outputs]) {
+                *y += weight * v;
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Stage {
+    Curves(Vec<Curve>),
+    /// A row major 3x3 matrix and an offset.
+    Matrix([f32; 9], [f32; 3]),
+    Clut(Clut),
+}
+
+/// The stages of a LUT based transform, on values in `0 ..= 1`.
+#[derive(Debug, Clone)]
+struct Pipeline {
+    stages: Vec<Stage>,
+    /// Lab values use the legacy encoding of `lut16`.
+    legacy_lab: bool,
+}
+impl Pipeline {
+    /// Parse a `lut8`, `lut16`, `lutAToB` or `lutBToA` tag from the device space with `n`
+    /// components to the PCS (`a2b`) or back.
+    fn parse(data: &[u8], a2b: bool, pcs_xyz: bool, n: usize) -> Result<Pipeline> {
+        let typ = sig_at(data, 0)?;
+        let (inputs, outputs) = (*data.get(8).ok_or(PdfError::EOF)? as usize, *data.get(9).ok_or(PdfError::EOF)? as usize);
+        let expected = if a2b { (n, 3) } else { (3, n) };
+        if (inputs, outputs) != expected {
+            bail!("ICC transform from {} to {} channels, expected {:?}", inputs, outputs, expected);
+        }
+        let mut stages = Vec::new();
+        let legacy_lab = &typ == b"mft2";
+        match &typ {
+            b"mft1" | b"mft2" => {
+                let grid = *data.get(10).ok_or(PdfError::EOF)? as usize;
+                let mut matrix = [0.; 9];
+                for (i, m) in matrix.iter_mut().enumerate() {
+                    *m = fixed_at(data, 12 + 4 * i)?;
+                }
+                let (bytes, in_entries, out_entries, mut pos) = match &typ {
+                    b"mft1" => (1, 256, 256, 48),
+                    _ => (2, u16_at(data, 48)? as usize, u16_at(data, 50)? as usize, 52)
+                };
+                if in_entries < 2 || out_entries < 2 {
+                    bail!("lut16 with {} input and {} output table entries", in_entries, out_entries);
+                }
+                let input = lut_tables(data, &mut pos, inputs, in_entries, bytes)?;
+                // the matrix is only used for XYZ input
+                if !a2b && pcs_xyz && matrix != [1., 0., 0., 0., 1., 0., 0., 0., 1.] {
+                    stages.push(Stage::Matrix(matrix, [0.; 3]));
+                }
+                stages.push(Stage::Curves(input));
+                let clut = Clut::parse(data.get(pos ..).ok_or(PdfError::EOF)?, vec![grid; inputs], outputs, bytes)?;
+                pos += clut.values.len() * bytes;
+                stages.push(Stage::Clut(clut));
+                stages.push(Stage::Curves(lut_tables(data, &mut pos, outputs, out_entries, bytes)?));
+            }
+            b"mAB " | b"mBA " => {
+                let offset = |pos| -> Result<Option<&[u8]>> {
+                    match u32_at(data, pos)? as usize {
+                        0 => Ok(None),
+                        o => data.get(o ..).map(Some).ok_or(PdfError::EOF)
+                    }
+                };
+                let (b, matrix, m, clut, a) = (offset(12)?, offset(16)?, offset(20)?, offset(24)?, offset(28)?);
+                let matrix = match matrix {
+                    Some(data) => {
+                        let mut values = [0.; 12];
+                        for (i, v) in values.iter_mut().enumerate() {
+                            *v = fixed_at(data, 4 * i)?;
+                        }
+                        let mut m = [0.; 9];
+                        m.copy_from_slice(&values[.. 9]);
+                        Some(Stage::Matrix(m, [values[9], values[10], values[11]]))
+                    }
+                    None => None
+                };
+                let clut = match clut {
+                    Some(data) => {
+                        let grid = data.get(.. inputs).ok_or(PdfError::EOF)?.iter().map(|&g| g as usize).collect();
+                        let bytes = *data.get(16).ok_or(PdfError::EOF)? as usize;
+                        Some(Stage::Clut(Clut::parse(data.get(20 ..).ok_or(PdfError::EOF)?, grid, outputs, bytes)?))
+                    }
+                    None => None
+                };
+                let b = match b {
+                    Some(data) => Stage::Curves(parse_curves(data, if a2b { outputs } else { inputs })?),
+                    None => bail!("ICC transform without B curves")
+                };
+                // the M curves and matrix are on the PCS side of the CLUT, the A curves on the device side
+                let m = match m {
+                    Some(data) => Some(Stage::Curves(parse_curves(data, 3)?)),
+                    None => None
+                };
+                let a = match a {
+                    Some(data) => Some(Stage::Curves(parse_curves(data, if a2b { inputs } else { outputs })?)),
+                    None => None
+                };
+                if a2b {
+                    stages.extend(a);
+                    stages.extend(clut);
+                    stages.extend(m);
+                    stages.extend(matrix);
+                    stages.push(b);
+                } else {
+                    stages.push(b);
+                    stages.extend(matrix);
+                    stages.extend(m);
+                    stages.extend(clut);
+                    stages.extend(a);
+                }
+            }
+            t => bail!("unsupported ICC transform type {:?}", String::from_utf8_lossy(t))
+        }
+
+        // check that the stages fit together
+        let mut channels = inputs;
+        for stage in &stages {
+            let (stage_in, stage_out) = match *stage {
+                Stage::Curves(ref curves) => (curves.len(), curves.len()),
+                Stage::Matrix(..) => (3, 3),
+                Stage::Clut(ref clut) => (clut.grid.len(), clut.outputs),
+            };
+            if stage_in != channels {
+                bail!("ICC transform stage with {} inputs after {} channels", stage_in, channels);
+            }
+            channels = stage_out;
+        }
+        if channels != outputs {
+            bail!("ICC transform with {} outputs, expected {}", channels, outputs);
+        }
+        Ok(Pipeline { stages, legacy_lab })
+    }
+
+    fn apply(&self, x: &[f32]) -> Vec<f32> {
+        let mut v: Vec<f32> = x.iter().map(|x| x.clamp(0., 1.)).collect();
+        for stage in &self.stages {
+            match *stage {
+                Stage::Curves(ref curves) => for (v, curve) in v.iter_mut().zip(curves) {
+                    *v = curve.eval(*v);
+                },
+                Stage::Matrix(ref m, offset) => {
+                    let y = mul(m, [v[0], v[1], v[2]]);
+                    for i in 0 .. 3 {
+                        v[i] = (y[i] + offset[i]).clamp(0., 1.);
+                    }
+                }
+                Stage::Clut(ref clut) => v = clut.apply(&v),
+            }
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A profile with the `tags`, in order.
+    fn profile(space: &[u8; 4], pcs: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[8] = 4;
+        data[12 .. 16].copy_from_slice(b"mntr");
+        data[16 .. 20].copy_from_slice(space);
+        data[20 .. 24].copy_from_slice(pcs);
+        data[36 .. 40].copy_from_slice(b"acsp");
+        data.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut body = Vec::new();
+        for (sig, tag) in tags {
+            let offset = 132 + 12 * tags.len() + body.len();
+            data.extend_from_slice(&sig[..]);
+            data.extend_from_slice(&(offset as u32).to_be_bytes());
+            data.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            body.extend_from_slice(tag);
+            body.resize(body.len().div_ceil(4) * 4, 0);
+        }
+        data.extend_from_slice(&body);
+        let size = data.len() as u32;
+        data[.. 4].copy_from_slice(&size.to_be_bytes());
+        data
+    }
+    fn fixed(v: f32) -> [u8; 4] {
+        ((v * 65536.).round() as i32).to_be_bytes()
+    }
+    fn xyz(v: [f32; 3]) -> Vec<u8> {
+        let mut data = b"XYZ \0\0\0\0".to_vec();
+        for v in v {
+            data.extend_from_slice(&fixed(v));
+        }
+        data
+    }
+    fn curv(values: &[u16]) -> Vec<u8> {
+        let mut data = b"curv\0\0\0\0".to_vec();
+        data.extend_from_slice(&(values.len() as u32).to_be_bytes());
+        for v in values {
+            data.extend_from_slice(&v.to_be_bytes());
+        }
+        data
+    }
+    fn para(function: u16, params: &[f32]) -> Vec<u8> {
+        let mut data = b"para\0\0\0\0".to_vec();
+        data.extend_from_slice(&function.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        for &p in params {
+            data.extend_from_slice(&fixed(p));
+        }
+        data
+    }
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+    fn assert_close(a: &[f32], b: &[f32], tolerance: f32) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() <= tolerance), "{:?} != {:?}", a, b);
+    }
+
+    /// sRGB with primaries adapted to D50.
+    fn srgb() -> IccProfile {
+        let trc = para(3, &[2.4, 1. / 1.055, 0.055 / 1.055, 1. / 12.92, 0.04045]);
+        let data = profile(b"RGB ", b"XYZ ", &[
+            (b"wtpt", xyz(D50)),
+            (b"rXYZ", xyz([0.4361, 0.2225, 0.0139])),
+            (b"gXYZ", xyz([0.3851, 0.7169, 0.0971])),
+            (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+            (b"rTRC", trc.clone()),
+            (b"gTRC", trc.clone()),
+            (b"bTRC", trc),
+        ]);
+        IccProfile::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn matrix_trc() {
+        use RenderingIntent::RelativeColorimetric as Relative;
+
+        let rgb = srgb();
+        assert_eq!(rgb.components().unwrap(), 3);
+        for c in [[1., 0., 0.], [0.5, 0.5, 0.5], [0.2, 0.6, 0.9]] {
+            assert_close(&rgb.to_rgb(&c, Relative).unwrap(), &c, 0.01);
+            let mut back = [0.; 3];
+            rgb.to_device(rgb.to_xyz(&c, Relative).unwrap(), Relative, &mut back).unwrap();
+            assert_close(&back, &c, 0.001);
+        }
+
+        let gray = IccProfile::parse(&profile(b"GRAY", b"XYZ ", &[(b"kTRC", curv(&[(2.2 * 256.) as u16]))])).unwrap();
+        assert_close(&gray.to_rgb(&[0.5], Relative).unwrap(), &[0.504; 3], 0.005);
+
+        let truncated = profile(b"RGB ", b"XYZ ", &[(b"rXYZ", xyz([0.4361, 0.2225, 0.0139]))]);
+        assert!(IccProfile::parse(&truncated[.. 140]).is_err());
+        assert!(IccProfile::parse(&profile(b"RGB ", b"XYZ ", &[(b"rXYZ", vec![0; 4])])).unwrap().to_rgb(&[0.; 3], Relative).is_err());
+    }
+
+    #[test]
+    fn lut_transforms() {
+        use RenderingIntent::RelativeColorimetric as Relative;
+
+        // a CMYK lut16 to Lab, where only black darkens, with the legacy encoding of 100 as 0xFF00
+        let mut lut = b"mft2\0\0\0\0".to_vec();
+        lut.extend_from_slice(&[4, 3, 2, 0]);
+        for m in [1., 0., 0., 0., 1., 0., 0., 0., 1.] {
+            lut.extend_from_slice(&fixed(m));
+        }
+        lut.extend_from_slice(&u16s(&[2, 2]));
+        for _ in 0 .. 4 {
+            lut.extend_from_slice(&u16s(&[0, 0xFFFF]));
+        }
+        for i in 0 .. 16 {
+            let l = if i & 1 == 0 { 0xFF00 } else { 0 };
+            lut.extend_from_slice(&u16s(&[l, 0x8000, 0x8000]));
+        }
+        for _ in 0 .. 3 {
+            lut.extend_from_slice(&u16s(&[0, 0xFFFF]));
+        }
+        let cmyk = IccProfile::parse(&profile(b"CMYK", b"Lab ", &[(b"A2B0", lut)])).unwrap();
+        assert_close(&cmyk.to_rgb(&[0., 0., 0., 0.], Relative).unwrap(), &[1.; 3], 0.005);
+        assert_close(&cmyk.to_rgb(&[1., 1., 0., 0.5], Relative).unwrap(), &[0.466; 3], 0.005);
+        assert_close(&cmyk.to_rgb(&[0., 0., 0., 1.], Relative).unwrap(), &[0.; 3], 0.005);
+        assert!(cmyk.to_device(D50, Relative, &mut [0.; 4]).is_err());
+
+        // to the sRGB profile
+        let mut out = [0.; 3];
+        cmyk.convert(&[0., 0., 0., 0.], Relative, &srgb(), &mut out).unwrap();
+        assert_close(&out, &[1.; 3], 0.01);
+
+        // a gray lutAToB to Lab with the A curves, a CLUT and the B curves
+        let mut lut = b"mAB \0\0\0\0".to_vec();
+        lut.extend_from_slice(&[1, 3, 0, 0]);
+        for offset in [32u32, 0, 0, 68, 100] {
+            lut.extend_from_slice(&offset.to_be_bytes());
+        }
+        for _ in 0 .. 3 {
+            lut.extend_from_slice(&curv(&[]));
+        }
+        let mut grid = [0; 16];
+        grid[0] = 2;
+        lut.extend_from_slice(&grid);
+        lut.extend_from_slice(&[2, 0, 0, 0]);
+        lut.extend_from_slice(&u16s(&[0, 0x8080, 0x8080, 0xFFFF, 0x8080, 0x8080]));
+        lut.extend_from_slice(&curv(&[0, 0x8000, 0xFFFF]));
+        let gray = IccProfile::parse(&profile(b"GRAY", b"Lab ", &[(b"A2B0", lut)])).unwrap();
+        let xyz = gray.to_xyz(&[0.5], Relative).unwrap();
+        assert_close(&xyz, &[0.1842 * D50[0], 0.1842, 0.1842 * D50[2]], 0.001);
+    }
+}
@@ -18,9 +18,11 @@ pub mod font;
 pub mod any;
 pub mod encoding;
 pub mod build;
+pub mod optimize;
+pub mod icc;
 
 // mod content;
-mod enc;
+pub mod enc;
 pub mod crypt;
 
 // pub use content::*;
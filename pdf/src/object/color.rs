@@ -1,8 +1,11 @@
 use crate as pdf;
 use crate::object::*;
 use crate::error::*;
+use crate::icc::IccProfile;
+use once_cell::unsync::OnceCell;
+use std::cell::Cell;
 
-#[derive(Object, Debug)]
+#[derive(Object, ObjectWrite, Debug, Clone)]
 pub struct IccInfo {
     #[pdf(key="N")]
     pub components: u32,
@@ -15,32 +18,354 @@ pub struct IccInfo {
 
     #[pdf(key="Metadata")]
     pub metadata: Option<Stream<()>>,
+
+    /// The parsed profile, see `profile`.
+    #[pdf(skip)]
+    profile: OnceCell<Option<IccProfile>>,
+}
+impl IccInfo {
+    /// The dictionary of a profile with `components` components and no other entries.
+    pub fn new(components: u32) -> IccInfo {
+        IccInfo {
+            components,
+            alternate: None,
+            range: None,
+            metadata: None,
+            profile: OnceCell::new(),
+        }
+    }
+}
+impl Stream<IccInfo> {
+    /// The embedded profile, parsed on first use. `None` if it can't be read or does not
+    /// have `N` components.
+    pub fn profile(&self) -> Option<&IccProfile> {
+        self.info.info.profile.get_or_init(|| {
+            let profile = self.decode().and_then(|data| IccProfile::parse(&data));
+            match profile {
+                Ok(profile) if profile.components().ok() == Some(self.info.info.components as usize) => Some(profile),
+                Ok(profile) => {
+                    warn!("ICC profile {:?} does not have {} components", profile.header, self.info.info.components);
+                    None
+                }
+                Err(e) => {
+                    warn!("can't read ICC profile: {}", e);
+                    None
+                }
+            }
+        }).as_ref()
+    }
+}
+
+/// Parameters of a `CalGray` colour space.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct CalGrayInfo {
+    #[pdf(key="WhitePoint")]
+    pub white_point: Vec<f32>,
+
+    /// Defaults to `[0 0 0]`.
+    #[pdf(key="BlackPoint")]
+    pub black_point: Option<Vec<f32>>,
+
+    /// Defaults to 1.
+    #[pdf(key="Gamma")]
+    pub gamma: Option<f32>,
+}
+
+/// Parameters of a `CalRGB` colour space.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct CalRgbInfo {
+    #[pdf(key="WhitePoint")]
+    pub white_point: Vec<f32>,
+
+    /// Defaults to `[0 0 0]`.
+    #[pdf(key="BlackPoint")]
+    pub black_point: Option<Vec<f32>>,
+
+    /// The gamma of each component, defaults to `[1 1 1]`.
+    #[pdf(key="Gamma")]
+    pub gamma: Option<Vec<f32>>,
+
+    /// Maps the components to XYZ, column by column. Defaults to the identity.
+    #[pdf(key="Matrix")]
+    pub matrix: Option<Vec<f32>>,
+}
+
+/// Parameters of a `Lab` colour space.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct LabInfo {
+    #[pdf(key="WhitePoint")]
+    pub white_point: Vec<f32>,
+
+    /// Defaults to `[0 0 0]`.
+    #[pdf(key="BlackPoint")]
+    pub black_point: Option<Vec<f32>>,
+
+    /// The ranges of a* and b*, defaults to `[-100 100 -100 100]`.
+    #[pdf(key="Range")]
+    pub range: Option<Vec<f32>>,
+}
+
+/// The attributes dictionary of a `DeviceN` colour space.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct DeviceNInfo {
+    /// `DeviceN` or `NChannel`.
+    #[pdf(key="Subtype")]
+    pub subtype: Option<String>,
+
+    /// Separation colour spaces for the colorants.
+    #[pdf(key="Colorants")]
+    pub colorants: HashMap<String, ColorSpace>,
+
+    #[pdf(key="Process")]
+    pub process: Option<DeviceNProcess>,
+
+    #[pdf(key="MixingHints")]
+    pub mixing_hints: Option<Dictionary>,
 }
 
-#[derive(Debug)]
+/// The process colorants of a `DeviceN` colour space.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct DeviceNProcess {
+    #[pdf(key="ColorSpace")]
+    pub color_space: Box<ColorSpace>,
+
+    /// The names of the components of `color_space`, in order.
+    #[pdf(key="Components")]
+    pub components: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
 pub enum ColorSpace {
     DeviceGray,
     DeviceRGB,
     DeviceCMYK,
+    CalGray(CalGrayInfo),
+    CalRGB(CalRgbInfo),
+    Lab(LabInfo),
     Indexed(Box<ColorSpace>, Vec<u8>),
-    Separation(String, Box<ColorSpace>, Function),
+    Separation(String, Box<ColorSpace>, MaybeRef<Function>),
+    DeviceN {
+        names: Vec<String>,
+        alternate: Box<ColorSpace>,
+        tint: MaybeRef<Function>,
+        attributes: Option<DeviceNInfo>,
+    },
     Icc(RcRef<Stream<IccInfo>>),
+    /// A pattern colour space. Uncoloured patterns have the colour space of their colour.
+    Pattern(Option<Box<ColorSpace>>),
     Other(Vec<Primitive>)
 }
+impl ColorSpace {
+    /// The number of colour components. Patterns don't have any, unless they are uncoloured.
+    pub fn components(&self) -> Result<usize> {
+        Ok(match *self {
+            ColorSpace::DeviceGray | ColorSpace::CalGray(_) | ColorSpace::Indexed(..) | ColorSpace::Separation(..) => 1,
+            ColorSpace::DeviceRGB | ColorSpace::CalRGB(_) | ColorSpace::Lab(_) => 3,
+            ColorSpace::DeviceCMYK => 4,
+            ColorSpace::DeviceN { ref names, .. } => names.len(),
+            ColorSpace::Icc(ref icc) => icc.info.components as usize,
+            ColorSpace::Pattern(None) => 0,
+            ColorSpace::Pattern(Some(ref base)) => base.components()?,
+            ColorSpace::Other(ref arr) => bail!("unsupported colour space {:?}", arr.first())
+        })
+    }
 
+    /// The range of each component, which 8 bit lookup tables and image samples are mapped to
+    /// by default.
+    pub(crate) fn component_ranges(&self) -> Result<Vec<(f32, f32)>> {
+        Ok(match *self {
+            ColorSpace::Lab(ref info) => {
+                let r = match info.range {
+                    Some(ref r) if r.len() == 4 => r.clone(),
+                    _ => vec![-100., 100., -100., 100.]
+                };
+                vec![(0., 100.), (r[0], r[1]), (r[2], r[3])]
+            }
+            ColorSpace::Icc(ref icc) => match icc.info.range {
+                Some(ref r) if r.len() == 2 * icc.info.components as usize => r.chunks_exact(2).map(|r| (r[0], r[1])).collect(),
+                _ => vec![(0., 1.); icc.info.components as usize]
+            },
+            ref cs => vec![(0., 1.); cs.components()?]
+        })
+    }
+
+    /// Convert the components `c` of a colour in this colour space to sRGB, each component in
+    /// `0 ..= 1`.
+    ///
+    /// Tint transforms are evaluated and palettes looked up. `CalGray`, `CalRGB` and `Lab`
+    /// colours are converted through CIE XYZ, adapted to the D65 white point of sRGB. ICC based
+    /// colours are converted with their profile, with the relative colorimetric intent, or the
+    /// alternate colour space if the profile can't be used. CMYK is converted without a profile.
+    pub fn to_rgb(&self, c: &[f32]) -> Result<[f32; 3]> {
+        let n = self.components()?;
+        if c.len() < n {
+            bail!("{} components for a colour space with {}", c.len(), n);
+        }
+        Ok(match *self {
+            ColorSpace::DeviceGray => [c[0]; 3],
+            ColorSpace::DeviceRGB => [c[0], c[1], c[2]],
+            ColorSpace::DeviceCMYK => {
+                let k = 1. - c[3];
+                [(1. - c[0]) * k, (1. - c[1]) * k, (1. - c[2]) * k]
+            }
+            ColorSpace::CalGray(ref info) => {
+                let a = c[0].clamp(0., 1.).powf(info.gamma.unwrap_or(1.));
+                let w = white_point(&info.white_point)?;
+                xyz_to_rgb([w[0] * a, w[1] * a, w[2] * a], w)
+            }
+            ColorSpace::CalRGB(ref info) => {
+                let g = match info.gamma {
+                    Some(ref g) if g.len() == 3 => [g[0], g[1], g[2]],
+                    _ => [1.; 3]
+                };
+                let m = match info.matrix {
+                    Some(ref m) if m.len() == 9 => m.clone(),
+                    _ => vec![1., 0., 0., 0., 1., 0., 0., 0., 1.]
+                };
+                let abc: Vec<f32> = (0 .. 3).map(|i| c[i].clamp(0., 1.).powf(g[i])).collect();
+                let xyz = [
+                    m[0] * abc[0] + m[3] * abc[1] + m[6] * abc[2],
+                    m[1] * abc[0] + m[4] * abc[1] + m[7] * abc[2],
+                    m[2] * abc[0] + m[5] * abc[1] + m[8] * abc[2],
+                ];
+                xyz_to_rgb(xyz, white_point(&info.white_point)?)
+            }
+            ColorSpace::Lab(ref info) => {
+                let ranges = self.component_ranges()?;
+                let [l, a, b] = [0, 1, 2].map(|i| c[i].max(ranges[i].0).min(ranges[i].1));
+                let m = (l + 16.) / 116.;
+                let g = |x: f32| if x >= 6. / 29. { x * x * x } else { 108. / 841. * (x - 4. / 29.) };
+                let w = white_point(&info.white_point)?;
+                xyz_to_rgb([w[0] * g(m + a / 500.), w[1] * g(m), w[2] * g(m - b / 200.)], w)
+            }
+            ColorSpace::Indexed(ref base, ref lookup) => {
+                let n = base.components()?;
+                let entries = lookup.len() / n.max(1);
+                if entries == 0 {
+                    bail!("empty palette");
+                }
+                let index = (c[0].round().max(0.) as usize).min(entries - 1);
+                let ranges = base.component_ranges()?;
+                let entry: Vec<f32> = lookup[index * n .. (index + 1) * n].iter().zip(ranges)
+                    .map(|(&b, (r0, r1))| r0 + b as f32 * (r1 - r0) / 255.)
+                    .collect();
+                base.to_rgb(&entry)?
+            }
+            ColorSpace::Separation(_, ref alternate, ref tint) => {
+                let mut out = vec![0.; alternate.components()?];
+                tint.apply(&c[.. 1], &mut out)?;
+                alternate.to_rgb(&out)?
+            }
+            ColorSpace::DeviceN { ref names, ref alternate, ref tint, .. } => {
+                let mut out = vec![0.; alternate.components()?];
+                tint.apply(&c[.. names.len()], &mut out)?;
+                alternate.to_rgb(&out)?
+            }
+            ColorSpace::Icc(ref icc) => match (icc.profile(), &icc.info.alternate) {
+                (Some(profile), _) => profile.to_rgb(c, RenderingIntent::RelativeColorimetric)?,
+                (None, Some(alternate)) => alternate.to_rgb(c)?,
+                (None, None) => device_space(icc.info.components as usize)?.to_rgb(c)?
+            },
+            ColorSpace::Pattern(Some(ref base)) => base.to_rgb(c)?,
+            ColorSpace::Pattern(None) => bail!("coloured patterns have no colour components"),
+            ColorSpace::Other(ref arr) => bail!("unsupported colour space {:?}", arr.first())
+        })
+    }
+}
+
+/// The device colour space with `components` components.
+pub(crate) fn device_space(components: usize) -> Result<ColorSpace> {
+    Ok(match components {
+        1 => ColorSpace::DeviceGray,
+        3 => ColorSpace::DeviceRGB,
+        4 => ColorSpace::DeviceCMYK,
+        n => bail!("no device colour space with {} components", n)
+    })
+}
+
+fn white_point(w: &[f32]) -> Result<[f32; 3]> {
+    match *w {
+        [x, y, z] if x > 0. && y == 1. && z > 0. => Ok([x, y, z]),
+        _ => bail!("invalid WhitePoint {:?}", w)
+    }
+}
+
+/// The Bradford cone response matrix and its inverse.
+const BRADFORD: [[f32; 3]; 3] = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+const BRADFORD_INV: [[f32; 3]; 3] = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+const D65: [f32; 3] = [0.95047, 1., 1.08883];
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.2406, -1.5372, -0.4986],
+    [-0.9689, 1.8758, 0.0415],
+    [0.0557, -0.2040, 1.0570],
+];
+
+fn mul(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+/// Convert `xyz`, relative to the white point `white`, to sRGB.
+pub(crate) fn xyz_to_rgb(xyz: [f32; 3], white: [f32; 3]) -> [f32; 3] {
+    let (src, dst) = (mul(&BRADFORD, white), mul(&BRADFORD, D65));
+    let cone = mul(&BRADFORD, xyz);
+    let adapted = mul(&BRADFORD_INV, [0, 1, 2].map(|i| cone[i] * dst[i] / src[i]));
+    mul(&XYZ_TO_SRGB, adapted).map(|v| {
+        let v = v.clamp(0., 1.);
+        if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.powf(1. / 2.4) - 0.055 }
+    })
+}
 
 fn get_index(arr: &[Primitive], idx: usize) -> Result<&Primitive> {
      arr.get(idx).ok_or(PdfError::Bounds { index: idx, len: arr.len() })
 }
 
+/// Colour spaces nest through `Indexed`, `Separation`, `DeviceN`, `Pattern` and `ICCBased`
+/// spaces at most this deep.
+const MAX_NESTING: usize = 16;
+
+thread_local! {
+    /// How many colour spaces are being read on this thread. Nested spaces are also read by
+    /// the derived impls of `IccInfo` and `DeviceNAttributes`, which can't pass a depth on.
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts a colour space as being read until dropped.
+struct NestingGuard;
+impl NestingGuard {
+    fn enter() -> Result<NestingGuard> {
+        NESTING.with(|depth| {
+            if depth.get() >= MAX_NESTING {
+                bail!("colour spaces nested deeper than {}", MAX_NESTING);
+            }
+            depth.set(depth.get() + 1);
+            Ok(NestingGuard)
+        })
+    }
+}
+impl Drop for NestingGuard {
+    fn drop(&mut self) {
+        NESTING.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
 impl Object for ColorSpace {
     fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<ColorSpace> {
+        let _nesting = NestingGuard::enter()?;
         if let Ok(name) = p.as_name() {
             let cs = match name {
                 "DeviceGray" => ColorSpace::DeviceGray,
                 "DeviceRGB" => ColorSpace::DeviceRGB,
                 "DeviceCMYK" => ColorSpace::DeviceCMYK,
-                _ => unimplemented!()
+                "Pattern" => ColorSpace::Pattern(None),
+                name => bail!("unknown colour space /{}", name)
             };
             return Ok(cs);
         }
@@ -48,6 +373,12 @@ impl Object for ColorSpace {
         let typ = t!(t!(get_index(&arr, 0)).as_name());
         
         match typ {
+            "DeviceGray" if arr.len() == 1 => Ok(ColorSpace::DeviceGray),
+            "DeviceRGB" if arr.len() == 1 => Ok(ColorSpace::DeviceRGB),
+            "DeviceCMYK" if arr.len() == 1 => Ok(ColorSpace::DeviceCMYK),
+            "CalGray" => Ok(ColorSpace::CalGray(t!(CalGrayInfo::from_primitive(t!(get_index(&arr, 1)).clone(), resolve)))),
+            "CalRGB" => Ok(ColorSpace::CalRGB(t!(CalRgbInfo::from_primitive(t!(get_index(&arr, 1)).clone(), resolve)))),
+            "Lab" => Ok(ColorSpace::Lab(t!(LabInfo::from_primitive(t!(get_index(&arr, 1)).clone(), resolve)))),
             "Indexed" => {
                 let base = t!(Object::from_primitive(t!(get_index(&arr, 1)).clone(), resolve));
                 let lookup = match t!(get_index(&arr, 3)) {
@@ -70,23 +401,183 @@ impl Object for ColorSpace {
             "Separation" => {
                 let name = t!(t!(get_index(&arr, 1)).clone().into_name());
                 let alternate = t!(Object::from_primitive(t!(get_index(&arr, 2)).clone(), resolve));
-                let tint = t!(Function::from_primitive(t!(get_index(&arr, 3)).clone(), resolve));
+                let tint = t!(MaybeRef::from_primitive(t!(get_index(&arr, 3)).clone(), resolve));
                 Ok(ColorSpace::Separation(name, alternate, tint))
             }
             "ICCBased" => {
                 let s = t!(RcRef::from_primitive(t!(get_index(&arr, 1)).clone(), resolve));
                 Ok(ColorSpace::Icc(s))
             }
+            "DeviceN" => {
+                let names = t!(Vec::<String>::from_primitive(t!(get_index(&arr, 1)).clone(), resolve));
+                let alternate = t!(Object::from_primitive(t!(get_index(&arr, 2)).clone(), resolve));
+                let tint = t!(MaybeRef::from_primitive(t!(get_index(&arr, 3)).clone(), resolve));
+                let attributes = match arr.get(4) {
+                    Some(p) => t!(Object::from_primitive(p.clone(), resolve)),
+                    None => None
+                };
+                Ok(ColorSpace::DeviceN { names, alternate, tint, attributes })
+            }
+            "Pattern" => {
+                let base = match arr.get(1) {
+                    Some(p) => Some(t!(Object::from_primitive(p.clone(), resolve))),
+                    None => None
+                };
+                Ok(ColorSpace::Pattern(base))
+            }
             _ => Ok(ColorSpace::Other(arr))
         }
     }
 }
 impl ObjectWrite for ColorSpace {
     fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
-        match *self {
-            ColorSpace::DeviceCMYK => Ok(Primitive::name("DeviceCMYK")),
-            ColorSpace::DeviceRGB => Ok(Primitive::name("DeviceRGB")),
-            _ => unimplemented!()
+        let arr = match *self {
+            ColorSpace::DeviceGray => return Ok(Primitive::name("DeviceGray")),
+            ColorSpace::DeviceRGB => return Ok(Primitive::name("DeviceRGB")),
+            ColorSpace::DeviceCMYK => return Ok(Primitive::name("DeviceCMYK")),
+            ColorSpace::Pattern(None) => return Ok(Primitive::name("Pattern")),
+            ColorSpace::CalGray(ref info) => vec![Primitive::name("CalGray"), info.to_primitive(update)?],
+            ColorSpace::CalRGB(ref info) => vec![Primitive::name("CalRGB"), info.to_primitive(update)?],
+            ColorSpace::Lab(ref info) => vec![Primitive::name("Lab"), info.to_primitive(update)?],
+            ColorSpace::Indexed(ref base, ref lookup) => {
+                let n = base.components()?;
+                if n == 0 || lookup.len() < n {
+                    bail!("Indexed colour space with a lookup table of {} bytes", lookup.len());
+                }
+                vec![
+                    Primitive::name("Indexed"),
+                    base.to_primitive(update)?,
+                    Primitive::Integer((lookup.len() / n) as i32 - 1),
+                    Primitive::String(PdfString::new(lookup.clone())),
+                ]
+            }
+            ColorSpace::Separation(ref name, ref alternate, ref tint) => vec![
+                Primitive::name("Separation"),
+                Primitive::name(name.as_str()),
+                alternate.to_primitive(update)?,
+                tint.to_primitive(update)?,
+            ],
+            ColorSpace::DeviceN { ref names, ref alternate, ref tint, ref attributes } => {
+                let mut arr = vec![
+                    Primitive::name("DeviceN"),
+                    names.to_primitive(update)?,
+                    alternate.to_primitive(update)?,
+                    tint.to_primitive(update)?,
+                ];
+                if let Some(ref attributes) = *attributes {
+                    arr.push(attributes.to_primitive(update)?);
+                }
+                arr
+            }
+            ColorSpace::Icc(ref stream) => vec![Primitive::name("ICCBased"), stream.to_primitive(update)?],
+            ColorSpace::Pattern(Some(ref base)) => vec![Primitive::name("Pattern"), base.to_primitive(update)?],
+            ColorSpace::Other(ref arr) => arr.clone(),
+        };
+        Ok(Primitive::Array(arr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::Color;
+
+    fn color_space(s: &str) -> ColorSpace {
+        let p = crate::parser::parse(s.as_bytes(), &NoResolve).unwrap();
+        ColorSpace::from_primitive(p, &NoResolve).unwrap()
+    }
+
+    fn assert_rgb(cs: &ColorSpace, c: &[f32], expected: [f32; 3]) {
+        let rgb = cs.to_rgb(c).unwrap();
+        for (v, e) in rgb.iter().zip(&expected) {
+            assert!((v - e).abs() < 0.005, "{:?} to RGB: {:?} != {:?}", c, rgb, expected);
+        }
+    }
+
+    #[test]
+    fn to_rgb() {
+        assert_rgb(&ColorSpace::DeviceCMYK, &[0., 1., 1., 0.5], [0.5, 0., 0.]);
+
+        // CIE spaces with a D50 white point are adapted to the D65 white of sRGB
+        let lab = color_space("[/Lab << /WhitePoint [0.9642 1 0.8249] /Range [-128 127 -128 127] >>]");
+        assert_rgb(&lab, &[100., 0., 0.], [1., 1., 1.]);
+        assert_rgb(&lab, &[50., 0., 0.], [0.466, 0.466, 0.466]);
+        assert_rgb(&lab, &[50., -300., 0.], lab.to_rgb(&[50., -128., 0.]).unwrap());
+        let gray = color_space("[/CalGray << /WhitePoint [0.9505 1 1.089] /Gamma 2.2 >>]");
+        assert_rgb(&gray, &[0.], [0., 0., 0.]);
+        assert_rgb(&gray, &[1.], [1., 1., 1.]);
+        // the sRGB primaries with a linear transfer function
+        let cal = color_space("[/CalRGB << /WhitePoint [0.9505 1 1.089] /Matrix [0.4124 0.2126 0.0193 0.3576 0.7152 0.1192 0.1805 0.0722 0.9505] >>]");
+        assert_rgb(&cal, &[1., 0., 0.], [1., 0., 0.]);
+        assert_rgb(&cal, &[0., 0.2140, 0.], [0., 0.5, 0.]);
+
+        // a palette of Lab colours, mapped to the Lab ranges
+        let indexed = color_space("[/Indexed [/Lab << /WhitePoint [0.9642 1 0.8249] >>] 1 <000000 ff8080>]");
+        assert_rgb(&indexed, &[1.], [1., 1., 1.]);
+
+        let devicen = color_space("[/DeviceN [/Cyan /Black] /DeviceCMYK << /FunctionType 2 /Domain [0 1] /C0 [0 0 0 0] /C1 [1 0 0 1] /N 1 >>]");
+        assert!(devicen.to_rgb(&[0.5, 0.5]).is_err());
+        let devicen = color_space("[/DeviceN [/Cyan] /DeviceCMYK << /FunctionType 2 /Domain [0 1] /C0 [0 0 0 0] /C1 [1 0 0 0] /N 1 >>]");
+        assert_rgb(&devicen, &[1.], [0., 1., 1.]);
+
+        // an uncoloured pattern takes its colour from the base space, a coloured one has none
+        let pattern = color_space("[/Pattern /DeviceRGB]");
+        let scn = Color::Other(vec![Primitive::Number(1.), Primitive::Integer(0), Primitive::Number(0.5), Primitive::name("P1")]);
+        assert_eq!(scn.to_rgb(&pattern).unwrap(), crate::content::Rgb { red: 1., green: 0., blue: 0.5 });
+        assert!(Color::Other(vec![Primitive::name("P1")]).to_rgb(&ColorSpace::Pattern(None)).is_err());
+    }
+
+    #[test]
+    fn write_icc_info() {
+        let info = IccInfo { alternate: Some(Box::new(ColorSpace::DeviceRGB)), ..IccInfo::new(3) };
+        let p = info.to_primitive(&mut NoUpdate).unwrap();
+        let info = IccInfo::from_primitive(p, &NoResolve).unwrap();
+        assert_eq!(info.components, 3);
+        assert!(matches!(info.alternate.as_deref(), Some(ColorSpace::DeviceRGB)));
+        assert!(info.range.is_none());
+    }
+
+    #[test]
+    fn write_tint_transform() {
+        let tint = Rc::new(Function::PostScript {
+            func: PsFunc::parse("{ 0 0 }").unwrap(),
+            domain: vec![0., 1.],
+            range: vec![0., 1., 0., 1., 0., 1.]
+        });
+
+        // a stream inside the array would be invalid
+        let cs = ColorSpace::Separation("Spot".into(), Box::new(ColorSpace::DeviceRGB), MaybeRef::Direct(tint.clone()));
+        assert!(cs.to_primitive(&mut NoUpdate).is_err());
+
+        let r = PlainRef { id: 7, gen: 0 };
+        let cs = ColorSpace::Separation("Spot".into(), Box::new(ColorSpace::DeviceRGB), MaybeRef::Indirect(RcRef::new(r, tint)));
+        match cs.to_primitive(&mut NoUpdate).unwrap() {
+            Primitive::Array(parts) => assert!(matches!(parts[3], Primitive::Reference(p) if p == r)),
+            p => panic!("{:?}", p)
+        }
+    }
+
+    struct SelfResolve(Primitive);
+    impl Resolve for SelfResolve {
+        fn resolve(&self, _: PlainRef) -> Result<Primitive> {
+            Ok(self.0.clone())
+        }
+        fn get<T: Object>(&self, _: Ref<T>) -> Result<RcRef<T>> {
+            Err(PdfError::Reference)
         }
     }
+
+    #[test]
+    fn nested_color_spaces() {
+        let parse = |s: &str| crate::parser::parse(s.as_bytes(), &NoResolve).unwrap();
+        let nested = (0..MAX_NESTING - 1).fold("/DeviceGray".to_string(), |base, _| format!("[/Pattern {}]", base));
+        assert!(ColorSpace::from_primitive(parse(&nested), &NoResolve).is_ok());
+        assert!(ColorSpace::from_primitive(parse(&format!("[/Pattern {}]", nested)), &NoResolve).is_err());
+
+        // 5 0 obj [/Pattern 5 0 R]
+        let cycle = parse("[/Pattern 5 0 R]");
+        assert!(ColorSpace::from_primitive(cycle.clone(), &SelfResolve(cycle)).is_err());
+        // the depth is reset after an error
+        assert!(ColorSpace::from_primitive(parse(&nested), &NoResolve).is_ok());
+    }
 }
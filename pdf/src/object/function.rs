@@ -1,6 +1,10 @@
 use crate as pdf;
 use crate::object::*;
 use crate::error::*;
+use crate::enc::{self, StreamFilter, LZWFlateParams};
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
 
 #[derive(Object, Debug)]
 struct RawFunction {
@@ -29,35 +33,101 @@ struct Function2 {
     exponent: f32,
 }
 
-#[derive(Debug)]
+#[derive(Object, Debug)]
+struct SampledFunctionDict {
+    #[pdf(key="Domain")]
+    domain: Vec<f32>,
+
+    #[pdf(key="Range")]
+    range: Vec<f32>,
+
+    #[pdf(key="Size")]
+    size: Vec<u32>,
+
+    #[pdf(key="BitsPerSample")]
+    bits_per_sample: u32,
+
+    #[pdf(key="Order", default="1")]
+    order: u32,
+
+    #[pdf(key="Encode")]
+    encode: Option<Vec<f32>>,
+
+    #[pdf(key="Decode")]
+    decode: Option<Vec<f32>>,
+}
+
+/// Stitching functions nested deeper than this are rejected.
+const MAX_NESTING: usize = 32;
+
+#[derive(Object, Debug)]
+struct Function3 {
+    #[pdf(key="Bounds")]
+    bounds: Vec<f32>,
+
+    #[pdf(key="Encode")]
+    encode: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
 pub enum Function {
-    Sampled,
+    Sampled(SampledFunction),
     Interpolated(Vec<InterpolatedFunctionDim>),
-    Stiching,
-    Calculator,
-    PostScript(PsFunc),
+    Stitching(StitchingFunction),
+    PostScript { func: PsFunc, domain: Vec<f32>, range: Vec<f32> },
 }
 impl Function {
-    pub fn apply(&self, x: f32, out: &mut [f32]) -> Result<()> {
+    /// Evaluate the function for the inputs `x`, writing the outputs to `out`.
+    ///
+    /// Inputs are clipped to the `Domain` and outputs to the `Range` of the function.
+    pub fn apply(&self, x: &[f32], out: &mut [f32]) -> Result<()> {
         match *self {
             Function::Interpolated(ref parts) => {
+                if x.len() != 1 {
+                    bail!("incorrect input length: expected 1, found {}.", x.len())
+                }
                 if parts.len() != out.len() {
                     bail!("incorrect output length: expected {}, found {}.", parts.len(), out.len())
                 }
                 for (f, y) in parts.iter().zip(out) {
-                    *y = f.apply(x);
+                    *y = f.apply(x[0]);
                 }
                 Ok(())
             }
-            Function::PostScript(ref func) => func.exec(x, out),
-            _ => bail!("unimplemted function {:?}", self)
+            Function::Sampled(ref func) => func.apply(x, out),
+            Function::Stitching(ref func) => func.apply(x, out),
+            Function::PostScript { ref func, ref domain, ref range } => {
+                if x.len() * 2 != domain.len() {
+                    bail!("incorrect input length: expected {}, found {}.", domain.len() / 2, x.len())
+                }
+                let x: Vec<f32> = x.iter().zip(domain.chunks_exact(2)).map(|(&x, d)| x.max(d[0]).min(d[1])).collect();
+                func.exec(&x, out)?;
+                clip(out, range)
+            }
         }
     }
 }
+
+/// Clip `out` to the pairs of `range`.
+fn clip(out: &mut [f32], range: &[f32]) -> Result<()> {
+    if out.len() * 2 != range.len() {
+        bail!("incorrect output length: expected {}, found {}.", range.len() / 2, out.len())
+    }
+    for (y, r) in out.iter_mut().zip(range.chunks_exact(2)) {
+        *y = y.max(r[0]).min(r[1]);
+    }
+    Ok(())
+}
 impl FromDict for Function {
     fn from_dict(dict: Dictionary, resolve: &impl Resolve) -> Result<Self> {
+        Self::from_dict_nested(dict, resolve, 0)
+    }
+}
+impl Function {
+    /// Read a function that is nested in `depth` stitching functions.
+    fn from_dict_nested(dict: Dictionary, resolve: &impl Resolve, depth: usize) -> Result<Self> {
         use std::f32::INFINITY;
-        let raw = RawFunction::from_dict(dict, resolve)?;
+        let mut raw = RawFunction::from_dict(dict, resolve)?;
         match raw.function_type {
             2 => {
                 let f2 = Function2::from_dict(raw.other, resolve)?;
@@ -84,6 +154,34 @@ impl FromDict for Function {
                 }
                 Ok(Function::Interpolated(parts))
             },
+            3 => {
+                if depth >= MAX_NESTING {
+                    bail!("stitching functions nested deeper than {}", MAX_NESTING);
+                }
+                let functions = match raw.other.require("Function", "Functions")? {
+                    Primitive::Reference(r) => resolve.resolve(r)?,
+                    p => p
+                };
+                let functions = match functions {
+                    Primitive::Array(functions) => functions,
+                    p => vec![p]
+                };
+                let functions = functions.into_iter()
+                    .map(|p| Function::from_primitive_nested_ref(p, resolve, depth + 1))
+                    .collect::<Result<Vec<_>>>()?;
+                let f3 = t!(Function3::from_dict(raw.other, resolve));
+                let k = functions.len();
+                if raw.domain.len() != 2 || k == 0 || f3.bounds.len() != k - 1 || f3.encode.len() != 2 * k {
+                    bail!("stitching function with {} functions, Domain {:?}, Bounds {:?} and Encode {:?}", k, raw.domain, f3.bounds, f3.encode);
+                }
+                Ok(Function::Stitching(StitchingFunction {
+                    domain: (raw.domain[0], raw.domain[1]),
+                    range: raw.range,
+                    functions,
+                    bounds: f3.bounds,
+                    encode: f3.encode,
+                }))
+            }
             i => {
                 dbg!(raw);
                 bail!("unsupported function type {}", i)
@@ -93,31 +191,117 @@ impl FromDict for Function {
 }
 impl Object for Function {
     fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        Self::from_primitive_nested(p, resolve, 0)
+    }
+}
+impl Function {
+    fn from_primitive_nested(p: Primitive, resolve: &impl Resolve, depth: usize) -> Result<Self> {
         match p {
-            Primitive::Dictionary(dict) => Self::from_dict(dict, resolve),
-            Primitive::Stream(mut s) => {
-                let function_type = s.info.require("Function", "FunctionType")?.as_integer()?;
-                let stream = Stream::<()>::from_stream(s, resolve)?;
-                let data = stream.decode()?;
+            Primitive::Dictionary(dict) => Self::from_dict_nested(dict, resolve, depth),
+            Primitive::Stream(s) => {
+                let function_type = s.info.get("FunctionType")
+                    .ok_or(PdfError::MissingEntry { typ: "Function", field: "FunctionType".into() })?
+                    .as_integer()?;
                 match function_type {
                     4 => {
+                        let stream = Stream::<RawFunction>::from_stream(s, resolve)?;
+                        let data = stream.decode()?;
                         let s = std::str::from_utf8(&*data)?;
                         let func = PsFunc::parse(s)?;
-                        Ok(Function::PostScript(func))
+                        let info = &stream.info.info;
+                        let range = match info.range {
+                            Some(ref range) => range.clone(),
+                            None => bail!("PostScript function without Range")
+                        };
+                        Ok(Function::PostScript { func, domain: info.domain.clone(), range })
                     },
                     0 => {
-                        Ok(Function::Sampled) // TODO implement this!
+                        let stream = Stream::<SampledFunctionDict>::from_stream(s, resolve)?;
+                        let func = t!(SampledFunction::new(&stream.info.info, &stream.decode()?));
+                        Ok(Function::Sampled(func))
                     }
                     ref p => bail!("found a function stream with type {:?}", p)
                 }
             },
-            Primitive::Reference(r) => Self::from_primitive(resolve.resolve(r)?, resolve),
+            Primitive::Reference(r) => Self::from_primitive_nested(resolve.resolve(r)?, resolve, depth),
             _ => bail!("double indirection")
         }
     }
+
+    /// Like `from_primitive_nested`, but keeps the reference if `p` is one.
+    fn from_primitive_nested_ref(p: Primitive, resolve: &impl Resolve, depth: usize) -> Result<MaybeRef<Self>> {
+        match p {
+            Primitive::Reference(r) => {
+                let function = Self::from_primitive_nested(resolve.resolve(r)?, resolve, depth)?;
+                Ok(MaybeRef::Indirect(RcRef::new(r, Rc::new(function))))
+            }
+            p => Ok(MaybeRef::Direct(Rc::new(Self::from_primitive_nested(p, resolve, depth)?)))
+        }
+    }
 }
 
-#[derive(Debug)]
+impl ObjectWrite for Function {
+    /// Sampled and PostScript functions are streams, so they can only be written as objects of
+    /// their own. Other objects hold them as `MaybeRef::Indirect`.
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            Function::Interpolated(ref parts) => {
+                let first = match parts.first() {
+                    Some(f) => f,
+                    None => bail!("interpolated function without outputs")
+                };
+                let mut dict = Dictionary::new();
+                dict.insert("FunctionType", Primitive::Integer(2));
+                dict.insert("Domain", vec![first.input_range.0, first.input_range.1].to_primitive(update)?);
+                let range: Vec<f32> = parts.iter().flat_map(|f| vec![f.output_range.0, f.output_range.1]).collect();
+                if range.iter().all(|r| r.is_finite()) {
+                    dict.insert("Range", range.to_primitive(update)?);
+                }
+                dict.insert("C0", parts.iter().map(|f| f.c0).collect::<Vec<f32>>().to_primitive(update)?);
+                dict.insert("C1", parts.iter().map(|f| f.c1).collect::<Vec<f32>>().to_primitive(update)?);
+                dict.insert("N", Primitive::Number(first.exponent));
+                Ok(Primitive::Dictionary(dict))
+            }
+            Function::Stitching(ref func) => {
+                let mut dict = Dictionary::new();
+                dict.insert("FunctionType", Primitive::Integer(3));
+                dict.insert("Domain", vec![func.domain.0, func.domain.1].to_primitive(update)?);
+                if let Some(ref range) = func.range {
+                    dict.insert("Range", range.to_primitive(update)?);
+                }
+                dict.insert("Functions", func.functions.to_primitive(update)?);
+                dict.insert("Bounds", func.bounds.to_primitive(update)?);
+                dict.insert("Encode", func.encode.to_primitive(update)?);
+                Ok(Primitive::Dictionary(dict))
+            }
+            Function::Sampled(ref func) => {
+                let mut dict = Dictionary::new();
+                dict.insert("FunctionType", Primitive::Integer(0));
+                dict.insert("Domain", func.domain.to_primitive(update)?);
+                dict.insert("Range", func.range.to_primitive(update)?);
+                dict.insert("Size", func.size.iter().map(|&s| s as i32).collect::<Vec<i32>>().to_primitive(update)?);
+                dict.insert("BitsPerSample", Primitive::Integer(func.bits_per_sample as i32));
+                if func.cubic {
+                    dict.insert("Order", Primitive::Integer(3));
+                }
+                dict.insert("Encode", func.encode.to_primitive(update)?);
+                dict.insert("Decode", func.decode.to_primitive(update)?);
+                let filter = StreamFilter::FlateDecode(LZWFlateParams::default());
+                let data = enc::encode(&func.data()?, &filter)?;
+                Stream::new_with_filters(dict, data, vec![filter]).to_primitive(update)
+            }
+            Function::PostScript { ref func, ref domain, ref range } => {
+                let mut dict = Dictionary::new();
+                dict.insert("FunctionType", Primitive::Integer(4));
+                dict.insert("Domain", domain.to_primitive(update)?);
+                dict.insert("Range", range.to_primitive(update)?);
+                Stream::new(dict, func.to_string().into_bytes()).to_primitive(update)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct InterpolatedFunctionDim {
     pub input_range: (f32, f32),
     pub output_range: (f32, f32),
@@ -127,22 +311,353 @@ pub struct InterpolatedFunctionDim {
 }
 impl InterpolatedFunctionDim {
     pub fn apply(&self, x: f32) -> f32 {
+        let (x0, x1) = self.input_range;
+        let x = x.max(x0).min(x1);
         let y = self.c0 + x.powf(self.exponent) * (self.c1 - self.c0);
         let (y0, y1) = self.output_range;
         y.min(y1).max(y0)
     }
 }
 
-#[derive(Debug)]
+/// A function given by a table of samples (type 0).
+#[derive(Debug, Clone)]
+pub struct SampledFunction {
+    /// Input range of each input, as pairs.
+    pub domain: Vec<f32>,
+    /// Output range of each output, as pairs.
+    pub range: Vec<f32>,
+    /// Number of samples along each input.
+    pub size: Vec<u32>,
+    /// Maps each input from `domain` to the sample index, as pairs.
+    pub encode: Vec<f32>,
+    /// Cubic instead of linear interpolation between the samples.
+    pub cubic: bool,
+    /// The size of the samples when the function is written.
+    pub bits_per_sample: u32,
+    /// Maps the written samples to the output range, as pairs.
+    pub decode: Vec<f32>,
+    /// The samples, already mapped through `Decode`. The outputs of a sample are consecutive,
+    /// and the first input varies fastest.
+    pub samples: Vec<f32>,
+}
+impl SampledFunction {
+    fn new(dict: &SampledFunctionDict, data: &[u8]) -> Result<SampledFunction> {
+        let m = dict.size.len();
+        let n = dict.range.len() / 2;
+        if m == 0 || n == 0 || dict.domain.len() != 2 * m || dict.range.len() != 2 * n {
+            bail!("sampled function with Domain {:?}, Range {:?} and Size {:?}", dict.domain, dict.range, dict.size);
+        }
+        if dict.size.contains(&0) {
+            bail!("sampled function with an empty Size {:?}", dict.size);
+        }
+        let bps = dict.bits_per_sample;
+        if !matches!(bps, 1 | 2 | 4 | 8 | 12 | 16 | 24 | 32) {
+            bail!("invalid BitsPerSample {}", bps);
+        }
+        let cubic = match dict.order {
+            1 => false,
+            3 => true,
+            o => bail!("invalid Order {}", o)
+        };
+        let encode = match dict.encode {
+            Some(ref e) if e.len() == 2 * m => e.clone(),
+            Some(ref e) => bail!("Encode {:?} does not match Size {:?}", e, dict.size),
+            None => dict.size.iter().flat_map(|&s| vec![0., (s - 1) as f32]).collect()
+        };
+        let decode = match dict.decode {
+            Some(ref d) if d.len() == 2 * n => d.clone(),
+            Some(ref d) => bail!("Decode {:?} does not match Range {:?}", d, dict.range),
+            None => dict.range.clone()
+        };
+
+        let count = dict.size.iter()
+            .try_fold(n, |count, &s| count.checked_mul(s as usize))
+            .filter(|&count| count.checked_mul(bps as usize).is_some_and(|bits| bits <= data.len() * 8));
+        let count = match count {
+            Some(count) => count,
+            None => bail!("not enough data for a sampled function of Size {:?}", dict.size)
+        };
+        let max = ((1u64 << bps) - 1) as f32;
+        let samples = (0 .. count).map(|i| {
+            let (d0, d1) = (decode[i % n * 2], decode[i % n * 2 + 1]);
+            d0 + read_bits(data, i * bps as usize, bps) as f32 * (d1 - d0) / max
+        }).collect();
+
+        Ok(SampledFunction {
+            domain: dict.domain.clone(),
+            range: dict.range.clone(),
+            size: dict.size.clone(),
+            encode,
+            cubic,
+            bits_per_sample: bps,
+            decode,
+            samples,
+        })
+    }
+
+    /// Quantize the samples to `bits_per_sample` bits through `decode`.
+    fn data(&self) -> Result<Vec<u8>> {
+        let n = self.range.len() / 2;
+        if !matches!(self.bits_per_sample, 1 | 2 | 4 | 8 | 12 | 16 | 24 | 32) || self.decode.len() != 2 * n {
+            bail!("can't write the samples with BitsPerSample {} and Decode {:?}", self.bits_per_sample, self.decode);
+        }
+        let max = ((1u64 << self.bits_per_sample) - 1) as f64;
+        let values = self.samples.iter().enumerate().map(|(i, &s)| {
+            let (d0, d1) = (self.decode[i % n * 2] as f64, self.decode[i % n * 2 + 1] as f64);
+            let v = if d1 == d0 { 0. } else { (s as f64 - d0) * max / (d1 - d0) };
+            v.round().max(0.).min(max) as u32
+        });
+        Ok(write_bits(values, self.bits_per_sample))
+    }
+
+    pub fn apply(&self, x: &[f32], out: &mut [f32]) -> Result<()> {
+        let (m, n) = (self.size.len(), self.range.len() / 2);
+        if x.len() != m {
+            bail!("incorrect input length: expected {}, found {}.", m, x.len());
+        }
+        if out.len() != n {
+            bail!("incorrect output length: expected {}, found {}.", n, out.len());
+        }
+
+        // the samples (as an index along the input) and weights each input interpolates between
+        let mut taps = Vec::with_capacity(m);
+        for (i, &x) in x.iter().enumerate() {
+            let (d0, d1) = (self.domain[2 * i], self.domain[2 * i + 1]);
+            let (e0, e1) = (self.encode[2 * i], self.encode[2 * i + 1]);
+            let last = self.size[i] as usize - 1;
+            let x = x.max(d0).min(d1);
+            let e = if d1 == d0 { e0 } else { e0 + (x - d0) * (e1 - e0) / (d1 - d0) };
+            let e = e.max(0.).min(last as f32);
+            let j = (e.floor() as usize).min(last);
+            let t = e - j as f32;
+            taps.push(if t == 0. {
+                vec![(j, 1.)]
+            } else if self.cubic {
+                // Catmull-Rom spline, with the samples beyond the ends extrapolated linearly
+                let (t2, t3) = (t * t, t * t * t);
+                let weights = [
+                    (-t3 + 2. * t2 - t) / 2.,
+                    (3. * t3 - 5. * t2 + 2.) / 2.,
+                    (-3. * t3 + 4. * t2 + t) / 2.,
+                    (t3 - t2) / 2.,
+                ];
+                let mut tap = Vec::with_capacity(6);
+                for (d, &w) in weights.iter().enumerate() {
+                    match (j + d).checked_sub(1) {
+                        None => tap.extend_from_slice(&[(0, 2. * w), (1, -w)]),
+                        Some(k) if k > last => tap.extend_from_slice(&[(last, 2. * w), (last - 1, -w)]),
+                        Some(k) => tap.push((k, w)),
+                    }
+                }
+                tap
+            } else {
+                vec![(j, 1. - t), (j + 1, t)]
+            });
+        }
+
+        for y in out.iter_mut() {
+            *y = 0.;
+        }
+        // visit every combination of taps
+        let mut pos = vec![0; m];
+        loop {
+            let mut index = 0;
+            let mut stride = 1;
+            let mut weight = 1.;
+            for (i, &p) in pos.iter().enumerate() {
+                let (sample, w) = taps[i][p];
+                index += sample * stride;
+                stride *= self.size[i] as usize;
+                weight *= w;
+            }
+            for (k, y) in out.iter_mut().enumerate() {
+                *y += weight * self.samples[index * n + k];
+            }
+
+            let mut i = 0;
+            loop {
+                if i == m {
+                    return clip(out, &self.range);
+                }
+                pos[i] += 1;
+                if pos[i] < taps[i].len() {
+                    break;
+                }
+                pos[i] = 0;
+                i += 1;
+            }
+        }
+    }
+}
+
+/// A function made of `functions` for consecutive parts of its input (type 3).
+#[derive(Debug, Clone)]
+pub struct StitchingFunction {
+    pub domain: (f32, f32),
+    pub range: Option<Vec<f32>>,
+    pub functions: Vec<MaybeRef<Function>>,
+    /// Where each part ends and the next starts, in increasing order.
+    pub bounds: Vec<f32>,
+    /// Maps each part of the domain to the input of its function, as pairs.
+    pub encode: Vec<f32>,
+}
+impl StitchingFunction {
+    pub fn apply(&self, x: &[f32], out: &mut [f32]) -> Result<()> {
+        let x = match *x {
+            [x] => x.max(self.domain.0).min(self.domain.1),
+            _ => bail!("incorrect input length: expected 1, found {}.", x.len())
+        };
+        let i = self.bounds.iter().position(|&b| x < b).unwrap_or(self.bounds.len());
+        let start = if i == 0 { self.domain.0 } else { self.bounds[i - 1] };
+        let end = self.bounds.get(i).copied().unwrap_or(self.domain.1);
+        let (e0, e1) = (self.encode[2 * i], self.encode[2 * i + 1]);
+        let x = if end == start { e0 } else { e0 + (x - start) * (e1 - e0) / (end - start) };
+        self.functions[i].apply(&[x], out)?;
+        match self.range {
+            Some(ref range) => clip(out, range),
+            None => Ok(())
+        }
+    }
+}
+
+/// Read `bits` bits (up to 32) at bit `pos` of `data`, most significant bit first.
+pub(crate) fn read_bits(data: &[u8], pos: usize, bits: u32) -> u32 {
+    let mut value = 0u64;
+    let (start, end) = (pos / 8, (pos + bits as usize).div_ceil(8));
+    for &b in &data[start .. end] {
+        value = value << 8 | b as u64;
+    }
+    let extra = (end * 8 - pos - bits as usize) as u32;
+    ((value >> extra) & ((1u64 << bits) - 1)) as u32
+}
+
+/// Pack `values` of `bits` bits (up to 32) each, most significant bit first.
+fn write_bits(values: impl Iterator<Item=u32>, bits: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let (mut acc, mut len) = (0u64, 0);
+    for v in values {
+        acc = acc << bits | v as u64;
+        len += bits;
+        while len >= 8 {
+            len -= 8;
+            data.push((acc >> len) as u8);
+        }
+        acc &= (1 << len) - 1;
+    }
+    if len > 0 {
+        data.push((acc << (8 - len)) as u8);
+    }
+    data
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PostScriptError {
     StackUnderflow,
-    IncorrectStackSize
+    StackOverflow,
+    IncorrectStackSize,
+    /// An operand has the wrong type.
+    TypeCheck,
+    /// An operand is out of range, like a negative `sqrt` or a `roll` of more values than the stack holds.
+    RangeCheck,
+    /// Division by zero or a similar result.
+    UndefinedResult,
 }
-#[derive(Debug)]
+
+/// The operand stack may not hold more values than this.
+const PS_STACK_LIMIT: usize = 100;
+/// `{}` blocks may not be nested deeper than this.
+const PS_NESTING_LIMIT: usize = 64;
+
+/// A PostScript calculator function (type 4).
+#[derive(Debug, Clone)]
 pub struct PsFunc {
     pub ops: Vec<PsOp>
 }
 
+/// A value on the operand stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PsValue {
+    Int(i32),
+    Real(f32),
+    Bool(bool),
+}
+impl PsValue {
+    fn real(self) -> Result<f32, PostScriptError> {
+        match self {
+            PsValue::Int(i) => Ok(i as f32),
+            PsValue::Real(r) => Ok(r),
+            PsValue::Bool(_) => Err(PostScriptError::TypeCheck)
+        }
+    }
+    fn int(self) -> Result<i32, PostScriptError> {
+        match self {
+            PsValue::Int(i) => Ok(i),
+            _ => Err(PostScriptError::TypeCheck)
+        }
+    }
+    fn bool(self) -> Result<bool, PostScriptError> {
+        match self {
+            PsValue::Bool(b) => Ok(b),
+            _ => Err(PostScriptError::TypeCheck)
+        }
+    }
+}
+
+/// Apply an integer operation if both operands are integers and the result fits,
+/// the real operation otherwise.
+fn arith(a: PsValue, b: PsValue, int: fn(i32, i32) -> Option<i32>, real: fn(f32, f32) -> f32) -> Result<PsValue, PostScriptError> {
+    if let (PsValue::Int(a), PsValue::Int(b)) = (a, b) {
+        if let Some(r) = int(a, b) {
+            return Ok(PsValue::Int(r));
+        }
+    }
+    Ok(PsValue::Real(real(a.real()?, b.real()?)))
+}
+
+/// A real result, which has to be a number.
+fn real(r: f32) -> Result<PsValue, PostScriptError> {
+    if r.is_finite() {
+        Ok(PsValue::Real(r))
+    } else {
+        Err(PostScriptError::UndefinedResult)
+    }
+}
+
+/// Apply a rounding function, which keeps integers as they are.
+fn rounded(v: PsValue, f: fn(f32) -> f32) -> Result<PsValue, PostScriptError> {
+    match v {
+        PsValue::Int(i) => Ok(PsValue::Int(i)),
+        v => Ok(PsValue::Real(f(v.real()?)))
+    }
+}
+
+fn compare(a: PsValue, b: PsValue) -> Result<std::cmp::Ordering, PostScriptError> {
+    a.real()?.partial_cmp(&b.real()?).ok_or(PostScriptError::UndefinedResult)
+}
+
+fn equal(a: PsValue, b: PsValue) -> bool {
+    match (a, b) {
+        (PsValue::Bool(a), PsValue::Bool(b)) => a == b,
+        (PsValue::Bool(_), _) | (_, PsValue::Bool(_)) => false,
+        (a, b) => a.real() == b.real()
+    }
+}
+
+/// `and`, `or` and `xor` of two booleans or integers.
+fn logic(a: PsValue, b: PsValue, f: fn(i32, i32) -> i32) -> Result<PsValue, PostScriptError> {
+    match (a, b) {
+        (PsValue::Bool(a), PsValue::Bool(b)) => Ok(PsValue::Bool(f(a as i32, b as i32) != 0)),
+        (PsValue::Int(a), PsValue::Int(b)) => Ok(PsValue::Int(f(a, b))),
+        _ => Err(PostScriptError::TypeCheck)
+    }
+}
+
+/// Pop a non-negative integer.
+fn pop_count(stack: &mut Vec<PsValue>) -> Result<usize, PostScriptError> {
+    let n = stack.pop().ok_or(PostScriptError::StackUnderflow)?.int()?;
+    usize::try_from(n).map_err(|_| PostScriptError::RangeCheck)
+}
+
 macro_rules! op {
     ($stack:ident; $($v:ident),* => $($e:expr),*) => ( {
         $(let $v = $stack.pop().ok_or(PostScriptError::StackUnderflow)?;)*
@@ -151,63 +666,577 @@ macro_rules! op {
 }
 
 impl PsFunc {
-    fn exec_inner(&self, stack: &mut Vec<f32>) -> Result<(), PostScriptError> {
-        for &op in &self.ops {
-            match op {
-                PsOp::Value(v) => stack.push(v),
+    fn exec_inner(ops: &[PsOp], stack: &mut Vec<PsValue>) -> Result<(), PostScriptError> {
+        use PsValue::{Int, Real, Bool};
+        use std::cmp::Ordering;
+
+        for op in ops {
+            match *op {
+                PsOp::Value(v) => stack.push(Real(v)),
+                PsOp::Int(i) => stack.push(Int(i)),
+                PsOp::Bool(b) => stack.push(Bool(b)),
+
+                PsOp::Add => op!(stack; b, a => arith(a, b, i32::checked_add, |a, b| a + b)?),
+                PsOp::Sub => op!(stack; b, a => arith(a, b, i32::checked_sub, |a, b| a - b)?),
+                PsOp::Mul => op!(stack; b, a => arith(a, b, i32::checked_mul, |a, b| a * b)?),
+                PsOp::Div => op!(stack; b, a => real(a.real()? / b.real()?)?),
+                PsOp::Idiv => op!(stack; b, a => Int(a.int()?.checked_div(b.int()?).ok_or(PostScriptError::UndefinedResult)?)),
+                PsOp::Mod => op!(stack; b, a => Int(a.int()?.checked_rem(b.int()?).ok_or(PostScriptError::UndefinedResult)?)),
+                PsOp::Neg => op!(stack; a => arith(Int(0), a, i32::checked_sub, |_, a| -a)?),
+                PsOp::Abs => op!(stack; a => match a {
+                    Int(i) => i.checked_abs().map(Int).unwrap_or(Real((i as f32).abs())),
+                    a => Real(a.real()?.abs())
+                }),
+                PsOp::Ceiling => op!(stack; a => rounded(a, f32::ceil)?),
+                PsOp::Floor => op!(stack; a => rounded(a, f32::floor)?),
+                PsOp::Round => op!(stack; a => rounded(a, |r| (r + 0.5).floor())?),
+                PsOp::Truncate => op!(stack; a => rounded(a, f32::trunc)?),
+                PsOp::Sqrt => op!(stack; a => match a.real()? {
+                    r if r < 0. => return Err(PostScriptError::RangeCheck),
+                    r => Real(r.sqrt())
+                }),
+                PsOp::Sin => op!(stack; a => Real(a.real()?.to_radians().sin())),
+                PsOp::Cos => op!(stack; a => Real(a.real()?.to_radians().cos())),
+                PsOp::Atan => op!(stack; den, num => {
+                    let (num, den) = (num.real()?, den.real()?);
+                    if num == 0. && den == 0. {
+                        return Err(PostScriptError::UndefinedResult);
+                    }
+                    let angle = num.atan2(den).to_degrees();
+                    Real(if angle < 0. { angle + 360. } else { angle })
+                }),
+                PsOp::Exp => op!(stack; e, base => real(base.real()?.powf(e.real()?))?),
+                PsOp::Ln => op!(stack; a => match a.real()? {
+                    r if r <= 0. => return Err(PostScriptError::RangeCheck),
+                    r => Real(r.ln())
+                }),
+                PsOp::Log => op!(stack; a => match a.real()? {
+                    r if r <= 0. => return Err(PostScriptError::RangeCheck),
+                    r => Real(r.log10())
+                }),
+                PsOp::Cvi => op!(stack; a => match a {
+                    Int(i) => Int(i),
+                    a => match a.real()?.trunc() {
+                        r if r >= i32::MIN as f32 && r <= i32::MAX as f32 => Int(r as i32),
+                        _ => return Err(PostScriptError::RangeCheck)
+                    }
+                }),
+                PsOp::Cvr => op!(stack; a => Real(a.real()?)),
+
+                PsOp::Eq => op!(stack; b, a => Bool(equal(a, b))),
+                PsOp::Ne => op!(stack; b, a => Bool(!equal(a, b))),
+                PsOp::Gt => op!(stack; b, a => Bool(compare(a, b)? == Ordering::Greater)),
+                PsOp::Ge => op!(stack; b, a => Bool(compare(a, b)? != Ordering::Less)),
+                PsOp::Lt => op!(stack; b, a => Bool(compare(a, b)? == Ordering::Less)),
+                PsOp::Le => op!(stack; b, a => Bool(compare(a, b)? != Ordering::Greater)),
+                PsOp::And => op!(stack; b, a => logic(a, b, |a, b| a & b)?),
+                PsOp::Or => op!(stack; b, a => logic(a, b, |a, b| a | b)?),
+                PsOp::Xor => op!(stack; b, a => logic(a, b, |a, b| a ^ b)?),
+                PsOp::Not => op!(stack; a => match a {
+                    Bool(b) => Bool(!b),
+                    a => Int(!a.int()?)
+                }),
+                PsOp::Bitshift => op!(stack; shift, a => {
+                    let (a, shift) = (a.int()? as u32, shift.int()?);
+                    Int(match shift {
+                        0 ..= 31 => a << shift,
+                        -31 ..= -1 => a >> -shift,
+                        _ => 0
+                    } as i32)
+                }),
+
+                PsOp::Pop => op!(stack; _a => ),
                 PsOp::Dup => op!(stack; v => v, v),
                 PsOp::Exch => op!(stack; a, b => a, b),
-                PsOp::Add => op!(stack; a, b => a + b),
-                PsOp::Mul => op!(stack; a, b => a * b),
-                PsOp::Abs => op!(stack; a => a.abs()),
+                PsOp::Copy => {
+                    let n = pop_count(stack)?;
+                    if n > stack.len() {
+                        return Err(PostScriptError::StackUnderflow);
+                    }
+                    if stack.len() + n > PS_STACK_LIMIT {
+                        return Err(PostScriptError::StackOverflow);
+                    }
+                    stack.extend_from_within(stack.len() - n ..);
+                }
+                PsOp::Index => {
+                    let n = pop_count(stack)?;
+                    if n >= stack.len() {
+                        return Err(PostScriptError::StackUnderflow);
+                    }
+                    stack.push(stack[stack.len() - 1 - n]);
+                }
+                PsOp::Roll => {
+                    let j = stack.pop().ok_or(PostScriptError::StackUnderflow)?.int()?;
+                    let n = pop_count(stack)?;
+                    if n > stack.len() {
+                        return Err(PostScriptError::StackUnderflow);
+                    }
+                    if n > 0 {
+                        let start = stack.len() - n;
+                        let j = j.rem_euclid(n as i32) as usize;
+                        stack[start ..].rotate_right(j);
+                    }
+                }
+
+                PsOp::If(ref then) => {
+                    if stack.pop().ok_or(PostScriptError::StackUnderflow)?.bool()? {
+                        PsFunc::exec_inner(then, stack)?;
+                    }
+                }
+                PsOp::IfElse(ref then, ref otherwise) => {
+                    let block = if stack.pop().ok_or(PostScriptError::StackUnderflow)?.bool()? { then } else { otherwise };
+                    PsFunc::exec_inner(block, stack)?;
+                }
+            }
+            if stack.len() > PS_STACK_LIMIT {
+                return Err(PostScriptError::StackOverflow);
             }
         }
         Ok(())
     }
-    pub fn exec(&self, input: f32, output: &mut [f32]) -> Result<()> {
-        let mut stack = Vec::with_capacity(10);
-        stack.push(input);
-        match self.exec_inner(&mut stack) {
-            Ok(()) => {},
-            Err(_) => return Err(PdfError::PostScriptExec)
-        }
-        if output.len() != stack.len() {
-            bail!("incorrect output length: expected {}, found {}.", stack.len(), output.len())
+
+    /// Run the function with the `input` values on the stack. The values left on the
+    /// stack are the outputs.
+    pub fn exec(&self, input: &[f32], output: &mut [f32]) -> Result<()> {
+        let mut stack: Vec<PsValue> = input.iter().map(|&x| PsValue::Real(x)).collect();
+        let result = PsFunc::exec_inner(&self.ops, &mut stack)
+            .and_then(|()| {
+                if output.len() != stack.len() {
+                    return Err(PostScriptError::IncorrectStackSize);
+                }
+                for (y, v) in output.iter_mut().zip(&stack) {
+                    *y = v.real()?;
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                debug!("PostScript function failed: {:?}, stack {:?}", e, stack);
+                Err(PdfError::PostScriptExec)
+            }
         }
-        output.copy_from_slice(&stack);
-        Ok(())
     }
+
+    /// Parse the program, a `{}` block.
     pub fn parse(s: &str) -> Result<Self, PdfError> {
-        let start = s.find("{").ok_or(PdfError::PostScriptParse)?;
-        let end = s.rfind("}").ok_or(PdfError::PostScriptParse)?;
+        let mut tokens = PsTokens { s: s.as_bytes(), pos: 0 };
+        if tokens.next() != Some("{") {
+            return Err(PdfError::PostScriptParse);
+        }
+        let ops = PsFunc::parse_block(&mut tokens, 0)?;
+        match tokens.next() {
+            None => Ok(PsFunc { ops }),
+            Some(_) => Err(PdfError::PostScriptParse)
+        }
+    }
 
-        let ops: Result<Vec<_>, _> = s[start + 1 .. end].split_ascii_whitespace().map(|p| PsOp::parse(p).ok_or(PdfError::PostScriptParse)).collect();
-        Ok(PsFunc { ops: ops? })
+    /// Parse the operators up to the closing `}`.
+    fn parse_block(tokens: &mut PsTokens, depth: usize) -> Result<Vec<PsOp>, PdfError> {
+        if depth > PS_NESTING_LIMIT {
+            return Err(PdfError::PostScriptParse);
+        }
+        let mut ops = Vec::new();
+        // blocks waiting for their `if` or `ifelse`
+        let mut blocks = Vec::new();
+        loop {
+            let op = match tokens.next().ok_or(PdfError::PostScriptParse)? {
+                "{" => {
+                    blocks.push(PsFunc::parse_block(tokens, depth + 1)?);
+                    continue;
+                }
+                "}" if blocks.is_empty() => return Ok(ops),
+                "if" if blocks.len() == 1 => PsOp::If(blocks.pop().unwrap()),
+                "ifelse" if blocks.len() == 2 => {
+                    let otherwise = blocks.pop().unwrap();
+                    PsOp::IfElse(blocks.pop().unwrap(), otherwise)
+                }
+                token if blocks.is_empty() => PsOp::parse(token).ok_or(PdfError::PostScriptParse)?,
+                _ => return Err(PdfError::PostScriptParse)
+            };
+            ops.push(op);
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Splits a PostScript program into tokens, skipping comments.
+struct PsTokens<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+impl<'a> Iterator for PsTokens<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            match *self.s.get(self.pos)? {
+                b'%' => while !matches!(self.s.get(self.pos), None | Some(b'\r') | Some(b'\n')) {
+                    self.pos += 1;
+                },
+                b if b.is_ascii_whitespace() || b == 0 => self.pos += 1,
+                _ => break
+            }
+        }
+        let start = self.pos;
+        self.pos += 1;
+        if !matches!(self.s[start], b'{' | b'}') {
+            while let Some(&b) = self.s.get(self.pos) {
+                if b.is_ascii_whitespace() || matches!(b, 0 | b'{' | b'}' | b'%') {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        // the input is a str and the token ends at an ASCII character
+        std::str::from_utf8(&self.s[start .. self.pos]).ok()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum PsOp {
+    /// A real number.
     Value(f32),
+    Int(i32),
+    Bool(bool),
+
     Add,
-    Abs,
+    Sub,
     Mul,
+    Div,
+    Idiv,
+    Mod,
+    Neg,
+    Abs,
+    Ceiling,
+    Floor,
+    Round,
+    Truncate,
+    Sqrt,
+    Sin,
+    Cos,
+    Atan,
+    Exp,
+    Ln,
+    Log,
+    Cvi,
+    Cvr,
+
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    And,
+    Or,
+    Xor,
+    Not,
+    Bitshift,
+
+    Pop,
     Dup,
     Exch,
+    Copy,
+    Index,
+    Roll,
+
+    If(Vec<PsOp>),
+    IfElse(Vec<PsOp>, Vec<PsOp>),
 }
 impl PsOp {
+    /// Parse a number or an operator. `if` and `ifelse` need their blocks and are parsed by `PsFunc::parse`.
     pub fn parse(s: &str) -> Option<Self> {
-        if let Ok(f) = s.parse() {
-            Some(PsOp::Value(f))
-        } else {
-            Some(match s {
-                "add" => PsOp::Add,
-                "abs" => PsOp::Abs,
-                "mul" => PsOp::Mul,
-                "dup" => PsOp::Dup,
-                "exch" => PsOp::Exch,
-                _ => return None
-            })
-        }
-    }
-}
\ No newline at end of file
+        if let Ok(i) = s.parse() {
+            return Some(PsOp::Int(i));
+        }
+        if s.starts_with(|c: char| c.is_ascii_digit() || matches!(c, '+' | '-' | '.')) {
+            return s.parse().ok().map(PsOp::Value);
+        }
+        Some(match s {
+            "true" => PsOp::Bool(true),
+            "false" => PsOp::Bool(false),
+            "add" => PsOp::Add,
+            "sub" => PsOp::Sub,
+            "mul" => PsOp::Mul,
+            "div" => PsOp::Div,
+            "idiv" => PsOp::Idiv,
+            "mod" => PsOp::Mod,
+            "neg" => PsOp::Neg,
+            "abs" => PsOp::Abs,
+            "ceiling" => PsOp::Ceiling,
+            "floor" => PsOp::Floor,
+            "round" => PsOp::Round,
+            "truncate" => PsOp::Truncate,
+            "sqrt" => PsOp::Sqrt,
+            "sin" => PsOp::Sin,
+            "cos" => PsOp::Cos,
+            "atan" => PsOp::Atan,
+            "exp" => PsOp::Exp,
+            "ln" => PsOp::Ln,
+            "log" => PsOp::Log,
+            "cvi" => PsOp::Cvi,
+            "cvr" => PsOp::Cvr,
+            "eq" => PsOp::Eq,
+            "ne" => PsOp::Ne,
+            "gt" => PsOp::Gt,
+            "ge" => PsOp::Ge,
+            "lt" => PsOp::Lt,
+            "le" => PsOp::Le,
+            "and" => PsOp::And,
+            "or" => PsOp::Or,
+            "xor" => PsOp::Xor,
+            "not" => PsOp::Not,
+            "bitshift" => PsOp::Bitshift,
+            "pop" => PsOp::Pop,
+            "dup" => PsOp::Dup,
+            "exch" => PsOp::Exch,
+            "copy" => PsOp::Copy,
+            "index" => PsOp::Index,
+            "roll" => PsOp::Roll,
+            _ => return None
+        })
+    }
+}
+impl fmt::Display for PsFunc {
+    /// Writes the program as a `{}` block.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_block(&self.ops, f)
+    }
+}
+fn write_block(ops: &[PsOp], f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{{")?;
+    for op in ops {
+        write!(f, " {}", op)?;
+    }
+    write!(f, " }}")
+}
+impl fmt::Display for PsOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            // keep a decimal point, so the number is read back as a real
+            PsOp::Value(v) if v.fract() == 0. => return write!(f, "{:.1}", v),
+            PsOp::Value(v) => return write!(f, "{}", v),
+            PsOp::Int(i) => return write!(f, "{}", i),
+            PsOp::Bool(b) => return write!(f, "{}", b),
+            PsOp::If(ref then) => {
+                write_block(then, f)?;
+                return write!(f, " if");
+            }
+            PsOp::IfElse(ref then, ref otherwise) => {
+                write_block(then, f)?;
+                write!(f, " ")?;
+                write_block(otherwise, f)?;
+                return write!(f, " ifelse");
+            }
+            PsOp::Add => "add",
+            PsOp::Sub => "sub",
+            PsOp::Mul => "mul",
+            PsOp::Div => "div",
+            PsOp::Idiv => "idiv",
+            PsOp::Mod => "mod",
+            PsOp::Neg => "neg",
+            PsOp::Abs => "abs",
+            PsOp::Ceiling => "ceiling",
+            PsOp::Floor => "floor",
+            PsOp::Round => "round",
+            PsOp::Truncate => "truncate",
+            PsOp::Sqrt => "sqrt",
+            PsOp::Sin => "sin",
+            PsOp::Cos => "cos",
+            PsOp::Atan => "atan",
+            PsOp::Exp => "exp",
+            PsOp::Ln => "ln",
+            PsOp::Log => "log",
+            PsOp::Cvi => "cvi",
+            PsOp::Cvr => "cvr",
+            PsOp::Eq => "eq",
+            PsOp::Ne => "ne",
+            PsOp::Gt => "gt",
+            PsOp::Ge => "ge",
+            PsOp::Lt => "lt",
+            PsOp::Le => "le",
+            PsOp::And => "and",
+            PsOp::Or => "or",
+            PsOp::Xor => "xor",
+            PsOp::Not => "not",
+            PsOp::Bitshift => "bitshift",
+            PsOp::Pop => "pop",
+            PsOp::Dup => "dup",
+            PsOp::Exch => "exch",
+            PsOp::Copy => "copy",
+            PsOp::Index => "index",
+            PsOp::Roll => "roll",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled(dict: &str, data: &[u8]) -> Function {
+        let info = crate::parser::parse(dict.as_bytes(), &NoResolve).unwrap().into_dictionary(&NoResolve).unwrap();
+        let stream = PdfStream { info, data: data.to_vec() };
+        Function::from_primitive(Primitive::Stream(stream), &NoResolve).unwrap()
+    }
+
+    #[test]
+    fn sampled_function() {
+        // a tint transform from white to blue
+        let tint = sampled("<< /FunctionType 0 /Domain [0 1] /Range [0 1 0 1 0 1] /Size [2] /BitsPerSample 8 /Length 6 >>", &[255, 255, 255, 0, 0, 255]);
+        let mut out = [0.; 3];
+        tint.apply(&[0.25], &mut out).unwrap();
+        assert_eq!(out, [0.75, 0.75, 1.]);
+        tint.apply(&[2.], &mut out).unwrap();
+        assert_eq!(out, [0., 0., 1.]);
+
+        // written as a stream, as `Updater::create` needs it
+        let written = Function::from_primitive(tint.to_primitive(&mut NoUpdate).unwrap(), &NoResolve).unwrap();
+        written.apply(&[0.25], &mut out).unwrap();
+        assert_eq!(out, [0.75, 0.75, 1.]);
+
+        // 12 bit samples with Decode
+        let f = sampled("<< /FunctionType 0 /Domain [0 1] /Range [0 10] /Decode [10 0] /Size [3] /BitsPerSample 12 /Length 5 >>", &[0x00, 0x0F, 0xFF, 0xFF, 0xF0]);
+        let mut out = [0.];
+        f.apply(&[0.25], &mut out).unwrap();
+        assert_eq!(out, [5.]);
+        f.apply(&[0.75], &mut out).unwrap();
+        assert_eq!(out, [0.]);
+
+        // bilinear interpolation of two inputs, and the cubic spline, which keeps lines straight
+        let dict = |order| {
+            let info = format!("<< /FunctionType 0 /Domain [0 1 0 1] /Range [0 1] /Size [2 4] /BitsPerSample 8 /Order {} /Length 8 >>", order);
+            sampled(&info, &[0, 17, 34, 51, 68, 85, 102, 119])
+        };
+        let (x, y) = (0.6, 0.5);
+        let expected = (17. * x + 34. * y * 3.) / 255.;
+        for order in [1, 3] {
+            let f = match dict(order) {
+                Function::Sampled(f) => f,
+                f => panic!("{:?}", f)
+            };
+            let mut out = [0.];
+            f.apply(&[x, y], &mut out).unwrap();
+            assert!((out[0] - expected).abs() < 1e-5, "{} != {}", out[0], expected);
+        }
+
+        let short = "<< /FunctionType 0 /Domain [0 1] /Range [0 1] /Size [9] /BitsPerSample 8 /Length 2 >>";
+        let info = crate::parser::parse(short.as_bytes(), &NoResolve).unwrap().into_dictionary(&NoResolve).unwrap();
+        let stream = PdfStream { info, data: vec![0, 1] };
+        assert!(Function::from_primitive(Primitive::Stream(stream), &NoResolve).is_err());
+    }
+
+    /// Resolves every reference to the same object.
+    struct StreamResolve(Primitive);
+    impl Resolve for StreamResolve {
+        fn resolve(&self, _: PlainRef) -> Result<Primitive> {
+            Ok(self.0.clone())
+        }
+        fn get<T: Object>(&self, _: Ref<T>) -> Result<RcRef<T>> {
+            Err(PdfError::Reference)
+        }
+    }
+
+    fn function(dict: &str) -> Function {
+        let p = crate::parser::parse(dict.as_bytes(), &NoResolve).unwrap();
+        Function::from_primitive(p, &NoResolve).unwrap()
+    }
+
+    #[test]
+    fn stitching_function() {
+        // black to red on [0 0.5], then red to yellow on [0.5 1], the second part reversed by Encode
+        let f = function("<< /FunctionType 3 /Domain [0 1] /Bounds [0.5] /Encode [0 1 1 0] /Functions [
+            << /FunctionType 2 /Domain [0 1] /C0 [0 0 0] /C1 [1 0 0] /N 1 >>
+            << /FunctionType 2 /Domain [0 1] /C0 [1 1 0] /C1 [1 0 0] /N 1 >>
+        ] >>");
+        let mut out = [0.; 3];
+        for &(x, expected) in &[(-1., [0., 0., 0.]), (0.25, [0.5, 0., 0.]), (0.5, [1., 0., 0.]), (0.75, [1., 0.5, 0.]), (2., [1., 1., 0.])] {
+            f.apply(&[x], &mut out).unwrap();
+            assert_eq!(out, expected, "f({})", x);
+        }
+        assert!(f.apply(&[0., 0.], &mut out).is_err());
+
+        let leaf = "<< /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [1] /N 1 >>";
+        let nested = |depth: usize| (0 .. depth).fold(leaf.to_string(), |f, _| {
+            format!("<< /FunctionType 3 /Domain [0 1] /Bounds [] /Encode [0 1] /Functions [{}] >>", f)
+        });
+        let p = crate::parser::parse(nested(MAX_NESTING).as_bytes(), &NoResolve).unwrap();
+        assert!(Function::from_primitive(p, &NoResolve).is_ok());
+        let p = crate::parser::parse(nested(MAX_NESTING + 1).as_bytes(), &NoResolve).unwrap();
+        assert!(Function::from_primitive(p, &NoResolve).is_err());
+
+        // functions that are streams keep their reference, and can't be written inline
+        let tint = "<< /FunctionType 0 /Domain [0 1] /Range [0 1] /Size [2] /BitsPerSample 8 /Length 2 >>";
+        let info = crate::parser::parse(tint.as_bytes(), &NoResolve).unwrap().into_dictionary(&NoResolve).unwrap();
+        let stream = Primitive::Stream(PdfStream { info, data: vec![0, 255] });
+        let f = "<< /FunctionType 3 /Domain [0 1] /Bounds [] /Encode [0 1] /Functions [5 0 R] >>";
+        let f = Function::from_primitive(crate::parser::parse(f.as_bytes(), &NoResolve).unwrap(), &StreamResolve(stream)).unwrap();
+        let written = f.to_primitive(&mut NoUpdate).unwrap().into_dictionary(&NoResolve).unwrap();
+        assert!(matches!(written["Functions"], Primitive::Array(ref fs) if matches!(fs[..], [Primitive::Reference(r)] if r.id == 5)));
+        let mut f = match f {
+            Function::Stitching(f) => f,
+            f => panic!("{:?}", f)
+        };
+        f.functions[0] = MaybeRef::Direct(Rc::new((*f.functions[0]).clone()));
+        assert!(Function::Stitching(f).to_primitive(&mut NoUpdate).is_err());
+
+        // Domain and Range clipping of two inputs
+        let f = Function::PostScript {
+            func: PsFunc::parse("{ add }").unwrap(),
+            domain: vec![0., 1., 0., 2.],
+            range: vec![0., 2.5],
+        };
+        let mut out = [0.];
+        f.apply(&[0.5, 1.], &mut out).unwrap();
+        assert_eq!(out, [1.5]);
+        f.apply(&[-3., 1.], &mut out).unwrap();
+        assert_eq!(out, [1.]);
+        f.apply(&[1., 5.], &mut out).unwrap();
+        assert_eq!(out, [2.5]);
+    }
+
+    fn ps(program: &str, input: &[f32], n: usize) -> Result<Vec<f32>> {
+        let func = PsFunc::parse(program)?;
+        let mut out = vec![0.; n];
+        func.exec(input, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn postscript_function() {
+        assert_eq!(ps("{ 2 copy add 3 1 roll sub }", &[5., 3.], 2).unwrap(), [8., 2.]);
+        assert_eq!(ps("{ 7 2 idiv -7 2 mod 2.5 cvi 3 cvr 2 3 exp }", &[], 5).unwrap(), [3., -1., 2., 3., 8.]);
+        assert_eq!(ps("{ -2.5 round -2.5 truncate 2.5 ceiling -2.5 floor 5 neg abs }", &[], 5).unwrap(), [-2., -2., 3., -3., 5.]);
+        assert_eq!(ps("{ 90 sin 0 cos 1 -1 atan 100 log 16 sqrt }", &[], 5).unwrap(), [1., 1., 135., 2., 4.]);
+        assert_eq!(ps("{ 1 4 bitshift 256 -4 bitshift 12 10 and 12 10 xor 0 not }", &[], 5).unwrap(), [16., 16., 8., 6., -1.]);
+        assert_eq!(ps("{ 1 2 3 2 index 1 index pop pop pop exch pop }", &[], 1).unwrap(), [2.]);
+
+        // a tint transform with nested conditionals and no spaces around the blocks
+        let program = "{dup 0.5 gt{pop 1}{dup 0.25 lt{2 mul}{pop 0.5}ifelse}ifelse % comment
+            dup 1 eq 1 1.0 eq and { 0 } { 1 } ifelse }";
+        assert_eq!(ps(program, &[0.75], 2).unwrap(), [1., 0.]);
+        assert_eq!(ps(program, &[0.1], 2).unwrap(), [0.2, 1.]);
+        assert_eq!(ps(program, &[0.3], 2).unwrap(), [0.5, 1.]);
+
+        // written as a stream, as `Updater::create` needs it
+        let f = Function::PostScript { func: PsFunc::parse(program).unwrap(), domain: vec![0., 1.], range: vec![0., 1., 0., 1.] };
+        let written = Function::from_primitive(f.to_primitive(&mut NoUpdate).unwrap(), &NoResolve).unwrap();
+        let mut out = [0.; 2];
+        written.apply(&[0.75], &mut out).unwrap();
+        assert_eq!(out, [1., 0.]);
+
+        // errors
+        assert!(ps("{ add }", &[1.], 1).is_err());
+        assert!(ps("{ 1 0 div }", &[], 1).is_err());
+        assert!(ps("{ true 1 add }", &[], 1).is_err());
+        assert!(ps("{ -1 sqrt }", &[], 1).is_err());
+        assert!(ps("{ 1 }", &[], 2).is_err());
+        assert!(ps("{ 1 2 lt }", &[], 1).is_err());
+        assert!(ps("{ 0 1 99 { dup } repeat }", &[], 1).is_err());
+        let overflow = format!("{{ {} }}", "1 ".repeat(101));
+        assert!(ps(&overflow, &[], 101).is_err());
+        assert!(ps("{ 1 { 2 } }", &[], 1).is_err());
+        assert!(ps("{ 1 { 2 } if", &[], 1).is_err());
+        assert!(ps("{ 1 } 2", &[], 1).is_err());
+    }
+}
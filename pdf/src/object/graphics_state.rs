@@ -0,0 +1,458 @@
+//! The typed entries of `GraphicsStateParameters` (`ExtGState`).
+
+use crate as pdf;
+use crate::object::*;
+use crate::primitive::*;
+use crate::error::*;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// `D`: the dash array and the phase, like the `d` operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashPattern {
+    pub pattern: Vec<f32>,
+    pub phase: f32,
+}
+impl Object for DashPattern {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let mut arr = p.into_array(resolve)?.into_iter();
+        match (arr.next(), arr.next(), arr.next()) {
+            (Some(pattern), Some(phase), None) => Ok(DashPattern {
+                pattern: Vec::from_primitive(pattern, resolve)?,
+                phase: phase.as_number()?,
+            }),
+            _ => bail!("dash pattern is not an array of the dash array and phase")
+        }
+    }
+}
+impl ObjectWrite for DashPattern {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        Ok(Primitive::Array(vec![self.pattern.to_primitive(update)?, Primitive::Number(self.phase)]))
+    }
+}
+
+/// `BM`: the blend mode used by the transparent imaging model.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    /// Same as `Normal`, only found in old files.
+    Compatible,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+impl BlendMode {
+    const NAMES: [(&'static str, BlendMode); 17] = [
+        ("Normal", BlendMode::Normal),
+        ("Compatible", BlendMode::Compatible),
+        ("Multiply", BlendMode::Multiply),
+        ("Screen", BlendMode::Screen),
+        ("Overlay", BlendMode::Overlay),
+        ("Darken", BlendMode::Darken),
+        ("Lighten", BlendMode::Lighten),
+        ("ColorDodge", BlendMode::ColorDodge),
+        ("ColorBurn", BlendMode::ColorBurn),
+        ("HardLight", BlendMode::HardLight),
+        ("SoftLight", BlendMode::SoftLight),
+        ("Difference", BlendMode::Difference),
+        ("Exclusion", BlendMode::Exclusion),
+        ("Hue", BlendMode::Hue),
+        ("Saturation", BlendMode::Saturation),
+        ("Color", BlendMode::Color),
+        ("Luminosity", BlendMode::Luminosity),
+    ];
+    pub fn from_name(name: &str) -> Option<BlendMode> {
+        BlendMode::NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, mode)| mode)
+    }
+    pub fn name(self) -> &'static str {
+        BlendMode::NAMES.iter().find(|&&(_, mode)| mode == self).unwrap().0
+    }
+    /// Whether the colour components are blended separately.
+    pub fn is_separable(self) -> bool {
+        !matches!(self, BlendMode::Hue | BlendMode::Saturation | BlendMode::Color | BlendMode::Luminosity)
+    }
+}
+/// `BM`: blend modes in the order of preference. Names that are not known are kept, so they
+/// are written back as they were read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlendModes(pub Vec<String>);
+impl BlendModes {
+    /// The first blend mode that is known, or `Normal` if there is none.
+    pub fn mode(&self) -> BlendMode {
+        self.0.iter().find_map(|name| BlendMode::from_name(name)).unwrap_or(BlendMode::Normal)
+    }
+}
+impl From<BlendMode> for BlendModes {
+    fn from(mode: BlendMode) -> BlendModes {
+        BlendModes(vec![mode.name().into()])
+    }
+}
+impl Object for BlendModes {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        Ok(BlendModes(Vec::from_primitive(p, resolve)?))
+    }
+}
+impl ObjectWrite for BlendModes {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match self.0.len() {
+            1 => Ok(Primitive::Name(self.0[0].clone())),
+            _ => self.0.to_primitive(update)
+        }
+    }
+}
+
+/// A transfer function (`TR`, `TR2`, `TransferFunction`), or the functions of `BG2` and `UCR2`.
+#[derive(Debug, Clone)]
+pub enum TransferFunction {
+    /// `/Identity`
+    Identity,
+    /// `/Default`, the device's default (only in `TR2`, `BG2` and `UCR2`).
+    Default,
+    /// One function for all colour components.
+    Single(MaybeRef<Function>),
+    /// A function for each of the four device colorants.
+    Multiple(Vec<MaybeRef<Function>>),
+}
+impl TransferFunction {
+    /// Evaluate the function for colour component `component`.
+    pub fn apply(&self, component: usize, x: f32) -> Result<f32> {
+        let f = match *self {
+            TransferFunction::Identity | TransferFunction::Default => return Ok(x),
+            TransferFunction::Single(ref f) => f,
+            TransferFunction::Multiple(ref fs) => match fs.get(component) {
+                Some(f) => f,
+                None => bail!("no transfer function for component {}", component)
+            }
+        };
+        let mut out = [0.];
+        f.apply(&[x], &mut out)?;
+        Ok(out[0])
+    }
+}
+impl Object for TransferFunction {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        match p {
+            Primitive::Reference(r) => Self::from_primitive(resolve.resolve(r)?, resolve),
+            Primitive::Name(ref name) if name == "Identity" => Ok(TransferFunction::Identity),
+            Primitive::Name(ref name) if name == "Default" => Ok(TransferFunction::Default),
+            Primitive::Array(parts) => {
+                let functions = parts.into_iter()
+                    .map(|p| MaybeRef::from_primitive(p, resolve))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(TransferFunction::Multiple(functions))
+            }
+            p => Ok(TransferFunction::Single(MaybeRef::from_primitive(p, resolve)?))
+        }
+    }
+}
+impl ObjectWrite for TransferFunction {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            TransferFunction::Identity => Ok(Primitive::Name("Identity".into())),
+            TransferFunction::Default => Ok(Primitive::Name("Default".into())),
+            TransferFunction::Single(ref f) => f.to_primitive(update),
+            TransferFunction::Multiple(ref fs) => fs.to_primitive(update),
+        }
+    }
+}
+
+#[derive(Object, ObjectWrite, Debug, Copy, Clone, PartialEq)]
+pub enum SoftMaskType {
+    /// The mask is the alpha of the group.
+    Alpha,
+    /// The mask is the luminosity of the group, composited over the backdrop.
+    Luminosity,
+}
+
+/// A soft mask dictionary.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+#[pdf(Type="Mask?")]
+pub struct SoftMask {
+    #[pdf(key="S")]
+    pub subtype: SoftMaskType,
+
+    /// The transparency group XObject that is painted to produce the mask.
+    #[pdf(key="G")]
+    pub group: Ref<XObject>,
+
+    /// The backdrop colour of a `Luminosity` mask, in the colour space of the group.
+    #[pdf(key="BC")]
+    pub backdrop: Option<Vec<f32>>,
+
+    /// Maps the alpha or luminosity to the mask value.
+    #[pdf(key="TR")]
+    pub transfer: Option<TransferFunction>,
+}
+
+/// `SMask`: a soft mask, or `/None` to remove the current one.
+#[derive(Debug, Clone)]
+pub enum SoftMaskEntry {
+    None,
+    Mask(SoftMask),
+}
+impl Object for SoftMaskEntry {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        match p {
+            Primitive::Name(ref name) if name == "None" => Ok(SoftMaskEntry::None),
+            p => Ok(SoftMaskEntry::Mask(SoftMask::from_primitive(p, resolve)?))
+        }
+    }
+}
+impl ObjectWrite for SoftMaskEntry {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            SoftMaskEntry::None => Ok(Primitive::Name("None".into())),
+            SoftMaskEntry::Mask(ref mask) => mask.to_primitive(update),
+        }
+    }
+}
+
+/// The spot function of a screen halftone.
+#[derive(Debug, Clone)]
+pub enum SpotFunction {
+    /// Predefined spot functions, like `Round`. The first one that is known is used.
+    Predefined(Vec<String>),
+    Function(MaybeRef<Function>),
+}
+impl Object for SpotFunction {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        match p {
+            Primitive::Reference(r) => Self::from_primitive(resolve.resolve(r)?, resolve),
+            Primitive::Name(name) => Ok(SpotFunction::Predefined(vec![name])),
+            p @ Primitive::Array(_) => Ok(SpotFunction::Predefined(Vec::from_primitive(p, resolve)?)),
+            p => Ok(SpotFunction::Function(MaybeRef::from_primitive(p, resolve)?))
+        }
+    }
+}
+impl ObjectWrite for SpotFunction {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            SpotFunction::Predefined(ref names) if names.len() == 1 => Ok(Primitive::Name(names[0].clone())),
+            SpotFunction::Predefined(ref names) => names.to_primitive(update),
+            SpotFunction::Function(ref f) => f.to_primitive(update),
+        }
+    }
+}
+
+/// A halftone screen given by its frequency, angle and spot function (`HalftoneType` 1).
+#[derive(Object, ObjectWrite, Debug, Clone)]
+#[pdf(Type="Halftone?")]
+pub struct ScreenHalftone {
+    #[pdf(key="HalftoneName")]
+    pub name: Option<PdfString>,
+
+    /// Screen frequency, in halftone cells per inch.
+    #[pdf(key="Frequency")]
+    pub frequency: f32,
+
+    /// Screen angle, in degrees.
+    #[pdf(key="Angle")]
+    pub angle: f32,
+
+    #[pdf(key="SpotFunction")]
+    pub spot_function: SpotFunction,
+
+    #[pdf(key="AccurateScreens", default="false")]
+    pub accurate_screens: bool,
+
+    #[pdf(key="TransferFunction")]
+    pub transfer: Option<TransferFunction>,
+}
+
+/// The dictionary of a threshold array halftone stream (`HalftoneType` 6, 10 or 16).
+#[derive(Object, ObjectWrite, Debug, Clone)]
+#[pdf(Type="Halftone?")]
+pub struct ThresholdHalftone {
+    #[pdf(key="HalftoneType")]
+    pub halftone_type: i32,
+
+    #[pdf(key="HalftoneName")]
+    pub name: Option<PdfString>,
+
+    /// Types 6 and 16
+    #[pdf(key="Width")]
+    pub width: Option<u32>,
+
+    /// Types 6 and 16
+    #[pdf(key="Height")]
+    pub height: Option<u32>,
+
+    /// Type 16: the size of the second rectangle.
+    #[pdf(key="Width2")]
+    pub width2: Option<u32>,
+
+    /// Type 16: the size of the second rectangle.
+    #[pdf(key="Height2")]
+    pub height2: Option<u32>,
+
+    /// Type 10: the sides of the two squares.
+    #[pdf(key="Xsquare")]
+    pub x_square: Option<u32>,
+
+    /// Type 10: the sides of the two squares.
+    #[pdf(key="Ysquare")]
+    pub y_square: Option<u32>,
+
+    #[pdf(key="TransferFunction")]
+    pub transfer: Option<TransferFunction>,
+}
+
+/// `HT`: the halftone screen.
+#[derive(Debug, Clone)]
+pub enum Halftone {
+    /// `/Default`, the device's default halftone.
+    Default,
+    /// Type 1
+    Screen(Box<ScreenHalftone>),
+    /// Types 6, 10 and 16, which are streams and so are held by reference.
+    Threshold(MaybeRef<Stream<ThresholdHalftone>>),
+    /// Type 5: a halftone for each colorant, and a default for the other ones.
+    Multiple {
+        default: Box<Halftone>,
+        colorants: HashMap<String, Halftone>,
+    },
+}
+/// Halftones are read through at most this many references.
+const MAX_HALFTONE_REFERENCES: usize = 32;
+
+impl Halftone {
+    /// Read a halftone after following `depth` references. The halftones of a type 5 halftone
+    /// are `component`s, which can not be of type 5 themselves.
+    fn from_primitive_nested(p: Primitive, resolve: &impl Resolve, depth: usize, component: bool) -> Result<Self> {
+        match p {
+            Primitive::Reference(r) => {
+                if depth >= MAX_HALFTONE_REFERENCES {
+                    bail!("halftone nested deeper than {} references", MAX_HALFTONE_REFERENCES);
+                }
+                match resolve.resolve(r)? {
+                    Primitive::Stream(s) => {
+                        let stream = Self::threshold(s, resolve)?;
+                        Ok(Halftone::Threshold(MaybeRef::Indirect(RcRef::new(r, Rc::new(stream)))))
+                    }
+                    p => Self::from_primitive_nested(p, resolve, depth + 1, component)
+                }
+            }
+            Primitive::Name(ref name) if name == "Default" => Ok(Halftone::Default),
+            Primitive::Stream(s) => Ok(Halftone::Threshold(MaybeRef::Direct(Rc::new(Self::threshold(s, resolve)?)))),
+            Primitive::Dictionary(mut dict) => {
+                let halftone_type = dict.get("HalftoneType")
+                    .ok_or(PdfError::MissingEntry { typ: "Halftone", field: "HalftoneType".into() })?
+                    .as_integer()?;
+                match halftone_type {
+                    1 => Ok(Halftone::Screen(Box::new(t!(ScreenHalftone::from_dict(dict, resolve))))),
+                    5 if component => bail!("type 5 halftone inside a type 5 halftone"),
+                    5 => {
+                        for key in ["Type", "HalftoneType", "HalftoneName"] {
+                            dict.remove(key);
+                        }
+                        let default = dict.require("Halftone", "Default")?;
+                        let default = t!(Halftone::from_primitive_nested(default, resolve, depth, true));
+                        let colorants = dict.into_iter()
+                            .map(|(name, p)| Ok((name, t!(Halftone::from_primitive_nested(p, resolve, depth, true)))))
+                            .collect::<Result<_>>()?;
+                        Ok(Halftone::Multiple { default: Box::new(default), colorants })
+                    }
+                    t => bail!("invalid halftone type {}", t)
+                }
+            }
+            p => Err(PdfError::UnexpectedPrimitive { expected: "Dictionary", found: p.get_debug_name() })
+        }
+    }
+}
+impl Halftone {
+    fn threshold(s: PdfStream, resolve: &impl Resolve) -> Result<Stream<ThresholdHalftone>> {
+        let stream = t!(Stream::<ThresholdHalftone>::from_stream(s, resolve));
+        match stream.info.halftone_type {
+            6 | 10 | 16 => Ok(stream),
+            t => bail!("halftone stream of type {}", t)
+        }
+    }
+}
+impl Object for Halftone {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        Halftone::from_primitive_nested(p, resolve, 0, false)
+    }
+}
+impl ObjectWrite for Halftone {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            Halftone::Default => Ok(Primitive::Name("Default".into())),
+            Halftone::Screen(ref screen) => {
+                let mut dict = screen.to_dict(update)?;
+                dict.insert("HalftoneType", Primitive::Integer(1));
+                Ok(Primitive::Dictionary(dict))
+            }
+            Halftone::Threshold(ref stream) => stream.to_primitive(update),
+            Halftone::Multiple { ref default, ref colorants } => {
+                let mut dict = Dictionary::new();
+                dict.insert("Type", Primitive::Name("Halftone".into()));
+                dict.insert("HalftoneType", Primitive::Integer(5));
+                dict.insert("Default", default.to_primitive(update)?);
+                for (name, halftone) in colorants {
+                    dict.insert(name.clone(), halftone.to_primitive(update)?);
+                }
+                Ok(Primitive::Dictionary(dict))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolves every reference to the same object.
+    struct SelfResolve(Primitive);
+    impl Resolve for SelfResolve {
+        fn resolve(&self, _: PlainRef) -> Result<Primitive> {
+            Ok(self.0.clone())
+        }
+        fn get<T: Object>(&self, _: Ref<T>) -> Result<RcRef<T>> {
+            Err(PdfError::Reference)
+        }
+    }
+
+    #[test]
+    fn nested_halftones() {
+        let parse = |s: &str| crate::parser::parse(s.as_bytes(), &NoResolve).unwrap();
+        let screen = "<< /HalftoneType 1 /Frequency 60 /Angle 45 /SpotFunction /Round >>";
+        let multiple = Halftone::from_primitive(parse(&format!("<< /HalftoneType 5 /Default {} >>", screen)), &NoResolve).unwrap();
+        assert!(matches!(multiple, Halftone::Multiple { .. }));
+
+        let nested = format!("<< /HalftoneType 5 /Default << /HalftoneType 5 /Default {} >> >>", screen);
+        assert!(Halftone::from_primitive(parse(&nested), &NoResolve).is_err());
+
+        // 5 0 obj << /HalftoneType 5 /Default 5 0 R >>
+        let cycle = parse("<< /HalftoneType 5 /Default 5 0 R >>");
+        assert!(Halftone::from_primitive(parse("5 0 R"), &SelfResolve(cycle)).is_err());
+        // 5 0 obj 5 0 R
+        assert!(Halftone::from_primitive(parse("5 0 R"), &SelfResolve(parse("5 0 R"))).is_err());
+    }
+
+    #[test]
+    fn blend_modes() {
+        let parse = |s: &str| crate::parser::parse(s.as_bytes(), &NoResolve).unwrap();
+        let modes = BlendModes::from_primitive(parse("[/Unknown /Multiply]"), &NoResolve).unwrap();
+        assert_eq!(modes.mode(), BlendMode::Multiply);
+        match modes.to_primitive(&mut NoUpdate).unwrap() {
+            Primitive::Array(ref names) => assert_eq!(names.len(), 2),
+            p => panic!("{:?}", p)
+        }
+        let unknown = BlendModes::from_primitive(parse("/Unknown"), &NoResolve).unwrap();
+        assert_eq!(unknown.mode(), BlendMode::Normal);
+        assert!(matches!(unknown.to_primitive(&mut NoUpdate).unwrap(), Primitive::Name(ref name) if name == "Unknown"));
+    }
+}
@@ -0,0 +1,823 @@
+//! Decoding image XObjects and inline images to pixels, and creating them from PNG and JPEG files.
+
+use crate::object::*;
+use crate::error::*;
+use crate::enc::{self, sample, set_sample, StreamFilter, LZWFlateParams, DCTDecodeParams, DCTEncoding, OutputLimit};
+
+use std::borrow::Cow;
+
+/// The layout of the pixels of a `RasterImage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One byte per pixel.
+    Gray,
+    /// Four bytes per pixel: red, green, blue and (not premultiplied) alpha.
+    Rgba,
+}
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgba => 4,
+        }
+    }
+}
+
+/// How `ImageXObject::resample` compresses the new samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageCompression {
+    /// `FlateDecode` with PNG predictors, which is lossless.
+    Flate,
+    /// `DCTDecode` with a JPEG quality from 1 to 100. Images that can't be stored as JPEG use `Flate`.
+    Dct { quality: u8 },
+}
+
+/// A decoded image of `width` by `height` pixels, row by row from the top.
+#[derive(Debug, Clone)]
+pub struct RasterImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub data: Vec<u8>,
+}
+
+impl ImageXObject {
+    /// Decode the image to 8 bit pixels.
+    ///
+    /// Images in a gray colour space without any transparency give `Gray` pixels, all others
+    /// `Rgba`, with the alpha channel taken from `Mask`, `SMask` or the JPEG 2000 data.
+    /// `ImageMask` stencils give `Gray` pixels that are 255 where the fill colour is painted
+    /// and 0 elsewhere.
+    ///
+    /// `resources` are used to look up colour spaces of inline images given by name.
+    pub fn raster(&self, resolve: &impl Resolve, resources: Option<&Resources>) -> Result<RasterImage> {
+        self.raster_with_masks(resolve, resources, true)
+    }
+
+    /// Like `raster`, but a `Mask` or `SMask` is only applied if `masks` is set.
+    fn raster_with_masks(&self, resolve: &impl Resolve, resources: Option<&Resources>, masks: bool) -> Result<RasterImage> {
+        let (width, height) = (self.width, self.height);
+        if width <= 0 || height <= 0 {
+            bail!("invalid image size {}x{}", width, height);
+        }
+        let (width, height) = (width as usize, height as usize);
+        let pixels = match width.checked_mul(height) {
+            Some(n) if n.checked_mul(4).is_some() => n,
+            _ => bail!("image too large ({}x{})", width, height)
+        };
+        if let Some(limits) = self.limits() {
+            limits.add_decoded_bytes(pixels * 4)?;
+        }
+
+        if self.image_mask {
+            let data = self.sample_data()?;
+            let samples = Samples::new(&data, width, height, 1, 1)?;
+            let painted = match self.decode.as_deref() {
+                Some([d0, _, ..]) if *d0 == 1.0 => 1,
+                _ => 0
+            };
+            let data = (0 .. pixels)
+                .map(|i| if samples.get(i, 0) == painted { 255 } else { 0 })
+                .collect();
+            return Ok(RasterImage { width: width as u32, height: height as u32, format: PixelFormat::Gray, data });
+        }
+
+        let (data, bpc, jpx) = match self.jpx_image()? {
+            Some(mut image) => {
+                let data = std::mem::take(&mut image.data);
+                (Cow::Owned(data), 8, Some(image))
+            }
+            None => match self.bits_per_component {
+                Some(bpc) => (self.sample_data()?, bpc as usize, None),
+                None => bail!("image without BitsPerComponent")
+            }
+        };
+        match bpc {
+            1 | 2 | 4 | 8 | 16 => {}
+            _ => bail!("invalid BitsPerComponent {}", bpc)
+        }
+
+        let named = match self.color_space {
+            Some(Primitive::Name(ref name)) => resources.and_then(|r| r.color_spaces.get(name)),
+            _ => None
+        };
+        let owned;
+        let cs = match named {
+            Some(cs) => cs,
+            None => {
+                owned = match (self.color_space.clone(), jpx.as_ref()) {
+                    (Some(p), _) => t!(ColorSpace::from_primitive(p, resolve)),
+                    (None, Some(image)) => device_space(image.components)?,
+                    (None, None) => bail!("image without a colour space")
+                };
+                &owned
+            }
+        };
+
+        let n = cs.components()?;
+        let samples = Samples::new(&data, width, height, n, bpc)?;
+        let max = ((1u32 << bpc) - 1) as f32;
+        let decode: Vec<(f32, f32)> = match self.decode {
+            Some(ref d) if d.len() >= 2 * n => d.chunks(2).map(|d| (d[0], d[1])).collect(),
+            _ if matches!(*cs, ColorSpace::Indexed(..)) => vec![(0., max)],
+            _ => cs.component_ranges()?
+        };
+
+        // single component images with up to 8 bits are converted through a table
+        let table = if n == 1 && bpc <= 8 {
+            let (d0, d1) = decode[0];
+            let mut table = Vec::with_capacity(1 << bpc);
+            for s in 0 ..= max as u32 {
+                table.push(cs.to_rgb(&[d0 + s as f32 * (d1 - d0) / max])?);
+            }
+            Some(table)
+        } else {
+            None
+        };
+
+        let color_key = match self.mask {
+            Some(Primitive::Array(ref ranges)) => {
+                let mut keys = Vec::with_capacity(ranges.len());
+                for p in ranges {
+                    match p.as_integer()? {
+                        i @ 0 ..= 0xffff => keys.push(i as u16),
+                        i => bail!("invalid colour key {}", i)
+                    }
+                }
+                if keys.len() < 2 * n {
+                    bail!("colour key mask {:?} does not match {} components", keys, n);
+                }
+                Some(keys)
+            }
+            _ => None
+        };
+        let alpha = match masks {
+            true => self.alpha(resolve, width, height)?,
+            false => None
+        };
+        let alpha = alpha.or_else(|| jpx.and_then(|image| image.alpha));
+
+        if is_gray(cs) && color_key.is_none() && alpha.is_none() {
+            let mut out = Vec::with_capacity(pixels);
+            let mut c = vec![0.; n];
+            for i in 0 .. pixels {
+                let [v, _, _] = match table {
+                    Some(ref table) => table[samples.get(i, 0) as usize],
+                    None => {
+                        let (d0, d1) = decode[0];
+                        c[0] = d0 + samples.get(i, 0) as f32 * (d1 - d0) / max;
+                        cs.to_rgb(&c)?
+                    }
+                };
+                out.push(to_byte(v));
+            }
+            return Ok(RasterImage { width: width as u32, height: height as u32, format: PixelFormat::Gray, data: out });
+        }
+
+        let mut out = Vec::with_capacity(pixels * 4);
+        let mut c = vec![0.; n];
+        let mut raw = vec![0u16; n];
+        for i in 0 .. pixels {
+            for (k, s) in raw.iter_mut().enumerate() {
+                *s = samples.get(i, k);
+            }
+            let color = match table {
+                Some(ref table) => table[raw[0] as usize],
+                None => {
+                    for k in 0 .. n {
+                        let (d0, d1) = decode[k];
+                        c[k] = d0 + raw[k] as f32 * (d1 - d0) / max;
+                    }
+                    cs.to_rgb(&c)?
+                }
+            };
+            let keyed = match color_key {
+                Some(ref ranges) => raw.iter().zip(ranges.chunks(2)).all(|(&s, r)| r[0] <= s && s <= r[1]),
+                None => false
+            };
+            let a = match alpha {
+                _ if keyed => 0,
+                Some(ref alpha) => alpha[i],
+                None => 255
+            };
+            out.extend_from_slice(&[to_byte(color[0]), to_byte(color[1]), to_byte(color[2]), a]);
+        }
+        Ok(RasterImage { width: width as u32, height: height as u32, format: PixelFormat::Rgba, data: out })
+    }
+
+    /// The decoded samples. `CCITTFaxDecode` gives a byte per pixel, which is packed into bits
+    /// here, setting the bits of black pixels only if `BlackIs1` is set.
+    fn sample_data(&self) -> Result<Cow<'_, [u8]>> {
+        let data = self.data()?;
+        match self.active_filters().last() {
+            Some(StreamFilter::CCITTFaxDecode(ref params)) => {
+                let black = params.black_is_1 as u8;
+                let mut packed = Vec::with_capacity(data.len() / 8 + self.height.max(0) as usize);
+                for row in data.chunks(params.columns as usize) {
+                    packed.extend(row.chunks(8).map(|pels| {
+                        pels.iter().enumerate().fold(0, |byte, (i, &p)| {
+                            let bit = if p == 0 { black } else { black ^ 1 };
+                            byte | bit << (7 - i)
+                        })
+                    }));
+                }
+                Ok(Cow::Owned(packed))
+            }
+            _ => Ok(Cow::Borrowed(data))
+        }
+    }
+
+    /// The alpha channel given by a stencil `Mask` or an `SMask`, scaled to `width` by `height`.
+    ///
+    /// Masks of the mask itself are ignored.
+    fn alpha(&self, resolve: &impl Resolve, width: usize, height: usize) -> Result<Option<Vec<u8>>> {
+        let mask = match self.mask {
+            Some(Primitive::Reference(r)) => Some(resolve.resolve(r)?),
+            Some(ref p @ Primitive::Stream(_)) => Some(p.clone()),
+            _ => None
+        };
+        let mask = match mask {
+            Some(p @ Primitive::Stream(_)) => Some(t!(ImageXObject::from_primitive(p, resolve)).raster_with_masks(resolve, None, false)?),
+            _ => None
+        };
+        let mask = match (mask, self.smask) {
+            (Some(mask), _) => mask,
+            (None, Some(smask)) => t!(resolve.get(smask)).raster_with_masks(resolve, None, false)?,
+            (None, None) => return Ok(None)
+        };
+        let stride = mask.format.bytes_per_pixel();
+        let (mask_width, mask_height) = (mask.width as usize, mask.height as usize);
+        let mut alpha = Vec::with_capacity(width * height);
+        for y in 0 .. height {
+            let row = y * mask_height / height * mask_width;
+            for x in 0 .. width {
+                alpha.push(mask.data[(row + x * mask_width / width) * stride]);
+            }
+        }
+        Ok(Some(alpha))
+    }
+}
+
+impl ImageXObject {
+    /// An image holding the JPEG file `data`, which is embedded as is with `DCTDecode`.
+    ///
+    /// Gray, RGB and CMYK images are supported. CMYK data written by Adobe applications is
+    /// stored inverted, which is undone with a `Decode` array.
+    pub fn from_jpeg(data: Vec<u8>) -> Result<ImageXObject> {
+        let info = t!(JpegInfo::parse(&data));
+        let color_space = match info.components {
+            1 => "DeviceGray",
+            3 => "DeviceRGB",
+            4 => "DeviceCMYK",
+            n => bail!("unsupported number of JPEG components: {}", n)
+        };
+        let mut dict = image_dict(info.width as i32, info.height as i32, Primitive::name(color_space), 8);
+        if info.components == 4 && info.adobe {
+            dict.decode = Some(vec![1., 0., 1., 0., 1., 0., 1., 0.]);
+        }
+        Ok(Stream::new_with_filters(dict, data, vec![StreamFilter::DCTDecode(DCTDecodeParams::default())]))
+    }
+
+    /// An image holding the PNG file `data`.
+    ///
+    /// Images without an alpha channel keep the compressed PNG data, with the palette of
+    /// indexed images as an `Indexed` colour space and a `tRNS` colour as a colour key `Mask`.
+    /// An alpha channel (or the `tRNS` alpha values of a palette) is split off into an `SMask`,
+    /// which is created with `update`. Interlaced images are not supported.
+    pub fn from_png(data: &[u8], update: &mut impl Updater) -> Result<ImageXObject> {
+        let png = t!(Png::parse(data));
+        let (width, height, bpc) = (png.width as i32, png.height as i32, png.bit_depth as i32);
+
+        let color_space = match png.color_type {
+            0 | 4 => Primitive::name("DeviceGray"),
+            2 | 6 => Primitive::name("DeviceRGB"),
+            _ => {
+                let palette = match png.palette {
+                    Some(palette) => palette,
+                    None => bail!("indexed PNG image without a palette")
+                };
+                Primitive::Array(vec![
+                    Primitive::name("Indexed"),
+                    Primitive::name("DeviceRGB"),
+                    Primitive::Integer(palette.len() as i32 / 3 - 1),
+                    Primitive::String(PdfString::new(palette.into())),
+                ])
+            }
+        };
+
+        let channels = png.channels();
+        let has_alpha = png.color_type & 4 != 0;
+        let colors = channels - has_alpha as usize;
+        let flate = png_flate(png.width, channels, png.bit_depth);
+        let limit = Some(OutputLimit { max: png.idat_len()?, limit: "PNG image size" });
+
+        let mut dict = image_dict(width, height, color_space, bpc);
+        let image_data = match (has_alpha, png.trns) {
+            (true, _) => {
+                let raw = enc::decode(&png.idat, &flate, limit)?;
+                let bytes = png.bit_depth as usize / 8;
+                let mut color = Vec::with_capacity(raw.len() / channels * colors);
+                let mut alpha = Vec::with_capacity(raw.len() / channels);
+                for pixel in raw.chunks_exact(channels * bytes) {
+                    let (c, a) = pixel.split_at(colors * bytes);
+                    color.extend_from_slice(c);
+                    alpha.extend_from_slice(a);
+                }
+                dict.smask = Some(smask(&alpha, width, height, bpc, update)?);
+                let filter = png_flate(png.width, colors, png.bit_depth);
+                let data = enc::encode(&color, &filter)?;
+                return Ok(Stream::new_with_filters(dict, data, vec![filter]));
+            }
+            (false, Some(trns)) if png.color_type == 3 => {
+                let raw = enc::decode(&png.idat, &flate, limit)?;
+                let stride = (png.width as usize * png.bit_depth as usize).div_ceil(8);
+                let mut alpha = Vec::with_capacity(png.width as usize * png.height as usize);
+                for row in raw.chunks_exact(stride) {
+                    for x in 0 .. png.width as usize {
+                        let index = sample(row, png.bit_depth as usize, x) as usize;
+                        alpha.push(trns.get(index).copied().unwrap_or(255));
+                    }
+                }
+                dict.smask = Some(smask(&alpha, width, height, 8, update)?);
+                png.idat
+            }
+            (false, Some(trns)) => {
+                if trns.len() < colors * 2 {
+                    bail!("PNG tRNS chunk too short");
+                }
+                dict.mask = Some(Primitive::Array(trns[.. colors * 2].chunks_exact(2)
+                    .flat_map(|v| {
+                        let v = Primitive::Integer(u16::from_be_bytes([v[0], v[1]]) as i32);
+                        vec![v.clone(), v]
+                    })
+                    .collect()
+                ));
+                png.idat
+            }
+            (false, None) => png.idat
+        };
+        Ok(Stream::new_with_filters(dict, image_data, vec![flate]))
+    }
+}
+
+impl ImageXObject {
+    /// Resample the image to `width` by `height` pixels and compress it with `compression`.
+    ///
+    /// Each new sample is the average of the samples it covers, with 8 bits per component.
+    /// Indexed images and images with a colour key `Mask` keep their bit depth and take the
+    /// nearest sample instead. All other entries of the image dictionary are kept.
+    pub fn resample(&self, width: u32, height: u32, compression: ImageCompression, resolve: &impl Resolve) -> Result<ImageXObject> {
+        if self.image_mask {
+            bail!("stencil masks can't be resampled");
+        }
+        if let Some(StreamFilter::JPXDecode) = self.active_filters().last() {
+            bail!("JPX images can't be resampled");
+        }
+        if self.width <= 0 || self.height <= 0 || width == 0 || height == 0 {
+            bail!("can't resample a {}x{} image to {}x{}", self.width, self.height, width, height);
+        }
+        let (src_width, src_height) = (self.width as usize, self.height as usize);
+        let (width, height) = (width as usize, height as usize);
+        let cs = match self.color_space {
+            Some(ref p) => t!(ColorSpace::from_primitive(p.clone(), resolve)),
+            None => bail!("image without a colour space")
+        };
+        let n = cs.components()?;
+        let bpc = match self.bits_per_component {
+            Some(bpc) => bpc as usize,
+            None => bail!("image without BitsPerComponent")
+        };
+        match bpc {
+            1 | 2 | 4 | 8 | 16 => {}
+            _ => bail!("invalid BitsPerComponent {}", bpc)
+        }
+        let data = self.sample_data()?;
+        let samples = Samples::new(&data, src_width, src_height, n, bpc)?;
+
+        let nearest = matches!(cs, ColorSpace::Indexed(..)) || matches!(self.mask, Some(Primitive::Array(_)));
+        let out_bpc = if nearest { bpc } else { 8 };
+        let stride = (width * n * out_bpc).div_ceil(8);
+        let mut data = vec![0; stride * height];
+        let max = (1u64 << bpc) - 1;
+        for (y, row) in data.chunks_exact_mut(stride).enumerate() {
+            let (y0, y1) = span(y, height, src_height);
+            for x in 0 .. width {
+                let (x0, x1) = span(x, width, src_width);
+                for k in 0 .. n {
+                    if nearest {
+                        let v = samples.get((y0 + y1) / 2 * src_width + (x0 + x1) / 2, k);
+                        set_sample(row, out_bpc, x * n + k, v);
+                    } else {
+                        let mut sum = 0;
+                        for sy in y0 .. y1 {
+                            for sx in x0 .. x1 {
+                                sum += samples.get(sy * src_width + sx, k) as u64;
+                            }
+                        }
+                        let total = ((y1 - y0) * (x1 - x0)) as u64 * max;
+                        row[x * n + k] = ((sum * 255 + total / 2) / total) as u8;
+                    }
+                }
+            }
+        }
+
+        let jpeg = !nearest && matches!(n, 1 | 3 | 4) && width <= u16::MAX as usize && height <= u16::MAX as usize;
+        let (data, filter) = match compression {
+            ImageCompression::Dct { quality } if jpeg => {
+                let params = DCTDecodeParams::default();
+                let encoding = DCTEncoding { width: width as u16, height: height as u16, components: n as u8, quality };
+                let data = enc::dct_encode(&data, &encoding, &params)?;
+                (data, StreamFilter::DCTDecode(params))
+            }
+            _ => {
+                let filter = png_flate(width as u32, n, out_bpc as u8);
+                (enc::encode(&data, &filter)?, filter)
+            }
+        };
+        let mut dict = self.info.info.clone();
+        dict.width = width as i32;
+        dict.height = height as i32;
+        dict.bits_per_component = Some(out_bpc as i32);
+        Ok(Stream::new_with_filters(dict, data, vec![filter]))
+    }
+}
+
+/// The range of the `len` source samples covered by sample `i` of `new_len`.
+fn span(i: usize, new_len: usize, len: usize) -> (usize, usize) {
+    let start = i * len / new_len;
+    let end = ((i + 1) * len / new_len).max(start + 1);
+    (start, end)
+}
+
+fn image_dict(width: i32, height: i32, color_space: Primitive, bits_per_component: i32) -> ImageDict {
+    ImageDict {
+        width,
+        height,
+        color_space: Some(color_space),
+        bits_per_component: Some(bits_per_component),
+        intent: None,
+        image_mask: false,
+        mask: None,
+        decode: None,
+        interpolate: false,
+        struct_parent: None,
+        id: None,
+        smask: None,
+        smask_in_data: 0,
+        matte: None,
+        other: Dictionary::new(),
+    }
+}
+
+/// `FlateDecode` with the PNG predictors, as used by the `IDAT` data of a PNG file.
+fn png_flate(width: u32, colors: usize, bit_depth: u8) -> StreamFilter {
+    StreamFilter::FlateDecode(LZWFlateParams {
+        predictor: 15,
+        n_components: colors as i32,
+        bits_per_component: bit_depth as i32,
+        columns: width as i32,
+        ..Default::default()
+    })
+}
+
+/// Create a soft mask holding the gray samples `alpha`.
+fn smask(alpha: &[u8], width: i32, height: i32, bpc: i32, update: &mut impl Updater) -> Result<Ref<ImageXObject>> {
+    let filter = png_flate(width as u32, 1, bpc as u8);
+    let data = enc::encode(alpha, &filter)?;
+    let dict = image_dict(width, height, Primitive::name("DeviceGray"), bpc);
+    Ok(update.create(Stream::new_with_filters(dict, data, vec![filter]))?.get_ref())
+}
+
+/// The chunks of a PNG file needed to embed it.
+struct Png<'a> {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+    palette: Option<&'a [u8]>,
+    trns: Option<&'a [u8]>,
+    idat: Vec<u8>,
+}
+impl<'a> Png<'a> {
+    fn parse(data: &'a [u8]) -> Result<Png<'a>> {
+        const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        if !data.starts_with(&SIGNATURE) {
+            bail!("not a PNG file");
+        }
+        let mut header = None;
+        let mut palette = None;
+        let mut trns = None;
+        let mut idat = Vec::new();
+
+        let mut pos = SIGNATURE.len();
+        while pos + 8 <= data.len() {
+            let len = u32::from_be_bytes([data[pos], data[pos+1], data[pos+2], data[pos+3]]) as usize;
+            let kind = &data[pos + 4 .. pos + 8];
+            let body = match data.get(pos + 8 ..).and_then(|rest| rest.get(.. len)) {
+                Some(body) => body,
+                None => bail!("PNG chunk {:?} exceeds the file", String::from_utf8_lossy(kind))
+            };
+            match kind {
+                b"IHDR" if body.len() >= 13 => header = Some(body),
+                b"PLTE" => palette = Some(body),
+                b"tRNS" => trns = Some(body),
+                b"IDAT" => idat.extend_from_slice(body),
+                b"IEND" => break,
+                _ => {}
+            }
+            pos += 12 + len;
+        }
+
+        let header = match header {
+            Some(header) => header,
+            None => bail!("PNG file without IHDR chunk")
+        };
+        let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let (bit_depth, color_type) = (header[8], header[9]);
+        let valid = match color_type {
+            0 => matches!(bit_depth, 1 | 2 | 4 | 8 | 16),
+            3 => matches!(bit_depth, 1 | 2 | 4 | 8),
+            2 | 4 | 6 => matches!(bit_depth, 8 | 16),
+            _ => false
+        };
+        if !valid {
+            bail!("invalid PNG color type {} with bit depth {}", color_type, bit_depth);
+        }
+        if header[10] != 0 || header[11] != 0 {
+            bail!("unknown PNG compression or filter method");
+        }
+        if header[12] != 0 {
+            bail!("interlaced PNG images are not supported");
+        }
+        if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            bail!("invalid PNG image size {}x{}", width, height);
+        }
+        if let Some(palette) = palette {
+            if palette.is_empty() || palette.len() > 256 * 3 || palette.len() % 3 != 0 {
+                bail!("invalid PNG palette of {} bytes", palette.len());
+            }
+        }
+        Ok(Png { width, height, bit_depth, color_type, palette, trns, idat })
+    }
+    /// Samples per pixel in the `IDAT` data.
+    fn channels(&self) -> usize {
+        match self.color_type {
+            0 | 3 => 1,
+            4 => 2,
+            2 => 3,
+            _ => 4
+        }
+    }
+    /// Length of the inflated `IDAT` data: every row with its filter type byte.
+    fn idat_len(&self) -> Result<usize> {
+        let bits = (self.width as usize).checked_mul(self.channels() * self.bit_depth as usize);
+        match bits.and_then(|bits| (bits.div_ceil(8) + 1).checked_mul(self.height as usize)) {
+            Some(len) => Ok(len),
+            None => bail!("PNG image of {}x{} pixels is too large", self.width, self.height)
+        }
+    }
+}
+
+/// The frame header of a JPEG file.
+struct JpegInfo {
+    width: u16,
+    height: u16,
+    components: u8,
+    /// Whether the file has an Adobe `APP14` marker.
+    adobe: bool,
+}
+impl JpegInfo {
+    fn parse(data: &[u8]) -> Result<JpegInfo> {
+        if !data.starts_with(&[0xFF, 0xD8]) {
+            bail!("not a JPEG file");
+        }
+        let mut adobe = false;
+        let mut pos = 2;
+        loop {
+            while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+                pos += 1;
+            }
+            let (marker, len) = match data.get(pos .. pos + 4) {
+                Some(&[0xFF, marker, a, b]) => (marker, u16::from_be_bytes([a, b]) as usize),
+                _ => bail!("invalid JPEG marker at {}", pos)
+            };
+            let segment = match data.get(pos + 4 .. pos + 2 + len) {
+                Some(segment) if len >= 2 => segment,
+                _ => bail!("JPEG segment exceeds the file")
+            };
+            match marker {
+                0xEE if segment.starts_with(b"Adobe") => adobe = true,
+                0xC0 ..= 0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                    if segment.len() < 6 {
+                        bail!("JPEG frame header too short");
+                    }
+                    if segment[0] != 8 {
+                        bail!("unsupported JPEG precision {}", segment[0]);
+                    }
+                    let height = u16::from_be_bytes([segment[1], segment[2]]);
+                    let width = u16::from_be_bytes([segment[3], segment[4]]);
+                    if width == 0 || height == 0 {
+                        bail!("invalid JPEG image size {}x{}", width, height);
+                    }
+                    return Ok(JpegInfo { width, height, components: segment[5], adobe });
+                }
+                0xDA | 0xD9 => bail!("JPEG file without frame header"),
+                _ => {}
+            }
+            pos += 2 + len;
+        }
+    }
+}
+
+/// The samples of an image.
+struct Samples<'a> {
+    data: &'a [u8],
+    stride: usize,
+    width: usize,
+    n: usize,
+    bpc: usize,
+}
+impl<'a> Samples<'a> {
+    /// Fails if `data` is too short for `width` by `height` samples of `n` components.
+    fn new(data: &'a [u8], width: usize, height: usize, n: usize, bpc: usize) -> Result<Samples<'a>> {
+        let stride = width.checked_mul(n).and_then(|s| s.checked_mul(bpc)).map(|bits| bits.div_ceil(8));
+        match stride.and_then(|stride| stride.checked_mul(height)) {
+            Some(len) if len <= data.len() => Ok(Samples { data, stride: stride.unwrap(), width, n, bpc }),
+            _ => bail!("{} bytes of image data are too short for {}x{} samples of {} components", data.len(), width, height, n)
+        }
+    }
+    /// Component `k` of pixel `i`.
+    fn get(&self, i: usize, k: usize) -> u16 {
+        let (y, x) = (i / self.width, i % self.width);
+        let row = &self.data[y * self.stride .. (y + 1) * self.stride];
+        sample(row, self.bpc, x * self.n + k)
+    }
+}
+
+fn to_byte(v: f32) -> u8 {
+    (v.clamp(0., 1.) * 255. + 0.5) as u8
+}
+
+fn is_gray(cs: &ColorSpace) -> bool {
+    match *cs {
+        ColorSpace::DeviceGray | ColorSpace::CalGray(_) => true,
+        ColorSpace::Icc(ref icc) => icc.info.components == 1,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(dict: &str, data: &[u8]) -> ImageXObject {
+        let dict = crate::parser::parse(dict.as_bytes(), &NoResolve).unwrap();
+        Stream::new(ImageDict::from_primitive(dict, &NoResolve).unwrap(), data.to_vec())
+    }
+
+    #[test]
+    fn raster() {
+        let gray = image("<< /Subtype /Image /Width 3 /Height 1 /ColorSpace /DeviceGray /BitsPerComponent 2 /Decode [1 0] >>", &[0b00011011]);
+        let raster = gray.raster(&NoResolve, None).unwrap();
+        assert_eq!(raster.format, PixelFormat::Gray);
+        assert_eq!(raster.data, [255, 170, 85]);
+
+        let indexed = image("<< /Subtype /Image /Width 2 /Height 2 /ColorSpace [/Indexed /DeviceRGB 1 <ff000000ff00>] /BitsPerComponent 1 /Mask [1 1] >>", &[0b01000000, 0b10000000]);
+        let raster = indexed.raster(&NoResolve, None).unwrap();
+        assert_eq!(raster.format, PixelFormat::Rgba);
+        assert_eq!(raster.data, [255, 0, 0, 255, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 255]);
+
+        let cmyk = image("<< /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceCMYK /BitsPerComponent 16 >>", &[0, 0, 0xff, 0xff, 0, 0, 0, 0]);
+        assert_eq!(cmyk.raster(&NoResolve, None).unwrap().data, [255, 0, 255, 255]);
+
+        let mask = image("<< /Subtype /Image /Width 9 /Height 1 /BitsPerComponent 1 /ImageMask true /Decode [1 0] >>", &[0b10000000, 0b10000000]);
+        let raster = mask.raster(&NoResolve, None).unwrap();
+        assert_eq!(raster.data, [255, 0, 0, 0, 0, 0, 0, 0, 255]);
+
+        // CCITT pixels are black if 0; with BlackIs1 the samples of black pixels are 1
+        let pels = [0, 255, 255, 255, 255, 255, 255, 255, 0];
+        for &black_is_1 in &[false, true] {
+            let params = enc::CCITTFaxDecodeParams { k: -1, columns: 9, black_is_1, ..Default::default() };
+            let data = enc::fax_encode(&pels, &params).unwrap();
+            let decode = if black_is_1 { "[1 0]" } else { "[0 1]" };
+            let dict = format!("<< /Subtype /Image /Width 9 /Height 1 /ColorSpace /DeviceGray /BitsPerComponent 1 /Decode {} >>", decode);
+            let dict = ImageDict::from_primitive(crate::parser::parse(dict.as_bytes(), &NoResolve).unwrap(), &NoResolve).unwrap();
+            let fax = Stream::new_with_filters(dict, data, vec![StreamFilter::CCITTFaxDecode(params)]);
+            assert_eq!(fax.raster(&NoResolve, None).unwrap().data, pels);
+        }
+    }
+
+    #[test]
+    fn invalid_samples() {
+        // the size is checked against the data before anything is allocated
+        let huge = image("<< /Subtype /Image /Width 200000 /Height 200000 /ColorSpace /DeviceRGB /BitsPerComponent 8 >>", &[0; 16]);
+        assert!(huge.raster(&NoResolve, None).is_err());
+        let short = image("<< /Subtype /Image /Width 2 /Height 2 /ColorSpace /DeviceGray /BitsPerComponent 8 >>", &[0; 3]);
+        assert!(short.raster(&NoResolve, None).is_err());
+        assert!(short.resample(1, 1, ImageCompression::Flate, &NoResolve).is_err());
+
+        let keyed = |mask| image(&format!("<< /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceGray /BitsPerComponent 8 /Mask {} >>", mask), &[0]);
+        assert!(keyed("[0 0]").raster(&NoResolve, None).is_ok());
+        assert!(keyed("[-65536 0]").raster(&NoResolve, None).is_err());
+        assert!(keyed("[0 65536]").raster(&NoResolve, None).is_err());
+    }
+
+    #[test]
+    fn missing_bits_per_component() {
+        let mask = image("<< /Subtype /Image /Width 9 /Height 1 /ImageMask true >>", &[0b10000000, 0b10000000]);
+        assert_eq!(mask.bits_per_component, None);
+        assert_eq!(mask.raster(&NoResolve, None).unwrap().data, [0, 255, 255, 255, 255, 255, 255, 255, 0]);
+
+        let gray = image("<< /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceGray >>", &[0]);
+        assert!(gray.raster(&NoResolve, None).is_err());
+
+        // the colour space and bit depth of JPX images come from the codestream
+        let dict = crate::parser::parse(b"<< /Subtype /Image /Width 37 /Height 29 >>", &NoResolve).unwrap();
+        let dict = ImageDict::from_primitive(dict, &NoResolve).unwrap();
+        let data = include_bytes!("../data/jpx/rgb_rct.j2k").to_vec();
+        let jpx = Stream::new_with_filters(dict, data, vec![StreamFilter::JPXDecode]);
+        let raster = jpx.raster(&NoResolve, None).unwrap();
+        assert_eq!((raster.width, raster.height, raster.format), (37, 29, PixelFormat::Rgba));
+        assert_eq!(raster.data[.. 4], [0, 50, 100, 255]);
+    }
+
+    fn png(width: u32, height: u32, bit_depth: u8, color_type: u8, chunks: &[(&[u8], &[u8])], raw: &[u8]) -> Vec<u8> {
+        let channels = [1, 0, 3, 1, 2, 0, 4][color_type as usize];
+        let idat = enc::encode(raw, &png_flate(width, channels, bit_depth)).unwrap();
+        let mut header = Vec::new();
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        let chunks = Some((&b"IHDR"[..], &header[..])).into_iter()
+            .chain(chunks.iter().cloned())
+            .chain(Some((&b"IDAT"[..], &idat[..])))
+            .chain(Some((&b"IEND"[..], &[][..])));
+        for (kind, body) in chunks {
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(kind);
+            data.extend_from_slice(body);
+            data.extend_from_slice(&[0; 4]); // the CRC is not checked
+        }
+        data
+    }
+
+    #[test]
+    fn from_png() {
+        let gray = png(3, 1, 16, 0, &[(b"tRNS", &[0x12, 0x34])], &[0, 0, 0x12, 0x34, 0xff, 0xff]);
+        let image = ImageXObject::from_png(&gray, &mut NoUpdate).unwrap();
+        assert_eq!(image.bits_per_component, Some(16));
+        let raster = image.raster(&NoResolve, None).unwrap();
+        assert_eq!(raster.format, PixelFormat::Rgba);
+        assert_eq!(raster.data, [0, 0, 0, 255, 18, 18, 18, 0, 255, 255, 255, 255]);
+
+        let indexed = png(2, 2, 2, 3, &[(b"PLTE", &[255, 0, 0, 0, 0, 255, 9, 9, 9])], &[0b0001_0000, 0b1000_0000]);
+        let image = ImageXObject::from_png(&indexed, &mut NoUpdate).unwrap();
+        let raster = image.raster(&NoResolve, None).unwrap();
+        assert_eq!(raster.data, [255, 0, 0, 255, 0, 0, 255, 255, 9, 9, 9, 255, 255, 0, 0, 255]);
+
+        let palette = [0; 257 * 3];
+        assert!(ImageXObject::from_png(&png(1, 1, 8, 3, &[(b"PLTE", &palette)], &[0]), &mut NoUpdate).is_err());
+        assert!(ImageXObject::from_png(&png(1, 1, 8, 3, &[(b"PLTE", &palette[.. 4])], &[0]), &mut NoUpdate).is_err());
+
+        // IDAT data that inflates to more rows than the header declares
+        let mut long = png(1, 100, 8, 6, &[], &[0; 400]);
+        long[20 .. 24].copy_from_slice(&1u32.to_be_bytes());
+        assert!(ImageXObject::from_png(&long, &mut NoUpdate).unwrap_err().is_limit_exceeded());
+
+        let mut interlaced = png(1, 1, 8, 0, &[], &[0]);
+        interlaced[28] = 1;
+        assert!(ImageXObject::from_png(&interlaced, &mut NoUpdate).is_err());
+    }
+
+    #[test]
+    fn resample() {
+        let gray = image("<< /Subtype /Image /Width 4 /Height 2 /ColorSpace /DeviceGray /BitsPerComponent 4 /Decode [1 0] >>", &[0x0f, 0x55, 0xff, 0x00]);
+        let small = gray.resample(2, 1, ImageCompression::Flate, &NoResolve).unwrap();
+        assert_eq!((small.width, small.height, small.bits_per_component), (2, 1, Some(8)));
+        assert_eq!(small.decode, Some(vec![1., 0.]));
+        assert_eq!(small.data().unwrap(), [191, 43]);
+
+        let indexed = image("<< /Subtype /Image /Width 4 /Height 1 /ColorSpace [/Indexed /DeviceGray 3 <00408000>] /BitsPerComponent 2 >>", &[0b00_01_10_11]);
+        let small = indexed.resample(2, 1, ImageCompression::Dct { quality: 90 }, &NoResolve).unwrap();
+        assert_eq!(small.bits_per_component, Some(2));
+        assert!(matches!(small.filters[..], [StreamFilter::FlateDecode(_)]));
+        assert_eq!(small.data().unwrap(), [0b0111_0000]);
+    }
+
+    #[test]
+    fn from_jpeg() {
+        let rgb: Vec<u8> = (0 .. 16 * 8).flat_map(|_| vec![200, 20, 20]).collect();
+        let encoding = DCTEncoding { width: 16, height: 8, components: 3, quality: 95 };
+        let data = enc::dct_encode(&rgb, &encoding, &DCTDecodeParams::default()).unwrap();
+        let image = ImageXObject::from_jpeg(data).unwrap();
+        assert_eq!((image.width, image.height), (16, 8));
+        assert_eq!(image.color_space.as_ref().unwrap().as_name().unwrap(), "DeviceRGB");
+        let raster = image.raster(&NoResolve, None).unwrap();
+        assert!(raster.data.chunks(4).all(|p| p[0] > 190 && p[1] < 30 && p[2] < 30));
+
+        assert!(ImageXObject::from_jpeg(b"\xff\xd8\xff\xd9".to_vec()).is_err());
+    }
+}
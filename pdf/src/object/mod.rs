@@ -6,12 +6,21 @@ mod types;
 mod stream;
 mod color;
 mod function;
+mod image;
+mod pattern;
+mod graphics_state;
+mod transparency;
 
 pub use self::types::*;
 pub use self::stream::*;
 pub use self::color::*;
 pub use self::function::*;
-pub use crate::file::PromisedRef;
+pub use self::image::*;
+pub use self::pattern::*;
+pub use self::graphics_state::*;
+pub use self::transparency::*;
+pub use crate::file::{PromisedRef, Limits, FileResolver, DirResolver};
+pub use crate::enc::jpx::{JpxImage, JpxColorSpace};
 
 use crate::primitive::*;
 use crate::error::*;
@@ -30,6 +39,11 @@ pub type GenNr = u16;
 pub trait Resolve: {
     fn resolve(&self, r: PlainRef) -> Result<Primitive>;
     fn get<T: Object>(&self, r: Ref<T>) -> Result<RcRef<T>>;
+
+    /// Limits that apply to objects read through this resolver, if any.
+    fn limits(&self) -> Option<&Limits> {
+        None
+    }
 }
 
 pub struct NoResolve;
@@ -240,9 +254,13 @@ impl<T: Object> Object for MaybeRef<T> {
     }
 }
 impl<T: ObjectWrite> ObjectWrite for MaybeRef<T> {
+    /// Fails for `Direct` values that are streams, as streams can only be objects of their own.
     fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
         match self {
-            MaybeRef::Direct(ref inner) => inner.to_primitive(update),
+            MaybeRef::Direct(ref inner) => match inner.to_primitive(update)? {
+                Primitive::Stream(_) => bail!("a stream can't be written inside another object, create it and use MaybeRef::Indirect"),
+                p => Ok(p)
+            },
             MaybeRef::Indirect(r) => r.to_primitive(update)
         }
     }
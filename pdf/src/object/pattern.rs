@@ -0,0 +1,895 @@
+//! Shadings and patterns (section 8.7 of the PDF 1.7 reference).
+
+use crate as pdf;
+use crate::object::*;
+use crate::primitive::*;
+use crate::error::*;
+use crate::content::{Matrix, Point, TilingPattern};
+use crate::enc::{self, StreamFilter, LZWFlateParams};
+
+/// The entries common to all shading dictionaries.
+#[derive(Object, Debug)]
+struct ShadingDict {
+    #[pdf(key="ShadingType")]
+    shading_type: i32,
+
+    #[pdf(key="ColorSpace")]
+    color_space: ColorSpace,
+
+    #[pdf(key="Background")]
+    background: Option<Vec<f32>>,
+
+    #[pdf(key="BBox")]
+    bbox: Option<Rect>,
+
+    #[pdf(key="AntiAlias", default="false")]
+    anti_alias: bool,
+
+    #[pdf(other)]
+    other: Dictionary,
+}
+
+/// A shading, as found in the `/Shading` resources and in shading patterns.
+#[derive(Debug, Clone)]
+pub struct Shading {
+    pub color_space: ColorSpace,
+
+    /// The colour of the area outside the shading, when painted as part of a pattern.
+    pub background: Option<Vec<f32>>,
+
+    /// The shading is clipped to this box, in shading space.
+    pub bbox: Option<Rect>,
+
+    pub anti_alias: bool,
+
+    pub kind: ShadingKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum ShadingKind {
+    /// Type 1
+    Function(FunctionShading),
+    /// Type 2
+    Axial(GradientShading),
+    /// Type 3
+    Radial(GradientShading),
+    /// Type 4
+    FreeForm(MeshShading),
+    /// Type 5
+    Lattice(MeshShading),
+    /// Type 6
+    Coons(MeshShading),
+    /// Type 7
+    Tensor(MeshShading),
+}
+impl ShadingKind {
+    /// The `ShadingType` of this kind of shading.
+    pub fn shading_type(&self) -> i32 {
+        match *self {
+            ShadingKind::Function(_) => 1,
+            ShadingKind::Axial(_) => 2,
+            ShadingKind::Radial(_) => 3,
+            ShadingKind::FreeForm(_) => 4,
+            ShadingKind::Lattice(_) => 5,
+            ShadingKind::Coons(_) => 6,
+            ShadingKind::Tensor(_) => 7,
+        }
+    }
+    fn mesh(&self) -> Option<&MeshShading> {
+        match *self {
+            ShadingKind::FreeForm(ref m) | ShadingKind::Lattice(ref m) |
+            ShadingKind::Coons(ref m) | ShadingKind::Tensor(ref m) => Some(m),
+            _ => None
+        }
+    }
+}
+
+/// Either one function with an output per colour component, or one function per component.
+#[derive(Debug, Clone)]
+pub enum ShadingFunction {
+    Single(MaybeRef<Function>),
+    Multiple(Vec<MaybeRef<Function>>),
+}
+impl Object for ShadingFunction {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        match p {
+            Primitive::Reference(r) => Self::from_primitive(resolve.resolve(r)?, resolve),
+            Primitive::Array(parts) => {
+                let functions = parts.into_iter()
+                    .map(|p| MaybeRef::from_primitive(p, resolve))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(ShadingFunction::Multiple(functions))
+            }
+            p => Ok(ShadingFunction::Single(MaybeRef::from_primitive(p, resolve)?))
+        }
+    }
+}
+impl ObjectWrite for ShadingFunction {
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            ShadingFunction::Single(ref f) => f.to_primitive(update),
+            ShadingFunction::Multiple(ref fs) => fs.to_primitive(update),
+        }
+    }
+}
+impl ShadingFunction {
+    /// Evaluate the function(s) for the inputs `x`, writing a colour component per function
+    /// to `out` in the case of `Multiple`.
+    pub fn apply(&self, x: &[f32], out: &mut [f32]) -> Result<()> {
+        match *self {
+            ShadingFunction::Single(ref f) => f.apply(x, out),
+            ShadingFunction::Multiple(ref fs) => {
+                if fs.len() != out.len() {
+                    bail!("incorrect output length: expected {}, found {}.", fs.len(), out.len())
+                }
+                for (f, y) in fs.iter().zip(out) {
+                    f.apply(x, std::slice::from_mut(y))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Type 1: the colour is a function of the position.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct FunctionShading {
+    /// `[x0 x1 y0 y1]`, defaults to `[0 1 0 1]`.
+    #[pdf(key="Domain")]
+    pub domain: Option<Vec<f32>>,
+
+    /// Maps the domain into shading space.
+    #[pdf(key="Matrix")]
+    pub matrix: Option<Matrix>,
+
+    #[pdf(key="Function")]
+    pub function: ShadingFunction,
+}
+
+/// Types 2 and 3: a blend along an axis or between two circles.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct GradientShading {
+    /// `[x0 y0 x1 y1]` for axial shadings, `[x0 y0 r0 x1 y1 r1]` for radial shadings.
+    #[pdf(key="Coords")]
+    pub coords: Vec<f32>,
+
+    /// `[t0 t1]`, defaults to `[0 1]`.
+    #[pdf(key="Domain")]
+    pub domain: Option<Vec<f32>>,
+
+    #[pdf(key="Function")]
+    pub function: ShadingFunction,
+
+    /// Whether to extend the shading beyond the start and end.
+    #[pdf(key="Extend")]
+    pub extend: Option<Vec<bool>>,
+}
+
+/// Types 4 to 7: triangle meshes and patch meshes.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+pub struct MeshShading {
+    #[pdf(key="BitsPerCoordinate")]
+    pub bits_per_coordinate: u32,
+
+    #[pdf(key="BitsPerComponent")]
+    pub bits_per_component: u32,
+
+    /// Not used by lattice-form meshes (type 5).
+    #[pdf(key="BitsPerFlag")]
+    pub bits_per_flag: Option<u32>,
+
+    /// Only used by lattice-form meshes (type 5).
+    #[pdf(key="VerticesPerRow")]
+    pub vertices_per_row: Option<u32>,
+
+    /// The ranges of the coordinates and colour components (or the parametric value,
+    /// if there is a function).
+    #[pdf(key="Decode")]
+    pub decode: Vec<f32>,
+
+    #[pdf(key="Function")]
+    pub function: Option<ShadingFunction>,
+
+    /// The decoded stream data.
+    #[pdf(skip)]
+    pub data: Vec<u8>,
+}
+
+impl Object for Shading {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let (dict, data) = match p {
+            Primitive::Reference(r) => return Self::from_primitive(resolve.resolve(r)?, resolve),
+            Primitive::Stream(s) => {
+                let dict = s.info.clone();
+                let stream = t!(Stream::<()>::from_stream(s, resolve));
+                (dict, Some(t!(stream.data()).to_vec()))
+            }
+            p => (p.into_dictionary(resolve)?, None)
+        };
+        let ShadingDict { shading_type, color_space, background, bbox, anti_alias, other } = t!(ShadingDict::from_dict(dict, resolve));
+        let kind = match shading_type {
+            1 => ShadingKind::Function(t!(FunctionShading::from_dict(other, resolve))),
+            2 => ShadingKind::Axial(t!(GradientShading::from_dict(other, resolve))),
+            3 => ShadingKind::Radial(t!(GradientShading::from_dict(other, resolve))),
+            4 ..= 7 => {
+                let data = match data {
+                    Some(data) => data,
+                    None => bail!("mesh shading (type {}) is not a stream", shading_type)
+                };
+                let mesh = MeshShading { data, ..t!(MeshShading::from_dict(other, resolve)) };
+                match (shading_type, mesh.bits_per_flag, mesh.vertices_per_row) {
+                    (5, _, None) => bail!("lattice-form mesh without VerticesPerRow"),
+                    (4 | 6 | 7, None, _) => bail!("mesh shading (type {}) without BitsPerFlag", shading_type),
+                    (4, ..) => ShadingKind::FreeForm(mesh),
+                    (5, ..) => ShadingKind::Lattice(mesh),
+                    (6, ..) => ShadingKind::Coons(mesh),
+                    _ => ShadingKind::Tensor(mesh),
+                }
+            }
+            t => bail!("invalid shading type {}", t)
+        };
+        Ok(Shading { color_space, background, bbox, anti_alias, kind })
+    }
+}
+impl ObjectWrite for Shading {
+    /// Mesh shadings are streams, so they can only be written as objects of their own.
+    /// Resources and patterns hold them as `MaybeRef`, which fails to write a direct stream.
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        let mut dict = match self.kind {
+            ShadingKind::Function(ref f) => f.to_dict(update)?,
+            ShadingKind::Axial(ref g) | ShadingKind::Radial(ref g) => g.to_dict(update)?,
+            ShadingKind::FreeForm(ref m) | ShadingKind::Lattice(ref m) |
+            ShadingKind::Coons(ref m) | ShadingKind::Tensor(ref m) => m.to_dict(update)?,
+        };
+        dict.insert("ShadingType", Primitive::Integer(self.kind.shading_type()));
+        dict.insert("ColorSpace", self.color_space.to_primitive(update)?);
+        if let Some(ref background) = self.background {
+            dict.insert("Background", background.to_primitive(update)?);
+        }
+        if let Some(ref bbox) = self.bbox {
+            dict.insert("BBox", bbox.to_primitive(update)?);
+        }
+        if self.anti_alias {
+            dict.insert("AntiAlias", Primitive::Boolean(true));
+        }
+        match self.kind.mesh() {
+            Some(mesh) => {
+                let filter = StreamFilter::FlateDecode(LZWFlateParams::default());
+                let data = enc::encode(&mesh.data, &filter)?;
+                Stream::new_with_filters(dict, data, vec![filter]).to_primitive(update)
+            }
+            None => Ok(Primitive::Dictionary(dict))
+        }
+    }
+}
+
+/// A vertex of a mesh shading.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshVertex {
+    pub point: Point,
+    /// The colour components, or the parametric value if the shading has a function.
+    /// See `Shading::color`.
+    pub color: Vec<f32>,
+}
+
+/// A triangle of a free-form or lattice-form mesh. The colour is interpolated between the vertices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Triangle(pub [MeshVertex; 3]);
+
+/// A tensor-product patch. Coons patches are converted to tensor-product patches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    /// The control points `p_ij` of the patch, indexed as `points[i][j]`.
+    pub points: [[Point; 4]; 4],
+    /// The colours at `p_00`, `p_03`, `p_33` and `p_30`, like `MeshVertex::color`.
+    pub colors: [Vec<f32>; 4],
+}
+
+/// The control points of a patch in the order they are stored in the stream.
+/// Coons patches only store the first 12.
+const PATCH_ORDER: [(usize, usize); 16] = [
+    (0, 0), (0, 1), (0, 2), (0, 3), (1, 3), (2, 3), (3, 3), (3, 2),
+    (3, 1), (3, 0), (2, 0), (1, 0), (1, 1), (1, 2), (2, 2), (2, 1)
+];
+
+/// Reads the bit-packed vertices of a mesh shading.
+struct MeshReader<'a> {
+    mesh: &'a MeshShading,
+    /// The position in bits.
+    pos: usize,
+    n_colors: usize,
+}
+impl<'a> MeshReader<'a> {
+    /// `flags` is whether the mesh stores an edge flag for each vertex or patch.
+    fn new(mesh: &'a MeshShading, n_colors: usize, flags: bool) -> Result<Self> {
+        if mesh.decode.len() < 4 + 2 * n_colors {
+            bail!("mesh shading Decode has {} entries, expected {}", mesh.decode.len(), 4 + 2 * n_colors);
+        }
+        if !(1 ..= 32).contains(&mesh.bits_per_coordinate) {
+            bail!("invalid mesh shading BitsPerCoordinate {}", mesh.bits_per_coordinate);
+        }
+        if !(1 ..= 16).contains(&mesh.bits_per_component) {
+            bail!("invalid mesh shading BitsPerComponent {}", mesh.bits_per_component);
+        }
+        if flags && !matches!(mesh.bits_per_flag, Some(2) | Some(4) | Some(8)) {
+            bail!("invalid mesh shading BitsPerFlag {:?}", mesh.bits_per_flag);
+        }
+        Ok(MeshReader { mesh, pos: 0, n_colors })
+    }
+    /// Whether there are at least `bits` more bits.
+    fn has(&self, bits: usize) -> bool {
+        self.pos + bits <= self.mesh.data.len() * 8
+    }
+    fn vertex_bits(&self) -> usize {
+        2 * self.mesh.bits_per_coordinate as usize + self.n_colors * self.mesh.bits_per_component as usize
+    }
+    fn bits(&mut self, bits: u32) -> u32 {
+        let value = read_bits(&self.mesh.data, self.pos, bits);
+        self.pos += bits as usize;
+        value
+    }
+    fn value(&mut self, bits: u32, i: usize) -> f32 {
+        let (min, max) = (self.mesh.decode[2 * i] as f64, self.mesh.decode[2 * i + 1] as f64);
+        let raw = self.bits(bits) as f64;
+        (min + raw * (max - min) / ((1u64 << bits) - 1) as f64) as f32
+    }
+    fn flag(&mut self) -> u32 {
+        self.bits(self.mesh.bits_per_flag.unwrap_or(0))
+    }
+    fn point(&mut self) -> Point {
+        let bits = self.mesh.bits_per_coordinate;
+        let x = self.value(bits, 0);
+        let y = self.value(bits, 1);
+        Point { x, y }
+    }
+    fn color(&mut self) -> Vec<f32> {
+        let bits = self.mesh.bits_per_component;
+        (0 .. self.n_colors).map(|i| self.value(bits, 2 + i)).collect()
+    }
+    fn vertex(&mut self) -> MeshVertex {
+        let point = self.point();
+        MeshVertex { point, color: self.color() }
+    }
+    /// Skip to the next byte.
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+impl Shading {
+    fn function(&self) -> Option<&ShadingFunction> {
+        match self.kind {
+            ShadingKind::Function(ref f) => Some(&f.function),
+            ShadingKind::Axial(ref g) | ShadingKind::Radial(ref g) => Some(&g.function),
+            ref kind => kind.mesh().and_then(|m| m.function.as_ref())
+        }
+    }
+
+    /// The colour components for the values of a point in the shading: `[x, y]` in the
+    /// domain of a function-based shading, `[t]` for gradients, and `MeshVertex::color`
+    /// (possibly interpolated) for meshes.
+    pub fn color(&self, values: &[f32]) -> Result<Vec<f32>> {
+        match self.function() {
+            Some(function) => {
+                let mut out = vec![0.; self.color_space.components()?];
+                function.apply(values, &mut out)?;
+                Ok(out)
+            }
+            None => Ok(values.to_vec())
+        }
+    }
+
+    /// The colour components at `p` in shading space, or `None` if the shading does not cover `p`.
+    ///
+    /// Only function-based, axial and radial shadings are supported. Axial and radial shadings
+    /// are extended as given by `Extend`. `Background` and `BBox` are not taken into account.
+    pub fn color_at(&self, p: Point) -> Result<Option<Vec<f32>>> {
+        let values = match self.kind {
+            ShadingKind::Function(ref f) => {
+                let (x0, x1, y0, y1) = match f.domain.as_deref() {
+                    Some(&[x0, x1, y0, y1]) => (x0, x1, y0, y1),
+                    Some(d) => bail!("invalid function shading domain {:?}", d),
+                    None => (0., 1., 0., 1.)
+                };
+                let matrix = f.matrix.unwrap_or_default();
+                let inverse = match matrix.inverse() {
+                    Some(inverse) => inverse,
+                    None => return Ok(None)
+                };
+                let q = inverse.transform(p);
+                if q.x < x0 || q.x > x1 || q.y < y0 || q.y > y1 {
+                    return Ok(None);
+                }
+                vec![q.x, q.y]
+            }
+            ShadingKind::Axial(ref g) | ShadingKind::Radial(ref g) => {
+                let s = match (&self.kind, &g.coords[..]) {
+                    (ShadingKind::Axial(_), &[x0, y0, x1, y1]) => axial_param([x0, y0, x1, y1], p, g.extend()),
+                    (ShadingKind::Radial(_), &[x0, y0, r0, x1, y1, r1]) => radial_param([x0, y0, r0, x1, y1, r1], p, g.extend()),
+                    (_, coords) => bail!("invalid shading Coords {:?}", coords)
+                };
+                let (t0, t1) = g.domain();
+                match s {
+                    Some(s) => vec![t0 + s * (t1 - t0)],
+                    None => return Ok(None)
+                }
+            }
+            _ => bail!("mesh shadings have no colour lookup, use triangles or patches")
+        };
+        self.color(&values).map(Some)
+    }
+
+    /// The number of colour values of each mesh vertex.
+    fn mesh_colors(&self, mesh: &MeshShading) -> Result<usize> {
+        match mesh.function {
+            Some(_) => Ok(1),
+            None => self.color_space.components()
+        }
+    }
+
+    /// Decode the triangles of a free-form (type 4) or lattice-form (type 5) mesh.
+    ///
+    /// Incomplete data at the end of the stream is ignored.
+    pub fn triangles(&self) -> Result<Vec<Triangle>> {
+        let mut triangles = Vec::new();
+        match self.kind {
+            ShadingKind::FreeForm(ref mesh) => {
+                let mut r = MeshReader::new(mesh, self.mesh_colors(mesh)?, true)?;
+                let vertex_bits = mesh.bits_per_flag.unwrap_or(0) as usize + r.vertex_bits();
+                // vertices that are still missing from a new triangle
+                let mut pending = Vec::with_capacity(3);
+                while r.has(vertex_bits) {
+                    let flag = r.flag();
+                    let v = r.vertex();
+                    // each vertex starts at a byte boundary
+                    r.align();
+                    if !pending.is_empty() || flag == 0 {
+                        pending.push(v);
+                        if pending.len() == 3 {
+                            triangles.push(Triangle([pending.remove(0), pending.remove(0), pending.remove(0)]));
+                        }
+                        continue;
+                    }
+                    let Triangle([ref va, ref vb, ref vc]) = match triangles.last() {
+                        Some(t) => t,
+                        None => bail!("mesh shading continues a triangle that does not exist")
+                    };
+                    let vertices = match flag {
+                        1 => [vb.clone(), vc.clone(), v],
+                        2 => [va.clone(), vc.clone(), v],
+                        f => bail!("invalid mesh shading flag {}", f)
+                    };
+                    triangles.push(Triangle(vertices));
+                }
+            }
+            ShadingKind::Lattice(ref mesh) => {
+                let per_row = mesh.vertices_per_row.unwrap_or(0) as usize;
+                if per_row < 2 {
+                    bail!("lattice-form mesh with {} vertices per row", per_row);
+                }
+                let mut r = MeshReader::new(mesh, self.mesh_colors(mesh)?, false)?;
+                let vertex_bits = r.vertex_bits();
+                let mut rows: Vec<Vec<MeshVertex>> = Vec::new();
+                while r.has(vertex_bits * per_row) {
+                    rows.push((0 .. per_row).map(|_| r.vertex()).collect());
+                }
+                for pair in rows.windows(2) {
+                    let (top, bottom) = (&pair[0], &pair[1]);
+                    for j in 0 .. per_row - 1 {
+                        triangles.push(Triangle([top[j].clone(), top[j + 1].clone(), bottom[j].clone()]));
+                        triangles.push(Triangle([top[j + 1].clone(), bottom[j + 1].clone(), bottom[j].clone()]));
+                    }
+                }
+            }
+            _ => bail!("shading type {} is not a triangle mesh", self.kind.shading_type())
+        }
+        Ok(triangles)
+    }
+
+    /// Decode the patches of a Coons (type 6) or tensor-product (type 7) patch mesh.
+    ///
+    /// Incomplete data at the end of the stream is ignored.
+    pub fn patches(&self) -> Result<Vec<Patch>> {
+        let (mesh, n_points) = match self.kind {
+            ShadingKind::Coons(ref mesh) => (mesh, 12),
+            ShadingKind::Tensor(ref mesh) => (mesh, 16),
+            _ => bail!("shading type {} is not a patch mesh", self.kind.shading_type())
+        };
+        let mut r = MeshReader::new(mesh, self.mesh_colors(mesh)?, true)?;
+        let flag_bits = mesh.bits_per_flag.unwrap_or(0) as usize;
+        let point_bits = 2 * mesh.bits_per_coordinate as usize;
+        let color_bits = r.n_colors * mesh.bits_per_component as usize;
+
+        let mut patches: Vec<Patch> = Vec::new();
+        while r.has(flag_bits) {
+            let flag = r.flag();
+            if flag > 3 {
+                bail!("invalid mesh shading flag {}", flag);
+            }
+            let (shared_points, shared_colors) = if flag == 0 { (0, 0) } else { (4, 2) };
+            if !r.has((n_points - shared_points) * point_bits + (4 - shared_colors) * color_bits) {
+                break;
+            }
+            let mut points = [Point::default(); 16];
+            let mut colors: [Vec<f32>; 4] = Default::default();
+            if flag != 0 {
+                let prev = match patches.last() {
+                    Some(prev) => prev,
+                    None => bail!("mesh shading continues a patch that does not exist")
+                };
+                // the edge shared with the previous patch, starting at stream index `3 * flag`
+                for (k, point) in points[.. 4].iter_mut().enumerate() {
+                    let (i, j) = PATCH_ORDER[(3 * flag as usize + k) % 12];
+                    *point = prev.points[i][j];
+                }
+                colors[0] = prev.colors[flag as usize].clone();
+                colors[1] = prev.colors[(flag as usize + 1) % 4].clone();
+            }
+            for point in &mut points[shared_points .. n_points] {
+                *point = r.point();
+            }
+            for color in &mut colors[shared_colors ..] {
+                *color = r.color();
+            }
+            // each patch starts at a byte boundary
+            r.align();
+
+            let mut grid = [[Point::default(); 4]; 4];
+            for (&(i, j), &point) in PATCH_ORDER.iter().zip(&points[.. n_points]) {
+                grid[i][j] = point;
+            }
+            if n_points == 12 {
+                coons_interior(&mut grid);
+            }
+            patches.push(Patch { points: grid, colors });
+        }
+        Ok(patches)
+    }
+}
+
+impl GradientShading {
+    /// `Extend` for the start and the end.
+    pub fn extend(&self) -> (bool, bool) {
+        match self.extend.as_deref() {
+            Some(&[start, end]) => (start, end),
+            _ => (false, false)
+        }
+    }
+    /// `Domain`, defaulting to `(0, 1)`.
+    pub fn domain(&self) -> (f32, f32) {
+        match self.domain.as_deref() {
+            Some(&[t0, t1]) => (t0, t1),
+            _ => (0., 1.)
+        }
+    }
+}
+
+/// Clamp `s` to `0 ..= 1` if the shading is extended in that direction.
+fn extended(s: f64, extend: (bool, bool)) -> Option<f32> {
+    match s {
+        s if s < 0. => if extend.0 { Some(0.) } else { None },
+        s if s > 1. => if extend.1 { Some(1.) } else { None },
+        s => Some(s as f32)
+    }
+}
+
+/// The position of `p` along the axis from `(x0, y0)` to `(x1, y1)`, from 0 to 1.
+fn axial_param(coords: [f32; 4], p: Point, extend: (bool, bool)) -> Option<f32> {
+    let [x0, y0, x1, y1] = coords.map(|c| c as f64);
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let len2 = dx * dx + dy * dy;
+    if len2 == 0. {
+        return None;
+    }
+    extended(((p.x as f64 - x0) * dx + (p.y as f64 - y0) * dy) / len2, extend)
+}
+
+/// The largest `s` for which `p` lies on the circle interpolated between the start and end
+/// circle, from 0 to 1.
+fn radial_param(coords: [f32; 6], p: Point, extend: (bool, bool)) -> Option<f32> {
+    let [x0, y0, r0, x1, y1, r1] = coords.map(|c| c as f64);
+    let (cx, cy, dr) = (x1 - x0, y1 - y0, r1 - r0);
+    let (px, py) = (p.x as f64 - x0, p.y as f64 - y0);
+    // |p - c(s)|² = r(s)², which is a s² - 2 b s + c = 0
+    let a = cx * cx + cy * cy - dr * dr;
+    let b = px * cx + py * cy + r0 * dr;
+    let c = px * px + py * py - r0 * r0;
+    let roots = if a.abs() < 1e-9 {
+        if b == 0. {
+            return None;
+        }
+        [c / (2. * b), f64::NAN]
+    } else {
+        let disc = b * b - a * c;
+        if disc < 0. {
+            return None;
+        }
+        let (s0, s1) = ((b + disc.sqrt()) / a, (b - disc.sqrt()) / a);
+        [s0.max(s1), s0.min(s1)]
+    };
+    roots.iter()
+        .filter(|&&s| !s.is_nan() && r0 + s * dr >= 0.)
+        .find_map(|&s| extended(s, extend))
+}
+
+/// Compute the inner control points of a Coons patch from its boundary.
+fn coons_interior(p: &mut [[Point; 4]; 4]) {
+    let f = |w: [(f32, (usize, usize)); 8]| {
+        let (mut x, mut y) = (0., 0.);
+        for &(w, (i, j)) in &w {
+            x += w * p[i][j].x;
+            y += w * p[i][j].y;
+        }
+        Point { x: x / 9., y: y / 9. }
+    };
+    let p11 = f([(-4., (0, 0)), (6., (0, 1)), (6., (1, 0)), (-2., (0, 3)), (-2., (3, 0)), (3., (3, 1)), (3., (1, 3)), (-1., (3, 3))]);
+    let p12 = f([(-4., (0, 3)), (6., (0, 2)), (6., (1, 3)), (-2., (0, 0)), (-2., (3, 3)), (3., (3, 2)), (3., (1, 0)), (-1., (3, 0))]);
+    let p21 = f([(-4., (3, 0)), (6., (3, 1)), (6., (2, 0)), (-2., (3, 3)), (-2., (0, 0)), (3., (0, 1)), (3., (2, 3)), (-1., (0, 3))]);
+    let p22 = f([(-4., (3, 3)), (6., (3, 2)), (6., (2, 3)), (-2., (3, 0)), (-2., (0, 3)), (3., (0, 2)), (3., (2, 0)), (-1., (0, 0))]);
+    p[1][1] = p11;
+    p[1][2] = p12;
+    p[2][1] = p21;
+    p[2][2] = p22;
+}
+
+#[derive(Object, ObjectWrite, Debug, Copy, Clone, PartialEq)]
+pub enum PaintType {
+    /// The pattern's content specifies its colours.
+    Colored = 1,
+    /// The pattern is a stencil painted in the colour given with `scn`.
+    Uncolored = 2,
+}
+
+#[derive(Object, ObjectWrite, Debug, Copy, Clone, PartialEq)]
+pub enum TilingType {
+    ConstantSpacing = 1,
+    NoDistortion = 2,
+    FasterTiling = 3,
+}
+
+/// The dictionary of a tiling pattern stream (`PatternType` 1).
+#[derive(Object, ObjectWrite, Debug)]
+#[pdf(Type="Pattern?")]
+pub struct TilingPatternDict {
+    #[pdf(key="PaintType")]
+    pub paint_type: PaintType,
+
+    #[pdf(key="TilingType")]
+    pub tiling_type: TilingType,
+
+    /// The bounds of a pattern cell, in pattern space.
+    #[pdf(key="BBox")]
+    pub bbox: Rect,
+
+    #[pdf(key="XStep")]
+    pub x_step: f32,
+
+    #[pdf(key="YStep")]
+    pub y_step: f32,
+
+    #[pdf(key="Resources")]
+    pub resources: Option<MaybeRef<Resources>>,
+
+    /// Maps pattern space to the default coordinate space of the pattern's parent.
+    #[pdf(key="Matrix")]
+    pub matrix: Option<Matrix>,
+}
+
+/// A shading pattern (`PatternType` 2).
+#[derive(Object, ObjectWrite, Debug)]
+#[pdf(Type="Pattern?")]
+pub struct ShadingPattern {
+    #[pdf(key="Shading")]
+    pub shading: MaybeRef<Shading>,
+
+    #[pdf(key="Matrix")]
+    pub matrix: Option<Matrix>,
+
+    #[pdf(key="ExtGState")]
+    pub ext_g_state: Option<MaybeRef<GraphicsStateParameters>>,
+}
+
+#[derive(Debug)]
+pub enum Pattern {
+    Tiling(Box<TilingPattern>),
+    Shading(ShadingPattern),
+}
+impl Pattern {
+    /// The pattern matrix, if any.
+    pub fn matrix(&self) -> Option<Matrix> {
+        match *self {
+            Pattern::Tiling(ref t) => t.dict().matrix,
+            Pattern::Shading(ref s) => s.matrix,
+        }
+    }
+}
+impl Object for Pattern {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let pattern_type = match p {
+            Primitive::Reference(r) => return Self::from_primitive(resolve.resolve(r)?, resolve),
+            Primitive::Stream(ref s) => s.info.get("PatternType"),
+            Primitive::Dictionary(ref d) => d.get("PatternType"),
+            ref p => return Err(PdfError::UnexpectedPrimitive { expected: "Dictionary or Stream", found: p.get_debug_name() })
+        };
+        match pattern_type.map(|t| t.as_integer()).transpose()? {
+            Some(1) => Ok(Pattern::Tiling(Box::new(t!(TilingPattern::from_primitive(p, resolve))))),
+            Some(2) => Ok(Pattern::Shading(t!(ShadingPattern::from_primitive(p, resolve)))),
+            Some(t) => bail!("invalid pattern type {}", t),
+            None => Err(PdfError::MissingEntry { typ: "Pattern", field: "PatternType".into() })
+        }
+    }
+}
+impl ObjectWrite for Pattern {
+    /// Tiling patterns are streams, so they have to be written as objects of their own.
+    fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
+        match *self {
+            Pattern::Tiling(ref tiling) => {
+                let mut stream = tiling.stream.to_pdf_stream(update)?;
+                stream.info.insert("PatternType", Primitive::Integer(1));
+                Ok(Primitive::Stream(stream))
+            }
+            Pattern::Shading(ref shading) => {
+                let mut dict = shading.to_dict(update)?;
+                dict.insert("PatternType", Primitive::Integer(2));
+                Ok(Primitive::Dictionary(dict))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shading(s: &str) -> Shading {
+        let p = crate::parser::parse(s.as_bytes(), &NoResolve).unwrap();
+        Shading::from_primitive(p, &NoResolve).unwrap()
+    }
+
+    fn gray(s: &Shading, x: f32, y: f32) -> Option<f32> {
+        s.color_at(Point { x, y }).unwrap().map(|c| c[0])
+    }
+
+    fn mesh(kind: fn(MeshShading) -> ShadingKind, bits: u32, vertices_per_row: Option<u32>, data: Vec<u8>) -> Shading {
+        let mesh = MeshShading {
+            bits_per_coordinate: bits,
+            bits_per_component: bits,
+            bits_per_flag: Some(8),
+            vertices_per_row,
+            decode: [0., ((1 << bits) - 1) as f32].repeat(3),
+            function: None,
+            data,
+        };
+        Shading { color_space: ColorSpace::DeviceGray, background: None, bbox: None, anti_alias: false, kind: kind(mesh) }
+    }
+
+    fn points(p: &[(u8, u8)]) -> Vec<u8> {
+        p.iter().flat_map(|&(x, y)| [x, y]).collect()
+    }
+
+    #[test]
+    fn gradients() {
+        let axial = shading("<< /ShadingType 2 /ColorSpace /DeviceGray /Coords [0 0 10 0] /Extend [false true]
+            /Function << /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [1] /N 1 >> >>");
+        assert_eq!(gray(&axial, 5., 3.), Some(0.5));
+        assert_eq!(gray(&axial, -1., 0.), None);
+        assert_eq!(gray(&axial, 20., 0.), Some(1.));
+
+        let radial = shading("<< /ShadingType 3 /ColorSpace /DeviceGray /Coords [0 0 0 0 0 10] /Domain [1 0]
+            /Function << /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [1] /N 1 >> >>");
+        assert_eq!(gray(&radial, 3., 4.), Some(0.5));
+        assert_eq!(gray(&radial, 0., 15.), None);
+
+        // the end circle lies inside the start circle, so points outside of the start circle
+        // are only covered once it is extended
+        let radial = |extend| shading(&format!("<< /ShadingType 3 /ColorSpace /DeviceGray /Coords [0 0 10 5 0 1] /Extend [{} false]
+            /Function << /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [1] /N 1 >> >>", extend));
+        assert_eq!(gray(&radial(false), 6., 0.), Some(1.));
+        assert!((gray(&radial(false), -5., 0.).unwrap() - 5. / 14.).abs() < 1e-6);
+        assert_eq!(gray(&radial(false), -15., 0.), None);
+        assert_eq!(gray(&radial(true), -15., 0.), Some(0.));
+
+        let function = shading("<< /ShadingType 1 /ColorSpace /DeviceGray /Matrix [10 0 0 10 0 0]
+            /Function << /FunctionType 4 /Domain [0 1 0 1] /Range [0 1] /Length 13 >> stream
+{ add 2 div }
+endstream >>");
+        assert_eq!(gray(&function, 5., 3.), Some(0.4));
+        assert_eq!(gray(&function, 15., 3.), None);
+    }
+
+    #[test]
+    fn triangles() {
+        let data = [
+            [0, 0, 0, 0], [0, 10, 0, 255], [0, 0, 10, 0],
+            [1, 10, 10, 0],
+            [2, 20, 20, 0],
+        ].concat();
+        let triangles = mesh(ShadingKind::FreeForm, 8, None, data).triangles().unwrap();
+        let corners: Vec<_> = triangles.iter()
+            .map(|t| t.0.iter().map(|v| (v.point.x, v.point.y)).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(corners, [
+            [(0., 0.), (10., 0.), (0., 10.)],
+            [(10., 0.), (0., 10.), (10., 10.)],
+            [(10., 0.), (10., 10.), (20., 20.)],
+        ]);
+        assert_eq!(triangles[0].0[1].color, [255.]);
+
+        // 4 bit values that are not byte aligned
+        let lattice = mesh(ShadingKind::Lattice, 4, Some(2), vec![0x00, 0x11, 0x02, 0x01, 0x31, 0x14]);
+        let triangles = lattice.triangles().unwrap();
+        assert_eq!(triangles.len(), 2);
+        let t = &triangles[1].0;
+        assert_eq!((t[0].point, t[1].point, t[2].point), (Point { x: 1., y: 0. }, Point { x: 1., y: 1. }, Point { x: 0., y: 1. }));
+        assert_eq!((t[0].color[0], t[1].color[0], t[2].color[0]), (2., 4., 3.));
+    }
+
+    #[test]
+    fn write_mesh_shading() {
+        let shading = Rc::new(mesh(ShadingKind::FreeForm, 8, None, vec![0; 12]));
+        assert!(matches!(shading.to_primitive(&mut NoUpdate).unwrap(), Primitive::Stream(_)));
+
+        let mut resources = Resources::default();
+        resources.shadings.insert("Sh1".into(), MaybeRef::Direct(shading.clone()));
+        assert!(resources.to_primitive(&mut NoUpdate).is_err());
+
+        let r = PlainRef { id: 3, gen: 0 };
+        resources.shadings.insert("Sh1".into(), MaybeRef::Indirect(RcRef::new(r, shading)));
+        let dict = resources.to_primitive(&mut NoUpdate).unwrap().into_dictionary(&NoResolve).unwrap();
+        let shadings = dict["Shading"].clone().into_dictionary(&NoResolve).unwrap();
+        assert!(matches!(shadings["Sh1"], Primitive::Reference(p) if p == r));
+    }
+
+    #[test]
+    fn patches() {
+        let mut data = vec![0];
+        data.extend(points(&[(0, 0), (0, 1), (0, 2), (0, 3), (1, 3), (2, 3), (3, 3), (3, 2), (3, 1), (3, 0), (2, 0), (1, 0)]));
+        data.extend([0, 85, 170, 255]);
+        // shares the edge from p_03 to p_33 of the first patch
+        data.push(1);
+        data.extend(points(&[(3, 4), (3, 5), (3, 6), (2, 6), (1, 6), (0, 6), (0, 5), (0, 4)]));
+        data.extend([255, 0]);
+
+        let patches = mesh(ShadingKind::Coons, 8, None, data).patches().unwrap();
+        assert_eq!(patches.len(), 2);
+        for i in 0 .. 4 {
+            for j in 0 .. 4 {
+                let (p, q) = (patches[0].points[i][j], patches[1].points[i][j]);
+                assert!((p.x - i as f32).abs() < 1e-5 && (p.y - j as f32).abs() < 1e-5, "{:?}", p);
+                assert!((q.x - j as f32).abs() < 1e-5 && (q.y - 3. - i as f32).abs() < 1e-5, "{:?}", q);
+            }
+        }
+        let colors: Vec<_> = patches[1].colors.iter().map(|c| c[0]).collect();
+        assert_eq!(colors, [85., 170., 255., 0.]);
+    }
+
+    #[test]
+    fn invalid_bit_depths() {
+        let with = |f: fn(&mut MeshShading)| {
+            let mut shading = mesh(ShadingKind::Coons, 8, Some(2), vec![0; 64]);
+            match shading.kind {
+                ShadingKind::Coons(ref mut m) => f(m),
+                _ => unreachable!()
+            }
+            shading
+        };
+        // a depth of 0 would never consume any data
+        assert!(with(|m| m.bits_per_coordinate = 0).patches().is_err());
+        assert!(with(|m| m.bits_per_coordinate = 33).patches().is_err());
+        assert!(with(|m| m.bits_per_component = 0).patches().is_err());
+        assert!(with(|m| m.bits_per_component = 17).patches().is_err());
+        assert!(with(|m| m.bits_per_flag = Some(0)).patches().is_err());
+        assert!(with(|m| m.bits_per_flag = Some(3)).patches().is_err());
+        assert!(with(|m| m.bits_per_flag = Some(2)).patches().is_ok());
+
+        let mut lattice = mesh(ShadingKind::Lattice, 8, Some(2), vec![0; 12]);
+        if let ShadingKind::Lattice(ref mut m) = lattice.kind {
+            m.bits_per_flag = None;
+        }
+        assert_eq!(lattice.triangles().unwrap().len(), 2);
+        if let ShadingKind::Lattice(ref mut m) = lattice.kind {
+            m.bits_per_coordinate = 0;
+        }
+        assert!(lattice.triangles().is_err());
+    }
+}
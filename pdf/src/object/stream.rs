@@ -3,13 +3,15 @@ use crate::object::*;
 use crate::primitive::*;
 use crate::error::*;
 use crate::parser::Lexer;
-use crate::enc::{self, decode};
+use crate::enc::{self, decode, DecodeReader};
 
 use once_cell::unsync::OnceCell;
 
 use std::borrow::Cow;
 use std::ops::Deref;
 use std::fmt;
+use std::io::Read;
+use std::cell::Cell;
 
 
 
@@ -18,13 +20,17 @@ use std::fmt;
 pub struct Stream<I=()> {
     pub info: StreamInfo<I>,
     raw_data: Vec<u8>,
-    decoded: OnceCell<Vec<u8>>
+    decoded: OnceCell<Vec<u8>>,
+    limits: Option<Limits>,
+    /// Whether the decoded size was added to the `limits`, which happens once per stream.
+    counted: Cell<bool>,
 }
 impl<I: Object + fmt::Debug> Stream<I> {
     pub fn from_stream(s: PdfStream, resolve: &impl Resolve) -> Result<Self> {
         let PdfStream {info, data} = s;
         let info = StreamInfo::<I>::from_primitive(Primitive::Dictionary (info), resolve)?;
-        Ok(Stream { info, raw_data: data, decoded: OnceCell::new() })
+        let limits = resolve.limits().cloned();
+        Ok(Stream { info, raw_data: data, decoded: OnceCell::new(), limits, counted: Cell::new(false) })
     }
 
     pub fn new_with_filters(i: I, data: Vec<u8>, filters: Vec<StreamFilter>) -> Stream<I> {
@@ -36,7 +42,9 @@ impl<I: Object + fmt::Debug> Stream<I> {
                 info: i
             },
             raw_data: data,
-            decoded: OnceCell::new()
+            decoded: OnceCell::new(),
+            limits: None,
+            counted: Cell::new(false),
         }
     }
     pub fn new(i: I, data: Vec<u8>) -> Stream<I> {
@@ -48,17 +56,70 @@ impl<I: Object + fmt::Debug> Stream<I> {
                 info: i
             },
             raw_data: data,
-            decoded: OnceCell::new()
+            decoded: OnceCell::new(),
+            limits: None,
+            counted: Cell::new(false),
         }
     }
 
     /// decode the data.
     /// does not store the result.
     /// The caller is responsible for caching the result
+    ///
+    /// The decoded size counts toward `max_decoded_bytes` the first time only.
     pub fn decode(&self) -> Result<Cow<[u8]>> {
-        let mut data = Cow::Borrowed(&*self.raw_data);
-        for filter in &self.info.filters {
-            data = match decode(&*data, filter) {
+        let data = self.decode_first(self.active_filters().len())?;
+        self.count_decoded_bytes(data.len())?;
+        Ok(data)
+    }
+
+    /// The largest output `filter` may produce from `input_len` bytes of input. Once the decoded
+    /// size of this stream was counted, it is known to fit in `max_decoded_bytes`.
+    pub(crate) fn output_limit(&self, filter: &StreamFilter, input_len: usize) -> Option<OutputLimit> {
+        let limits = self.limits.as_ref()?;
+        match self.counted.get() {
+            true => limits.ratio_limit(filter, input_len),
+            false => limits.output_limit(filter, input_len)
+        }
+    }
+
+    /// Add the `n` decoded bytes of this stream to the total of the file, unless they were
+    /// added before.
+    pub(crate) fn count_decoded_bytes(&self, n: usize) -> Result<()> {
+        match self.limits {
+            Some(ref limits) if !self.counted.get() => {
+                limits.add_decoded_bytes(n)?;
+                self.counted.set(true);
+                Ok(())
+            }
+            _ => Ok(())
+        }
+    }
+
+    /// Decode the data with the first `n` of `active_filters()` only.
+    pub(crate) fn decode_first(&self, n: usize) -> Result<Cow<'_, [u8]>> {
+        let mut data = match self.info.file {
+            Some(_) => {
+                let mut data = Vec::new();
+                let mut file = self.open_file()?;
+                match self.limits.as_ref().filter(|_| !self.counted.get()).and_then(|l| l.decoded_bytes_limit()) {
+                    Some(limit) => {
+                        file.take(limit.max as u64 + 1).read_to_end(&mut data)?;
+                        if data.len() > limit.max {
+                            return Err(PdfError::LimitExceeded { limit: limit.limit, max: limit.max });
+                        }
+                    }
+                    None => {
+                        file.read_to_end(&mut data)?;
+                    }
+                }
+                Cow::Owned(data)
+            }
+            None => Cow::Borrowed(&*self.raw_data)
+        };
+        for filter in &self.active_filters()[.. n] {
+            let limit = self.output_limit(filter, data.len());
+            data = match decode(&*data, filter, limit) {
                 Ok(data) => data.into(),
                 Err(e) => {
                     info!("Stream Info: {:?}", &self.info);
@@ -69,6 +130,45 @@ impl<I: Object + fmt::Debug> Stream<I> {
         }
         Ok(data)
     }
+
+    /// The filters the data is encoded with: `file_filters` if it is stored in an external file,
+    /// `filters` otherwise.
+    pub fn active_filters(&self) -> &[StreamFilter] {
+        match self.info.file {
+            Some(_) => &self.info.file_filters,
+            None => &self.info.filters
+        }
+    }
+
+    /// Open the external file holding the data, using the `FileResolver` of the file
+    /// this stream was read from.
+    fn open_file(&self) -> Result<Box<dyn Read>> {
+        let name = match self.info.file.as_ref().and_then(|f| f.name()) {
+            Some(name) => name,
+            None => bail!("stream refers to an external file without a name")
+        };
+        match self.limits.as_ref().and_then(|l| l.options.file_resolver.as_ref()) {
+            Some(resolver) => resolver.open(&name),
+            None => bail!("no file resolver to open the external stream data {:?}", name)
+        }
+    }
+
+    /// The limits of the file this stream was read from.
+    pub(crate) fn limits(&self) -> Option<&Limits> {
+        self.limits.as_ref()
+    }
+
+    /// A reader that decodes the data incrementally, instead of holding all of it in memory.
+    ///
+    /// Reading fails once more than `max` bytes were produced. Unlike `data()`, the result is not cached.
+    pub fn reader(&self, max: Option<usize>) -> Result<DecodeReader<'_>> {
+        let (input, len): (Box<dyn Read>, _) = match self.info.file {
+            Some(_) => (self.open_file()?, usize::MAX),
+            None => (Box::new(&*self.raw_data), self.raw_data.len())
+        };
+        Ok(DecodeReader::new(input, len, self.active_filters(), max, self.limits.as_ref(), Some(&self.counted)))
+    }
+
     pub fn data(&self) -> Result<&[u8]> {
         self.decoded.get_or_try_init(|| {
             let data = self.decode()?;
@@ -76,6 +176,13 @@ impl<I: Object + fmt::Debug> Stream<I> {
         }).map(|v| v.as_slice())
     }
 
+    /// The data as stored in the file, still encoded with `filters`.
+    ///
+    /// Empty if the data is stored in an external file.
+    pub fn raw_data(&self) -> &[u8] {
+        &self.raw_data
+    }
+
     /// If this is contains DCT encoded data, return the compressed data as is
     pub fn as_jpeg(&self) -> Option<&[u8]> {
         match *self.info.filters.as_slice() {
@@ -111,44 +218,10 @@ impl<I: ObjectWrite> Stream<I> {
             Primitive::Null => Dictionary::new(),
             p => bail!("stream info has to be a dictionary (found {:?})", p)
         };
-        let mut params = None;
-        if self.info.filters.len() > 0 {
-            for f in self.info.filters.iter() {
-                if let Some(para) = match f {
-                    StreamFilter::LZWDecode(ref p) => Some(p.to_primitive(update)?),
-                    StreamFilter::FlateDecode(ref p) => Some(p.to_primitive(update)?),
-                    StreamFilter::DCTDecode(ref p) => Some(p.to_primitive(update)?),
-                    _ => None
-                } {
-                    if params.is_some() {
-                        panic!();
-                    }
-                    params = Some(para);
-                }
-            }
-            let mut filters = self.info.filters.iter().map(|filter| match filter {
-                StreamFilter::ASCIIHexDecode => "ASCIIHexDecode",
-                StreamFilter::ASCII85Decode => "ASCII85Decode",
-                StreamFilter::LZWDecode(ref p) => "LZWDecode",
-                StreamFilter::FlateDecode(ref p) => "FlateDecode",
-                StreamFilter::JPXDecode => "JPXDecode",
-                StreamFilter::DCTDecode(ref p) => "DCTDecode",
-                StreamFilter::CCITTFaxDecode(ref p) => "CCITTFaxDecode",
-                StreamFilter::Crypt => "Crypt",
-            })
-            .map(|s| Primitive::Name(s.into()));
-            match self.info.filters.len() {
-                0 => {},
-                1 => {
-                    info.insert("Filter", filters.next().unwrap().to_primitive(update)?);
-                }
-                _ => {
-                    info.insert("Filter", Primitive::array::<Primitive, _, _, _>(filters, update)?);
-                }
-            }
-        }
-        if let Some(para) = params {
-            info.insert("DecodeParms", para);
+        write_filters(&mut info, "Filter", "DecodeParms", &self.info.filters, update)?;
+        if let Some(ref file) = self.info.file {
+            info.insert("F", file.to_primitive(update)?);
+            write_filters(&mut info, "FFilter", "FDecodeParms", &self.info.file_filters, update)?;
         }
         info.insert("Length", Primitive::Integer(self.raw_data.len() as _));
 
@@ -158,6 +231,35 @@ impl<I: ObjectWrite> Stream<I> {
         })
     }
 }
+
+/// Write `filters` to the `filter_key` and `params_key` entries of `info`.
+fn write_filters(info: &mut Dictionary, filter_key: &str, params_key: &str, filters: &[StreamFilter], update: &mut impl Updater) -> Result<()> {
+    let mut params = Vec::with_capacity(filters.len());
+    for filter in filters.iter() {
+        params.push(filter.params(update)?);
+    }
+    let mut names = filters.iter()
+        .map(|filter| Primitive::Name(filter.name().into()));
+    match filters.len() {
+        0 => {},
+        1 => {
+            info.insert(filter_key, names.next().unwrap());
+            if let Some(para) = params.pop().unwrap() {
+                info.insert(params_key, para);
+            }
+        }
+        _ => {
+            info.insert(filter_key, Primitive::Array(names.collect()));
+            if params.iter().any(Option::is_some) {
+                let params = params.into_iter()
+                    .map(|p| p.unwrap_or(Primitive::Null))
+                    .collect();
+                info.insert(params_key, Primitive::Array(params));
+            }
+        }
+    }
+    Ok(())
+}
 impl<I: ObjectWrite> ObjectWrite for Stream<I> {
     fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
         self.to_pdf_stream(update).map(Primitive::Stream)
@@ -242,7 +344,7 @@ impl<T: Object> Object for StreamInfo<T> {
             dict.remove("Filter").or(Some(Primitive::Null)).unwrap(),
             resolve)?;
 
-        let decode_params = Vec::<Dictionary>::from_primitive(
+        let decode_params = Vec::<Option<Dictionary>>::from_primitive(
             dict.remove("DecodeParms").or(Some(Primitive::Null)).unwrap(),
             resolve)?;
 
@@ -254,7 +356,7 @@ impl<T: Object> Object for StreamInfo<T> {
             dict.remove("FFilter").or(Some(Primitive::Null)).unwrap(),
             resolve)?;
 
-        let file_decode_params = Vec::<Dictionary>::from_primitive(
+        let file_decode_params = Vec::<Option<Dictionary>>::from_primitive(
             dict.remove("FDecodeParms").or(Some(Primitive::Null)).unwrap(),
             resolve)?;
 
@@ -264,15 +366,15 @@ impl<T: Object> Object for StreamInfo<T> {
 
         for (i, filter) in filters.iter().enumerate() {
             let params = match decode_params.get(i) {
-                Some(params) => params.clone(),
-                None => Dictionary::default(),
+                Some(Some(params)) => params.clone(),
+                _ => Dictionary::default(),
             };
             new_filters.push(StreamFilter::from_kind_and_params(filter, params, resolve)?);
         }
         for (i, filter) in file_filters.iter().enumerate() {
             let params = match file_decode_params.get(i) {
-                Some(params) => params.clone(),
-                None => Dictionary::default(),
+                Some(Some(params)) => params.clone(),
+                _ => Dictionary::default(),
             };
             new_file_filters.push(StreamFilter::from_kind_and_params(filter, params, resolve)?);
         }
@@ -358,3 +460,126 @@ impl ObjectStream {
         self.offsets.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enc::{encode, dct_encode, LZWFlateParams, CCITTFaxDecodeParams, DCTDecodeParams, DCTEncoding};
+    use crate::file::{OpenOptions, FileResolver};
+    use std::rc::Rc;
+
+    #[test]
+    fn filter_chain_params() {
+        let data: Vec<u8> = (0 .. 64u8).map(|i| i / 4).collect();
+        let filters = vec![
+            StreamFilter::ASCII85Decode,
+            StreamFilter::FlateDecode(LZWFlateParams { predictor: 12, columns: 8, ..Default::default() }),
+        ];
+        let mut encoded = data.clone();
+        for filter in filters.iter().rev() {
+            encoded = encode(&encoded, filter).unwrap();
+        }
+        let stream = Stream::new_with_filters((), encoded, filters).to_pdf_stream(&mut NoUpdate).unwrap();
+        match stream.info.get("DecodeParms") {
+            Some(Primitive::Array(params)) => {
+                assert!(matches!(params[..], [Primitive::Null, Primitive::Dictionary(_)]));
+            }
+            p => panic!("expected a DecodeParms array, found {:?}", p)
+        }
+        let stream = Stream::<()>::from_stream(stream, &NoResolve).unwrap();
+        assert_eq!(stream.data().unwrap(), &data[..]);
+
+        let params = CCITTFaxDecodeParams { k: -1, columns: 16, black_is_1: true, ..Default::default() };
+        let filter = StreamFilter::CCITTFaxDecode(params);
+        let data: Vec<u8> = data.iter().map(|&b| if b % 3 == 0 { 0 } else { 255 }).collect();
+        let bits = encode(&data, &filter).unwrap();
+        let stream = Stream::new_with_filters((), bits, vec![filter]);
+        let stream = stream.to_pdf_stream(&mut NoUpdate).unwrap();
+        let params = stream.info.get("DecodeParms").unwrap().clone().into_dictionary(&NoResolve).unwrap();
+        assert!(matches!(params.get("K"), Some(&Primitive::Integer(-1))));
+        assert!(matches!(params.get("BlackIs1"), Some(&Primitive::Boolean(true))));
+        let stream = Stream::<()>::from_stream(stream, &NoResolve).unwrap();
+        assert_eq!(stream.data().unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn dct_stream() {
+        let data: Vec<u8> = (0 .. 16 * 8).map(|i| (i * 2) as u8).collect();
+        let encoding = DCTEncoding { width: 16, height: 8, components: 1, quality: 95 };
+        let filter = StreamFilter::DCTDecode(DCTDecodeParams::default());
+        let jpeg = dct_encode(&data, &encoding, &DCTDecodeParams::default()).unwrap();
+        let stream = Stream::new_with_filters((), jpeg, vec![filter]).to_pdf_stream(&mut NoUpdate).unwrap();
+        let stream = Stream::<()>::from_stream(stream, &NoResolve).unwrap();
+        let decoded = stream.data().unwrap();
+        assert!(decoded.iter().zip(&data).all(|(&a, &b)| (a as i32 - b as i32).abs() < 8));
+
+        assert!(encode(&data, &StreamFilter::DCTDecode(DCTDecodeParams::default())).is_err());
+        assert!(encode(&data, &StreamFilter::JPXDecode).is_err());
+        assert!(encode(&data, &StreamFilter::Crypt).is_err());
+        assert!(decode(&data, &StreamFilter::Crypt, None).is_err());
+    }
+
+    struct ExternalResolve(Limits);
+    impl Resolve for ExternalResolve {
+        fn resolve(&self, _: PlainRef) -> Result<Primitive> {
+            Err(PdfError::Reference)
+        }
+        fn get<T: Object>(&self, _: Ref<T>) -> Result<RcRef<T>> {
+            Err(PdfError::Reference)
+        }
+        fn limits(&self) -> Option<&Limits> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn external_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let name = "pdf_external_stream.hex";
+        std::fs::write(dir.join(name), b"48656c6c6f>").unwrap();
+
+        let mut info = Dictionary::new();
+        info.insert("Length", Primitive::Integer(0));
+        info.insert("F", Primitive::String(PdfString::new(name.into())));
+        info.insert("FFilter", Primitive::Name("ASCIIHexDecode".into()));
+        let stream = PdfStream { info, data: vec![] };
+
+        let resolver: Rc<dyn FileResolver> = Rc::new(DirResolver { dir: dir.clone() });
+        let options = OpenOptions { file_resolver: Some(resolver.clone()), max_decoded_bytes: Some(4), .. OpenOptions::default() };
+        let limited = Stream::<()>::from_stream(stream.clone(), &ExternalResolve(Limits::new(options))).unwrap();
+        assert!(limited.data().unwrap_err().is_limit_exceeded());
+
+        // each stream counts once, however often it is decoded; the 11 bytes of the file fit
+        // the limit, which has 6 bytes left after the first stream
+        let limits = Limits::new(OpenOptions { file_resolver: Some(resolver.clone()), max_decoded_bytes: Some(11), .. OpenOptions::default() });
+        let once = Stream::<()>::from_stream(stream.clone(), &ExternalResolve(limits.clone())).unwrap();
+        for _ in 0 .. 2 {
+            assert_eq!(once.decode().unwrap(), &b"Hello"[..]);
+            once.reader(None).unwrap().read_to_end(&mut Vec::new()).unwrap();
+        }
+        let other = Stream::<()>::from_stream(stream.clone(), &ExternalResolve(limits)).unwrap();
+        assert!(other.decode().unwrap_err().is_limit_exceeded());
+
+        let options = OpenOptions { file_resolver: Some(resolver), .. OpenOptions::default() };
+        let external = Stream::<()>::from_stream(stream.clone(), &ExternalResolve(Limits::new(options))).unwrap();
+        assert_eq!(external.data().unwrap(), b"Hello");
+        let mut data = Vec::new();
+        external.reader(None).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"Hello");
+
+        let written = external.to_pdf_stream(&mut NoUpdate).unwrap();
+        assert!(written.info.get("F").is_some());
+        assert_eq!(written.info.get("FFilter").and_then(|p| p.as_name().ok()), Some("ASCIIHexDecode"));
+        assert!(written.info.get("Filter").is_none());
+
+        let unresolved = Stream::<()>::from_stream(stream, &NoResolve).unwrap();
+        assert!(unresolved.data().is_err());
+
+        let sub = dir.join("sub");
+        std::fs::create_dir_all(&sub).unwrap();
+        let resolver = DirResolver { dir: sub };
+        assert!(resolver.open(&format!("../{}", name)).is_err());
+        assert!(resolver.open(dir.join(name).to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Transparency groups and soft masks (chapter 11 of the PDF 1.7 reference).
+
+use crate as pdf;
+use crate::object::*;
+use crate::error::*;
+
+use std::collections::HashSet;
+
+/// Form XObjects and tiling patterns nested deeper than this are not searched for transparency.
+const MAX_DEPTH: usize = 16;
+
+/// The `Group` of a page or form XObject. A missing `S` is taken as `Transparency`.
+#[derive(Object, ObjectWrite, Debug, Clone)]
+#[pdf(Type="Group?", S="Transparency?")]
+pub struct TransparencyGroup {
+    /// The colour space the group is composited in.
+    #[pdf(key="CS")]
+    pub color_space: Option<Box<ColorSpace>>,
+
+    /// Whether the group is composited on a fully transparent backdrop instead of the
+    /// group's backdrop.
+    #[pdf(key="I", default="false")]
+    pub isolated: bool,
+
+    /// Whether the objects of the group are composited with the initial backdrop of the
+    /// group instead of each other.
+    #[pdf(key="K", default="false")]
+    pub knockout: bool,
+}
+
+/// Where the soft mask of an image comes from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageSoftMask {
+    /// An `SMask` image.
+    Image(Ref<ImageXObject>),
+    /// The opacity channel of the JPEG 2000 data (`SMaskInData`).
+    InData {
+        /// Whether the colour channels have been premultiplied with the opacity.
+        premultiplied: bool,
+    },
+}
+
+impl ImageDict {
+    /// The soft mask of the image, if any. `SMaskInData` is ignored if there is an `SMask`.
+    pub fn soft_mask(&self) -> Option<ImageSoftMask> {
+        match (self.smask, self.smask_in_data) {
+            (Some(r), _) => Some(ImageSoftMask::Image(r)),
+            (None, 1) => Some(ImageSoftMask::InData { premultiplied: false }),
+            (None, 2) => Some(ImageSoftMask::InData { premultiplied: true }),
+            _ => None
+        }
+    }
+}
+
+impl GraphicsStateParameters {
+    /// Whether this state sets a soft mask, a blend mode other than `Normal`, or an alpha below 1.
+    pub fn uses_transparency(&self) -> bool {
+        matches!(self.smask, Some(SoftMaskEntry::Mask(_)))
+            || matches!(self.blend_mode.as_ref().map(BlendModes::mode), Some(mode) if mode != BlendMode::Normal && mode != BlendMode::Compatible)
+            || self.stroke_alpha.is_some_and(|a| a < 1.)
+            || self.fill_alpha.is_some_and(|a| a < 1.)
+    }
+}
+
+impl Resources {
+    /// Whether the resources, or the resources of the forms and tiling patterns they
+    /// contain, include anything that needs the transparent imaging model: graphics states
+    /// for which `GraphicsStateParameters::uses_transparency` is true, images with a soft
+    /// mask, or transparency groups.
+    ///
+    /// This does not check whether the resources are actually used by the content.
+    pub fn uses_transparency(&self, resolve: &impl Resolve) -> Result<bool> {
+        resources_use_transparency(self, resolve, &mut HashSet::new(), 0)
+    }
+}
+
+impl Page {
+    /// Whether the page is a transparency group or its resources use transparency, see
+    /// `Resources::uses_transparency`. Annotations are not taken into account.
+    pub fn uses_transparency(&self, resolve: &impl Resolve) -> Result<bool> {
+        if self.group.is_some() {
+            return Ok(true);
+        }
+        match self.resources() {
+            Ok(resources) => resources.uses_transparency(resolve),
+            Err(_) => Ok(false)
+        }
+    }
+}
+
+/// `visited` holds the XObjects and patterns that were already searched.
+fn resources_use_transparency(resources: &Resources, resolve: &impl Resolve, visited: &mut HashSet<PlainRef>, depth: usize) -> Result<bool> {
+    if depth > MAX_DEPTH {
+        return Ok(false);
+    }
+    for gs in resources.graphics_states.values() {
+        if gs.uses_transparency() {
+            return Ok(true);
+        }
+    }
+    for &r in resources.xobjects.values() {
+        if !visited.insert(r.get_inner()) {
+            continue;
+        }
+        match *t!(resolve.get(r)) {
+            XObject::Image(ref image) if image.soft_mask().is_some() => return Ok(true),
+            XObject::Form(ref form) => {
+                let dict = form.dict();
+                if dict.group.is_some() {
+                    return Ok(true);
+                }
+                if let Some(ref form_resources) = dict.resources {
+                    if resources_use_transparency(form_resources, resolve, visited, depth + 1)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for &r in resources.patterns.values() {
+        if !visited.insert(r.get_inner()) {
+            continue;
+        }
+        let uses = match *t!(resolve.get(r)) {
+            Pattern::Tiling(ref tiling) => match tiling.dict().resources {
+                Some(ref pattern_resources) => resources_use_transparency(pattern_resources, resolve, visited, depth + 1)?,
+                None => false
+            },
+            Pattern::Shading(ref shading) => shading.ext_g_state.as_ref().is_some_and(|gs| gs.uses_transparency()),
+        };
+        if uses {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -1,12 +1,14 @@
 //! Models of PDF types
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 use crate as pdf;
 use crate::object::*;
 use crate::error::*;
-use crate::content::{Content, FormXObject};
+use crate::content::{Content, FormXObject, Op, Matrix};
 use crate::font::Font;
+use crate::enc::{StreamFilter, jpx};
 
 /// Node in a page tree - type is either `Page` or `PageTree`
 #[derive(Debug, Clone)]
@@ -65,6 +67,10 @@ impl PageRc {
     pub fn create(page: Page, update: &mut impl Updater) -> Result<PageRc> {
         Ok(PageRc(update.create(PagesNode::Leaf(page))?))
     }
+    /// Replace the page `old` with `page`.
+    pub fn update(page: Page, old: &PageRc, update: &mut impl Updater) -> Result<PageRc> {
+        Ok(PageRc(update.update(old.0.get_ref().get_inner(), PagesNode::Leaf(page))?))
+    }
 }
 
 /// A `PagesNode::Tree` wrapped in a `RcRef`
@@ -96,7 +102,7 @@ impl Object for PagesRc {
 }
 impl ObjectWrite for PagesRc {
     fn to_primitive(&self, update: &mut impl Updater) -> Result<Primitive> {
-        (**self).to_primitive(update)
+        self.0.to_primitive(update)
     }
 }
 
@@ -166,13 +172,19 @@ pub struct PageTree {
 }
 impl PageTree {
     pub fn page(&self, resolve: &impl Resolve, page_nr: u32) -> Result<PageRc> {
+        self.page_at_depth(resolve, page_nr, 1)
+    }
+    fn page_at_depth(&self, resolve: &impl Resolve, page_nr: u32, depth: usize) -> Result<PageRc> {
+        if let Some(limits) = resolve.limits() {
+            limits.check_page_tree_depth(depth)?;
+        }
         let mut pos = 0;
         for &kid in &self.kids {
             let node = resolve.get(kid)?;
             match *node {
                 PagesNode::Tree(ref tree) => {
                     if (pos .. pos + tree.count).contains(&page_nr) {
-                        return tree.page(resolve, page_nr - pos);
+                        return tree.page_at_depth(resolve, page_nr - pos, depth + 1);
                     }
                     pos += tree.count;
                 }
@@ -187,6 +199,19 @@ impl PageTree {
         Err(PdfError::PageOutOfBounds {page_nr, max: pos})
     }
 
+    /// Iterate over the pages of the tree, in order.
+    ///
+    /// Subtrees nested deeper than `max_page_tree_depth`, and subtrees that were already
+    /// visited, produce an error instead of their pages.
+    pub fn pages<'a, R: Resolve>(&self, resolve: &'a R) -> PageIter<'a, R> {
+        let root_error = resolve.limits().and_then(|limits| limits.check_page_tree_depth(1).err());
+        let stack = match root_error {
+            Some(_) => vec![],
+            None => vec![self.kids.clone().into_iter()]
+        };
+        PageIter { resolve, stack, visited: HashSet::new(), root_error }
+    }
+
     /*
     pub fn update_pages(&mut self, mut offset: u32, page_nr: u32, page: Page) -> Result<()> {
         for kid in &self.kids {
@@ -226,7 +251,51 @@ impl PageTree {
 }
 impl SubType<PagesNode> for PageTree {}
 
+/// Iterator over the pages of a `PageTree`, see `PageTree::pages`.
+pub struct PageIter<'a, R> {
+    resolve: &'a R,
+    stack: Vec<std::vec::IntoIter<Ref<PagesNode>>>,
+    visited: HashSet<PlainRef>,
+    root_error: Option<PdfError>,
+}
+impl<'a, R: Resolve> Iterator for PageIter<'a, R> {
+    type Item = Result<PageRc>;
+    fn next(&mut self) -> Option<Result<PageRc>> {
+        if let Some(e) = self.root_error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let kid = match self.stack.last_mut()?.next() {
+                Some(kid) => kid,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            let node = match self.resolve.get(kid) {
+                Ok(node) => node,
+                Err(e) => return Some(Err(e))
+            };
+            match *node {
+                PagesNode::Tree(ref tree) => {
+                    if !self.visited.insert(kid.get_inner()) {
+                        return Some(Err(PdfError::Other { msg: format!("page tree {:?} is visited twice", kid.get_inner()) }));
+                    }
+                    if let Some(limits) = self.resolve.limits() {
+                        if let Err(e) = limits.check_page_tree_depth(self.stack.len() + 1) {
+                            return Some(Err(e));
+                        }
+                    }
+                    self.stack.push(tree.kids.clone().into_iter());
+                }
+                PagesNode::Leaf(_) => return Some(Ok(PageRc(node)))
+            }
+        }
+    }
+}
+
 #[derive(Object, ObjectWrite, Debug, Clone)]
+#[pdf(Type = "Page?")]
 pub struct Page {
     #[pdf(key="Parent")]
     pub parent: PagesRc,
@@ -244,7 +313,11 @@ pub struct Page {
     pub trim_box:   Option<Rect>,
     
     #[pdf(key="Contents")]
-    pub contents:   Option<Content>
+    pub contents:   Option<Content>,
+
+    /// Set if the page is a transparency group.
+    #[pdf(key="Group")]
+    pub group:      Option<TransparencyGroup>,
 }
 fn inherit<'a, T: 'a, F>(mut parent: &'a PageTree, f: F) -> Result<Option<T>>
     where F: Fn(&'a PageTree) -> Option<T>
@@ -267,7 +340,8 @@ impl Page {
             crop_box:   None,
             trim_box:   None,
             resources:  None,
-            contents:   None
+            contents:   None,
+            group:      None,
         }
     }
     pub fn media_box(&self) -> Result<Rect> {
@@ -289,10 +363,48 @@ impl Page {
     pub fn resources(&self) -> Result<&MaybeRef<Resources>> {
         match self.resources {
             Some(ref r) => Ok(r),
-            None => inherit(&*self.parent, |pt| pt.resources.as_ref())?
+            None => inherit(&self.parent, |pt| pt.resources.as_ref())?
                 .ok_or_else(|| PdfError::MissingEntry { typ: "Page", field: "Resources".into() })
         }
     }
+
+    /// Add `image` to the resources of the page and paint it into `rect` (in default user space)
+    /// on top of the existing contents. Returns the name of the image in the resources.
+    ///
+    /// Inherited resources are copied into the page. The page itself still has to be written.
+    pub fn add_image(&mut self, image: ImageXObject, rect: Rect, update: &mut impl Updater) -> Result<String> {
+        let mut resources = match self.resources {
+            Some(ref r) => (**r).clone(),
+            None => inherit(&*self.parent, |pt| pt.resources.as_ref())?
+                .map(|r| (**r).clone())
+                .unwrap_or_default()
+        };
+        let name = (1 ..).map(|i| format!("Im{}", i))
+            .find(|name| !resources.xobjects.contains_key(name))
+            .unwrap();
+        let xobject = update.create(XObject::Image(image))?;
+        resources.xobjects.insert(name.clone(), xobject.get_ref());
+        self.resources = Some(MaybeRef::Direct(Rc::new(resources)));
+
+        let matrix = Matrix {
+            a: rect.right - rect.left, b: 0.,
+            c: 0., d: rect.top - rect.bottom,
+            e: rect.left, f: rect.bottom
+        };
+        let ops = vec![Op::Save, Op::Transform { matrix }, Op::XObject { name: name.clone() }, Op::Restore];
+        match self.contents {
+            Some(ref mut content) => {
+                // keep changes of the graphics state made by the existing contents out of the image
+                if !content.operations.is_empty() {
+                    content.operations.insert(0, Op::Save);
+                    content.operations.push(Op::Restore);
+                }
+                content.operations.extend(ops);
+            }
+            None => self.contents = Some(Content::from_ops(ops)?)
+        }
+        Ok(name)
+    }
 }
 impl SubType<PagesNode> for Page {}
 
@@ -308,7 +420,7 @@ pub struct PageLabel {
     pub start:  Option<usize>
 }
 
-#[derive(Object, ObjectWrite, Debug)]
+#[derive(ObjectWrite, Debug, Clone, Default)]
 pub struct Resources {
     #[pdf(key="ExtGState")]
     pub graphics_states: HashMap<String, GraphicsStateParameters>,
@@ -316,8 +428,13 @@ pub struct Resources {
     #[pdf(key="ColorSpace")]
     pub color_spaces: HashMap<String, ColorSpace>,
 
-    // pattern: Option<Pattern>,
-    // shading: Option<Shading>,
+    /// Patterns are read when they are used, as tiling patterns have resources of their own.
+    #[pdf(key="Pattern")]
+    pub patterns: HashMap<String, Ref<Pattern>>,
+
+    #[pdf(key="Shading")]
+    pub shadings: HashMap<String, MaybeRef<Shading>>,
+
     #[pdf(key="XObject")]
     pub xobjects: HashMap<String, Ref<XObject>>,
     // /XObject is a dictionary that map arbitrary names to XObjects
@@ -327,6 +444,45 @@ pub struct Resources {
     #[pdf(key="Properties")]
     pub properties: HashMap<String, RcRef<Dictionary>>,
 }
+impl FromDict for Resources {
+    /// Patterns and shadings that can't be read are skipped.
+    fn from_dict(mut dict: Dictionary, resolve: &impl Resolve) -> Result<Self> {
+        let mut entry = |key| dict.remove(key).unwrap_or(Primitive::Null);
+        Ok(Resources {
+            graphics_states: t!(HashMap::from_primitive(entry("ExtGState"), resolve)),
+            color_spaces: t!(HashMap::from_primitive(entry("ColorSpace"), resolve)),
+            patterns: t!(lenient_map(entry("Pattern"), resolve)),
+            shadings: t!(lenient_map(entry("Shading"), resolve)),
+            xobjects: t!(HashMap::from_primitive(entry("XObject"), resolve)),
+            fonts: t!(HashMap::from_primitive(entry("Font"), resolve)),
+            properties: t!(HashMap::from_primitive(entry("Properties"), resolve)),
+        })
+    }
+}
+impl Object for Resources {
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        Self::from_dict(p.into_dictionary(resolve)?, resolve)
+    }
+}
+
+/// Read the dictionary `p`, leaving out entries that can't be read.
+fn lenient_map<T: Object>(p: Primitive, resolve: &impl Resolve) -> Result<HashMap<String, T>> {
+    let dict = match p {
+        Primitive::Null => return Ok(HashMap::new()),
+        p => p.into_dictionary(resolve)?
+    };
+    let mut map = HashMap::with_capacity(dict.len());
+    for (key, p) in dict {
+        match T::from_primitive(p, resolve) {
+            Ok(value) => {
+                map.insert(key, value);
+            }
+            Err(e) => warn!("skipping resource {}: {}", key, e)
+        }
+    }
+    Ok(map)
+}
+
 impl Resources {
     pub fn fonts(&self) -> impl Iterator<Item=(&str, &Ref<Font>)> {
         self.fonts.iter().map(|(k, v)| (k.as_str(), v))
@@ -334,20 +490,20 @@ impl Resources {
 }
 
 
-#[derive(Object, ObjectWrite, Debug)]
+#[derive(Object, ObjectWrite, Debug, Copy, Clone)]
 pub enum LineCap {
     Butt = 0,
     Round = 1,
     Square = 2
 }
-#[derive(Object, ObjectWrite, Debug)]
+#[derive(Object, ObjectWrite, Debug, Copy, Clone)]
 pub enum LineJoin {
     Miter = 0,
     Round = 1,
     Bevel = 2
 }
 
-#[derive(Object, ObjectWrite, Debug)]
+#[derive(Object, ObjectWrite, Debug, Clone, Default)]
 #[pdf(Type = "ExtGState?")]
 /// `ExtGState`
 pub struct GraphicsStateParameters {
@@ -364,7 +520,7 @@ pub struct GraphicsStateParameters {
     pub miter_limit: Option<f32>,
     
     #[pdf(key="D")]
-    pub dash_pattern: Option<Vec<Primitive>>,
+    pub dash_pattern: Option<DashPattern>,
     
     #[pdf(key="RI")]
     pub rendering_intent: Option<String>,
@@ -381,22 +537,46 @@ pub struct GraphicsStateParameters {
     #[pdf(key="Font")]
     pub font: Option<(Ref<Font>, f32)>,
 
-    // BG
-    // BG2
-    // UCR
-    // UCR2
-    // TR
-    // TR2
-    // HT
-    // FL
-    // SM
-    // SA
+    /// Black generation
+    #[pdf(key="BG", lenient)]
+    pub black_generation: Option<MaybeRef<Function>>,
+
+    /// Black generation, takes precedence over `BG`.
+    #[pdf(key="BG2", lenient)]
+    pub black_generation2: Option<TransferFunction>,
+
+    /// Undercolor removal
+    #[pdf(key="UCR", lenient)]
+    pub undercolor_removal: Option<MaybeRef<Function>>,
+
+    /// Undercolor removal, takes precedence over `UCR`.
+    #[pdf(key="UCR2", lenient)]
+    pub undercolor_removal2: Option<TransferFunction>,
+
+    #[pdf(key="TR", lenient)]
+    pub transfer: Option<TransferFunction>,
+
+    /// Takes precedence over `TR`.
+    #[pdf(key="TR2", lenient)]
+    pub transfer2: Option<TransferFunction>,
+
+    #[pdf(key="HT", lenient)]
+    pub halftone: Option<Halftone>,
+
+    #[pdf(key="FL")]
+    pub flatness: Option<f32>,
+
+    #[pdf(key="SM")]
+    pub smoothness: Option<f32>,
+
+    #[pdf(key="SA")]
+    pub stroke_adjustment: Option<bool>,
 
     #[pdf(key="BM")]
-    pub blend_mode: Option<Primitive>,
+    pub blend_mode: Option<BlendModes>,
 
     #[pdf(key="SMask")]
-    pub smask: Option<Primitive>,
+    pub smask: Option<SoftMaskEntry>,
 
     
     #[pdf(key="CA")]
@@ -412,10 +592,10 @@ pub struct GraphicsStateParameters {
     pub text_knockout: Option<bool>,
 
     #[pdf(other)]
-    _other: Dictionary
+    pub other: Dictionary
 }
 
-#[derive(Object, Debug)]
+#[derive(Object, ObjectWrite, Debug)]
 #[pdf(is_stream)]
 pub enum XObject {
     #[pdf(name="PS")]
@@ -429,13 +609,13 @@ pub type PostScriptXObject = Stream<PostScriptDict>;
 /// A variant of XObject
 pub type ImageXObject = Stream<ImageDict>;
 
-#[derive(Object, Debug)]
+#[derive(Object, ObjectWrite, Debug)]
 #[pdf(Type="XObject", Subtype="PS")]
 pub struct PostScriptDict {
     // TODO
 }
 
-#[derive(Object, Debug, Clone)]
+#[derive(Object, ObjectWrite, Debug, Clone)]
 #[pdf(Type="XObject?", Subtype="Image")]
 /// A variant of XObject
 pub struct ImageDict {
@@ -447,8 +627,10 @@ pub struct ImageDict {
     #[pdf(key="ColorSpace")]
     pub color_space: Option<Primitive>,
 
+    /// Required, except for `ImageMask` stencils, which have 1 bit per component, and
+    /// `JPXDecode` images, which take it from the JPEG 2000 data.
     #[pdf(key="BitsPerComponent")]
-    pub bits_per_component: i32,
+    pub bits_per_component: Option<i32>,
     // Note: only allowed values are 1, 2, 4, 8, 16. Enum?
     
     #[pdf(key="Intent")]
@@ -476,7 +658,6 @@ pub struct ImageDict {
     // Alternates: Vec<AlternateImage>
 
     // SMask (soft mask): stream
-    // SMaskInData: i32
     ///The integer key of the image’s entry in the structural parent tree
     #[pdf(key="StructParent")]
     pub struct_parent: Option<i32>,
@@ -487,6 +668,16 @@ pub struct ImageDict {
     #[pdf(key="SMask")]
     pub smask: Option<Ref<Stream<ImageDict>>>,
 
+    /// For `JPXDecode` images: whether the opacity channel of the JPEG 2000 data is the soft mask
+    /// (1), is the soft mask and has been premultiplied into the colour channels (2), or is ignored (0).
+    #[pdf(key="SMaskInData", default="0")]
+    pub smask_in_data: i32,
+
+    /// For soft mask images: the colour the image the mask belongs to has been premultiplied
+    /// with, in that image's colour space.
+    #[pdf(key="Matte")]
+    pub matte: Option<Vec<f32>>,
+
     // OPI: dict
     // Metadata: stream
     // OC: dict
@@ -496,7 +687,32 @@ pub struct ImageDict {
 }
 
 
-#[derive(Object, Debug, Copy, Clone)]
+impl ImageXObject {
+    /// Decode a `JPXDecode` image along with the colour space and opacity channel stored in the
+    /// JPEG 2000 data. Returns `None` if the image is not JPX encoded.
+    ///
+    /// The colour samples are the same as those of `data()`. The opacity channel is only
+    /// returned if `smask_in_data` is set.
+    pub fn jpx_image(&self) -> Result<Option<JpxImage>> {
+        let filters = self.active_filters();
+        let n = match filters.last() {
+            Some(StreamFilter::JPXDecode) => filters.len() - 1,
+            _ => return Ok(None)
+        };
+        let data = self.decode_first(n)?;
+        let limit = self.output_limit(&StreamFilter::JPXDecode, data.len());
+        let mut image = t!(jpx::decode_image(&data, limit));
+        self.count_decoded_bytes(image.data.len())?;
+        if self.smask_in_data == 0 {
+            image.alpha = None;
+            image.premultiplied = false;
+        }
+        Ok(Some(image))
+    }
+}
+
+
+#[derive(Object, ObjectWrite, Debug, Copy, Clone)]
 pub enum RenderingIntent {
     AbsoluteColorimetric,
     RelativeColorimetric,
@@ -524,7 +740,7 @@ impl RenderingIntent {
 }
 
 
-#[derive(Object, Debug)]
+#[derive(Object, ObjectWrite, Debug)]
 #[pdf(Type="XObject?", Subtype="Form")]
 pub struct FormDict {
     #[pdf(key="FormType", default="1")]
@@ -546,7 +762,7 @@ pub struct FormDict {
     pub resources: Option<MaybeRef<Resources>>,
 
     #[pdf(key="Group")]
-    pub group: Option<Dictionary>,
+    pub group: Option<TransparencyGroup>,
 
     #[pdf(key="Ref")]
     pub reference: Option<Dictionary>,
@@ -842,8 +1058,14 @@ pub struct NameDictionary {
  * to embedded file streams through their EF entries.
 */
 
-#[derive(Object, ObjectWrite, Debug, Clone)]
+#[derive(ObjectWrite, Debug, Clone, Default)]
 pub struct FileSpec {
+    /// The file name, in PDF file specification string syntax.
+    #[pdf(key="F")]
+    pub f: Option<PdfString>,
+    /// The file name as a text string.
+    #[pdf(key="UF")]
+    pub uf: Option<PdfString>,
     #[pdf(key="EF")]
     ef: Option<Files<Ref<Stream<EmbeddedFile>>>>,
     /*
@@ -851,6 +1073,31 @@ pub struct FileSpec {
     rf: Option<Files<RelatedFilesArray>>,
     */
 }
+impl Object for FileSpec {
+    /// A file specification is either a string or a dictionary.
+    fn from_primitive(p: Primitive, resolve: &impl Resolve) -> Result<Self> {
+        let p = match p {
+            Primitive::Reference(r) => resolve.resolve(r)?,
+            p => p
+        };
+        if let Primitive::String(f) = p {
+            return Ok(FileSpec { f: Some(f), .. FileSpec::default() });
+        }
+        let mut dict = p.into_dictionary(resolve)?;
+        Ok(FileSpec {
+            f: Option::<PdfString>::from_primitive(dict.remove("F").unwrap_or(Primitive::Null), resolve)?,
+            uf: Option::<PdfString>::from_primitive(dict.remove("UF").unwrap_or(Primitive::Null), resolve)?,
+            ef: Option::from_primitive(dict.remove("EF").unwrap_or(Primitive::Null), resolve)?,
+        })
+    }
+}
+impl FileSpec {
+    /// The name of the file, preferring `UF` over `F`.
+    pub fn name(&self) -> Option<String> {
+        let name = self.uf.as_ref().or(self.f.as_ref())?;
+        name.as_str().ok().map(|s| s.into_owned())
+    }
+}
 
 /// Used only as elements in `FileSpec`
 #[derive(Object, ObjectWrite, Debug, Clone)]
@@ -1068,11 +1315,22 @@ pub enum StructType {
 
 #[cfg(test)]
 mod tests {
+    use std::rc::Rc;
     use crate::{
-        object::{NoResolve, Object, StructType},
+        object::*,
         primitive::Primitive,
     };
 
+    #[test]
+    fn write_page() {
+        // pages are told apart from page trees by their /Type, and refer to their parent
+        let parent = PlainRef { id: 2, gen: 0 };
+        let tree = super::PagesRc(RcRef::new(parent, Rc::new(PagesNode::Tree(PageTree::default()))));
+        let dict = Page::new(tree).to_primitive(&mut NoUpdate).unwrap().into_dictionary(&NoResolve).unwrap();
+        assert_eq!(dict["Type"].as_name().unwrap(), "Page");
+        assert!(matches!(dict["Parent"], Primitive::Reference(r) if r == parent));
+    }
+
     #[test]
     fn parse_struct_type() {
         assert!(matches!(
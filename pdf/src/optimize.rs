@@ -0,0 +1,183 @@
+//! Shrinking files by downsampling and recompressing their images.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::object::*;
+use crate::error::*;
+use crate::backend::Backend;
+use crate::file::File;
+use crate::content::{Op, Matrix};
+
+/// Form XObjects nested deeper than this are not searched for images.
+const MAX_FORM_DEPTH: usize = 16;
+
+/// Settings for `optimize_images`.
+#[derive(Debug, Clone)]
+pub struct ImageOptions {
+    /// Images painted at a higher resolution than this, in pixels per inch, are downsampled.
+    pub max_dpi: f32,
+    /// The resolution downsampled images are painted at.
+    pub target_dpi: f32,
+    /// How the downsampled images are compressed.
+    pub compression: ImageCompression,
+}
+impl Default for ImageOptions {
+    fn default() -> ImageOptions {
+        ImageOptions {
+            max_dpi: 225.,
+            target_dpi: 150.,
+            compression: ImageCompression::Dct { quality: 75 },
+        }
+    }
+}
+
+/// What `optimize_images` did.
+#[derive(Debug, Clone, Default)]
+pub struct ImageStats {
+    /// The number of images that were replaced.
+    pub replaced: usize,
+    /// The number of images above `max_dpi` that could not be resampled.
+    pub failed: usize,
+    /// The size of the stream data of the replaced images before and after.
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+/// Downsample the images of `file` that are painted at more than `options.max_dpi` anywhere
+/// in the page contents (including form XObjects) to `options.target_dpi`, and replace them
+/// with `Updater::update`.
+///
+/// The resolution is taken from the largest size an image is painted at. Images are only
+/// replaced if that makes their data smaller. Images that can't be decoded or resampled
+/// are left as they are, and pages and XObjects that can't be read are skipped.
+pub fn optimize_images<B: Backend>(file: &mut File<B>, options: &ImageOptions) -> Result<ImageStats> {
+    let mut sizes = HashMap::new();
+    let mut visited = HashSet::new();
+    for (i, page) in file.pages().enumerate() {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                warn!("skipping page {}: {}", i, e);
+                continue;
+            }
+        };
+        let resources = match page.resources() {
+            Ok(resources) => resources,
+            Err(_) => continue
+        };
+        let content = match page.contents {
+            Some(ref content) => content,
+            None => continue
+        };
+        let mut placements = Placements { resolve: &*file, sizes: &mut sizes, visited: &mut visited };
+        placements.ops(&content.operations, resources, Matrix::default(), 0);
+    }
+
+    let mut stats = ImageStats::default();
+    for (r, (width_pt, height_pt)) in sizes {
+        if width_pt <= 0. || height_pt <= 0. {
+            continue;
+        }
+        let xobject = match file.get(Ref::<XObject>::new(r)) {
+            Ok(xobject) => xobject,
+            Err(e) => {
+                warn!("can't read image {:?}: {}", r, e);
+                stats.failed += 1;
+                continue;
+            }
+        };
+        let image = match *xobject {
+            XObject::Image(ref image) => image,
+            _ => continue
+        };
+        let (width, height) = (image.width.max(1) as u32, image.height.max(1) as u32);
+        let dpi = (width as f32 / width_pt).max(height as f32 / height_pt) * 72.;
+        if dpi <= options.max_dpi {
+            continue;
+        }
+        let new_width = ((width_pt * options.target_dpi / 72.).ceil() as u32).clamp(1, width);
+        let new_height = ((height_pt * options.target_dpi / 72.).ceil() as u32).clamp(1, height);
+        if (new_width, new_height) == (width, height) {
+            continue;
+        }
+        let resampled = match image.resample(new_width, new_height, options.compression, file) {
+            Ok(resampled) => resampled,
+            Err(e) => {
+                warn!("can't resample image {:?}: {}", r, e);
+                stats.failed += 1;
+                continue;
+            }
+        };
+        let (before, after) = (image.raw_data().len(), resampled.raw_data().len());
+        if after >= before {
+            continue;
+        }
+        file.update(r, XObject::Image(resampled))?;
+        stats.replaced += 1;
+        stats.bytes_before += before;
+        stats.bytes_after += after;
+    }
+    Ok(stats)
+}
+
+/// Records the largest size, in default user space units, that each image is painted at.
+struct Placements<'a, R> {
+    resolve: &'a R,
+    sizes: &'a mut HashMap<PlainRef, (f32, f32)>,
+    /// Forms already searched, with the linear part of the transformation they were painted
+    /// with. The translation does not change the size of images.
+    visited: &'a mut HashSet<(PlainRef, [u32; 4])>,
+}
+impl<'a, R: Resolve> Placements<'a, R> {
+    /// Record the images painted by `ops`. XObjects that can't be read are skipped.
+    fn ops(&mut self, ops: &[Op], resources: &Resources, mut ctm: Matrix, depth: usize) {
+        let mut stack = Vec::new();
+        for op in ops {
+            match *op {
+                Op::Save => stack.push(ctm),
+                Op::Restore => if let Some(saved) = stack.pop() {
+                    ctm = saved;
+                }
+                Op::Transform { matrix } => ctm = matrix * ctm,
+                Op::XObject { ref name } => {
+                    let r = match resources.xobjects.get(name) {
+                        Some(&r) => r,
+                        None => continue
+                    };
+                    if let Err(e) = self.xobject(r, resources, ctm, depth) {
+                        warn!("skipping XObject {:?}: {}", r.get_inner(), e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn xobject(&mut self, r: Ref<XObject>, resources: &Resources, ctm: Matrix, depth: usize) -> Result<()> {
+        match *self.resolve.get(r)? {
+            XObject::Image(_) => {
+                // the image fills the unit square
+                let width = ctm.a.hypot(ctm.b);
+                let height = ctm.c.hypot(ctm.d);
+                let size = self.sizes.entry(r.get_inner()).or_insert((0., 0.));
+                size.0 = size.0.max(width);
+                size.1 = size.1.max(height);
+            }
+            XObject::Form(ref form) if depth < MAX_FORM_DEPTH => {
+                let dict = form.dict();
+                let matrix = match dict.matrix {
+                    Some(ref p) => Matrix::from_primitive(p.clone(), self.resolve)?,
+                    None => Matrix::default()
+                };
+                let ctm = matrix * ctm;
+                let key = (r.get_inner(), [ctm.a.to_bits(), ctm.b.to_bits(), ctm.c.to_bits(), ctm.d.to_bits()]);
+                if self.visited.insert(key) {
+                    let resources = dict.resources.as_deref().unwrap_or(resources);
+                    self.ops(&form.operations, resources, ctm, depth + 1);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -171,8 +171,8 @@ impl<'a> Lexer<'a> {
         if self.is_delimiter(pos) {
             if self.buf[pos] == b'/' {
                 pos = self.advance_pos(pos)?;
-                while !self.is_whitespace(pos) && !self.is_delimiter(pos) {
-                    pos = self.advance_pos(pos)?;
+                while pos < self.buf.len() && !self.is_whitespace(pos) && !self.is_delimiter(pos) {
+                    pos += 1;
                 }
                 return Ok((self.new_substr(start_pos..pos), pos));
             }
@@ -186,9 +186,9 @@ impl<'a> Lexer<'a> {
             return Ok((self.new_substr(start_pos..pos), pos));
         }
 
-        // Read to past the end of lexeme
-        while !self.is_whitespace(pos) && !self.is_delimiter(pos) {
-            pos = self.advance_pos(pos)?;
+        // Read to past the end of lexeme, which may also end at the end of the buffer
+        while pos < self.buf.len() && !self.is_whitespace(pos) && !self.is_delimiter(pos) {
+            pos += 1;
         }
         let result = self.new_substr(start_pos..pos);
 
@@ -289,23 +289,9 @@ impl<'a> Lexer<'a> {
         //
         let substr = substr.as_ref();
         let start = self.pos;
-        let mut matched = 0;
-        loop {
-            if self.buf[self.pos] == substr[matched] {
-                matched += 1;
-            } else {
-                matched = 0;
-            }
-            if matched == substr.len() {
-                break;
-            }
-            if self.pos >= self.buf.len() {
-                return None
-            }
-            self.pos += 1;
-        }
-        self.pos += 1;
-        Some(self.new_substr(start..(self.pos - substr.len())))
+        let found = self.buf.get(start ..)?.windows(substr.len()).position(|w| w == substr)?;
+        self.pos = start + found + substr.len();
+        Some(self.new_substr(start .. start + found))
     }
 
     //TODO perhaps seek_substr_back should, like back(), move to the first letter of the substr.
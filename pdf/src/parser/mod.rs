@@ -105,10 +105,11 @@ pub fn parse_with_lexer_ctx(lexer: &mut Lexer, r: &impl Resolve, ctx: Option<&Co
         // First backup position
         let pos_bk = lexer.get_pos();
 
-        let second_lexeme = t!(lexer.next());
-        if second_lexeme.is_integer() {
-            let third_lexeme = t!(lexer.next());
-            if third_lexeme.equals(b"R") {
+        // Running out of input while looking ahead just means it is not a reference.
+        let second_lexeme = lexer.next().ok().filter(|lexeme| lexeme.is_integer());
+        if let Some(second_lexeme) = second_lexeme {
+            let is_reference = lexer.next().map(|lexeme| lexeme.equals(b"R")).unwrap_or(false);
+            if is_reference {
                 // It is indeed a reference to an indirect object
                 Primitive::Reference (PlainRef {
                     id: t!(first_lexeme.to::<ObjNr>()),
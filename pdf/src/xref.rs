@@ -2,6 +2,7 @@ use std::fmt::{Debug, Formatter};
 use std::io::Write;
 use crate::error::*;
 use crate::object::*;
+use crate::enc::{StreamFilter, LZWFlateParams, encode};
 use crate as pdf;
 
 ///////////////////////////
@@ -145,7 +146,14 @@ impl XRefTable {
             prev: None,
             w: vec![1, a_w as i32, b_w as i32],
         };
-        Ok(Stream::new(info, data).hexencode())
+        let params = LZWFlateParams {
+            predictor: 12,
+            columns: (1 + a_w + b_w) as i32,
+            .. LZWFlateParams::default()
+        };
+        let filter = StreamFilter::FlateDecode(params);
+        let data = encode(&data, &filter)?;
+        Ok(Stream::new_with_filters(info, data, vec![filter]))
     }
 }
 
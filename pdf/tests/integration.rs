@@ -1,8 +1,9 @@
 use std::str;
 use std::rc::Rc;
-use pdf::file::File;
+use pdf::file::{File, OpenOptions};
 use pdf::object::*;
 use pdf::parser::parse;
+use pdf::primitive::Primitive;
 use glob::glob;
 
 macro_rules! file_path {
@@ -106,4 +107,566 @@ fn parse_objects_from_stream() {
     }
 }
 
+#[test]
+fn open_options_limits() {
+    let options = OpenOptions { max_objects: Some(1), .. OpenOptions::default() };
+    match File::open_with_options(file_path!("example.pdf"), b"", options) {
+        Err(e) => assert!(e.is_limit_exceeded()),
+        Ok(_) => panic!("max_objects was not enforced")
+    }
+
+    let options = OpenOptions { max_operators: Some(1), .. OpenOptions::default() };
+    let file = run!(File::open_with_options(file_path!("example.pdf"), b"", options));
+    assert!(file.get_page(0).unwrap_err().is_limit_exceeded());
+
+    let options = OpenOptions { max_page_tree_depth: Some(0), .. OpenOptions::default() };
+    let file = run!(File::open_with_options(file_path!("example.pdf"), b"", options));
+    assert!(file.get_page(0).unwrap_err().is_limit_exceeded());
+    assert!(file.pages().next().unwrap().unwrap_err().is_limit_exceeded());
+
+    let file = run!(File::<Vec<u8>>::open(file_path!("xelatex.pdf")));
+    assert_eq!(file.pages().filter(|p| p.is_ok()).count(), file.num_pages() as usize);
+
+    // the tree has one page less than its /Count says
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let mut tree = (*file.trailer.root.pages).clone();
+    tree.count += 1;
+    let pages = run!(PagesRc::create(tree, &mut file));
+    run!(file.update_catalog(Catalog { pages, names: None, dests: None, outlines: None, metadata: None, struct_tree_root: None }));
+    let pages: Vec<_> = file.pages().collect();
+    assert_eq!(pages.len(), file.num_pages() as usize);
+    assert!(pages[.. pages.len() - 1].iter().all(|p| p.is_ok()));
+    assert!(matches!(pages.last(), Some(Err(pdf::error::PdfError::PageOutOfBounds { .. }))));
+
+    let options = OpenOptions { max_decoded_bytes: Some(10), .. OpenOptions::default() };
+    let file = run!(File::open_with_options(file_path!("example.pdf"), b"", options));
+    assert!(file.get_page(0).unwrap_err().is_limit_exceeded());
+}
+
+#[test]
+fn external_stream_next_to_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let stream = "<< /Length 0 /F (data.hex) /FFilter /ASCIIHexDecode >> stream\n\nendstream";
+    let stream = run!(Stream::<()>::from_primitive(run!(parse(stream.as_bytes(), &file)), &file));
+    let r = run!(file.create(stream)).get_ref();
+    let path = dir.path().join("external.pdf");
+    run!(file.save_to(&path));
+    std::fs::write(dir.path().join("data.hex"), b"48656c6c6f>").unwrap();
+
+    let saved = run!(File::<Vec<u8>>::open(&path));
+    assert!(run!(saved.get(r)).data().is_err());
+
+    let options = OpenOptions { resolve_relative_files: true, .. OpenOptions::default() };
+    let saved = run!(File::open_with_options(&path, b"", options));
+    assert_eq!(run!(run!(saved.get(r)).data()), b"Hello");
+}
+
 // TODO test decoding
+
+#[test]
+fn save_and_reload() {
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("save_and_reload.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+    assert_eq!(saved.num_pages(), file.num_pages());
+    for i in 0 .. saved.num_pages() {
+        run!(saved.get_page(i));
+    }
+}
+
+/// A PNG file with 8 bit samples of `channels` (1 for gray, 4 for RGBA).
+fn png(width: u32, height: u32, channels: usize, raw: &[u8]) -> Vec<u8> {
+    use pdf::enc::{encode, StreamFilter, LZWFlateParams};
+
+    let params = LZWFlateParams { predictor: 15, n_components: channels as i32, columns: width as i32, ..Default::default() };
+    let idat = run!(encode(raw, &StreamFilter::FlateDecode(params)));
+    let color_type = if channels == 4 { 6 } else { 0 };
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, color_type, 0, 0, 0]);
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for &(kind, body) in &[(b"IHDR", &ihdr[..]), (b"IDAT", &idat[..]), (b"IEND", &[][..])] {
+        png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(body);
+        png.extend_from_slice(&[0; 4]);
+    }
+    png
+}
+
+#[test]
+fn add_png_to_page() {
+    use pdf::content::Op;
+
+    // a 2x1 RGBA image: opaque red and half transparent blue
+    let raw = [255, 0, 0, 255, 0, 0, 255, 128];
+    let png = png(2, 1, 4, &raw);
+
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let old = run!(file.get_page(0));
+    let mut page: Page = (*old).clone();
+    let image = run!(ImageXObject::from_png(&png, &mut file));
+    let rect = Rect { left: 10., bottom: 20., right: 110., top: 70. };
+    let name = run!(page.add_image(image, rect, &mut file));
+    run!(PageRc::update(page, &old, &mut file));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("add_png_to_page.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+
+    let page = run!(saved.get_page(0));
+    let ops = &page.contents.as_ref().unwrap().operations;
+    assert!(matches!(ops.last(), Some(Op::Restore)));
+    assert!(matches!(ops[ops.len() - 2], Op::XObject { name: ref n } if *n == name));
+    let xobject = run!(page.resources()).xobjects[&name];
+    let image = match *run!(saved.get(xobject)) {
+        XObject::Image(ref image) => run!(image.raster(&saved, None)),
+        _ => panic!("not an image")
+    };
+    assert_eq!(image.data, raw);
+}
+
+#[test]
+fn optimize_images() {
+    use pdf::optimize::{optimize_images, ImageOptions};
+
+    // a noisy 400x200 image painted 100pt wide, at 288 dpi
+    let raw: Vec<u8> = (0 .. 400 * 200u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+    let png = png(400, 200, 1, &raw);
+
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let old = run!(file.get_page(0));
+    let mut page: Page = (*old).clone();
+    let image = run!(ImageXObject::from_png(&png, &mut file));
+    let rect = Rect { left: 0., bottom: 0., right: 100., top: 50. };
+    let name = run!(page.add_image(image, rect, &mut file));
+
+    // a missing XObject is skipped, and forms painting each other twice are searched once
+    let mut resources = (**run!(page.resources())).clone();
+    resources.xobjects.insert("Missing".into(), Ref::new(PlainRef { id: 99999, gen: 0 }));
+    let mut form = None;
+    for _ in 0 .. 16 {
+        let xobjects = match form {
+            Some(r) => format!("/Resources << /XObject << /X {} 0 R >> >>", r),
+            None => String::new()
+        };
+        let s = format!("<< /Type /XObject /Subtype /Form /BBox [0 0 1 1] {} /Length 11 >> stream
+/X Do /X Do
+endstream", xobjects);
+        let xobject = run!(XObject::from_primitive(run!(parse(s.as_bytes(), &file)), &file));
+        form = Some(run!(file.create(xobject)).get_ref().get_inner().id);
+    }
+    resources.xobjects.insert("Forms".into(), Ref::new(PlainRef { id: form.unwrap(), gen: 0 }));
+    page.resources = Some(MaybeRef::Direct(Rc::new(resources)));
+    let content = page.contents.as_mut().unwrap();
+    content.operations.push(pdf::content::Op::XObject { name: "Missing".into() });
+    content.operations.push(pdf::content::Op::XObject { name: "Forms".into() });
+    run!(PageRc::update(page, &old, &mut file));
+
+    let options = ImageOptions { max_dpi: 150., target_dpi: 72., ..ImageOptions::default() };
+    let stats = run!(optimize_images(&mut file, &options));
+    assert_eq!(stats.replaced, 1);
+    assert!(stats.bytes_after < stats.bytes_before);
+
+    let page = run!(file.get_page(0));
+    let xobject = run!(page.resources()).xobjects[&name];
+    match *run!(file.get(xobject)) {
+        XObject::Image(ref image) => {
+            assert_eq!((image.width, image.height), (100, 50));
+            assert_eq!(run!(image.raster(&file, None)).data.len(), 100 * 50);
+        }
+        _ => panic!("not an image")
+    }
+
+    // nothing is left to do
+    assert_eq!(run!(optimize_images(&mut file, &options)).replaced, 0);
+}
+
+/// Panics if any object of `file` has a stream inside it, which can't be read back.
+fn assert_no_nested_streams<B: pdf::backend::Backend>(file: &File<B>) {
+    fn check(p: &Primitive, id: u64) {
+        match *p {
+            Primitive::Stream(_) => panic!("object {} contains a stream", id),
+            Primitive::Dictionary(ref dict) => dict.iter().for_each(|(_, p)| check(p, id)),
+            Primitive::Array(ref parts) => parts.iter().for_each(|p| check(p, id)),
+            _ => {}
+        }
+    }
+    for id in 1..file.trailer.highest_id as u64 {
+        match file.resolve(PlainRef { id, gen: 0 }) {
+            Ok(Primitive::Stream(ref stream)) => stream.info.iter().for_each(|(_, p)| check(p, id)),
+            Ok(ref p) => check(p, id),
+            Err(_) => {}
+        }
+    }
+}
+
+#[test]
+fn write_color_spaces() {
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+
+    // tint transforms that are streams have to be objects of their own
+    let postscript = "<< /FunctionType 4 /Domain [0 1 0 1] /Range [0 1 0 1 0 1 0 1] /Length 7 >> stream
+{ 0 0 }
+endstream";
+    let sampled = "<< /FunctionType 0 /Domain [0 1] /Range [0 1 0 1 0 1] /Size [2] /BitsPerSample 4 /Filter /ASCIIHexDecode /Length 7 >> stream
+fff00f>
+endstream";
+    let mut tints = Vec::new();
+    for s in &[postscript, sampled] {
+        let function = run!(Function::from_primitive(run!(parse(s.as_bytes(), &file)), &file));
+        tints.push(run!(file.create(function)).get_ref().get_inner().id);
+    }
+
+    let spaces = [
+        "/Pattern".into(),
+        "[/CalGray << /WhitePoint [0.9505 1 1.089] /Gamma 2.2 >>]".into(),
+        "[/CalRGB << /WhitePoint [0.9505 1 1.089] /Gamma [1.8 1.8 1.8] /Matrix [0.4497 0.2446 0.0252 0.3163 0.672 0.1412 0.1845 0.0833 0.9227] >>]".into(),
+        "[/Lab << /WhitePoint [0.9505 1 1.089] /Range [-128 127 -128 127] >>]".into(),
+        "[/Indexed /DeviceRGB 1 <ff000000ff00>]".into(),
+        "[/Pattern [/Separation /Gold /DeviceCMYK << /FunctionType 2 /Domain [0 1] /C0 [0 0 0 0] /C1 [0 0.2 1 0.1] /N 1 >>]]".into(),
+        format!("[/DeviceN [/Cyan /Spot] /DeviceCMYK {} 0 R
+            << /Subtype /NChannel /Process << /ColorSpace /DeviceCMYK /Components [/Cyan /Magenta /Yellow /Black] >> >>]", tints[0]),
+        format!("[/Separation /Blue /DeviceRGB {} 0 R]", tints[1]),
+    ];
+    let mut refs = Vec::new();
+    for s in &spaces {
+        let cs = run!(ColorSpace::from_primitive(run!(parse(s.as_bytes(), &file)), &file));
+        refs.push(run!(file.create(cs)).get_ref());
+    }
+    let rgb = run!(file.create(ColorSpace::DeviceRGB)).get_ref();
+    let number = run!(file.create(42i32)).get_ref();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("color_spaces.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+    assert_no_nested_streams(&saved);
+
+    assert!(matches!(*run!(saved.get(rgb)), ColorSpace::DeviceRGB));
+    assert_eq!(*run!(saved.get(number)), 42);
+
+    let spaces: Vec<_> = refs.iter().map(|&r| run!(saved.get(r))).collect();
+    assert!(matches!(*spaces[0], ColorSpace::Pattern(None)));
+    match *spaces[1] {
+        ColorSpace::CalGray(ref info) => assert_eq!(info.gamma, Some(2.2)),
+        ref cs => panic!("{:?}", cs)
+    }
+    match *spaces[2] {
+        ColorSpace::CalRGB(ref info) => assert_eq!(info.matrix.as_ref().unwrap().len(), 9),
+        ref cs => panic!("{:?}", cs)
+    }
+    match *spaces[3] {
+        ColorSpace::Lab(ref info) => assert_eq!(info.range, Some(vec![-128., 127., -128., 127.])),
+        ref cs => panic!("{:?}", cs)
+    }
+    match *spaces[4] {
+        ColorSpace::Indexed(ref base, ref lookup) => {
+            assert!(matches!(**base, ColorSpace::DeviceRGB));
+            assert_eq!(*lookup, [255, 0, 0, 0, 255, 0]);
+        }
+        ref cs => panic!("{:?}", cs)
+    }
+    match *spaces[5] {
+        ColorSpace::Pattern(Some(ref base)) => match **base {
+            ColorSpace::Separation(ref name, _, ref tint) => {
+                assert_eq!(name, "Gold");
+                let mut out = [0.; 4];
+                run!(tint.apply(&[0.5], &mut out));
+                assert_eq!(out, [0., 0.1, 0.5, 0.05]);
+            }
+            ref cs => panic!("{:?}", cs)
+        },
+        ref cs => panic!("{:?}", cs)
+    }
+    match *spaces[6] {
+        ColorSpace::DeviceN { ref names, ref tint, attributes: Some(ref attributes), .. } => {
+            assert_eq!(*names, ["Cyan", "Spot"]);
+            assert_eq!(attributes.subtype.as_deref(), Some("NChannel"));
+            assert_eq!(attributes.process.as_ref().unwrap().components.len(), 4);
+            let mut out = [0.; 4];
+            run!(tint.apply(&[0.25, 0.5], &mut out));
+            assert_eq!(out, [0.25, 0.5, 0., 0.]);
+        }
+        ref cs => panic!("{:?}", cs)
+    }
+    match *spaces[7] {
+        ColorSpace::Separation(_, _, ref tint) => {
+            let mut out = [0.; 3];
+            run!(tint.apply(&[0.5], &mut out));
+            assert_eq!(out, [0.5, 0.5, 1.]);
+        }
+        ref cs => panic!("{:?}", cs)
+    }
+}
+
+#[test]
+fn write_patterns() {
+    use pdf::content::{Op, Matrix, Point};
+
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let radial = "<< /ShadingType 3 /ColorSpace /DeviceRGB /Coords [0 0 0 0 0 50] /Extend [true false]
+        /Function [<< /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [1] /N 1 >> << /FunctionType 2 /Domain [0 1] /C0 [1] /C1 [0] /N 1 >> << /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [0] /N 1 >>] >>";
+    let radial = run!(Shading::from_primitive(run!(parse(radial.as_bytes(), &file)), &file));
+    let mesh = "<< /ShadingType 4 /ColorSpace /DeviceGray /BitsPerCoordinate 8 /BitsPerComponent 8 /BitsPerFlag 8
+        /Decode [0 255 0 255 0 1] /AntiAlias true /Filter /ASCIIHexDecode /Length 25 >> stream
+000000ff00ff000000ffff80>
+endstream";
+    let mesh = run!(Shading::from_primitive(run!(parse(mesh.as_bytes(), &file)), &file));
+    let mesh = run!(file.create(mesh));
+
+    let cell = TilingPatternDict {
+        paint_type: PaintType::Uncolored,
+        tiling_type: TilingType::ConstantSpacing,
+        bbox: Rect { left: 0., bottom: 0., right: 10., top: 10. },
+        x_step: 10.,
+        y_step: 10.,
+        resources: None,
+        matrix: Some(Matrix { a: 2., d: 2., ..Matrix::default() }),
+    };
+    let ops = vec![Op::MoveTo { p: Point { x: 0., y: 0. } }, Op::LineTo { p: Point { x: 5., y: 5. } }, Op::Stroke];
+    let tiling = run!(file.create(Pattern::Tiling(Box::new(run!(pdf::content::TilingPattern::from_ops(cell, ops))))));
+    let shading = Pattern::Shading(ShadingPattern {
+        shading: MaybeRef::Indirect(mesh),
+        matrix: None,
+        ext_g_state: None,
+    });
+
+    let mut resources = Resources::default();
+    resources.shadings.insert("Sh1".into(), MaybeRef::Direct(Rc::new(radial)));
+    resources.patterns.insert("P1".into(), tiling.get_ref());
+    resources.patterns.insert("P2".into(), run!(file.create(shading)).get_ref());
+    let r = run!(file.create(resources)).get_ref();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("write_patterns.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+
+    let resources = run!(saved.get(r));
+    match resources.shadings["Sh1"].kind {
+        ShadingKind::Radial(ref radial) => {
+            assert_eq!(radial.coords, [0., 0., 0., 0., 0., 50.]);
+            assert_eq!(radial.extend, Some(vec![true, false]));
+            assert!(matches!(radial.function, ShadingFunction::Multiple(ref f) if f.len() == 3));
+        }
+        ref kind => panic!("{:?}", kind)
+    }
+    match *run!(saved.get(resources.patterns["P1"])) {
+        Pattern::Tiling(ref tiling) => {
+            assert_eq!(tiling.dict().paint_type, PaintType::Uncolored);
+            assert_eq!(tiling.dict().matrix, Some(Matrix { a: 2., d: 2., ..Matrix::default() }));
+            assert_eq!(tiling.operations.len(), 3);
+        }
+        ref p => panic!("{:?}", p)
+    }
+    match *run!(saved.get(resources.patterns["P2"])) {
+        Pattern::Shading(ref pattern) => {
+            assert!(pattern.shading.anti_alias);
+            match pattern.shading.kind {
+                ShadingKind::FreeForm(ref mesh) => {
+                    assert_eq!(mesh.bits_per_flag, Some(8));
+                    assert_eq!(mesh.data, [0, 0, 0, 0xff, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0x80]);
+                }
+                ref kind => panic!("{:?}", kind)
+            }
+        }
+        ref p => panic!("{:?}", p)
+    }
+
+    // entries that can't be read are left out
+    let s = "<< /Shading << /Bad << /ShadingType 99 >> /Sh1 << /ShadingType 2 /ColorSpace /DeviceGray /Coords [0 0 1 0]
+        /Function << /FunctionType 2 /Domain [0 1] /C0 [0] /C1 [1] /N 1 >> >> >> /Pattern << /Direct << /PatternType 2 >> >> >>";
+    let resources = run!(Resources::from_primitive(run!(parse(s.as_bytes(), &saved)), &saved));
+    assert_eq!(resources.shadings.keys().collect::<Vec<_>>(), ["Sh1"]);
+    assert!(resources.patterns.is_empty());
+
+    // a tiling pattern that uses itself
+    let mut file = saved;
+    let promise = file.promise::<Pattern>();
+    let s = format!("<< /PatternType 1 /PaintType 1 /TilingType 1 /BBox [0 0 1 1] /XStep 1 /YStep 1
+        /Resources << /Pattern << /P {} 0 R >> >> /Length 0 >> stream
+
+endstream", promise.get_inner().id);
+    let pattern = run!(Pattern::from_primitive(run!(parse(s.as_bytes(), &file)), &file));
+    let pattern = run!(file.fulfill(promise, pattern));
+    let mut resources = Resources::default();
+    resources.patterns.insert("P".into(), pattern.get_ref());
+    assert!(!run!(resources.uses_transparency(&file)));
+}
+
+#[test]
+fn write_graphics_states() {
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let group = run!(file.create(Stream::new((), vec![]))).get_ref().get_inner();
+    let threshold = "<< /HalftoneType 6 /Width 2 /Height 2 /Filter /ASCIIHexDecode /Length 9 >> stream
+0040c0ff>
+endstream";
+    let threshold = run!(Stream::<ThresholdHalftone>::from_primitive(run!(parse(threshold.as_bytes(), &file)), &file));
+    let threshold = run!(file.create(threshold)).get_ref().get_inner();
+    let gs = format!("<< /Type /ExtGState /D [[3 1] 2] /BM [/Unknown /Multiply] /TR /Identity /BG2 /Default /SA true
+        /TR2 << /FunctionType 2 /Domain [0 1] /C0 [1] /C1 [0] /N 1 >>
+        /HT << /Type /Halftone /HalftoneType 5
+            /Default << /HalftoneType 1 /Frequency 60 /Angle 45 /SpotFunction /Round >>
+            /Cyan << /HalftoneType 1 /Frequency 60 /Angle 15 /SpotFunction [/SimpleDot /Round] >>
+            /Black {} 0 R >>
+        /SMask << /Type /Mask /S /Luminosity /G {} {} R /BC [0.5] >> /ca 0.5 >>", threshold.id, group.id, group.gen);
+    let gs = run!(GraphicsStateParameters::from_primitive(run!(parse(gs.as_bytes(), &file)), &file));
+    let r1 = run!(file.create(gs)).get_ref();
+    let r2 = run!(file.create(GraphicsStateParameters {
+        blend_mode: Some(BlendMode::Screen.into()),
+        smask: Some(SoftMaskEntry::None),
+        ..Default::default()
+    })).get_ref();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("write_graphics_states.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+    assert_no_nested_streams(&saved);
+
+    let gs = run!(saved.get(r1));
+    assert_eq!(gs.dash_pattern, Some(DashPattern { pattern: vec![3., 1.], phase: 2. }));
+    assert_eq!(gs.blend_mode, Some(BlendModes(vec!["Unknown".into(), "Multiply".into()])));
+    assert_eq!(gs.blend_mode.as_ref().unwrap().mode(), BlendMode::Multiply);
+    assert!(matches!(gs.transfer, Some(TransferFunction::Identity)));
+    assert!(matches!(gs.black_generation2, Some(TransferFunction::Default)));
+    assert_eq!(run!(gs.transfer2.as_ref().unwrap().apply(0, 0.25)), 0.75);
+    assert_eq!(gs.stroke_adjustment, Some(true));
+    match gs.halftone {
+        Some(Halftone::Multiple { ref default, ref colorants }) => {
+            assert!(matches!(**default, Halftone::Screen(ref s) if s.angle == 45.));
+            match colorants["Cyan"] {
+                Halftone::Screen(ref s) => assert!(matches!(s.spot_function, SpotFunction::Predefined(ref names) if names.len() == 2)),
+                ref h => panic!("{:?}", h)
+            }
+            match colorants["Black"] {
+                Halftone::Threshold(ref t) => {
+                    assert_eq!(t.as_ref().unwrap().get_inner(), threshold);
+                    assert_eq!((t.info.width, t.info.height), (Some(2), Some(2)));
+                    assert_eq!(run!(t.data()), [0x00, 0x40, 0xc0, 0xff]);
+                }
+                ref h => panic!("{:?}", h)
+            }
+        }
+        ref h => panic!("{:?}", h)
+    }
+    match gs.smask {
+        Some(SoftMaskEntry::Mask(ref mask)) => {
+            assert_eq!(mask.subtype, SoftMaskType::Luminosity);
+            assert_eq!(mask.group.get_inner(), group);
+            assert_eq!(mask.backdrop, Some(vec![0.5]));
+        }
+        ref m => panic!("{:?}", m)
+    }
+    assert_eq!(gs.fill_alpha, Some(0.5));
+
+    let gs = run!(saved.get(r2));
+    assert_eq!(gs.blend_mode, Some(BlendMode::Screen.into()));
+    assert!(matches!(gs.smask, Some(SoftMaskEntry::None)));
+
+    // unreadable entries don't fail the dictionary, and survive a write
+    let gs = run!(GraphicsStateParameters::from_primitive(run!(parse(b"<< /BM /Unknown /HT << /HalftoneType 42 >>
+        /TR /Bogus /BG 7 /UCR2 [1 2] /LW 2 >>", &file)), &file));
+    assert_eq!(gs.blend_mode.as_ref().unwrap().mode(), BlendMode::Normal);
+    assert!(gs.halftone.is_none() && gs.transfer.is_none());
+    assert!(gs.black_generation.is_none() && gs.undercolor_removal2.is_none());
+    assert_eq!(gs.line_width, Some(2.));
+    let r3 = run!(file.create(gs)).get_ref();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("write_graphics_states_lenient.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+    let gs = run!(saved.get(r3));
+    for key in ["HT", "TR", "BG", "UCR2"] {
+        assert!(gs.other.get(key).is_some(), "{}", key);
+    }
+    assert_eq!(gs.blend_mode, Some(BlendModes(vec!["Unknown".into()])));
+}
+
+#[test]
+fn transparency() {
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let xobject = |s: &str, file: &mut File<Vec<u8>>| {
+        let xobject = run!(XObject::from_primitive(run!(parse(s.as_bytes(), file)), file));
+        run!(file.create(xobject)).get_ref()
+    };
+    let group = xobject("<< /Type /XObject /Subtype /Form /BBox [0 0 1 1] /Length 0
+        /Group << /Type /Group /S /Transparency /I true /CS /DeviceRGB >> >> stream
+
+endstream", &mut file);
+    let image = xobject("<< /Type /XObject /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceGray
+        /BitsPerComponent 8 /SMaskInData 2 /Length 1 >> stream
+A
+endstream", &mut file);
+    let form = xobject(&format!("<< /Type /XObject /Subtype /Form /BBox [0 0 1 1] /Length 0
+        /Resources << /XObject << /Im1 {} 0 R >> >> >> stream
+
+endstream", image.get_inner().id), &mut file);
+
+    let resources = |gs: Option<GraphicsStateParameters>, xobject: Option<Ref<XObject>>| {
+        let mut resources = Resources::default();
+        if let Some(gs) = gs {
+            resources.graphics_states.insert("GS1".into(), gs);
+        }
+        if let Some(xobject) = xobject {
+            resources.xobjects.insert("X1".into(), xobject);
+        }
+        resources
+    };
+    let opaque = GraphicsStateParameters { fill_alpha: Some(1.), blend_mode: Some(BlendMode::Normal.into()), ..Default::default() };
+    let multiply = GraphicsStateParameters { blend_mode: Some(BlendMode::Multiply.into()), ..Default::default() };
+    let cases = [
+        (resources(Some(opaque), None), false),
+        (resources(Some(multiply), None), true),
+        (resources(None, Some(group)), true),
+        (resources(None, Some(image)), true),
+        (resources(None, Some(form)), true),
+    ];
+    for (resources, expected) in &cases {
+        assert_eq!(run!(resources.uses_transparency(&file)), *expected, "{:?}", resources);
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transparency.pdf");
+    run!(file.save_to(&path));
+    let saved = run!(File::<Vec<u8>>::open(&path));
+
+    match *run!(saved.get(group)) {
+        XObject::Form(ref form) => {
+            let group = form.dict().group.as_ref().unwrap();
+            assert!(group.isolated && !group.knockout);
+            assert!(matches!(group.color_space.as_deref(), Some(ColorSpace::DeviceRGB)));
+        }
+        ref x => panic!("{:?}", x)
+    }
+    match *run!(saved.get(image)) {
+        XObject::Image(ref image) => assert_eq!(image.soft_mask(), Some(ImageSoftMask::InData { premultiplied: true })),
+        ref x => panic!("{:?}", x)
+    }
+    let page = run!(saved.get_page(0));
+    assert!(page.group.is_none());
+
+    // a group without /S is a transparency group too
+    let group = run!(TransparencyGroup::from_primitive(run!(parse(b"<< /CS /DeviceGray >>", &file)), &file));
+    assert!(matches!(group.color_space.as_deref(), Some(ColorSpace::DeviceGray)));
+    assert!(matches!(run!(group.to_dict(&mut file)).get("S"), Some(pdf::primitive::Primitive::Name(s)) if s == "Transparency"));
+    run!(page.uses_transparency(&saved));
+}
+
+#[test]
+fn self_masked_image() {
+    let mut file = run!(File::<Vec<u8>>::open(file_path!("example.pdf")));
+    let promise = file.promise::<ImageXObject>();
+    let s = format!("<< /Type /XObject /Subtype /Image /Width 2 /Height 1 /ColorSpace /DeviceGray
+        /BitsPerComponent 8 /SMask {} 0 R /Length 2 >> stream
+AB
+endstream", promise.get_inner().id);
+    let image = run!(ImageXObject::from_primitive(run!(parse(s.as_bytes(), &file)), &file));
+    let image = run!(file.fulfill(promise, image));
+    let raster = run!(image.raster(&file, None));
+    assert_eq!(raster.format, pdf::object::PixelFormat::Rgba);
+    assert_eq!(raster.data, [65, 65, 65, 65, 66, 66, 66, 66]);
+}
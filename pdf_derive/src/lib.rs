@@ -32,7 +32,8 @@
 //! ```
 //!
 //! This enforces that the dictionary's `/Type` entry is present and equals `/XObject`, and that the
-//! `/Subtype` entry is present and equals `/Image`.
+//! `/Subtype` entry is present and equals `/Image`. A trailing `?`, as in `Subtype="Image?"`,
+//! allows the entry to be missing.
 //!
 //! Each field in the struct needs to implement `Object`. Implementation is provided already for
 //! common types like i32, f32, usize, bool, String (from Primitive::Name), Option<T> and Vec<T>.
@@ -61,6 +62,9 @@
 //! }
 //! ```
 //!
+//! A field marked `lenient` takes its default value when the entry can't be read, instead of
+//! failing the whole dictionary. The unreadable entry is then kept for the `other` field.
+//!
 //!
 //! ## 2. Struct from PDF Stream
 //! PDF Streams consist of a stream dictionary along with the stream itself. It is assumed that all
@@ -132,7 +136,8 @@ struct FieldAttrs {
     default: Option<LitStr>,
     name: Option<LitStr>,
     skip: bool,
-    other: bool
+    other: bool,
+    lenient: bool,
 }
 impl FieldAttrs {
     fn new() -> FieldAttrs {
@@ -141,7 +146,8 @@ impl FieldAttrs {
             default: None,
             name: None,
             skip: false,
-            other: false
+            other: false,
+            lenient: false,
         }
     }
     fn key(&self) -> &LitStr {
@@ -173,7 +179,8 @@ impl FieldAttrs {
                     },
                     NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("skip") => attrs.skip = true,
                     NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("other") => attrs.other = true,
-                    _ => panic!(r##"Derive error - Supported derive attributes: `key="Key"`, `default="some code"`."##)
+                    NestedMeta::Meta(Meta::Path(ref path)) if path.is_ident("lenient") => attrs.lenient = true,
+                    _ => panic!(r##"Derive error - Supported derive attributes: `key="Key"`, `default="some code"`, `name`, `skip`, `other`, `lenient`."##)
                 }
             }
         }
@@ -187,8 +194,9 @@ impl FieldAttrs {
 /// Just the attributes for the whole struct
 #[derive(Default)]
 struct GlobalAttrs {
-    /// List of checks to do in the dictionary (LHS is the key, RHS is the expected value)
-    checks: Vec<(String, String)>,
+    /// List of checks to do in the dictionary (LHS is the key, RHS is the expected value,
+    /// and whether the key is required)
+    checks: Vec<(String, String, bool)>,
     type_name: Option<String>,
     type_required: bool,
     is_stream: bool
@@ -226,7 +234,17 @@ impl GlobalAttrs {
                             }
                         } else {
                             match lit {
-                                Lit::Str(ref value) => attrs.checks.push((path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<String>>().join("::"), value.value())),
+                                Lit::Str(ref value) => {
+                                    let key = path.segments.iter().map(|s| s.ident.to_string()).collect::<Vec<String>>().join("::");
+                                    let mut value = value.value();
+                                    let required = if value.ends_with("?") {
+                                        value.pop(); // remove '?'
+                                        false
+                                    } else {
+                                        true
+                                    };
+                                    attrs.checks.push((key, value, required));
+                                }
                                 _ => panic!("Other checks must have RHS String."),
                             }
                         }
@@ -256,6 +274,7 @@ fn impl_objectwrite(ast: &DeriveInput) -> TokenStream {
     match (attrs.is_stream, &ast.data) {
         (false, Data::Struct(ref data)) => impl_objectwrite_for_struct(ast, &data.fields).into(),
         (false, Data::Enum(ref variants)) => impl_objectwrite_for_enum(ast, variants).into(),
+        (true, Data::Enum(ref variants)) => impl_enum_to_stream(ast, variants).into(),
         (_, _) => unimplemented!()
     }
 }
@@ -507,7 +526,9 @@ fn impl_object_for_struct(ast: &DeriveInput, fields: &Fields) -> SynStream {
         let name = &field.ident;
         let attrs = FieldAttrs::parse(&field.attrs);
         if attrs.skip {
-            return quote! {}
+            return quote! {
+                let #name = Default::default();
+            }
         }
         if attrs.other {
             return quote! {
@@ -518,6 +539,21 @@ fn impl_object_for_struct(ast: &DeriveInput, fields: &Fields) -> SynStream {
         let key = attrs.key();
 
         let ty = field.ty.clone();
+        if attrs.lenient {
+            // an entry that can't be read is left in the dictionary, for an `other` field
+            return quote! {
+                let #name: #ty = match dict.remove(#key) {
+                    Some(primitive) => match <#ty as pdf::object::Object>::from_primitive(primitive.clone(), resolve) {
+                        Ok(obj) => obj,
+                        Err(_) => {
+                            dict.insert(#key, primitive);
+                            Default::default()
+                        }
+                    },
+                    None => Default::default()
+                };
+            };
+        }
         if let Some(ref default) = attrs.default() {
             quote! {
                 let #name = {
@@ -569,9 +605,9 @@ fn impl_object_for_struct(ast: &DeriveInput, fields: &Fields) -> SynStream {
         quote! { #name: #name, }
     });
 
-    let checks: Vec<_> = attrs.checks.iter().map(|&(ref key, ref val)|
+    let checks: Vec<_> = attrs.checks.iter().map(|&(ref key, ref val, required)|
         quote! {
-            dict.expect(#typ, #key, #val, true)?;
+            dict.expect(#typ, #key, #val, #required)?;
         }
     ).collect();
 
@@ -631,7 +667,16 @@ fn impl_objectwrite_for_struct(ast: &DeriveInput, fields: &Fields) -> SynStream
             }
         }
     );
-    let checks_code = attrs.checks.iter().map(|&(ref key, ref val)|
+    let other_ser = parts.iter()
+    .filter(|(_, attrs, _)| attrs.other)
+    .map(|(field, _, _)|
+        quote! {
+            for (key, val) in self.#field.iter() {
+                dict.insert(key.clone(), val.clone());
+            }
+        }
+    );
+    let checks_code = attrs.checks.iter().map(|&(ref key, ref val, _)|
         quote! {
             dict.insert(#key, pdf::primitive::Primitive::Name(#val.into()));
         }
@@ -652,6 +697,7 @@ fn impl_objectwrite_for_struct(ast: &DeriveInput, fields: &Fields) -> SynStream
         impl #impl_generics pdf::object::ToDict for #id #ty_generics #where_clause {
             fn to_dict(&self, updater: &mut impl pdf::object::Updater) -> Result<pdf::primitive::Dictionary> {
                 let mut dict = pdf::primitive::Dictionary::new();
+                #(#other_ser)*
                 #pdf_type
                 #( #checks_code )*
                 #(#fields_ser)*
@@ -661,6 +707,29 @@ fn impl_objectwrite_for_struct(ast: &DeriveInput, fields: &Fields) -> SynStream
     }
 }
 
+/// Writes the stream of whichever variant `self` is.
+fn impl_enum_to_stream(ast: &DeriveInput, data: &DataEnum) -> SynStream {
+    let id = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let variants_code = data.variants.iter().map(|var| {
+        let variant_ident = &var.ident;
+        quote! {
+            #id::#variant_ident (ref inner) => pdf::object::ObjectWrite::to_primitive(inner, update)
+        }
+    });
+
+    quote! {
+        impl #impl_generics pdf::object::ObjectWrite for #id #ty_generics #where_clause {
+            fn to_primitive(&self, update: &mut impl pdf::object::Updater) -> Result<pdf::primitive::Primitive> {
+                match *self {
+                    #( #variants_code, )*
+                }
+            }
+        }
+    }
+}
+
 /// Note: must have info and dict (TODO explain in docs)
 fn impl_object_for_stream(ast: &DeriveInput, fields: &Fields) -> SynStream {
     let id = &ast.ident;